@@ -208,7 +208,7 @@ impl AdbMonitor {
     fn start_adb_daemon() -> bool {
         info!(target: TAG, "Restarting adb daemon");
         match process::Command::new("adb")
-            .args(&["start-server"])
+            .args(["start-server"])
             .status()
         {
             Ok(exit_status) => {
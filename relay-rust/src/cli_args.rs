@@ -19,6 +19,8 @@ pub const PARAM_SERIAL: u8 = 1;
 pub const PARAM_DNS_SERVERS: u8 = 1 << 1;
 pub const PARAM_ROUTES: u8 = 1 << 2;
 pub const PARAM_PORT: u8 = 1 << 3;
+pub const PARAM_CONFIG: u8 = 1 << 4;
+pub const PARAM_FILE: u8 = 1 << 5;
 
 pub const DEFAULT_PORT: u16 = 31416;
 
@@ -27,6 +29,8 @@ pub struct CommandLineArguments {
     dns_servers: Option<String>,
     routes: Option<String>,
     port: u16,
+    config: Option<String>,
+    file: Option<String>,
 }
 
 impl CommandLineArguments {
@@ -36,6 +40,8 @@ impl CommandLineArguments {
         let mut dns_servers = None;
         let mut routes = None;
         let mut port = 0;
+        let mut config = None;
+        let mut file = None;
 
         let mut iter = args.into_iter();
         while let Some(arg) = iter.next() {
@@ -70,8 +76,19 @@ impl CommandLineArguments {
                 } else {
                     return Err(String::from("Missing -p parameter"));
                 }
+            } else if (accepted_parameters & PARAM_CONFIG) != 0 && "-c" == arg {
+                if config.is_some() {
+                    return Err(String::from("Config file already set"));
+                }
+                if let Some(value) = iter.next() {
+                    config = Some(value.into());
+                } else {
+                    return Err(String::from("Missing -c parameter"));
+                }
             } else if (accepted_parameters & PARAM_SERIAL) != 0 && serial.is_none() {
                 serial = Some(arg);
+            } else if (accepted_parameters & PARAM_FILE) != 0 && file.is_none() {
+                file = Some(arg);
             } else {
                 return Err(format!("Unexpected argument: \"{}\"", arg));
             }
@@ -84,6 +101,8 @@ impl CommandLineArguments {
             dns_servers,
             routes,
             port,
+            config,
+            file,
         })
     }
 
@@ -102,6 +121,14 @@ impl CommandLineArguments {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    pub fn config(&self) -> Option<&str> {
+        self.config.as_deref()
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
 }
 
 #[cfg(test)]
@@ -178,4 +205,26 @@ mod tests {
         let raw_args = vec!["-r"];
         assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
     }
+
+    #[test]
+    fn test_config_parameter() {
+        let raw_args = vec!["-c", "relay.toml", "-p", "1234"];
+        let args = CommandLineArguments::parse(PARAM_CONFIG | PARAM_PORT, raw_args).unwrap();
+        assert_eq!("relay.toml", args.config.unwrap());
+        assert_eq!(1234, args.port);
+    }
+
+    #[test]
+    fn test_file_parameter() {
+        let raw_args = vec!["capture.pcap", "-c", "relay.toml"];
+        let args = CommandLineArguments::parse(PARAM_FILE | PARAM_CONFIG, raw_args).unwrap();
+        assert_eq!("capture.pcap", args.file.unwrap());
+        assert_eq!("relay.toml", args.config.unwrap());
+    }
+
+    #[test]
+    fn test_config_parameter_not_accepted() {
+        let raw_args = vec!["-c", "relay.toml"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
 }
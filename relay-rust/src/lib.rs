@@ -16,10 +16,18 @@
 
 mod relay;
 pub use crate::relay::byte_buffer;
+pub use crate::relay::{
+    replay_pcap, Cidr, CidrSet, ClosedConnection, ConnectionEvents, ConnectionId, ConnectionStats,
+    Listener, LogFilter, NextEvent, OpenStage, OpenedConnection, Protocol, QueuePolicy, Relay,
+    RelayBuilder, RelayConfig, RelayTask, ReplayReport, ShutdownHandle, UnknownProtocolPolicy,
+};
 
-use crate::relay::Relay;
 use std::io;
 
 pub fn relay(port: u16) -> io::Result<()> {
-    Relay::new(port).run()
+    relay_with_config(RelayConfig::new(port))
+}
+
+pub fn relay_with_config(config: RelayConfig) -> io::Result<()> {
+    Relay::new(config).run()
 }
@@ -16,16 +16,16 @@
 
 use chrono::prelude::Local;
 use log::*;
+use relaylib::LogFilter;
 use std::io::{self, Write};
 
-static LOGGER: SimpleLogger = SimpleLogger;
-const THRESHOLD: LevelFilter = LevelFilter::Info;
-
-pub struct SimpleLogger;
+pub struct SimpleLogger {
+    filter: LogFilter,
+}
 
 impl Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= THRESHOLD
+        self.filter.enabled(metadata.target(), metadata.level())
     }
 
     fn log(&self, record: &Record) {
@@ -53,7 +53,8 @@ impl Log for SimpleLogger {
     }
 }
 
-pub fn init() -> Result<(), SetLoggerError> {
-    set_max_level(THRESHOLD);
-    set_logger(&LOGGER)
+pub fn init(filter: LogFilter) -> Result<(), SetLoggerError> {
+    set_max_level(filter.max_level());
+    // the logger must live until the end of the program
+    set_logger(Box::leak(Box::new(SimpleLogger { filter })))
 }
@@ -28,7 +28,10 @@ mod logger;
 use crate::adb_monitor::AdbMonitor;
 use crate::cli_args::CommandLineArguments;
 use crate::execution_error::{Cmd, CommandExecutionError, ProcessIoError, ProcessStatusError};
+use relaylib::{LogFilter, Relay, RelayConfig};
 use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::{self, exit};
 use std::thread;
 // use std::time::Duration;
@@ -54,6 +57,11 @@ fn get_apk_path() -> String {
     }
 }
 
+#[inline]
+fn get_connection_log_path() -> Option<PathBuf> {
+    std::env::var_os("GNIREHTET_CONNECTION_LOG").map(PathBuf::from)
+}
+
 const COMMANDS: &[&dyn Command] = &[
     &InstallCommand,
     &UninstallCommand,
@@ -66,6 +74,7 @@ const COMMANDS: &[&dyn Command] = &[
     &RestartCommand,
     &TunnelCommand,
     &RelayCommand,
+    &ReplayCommand,
 ];
 
 trait Command {
@@ -86,6 +95,7 @@ struct StopCommand;
 struct RestartCommand;
 struct TunnelCommand;
 struct RelayCommand;
+struct ReplayCommand;
 
 impl Command for InstallCommand {
     fn command(&self) -> &'static str {
@@ -155,6 +165,7 @@ impl Command for RunCommand {
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
             | cli_args::PARAM_PORT
+            | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
@@ -171,6 +182,7 @@ impl Command for RunCommand {
             args.dns_servers(),
             args.routes(),
             args.port(),
+            args.config(),
         )
     }
 }
@@ -181,7 +193,10 @@ impl Command for AutorunCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_DNS_SERVERS | cli_args::PARAM_ROUTES | cli_args::PARAM_PORT
+        cli_args::PARAM_DNS_SERVERS
+            | cli_args::PARAM_ROUTES
+            | cli_args::PARAM_PORT
+            | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
@@ -191,7 +206,12 @@ impl Command for AutorunCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_autorun(args.dns_servers(), args.routes(), args.port())
+        cmd_autorun(
+            args.dns_servers(),
+            args.routes(),
+            args.port(),
+            args.config(),
+        )
     }
 }
 
@@ -328,19 +348,44 @@ impl Command for RelayCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_NONE | cli_args::PARAM_PORT
+        cli_args::PARAM_NONE | cli_args::PARAM_PORT | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
-        "Start the relay server in the current terminal."
+        "Start the relay server in the current terminal.\n\
+         If -c is given, then read the relay settings from the specified\n\
+         TOML file."
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_relay(args.port())?;
+        cmd_relay(args.port(), args.config(), || ())?;
         Ok(())
     }
 }
 
+impl Command for ReplayCommand {
+    fn command(&self) -> &'static str {
+        "replay"
+    }
+
+    fn accepted_parameters(&self) -> u8 {
+        cli_args::PARAM_FILE | cli_args::PARAM_CONFIG
+    }
+
+    fn description(&self) -> &'static str {
+        "Feed the raw IP packets of a pcap file through the relay\n\
+         parsers, without opening any connection, and report the\n\
+         routing decision of every packet."
+    }
+
+    fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
+        let path = args.file().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Missing pcap file to replay")
+        })?;
+        cmd_replay(path, args.config())
+    }
+}
+
 fn cmd_install(serial: Option<&str>) -> Result<(), CommandExecutionError> {
     info!(target: TAG, "Installing gnirehtet client...");
     exec_adb(serial, vec!["install".into(), "-r".into(), get_apk_path()])
@@ -362,30 +407,25 @@ fn cmd_run(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config_path: Option<&str>,
 ) -> Result<(), CommandExecutionError> {
     // start in parallel so that the relay server is ready when the client connects
     async_start(serial, dns_servers, routes, port);
 
     let ctrlc_serial = serial.map(String::from);
-    ctrlc::set_handler(move || {
-        info!(target: TAG, "Interrupted");
-
+    cmd_relay(port, config_path, move || {
         let serial = ctrlc_serial.as_ref().map(String::as_ref);
         if let Err(err) = cmd_stop(serial) {
             error!(target: TAG, "Cannot stop client: {}", err);
         }
-
-        exit(0);
     })
-    .expect("Error setting Ctrl-C handler");
-
-    cmd_relay(port)
 }
 
 fn cmd_autorun(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config_path: Option<&str>,
 ) -> Result<(), CommandExecutionError> {
     {
         let autostart_dns_servers = dns_servers.map(String::from);
@@ -399,7 +439,7 @@ fn cmd_autorun(
         });
     }
 
-    cmd_relay(port)
+    cmd_relay(port, config_path, || ())
 }
 
 #[allow(unused_variables)]
@@ -481,9 +521,44 @@ fn cmd_tunnel(serial: Option<&str>, port: u16) -> Result<(), CommandExecutionErr
     )
 }
 
-fn cmd_relay(port: u16) -> Result<(), CommandExecutionError> {
+/// Replay the client packets captured in the pcap file `path` through the relay, without any
+/// device.
+fn cmd_replay(path: &str, config_path: Option<&str>) -> Result<(), CommandExecutionError> {
+    info!(target: TAG, "Replaying {}...", path);
+    let config = match config_path {
+        Some(config_path) => RelayConfig::from_toml_file(cli_args::DEFAULT_PORT, config_path)?,
+        None => RelayConfig::new(cli_args::DEFAULT_PORT),
+    };
+    relaylib::replay_pcap(Path::new(path), config)?;
+    Ok(())
+}
+
+/// Run the relay server until interrupted, calling `on_interrupt` before it is stopped.
+fn cmd_relay<F>(
+    port: u16,
+    config_path: Option<&str>,
+    on_interrupt: F,
+) -> Result<(), CommandExecutionError>
+where
+    F: Fn() + Send + 'static,
+{
     info!(target: TAG, "Starting relay server on port {}...", port);
-    relaylib::relay(port)?;
+    let mut config = match config_path {
+        Some(config_path) => RelayConfig::from_toml_file(port, config_path)?,
+        None => RelayConfig::new(port),
+    };
+    if let Some(connection_log_path) = get_connection_log_path() {
+        config.set_connection_log_path(Some(connection_log_path));
+    }
+    let relay = Relay::new(config);
+    let shutdown_handle = relay.shutdown_handle();
+    ctrlc::set_handler(move || {
+        info!(target: TAG, "Interrupted");
+        on_interrupt();
+        shutdown_handle.shutdown();
+    })
+    .expect("Error setting Ctrl-C handler");
+    relay.run()?;
     Ok(())
 }
 
@@ -554,7 +629,7 @@ fn must_install_client(serial: Option<&str>) -> Result<bool, CommandExecutionErr
                 // read the versionCode of the installed package
                 if let Some(index) = dumpsys.find("    versionCode=") {
                     let start = index + 16; // size of "    versionCode=\""
-                    if let Some(end) = (&dumpsys[start..]).find(' ') {
+                    if let Some(end) = dumpsys[start..].find(' ') {
                         let installed_version_code = &dumpsys[start..start + end];
                         Ok(installed_version_code != REQUIRED_APK_VERSION_CODE)
                     } else {
@@ -599,6 +674,9 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_SERIAL) != 0 {
         msg.push_str(" [serial]");
     }
+    if (accepted_parameters & cli_args::PARAM_FILE) != 0 {
+        msg.push_str(" FILE");
+    }
     if (accepted_parameters & cli_args::PARAM_DNS_SERVERS) != 0 {
         msg.push_str(" [-d DNS[,DNS2,...]]");
     }
@@ -608,6 +686,9 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_ROUTES) != 0 {
         msg.push_str(" [-r ROUTE[,ROUTE2,...]]");
     }
+    if (accepted_parameters & cli_args::PARAM_CONFIG) != 0 {
+        msg.push_str(" [-c CONFIG]");
+    }
     msg.push('\n');
     for desc_line in command.description().split('\n') {
         msg.push_str("      ");
@@ -622,8 +703,19 @@ fn print_command_usage(command: &dyn Command) {
     eprint!("{}", msg);
 }
 
+/// Read the log levels per target from the `RUST_LOG` environment variable, if any.
+fn log_filter() -> LogFilter {
+    match env::var("RUST_LOG") {
+        Ok(spec) => LogFilter::parse(&spec).unwrap_or_else(|err| {
+            eprintln!("Ignoring RUST_LOG: {}", err);
+            LogFilter::default()
+        }),
+        Err(_) => LogFilter::default(),
+    }
+}
+
 fn main() {
-    logger::init().unwrap();
+    logger::init(log_filter()).unwrap();
     let mut args = env::args();
     // args.nth(1) will consume the two first arguments (the binary name and the command name)
     if let Some(command_name) = args.nth(1) {
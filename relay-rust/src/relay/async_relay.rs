@@ -0,0 +1,342 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use super::connection::ClosedConnection;
+use super::relay::{Relay, ShutdownHandle};
+use super::relay_config::RelayConfig;
+
+// the oldest events are kept, the next ones are dropped until some are read
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+#[derive(Default)]
+struct Completion {
+    result: Option<io::Result<()>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<ClosedConnection>,
+    dropped_events: u64,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+impl EventQueue {
+    fn push(&mut self, closed: &ClosedConnection) {
+        if self.events.len() < MAX_QUEUED_EVENTS {
+            self.events.push_back(closed.clone());
+        } else {
+            self.dropped_events += 1;
+        }
+    }
+}
+
+/// Relay running on a dedicated thread, as a future completing when it stops.
+///
+/// This allows to embed the relay in an async application (for instance on Tokio) without
+/// blocking its executor: the relay keeps its own poll loop, and only notifies the tasks awaiting
+/// it.
+///
+/// Dropping the task stops the relay, and waits for its thread to finish.
+pub struct RelayTask {
+    completion: Arc<Mutex<Completion>>,
+    shutdown_handle: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Connections closed by a relay started by `RelayTask::spawn()`, in order.
+///
+/// The events are buffered until they are read (up to 1024, the next ones are dropped); they are
+/// not recorded anymore once this is dropped.
+pub struct ConnectionEvents {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+/// Future returned by `ConnectionEvents::next_event()`.
+pub struct NextEvent<'a> {
+    events: &'a mut ConnectionEvents,
+}
+
+impl RelayTask {
+    /// Start a relay on a new thread.
+    ///
+    /// The errors of the relay itself (for instance if its port is not available) are the result
+    /// of the task.
+    pub fn spawn(config: RelayConfig) -> io::Result<(Self, ConnectionEvents)> {
+        let completion = Arc::new(Mutex::new(Completion::default()));
+        let queue = Arc::new(Mutex::new(EventQueue::default()));
+        let (handle_sender, handle_receiver) = mpsc::channel();
+        let thread = {
+            let completion = completion.clone();
+            // the events are only queued while they may be read
+            let queue = Arc::downgrade(&queue);
+            thread::Builder::new()
+                .name("relay".to_string())
+                .spawn(move || {
+                    // the relay is not Send, create it on its thread
+                    let mut relay = Relay::new(config);
+                    let listener_queue = queue.clone();
+                    relay.add_connection_close_listener(move |closed: &ClosedConnection| {
+                        notify(&listener_queue, |queue| queue.push(closed));
+                    });
+                    if handle_sender.send(relay.shutdown_handle()).is_err() {
+                        return;
+                    }
+                    let result = relay.run();
+                    notify(&queue, |queue| queue.finished = true);
+                    let waker = {
+                        let mut completion = completion.lock().unwrap();
+                        completion.result = Some(result);
+                        completion.waker.take()
+                    };
+                    wake(waker);
+                })?
+        };
+        let shutdown_handle = handle_receiver
+            .recv()
+            .map_err(|_| io::Error::other("The relay thread stopped"))?;
+        let task = Self {
+            completion,
+            shutdown_handle,
+            thread: Some(thread),
+        };
+        Ok((task, ConnectionEvents { queue }))
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+}
+
+impl Drop for RelayTask {
+    fn drop(&mut self) {
+        self.shutdown_handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            // the relay thread does not panic on its own, the result is already reported
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Future for RelayTask {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut completion = self.completion.lock().unwrap();
+        match completion.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                completion.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl ConnectionEvents {
+    /// Poll the next connection closed, or `None` once the relay is stopped.
+    ///
+    /// This is the signature of `futures::Stream::poll_next()`, so that the events may be wrapped
+    /// into a stream, for instance with `futures::stream::poll_fn()`.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<ClosedConnection>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(closed) = queue.events.pop_front() {
+            Poll::Ready(Some(closed))
+        } else if queue.finished {
+            Poll::Ready(None)
+        } else {
+            queue.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Wait for the next connection closed, or `None` once the relay is stopped.
+    pub fn next_event(&mut self) -> NextEvent<'_> {
+        NextEvent { events: self }
+    }
+
+    /// Number of connections closed while the buffer of the events not read yet was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.queue.lock().unwrap().dropped_events
+    }
+}
+
+impl Future for NextEvent<'_> {
+    type Output = Option<ClosedConnection>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.events.poll_next(cx)
+    }
+}
+
+// update the queue, unless the events are not read anymore, and wake the reader
+fn notify<F: FnOnce(&mut EventQueue)>(queue: &Weak<Mutex<EventQueue>>, update: F) {
+    if let Some(queue) = queue.upgrade() {
+        let waker = {
+            let mut queue = queue.lock().unwrap();
+            update(&mut queue);
+            queue.waker.take()
+        };
+        wake(waker);
+    }
+}
+
+// wake the task outside of the lock, it may be polled immediately on another thread
+fn wake(waker: Option<Waker>) {
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::client::tests::create_udp_packet;
+    use crate::relay::connection::{ConnectionId, ConnectionStats};
+    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket};
+    use std::task::Wake;
+    use std::time::Duration;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // minimal executor, to run a future on the current thread
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn connect_device(port: u16) -> TcpStream {
+        // the relay binds its port asynchronously
+        for _ in 0..100 {
+            if let Ok(device) = TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+                return device;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Cannot connect to the relay");
+    }
+
+    #[test]
+    fn relay_packet_with_async_api() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (task, mut events) = RelayTask::spawn(RelayConfig::new(port)).unwrap();
+
+        let mut device = connect_device(port);
+        device
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        device
+            .write_all(&create_udp_packet(server_port, b"ping"))
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let (r, source) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf[..r]);
+        server.send_to(b"pong", source).unwrap();
+
+        // the client id, then the reply
+        let mut id = [0u8; 4];
+        device.read_exact(&mut id).unwrap();
+        let mut raw = vec![0u8; 32];
+        device.read_exact(&mut raw).unwrap();
+        assert_eq!(b"pong", Ipv4Packet::parse(&mut raw).payload().unwrap());
+
+        // stopping the relay closes the connection
+        task.shutdown_handle().shutdown();
+        let closed = block_on(events.next_event()).unwrap();
+        assert_eq!(Protocol::Udp, closed.id.protocol());
+        assert_eq!(server_port, closed.id.destination().port());
+        assert!(block_on(events.next_event()).is_none());
+        block_on(task).unwrap();
+    }
+
+    #[test]
+    fn stop_relay_on_drop() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (task, mut events) = RelayTask::spawn(RelayConfig::new(port)).unwrap();
+        let completion = task.completion.clone();
+        drop(task);
+        // the relay thread is finished
+        assert!(completion.lock().unwrap().result.is_some());
+        assert!(block_on(events.next_event()).is_none());
+    }
+
+    #[test]
+    fn bound_event_queue() {
+        let mut raw = create_udp_packet(53, b"");
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let closed = ClosedConnection {
+            id: ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap()),
+            stats: ConnectionStats::default(),
+            expired: false,
+        };
+
+        let queue = Arc::new(Mutex::new(EventQueue::default()));
+        let weak = Arc::downgrade(&queue);
+        for _ in 0..MAX_QUEUED_EVENTS + 2 {
+            notify(&weak, |queue| queue.push(&closed));
+        }
+        let events = ConnectionEvents { queue };
+        assert_eq!(2, events.dropped_events());
+        assert_eq!(MAX_QUEUED_EVENTS, events.queue.lock().unwrap().events.len());
+
+        // nothing is queued once the events are not read anymore
+        drop(events);
+        notify(&weak, |queue| queue.push(&closed));
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -19,6 +19,7 @@ use std::cmp::min;
 use std::fmt::Write;
 
 const MAX_STRING_PACKET_SIZE: usize = 24;
+const MAX_TRACE_PACKET_SIZE: usize = 64;
 
 pub fn to_byte_array(value: u32) -> [u8; 4] {
     let mut raw = [0u8; 4];
@@ -27,8 +28,19 @@ pub fn to_byte_array(value: u32) -> [u8; 4] {
 }
 
 pub fn build_packet_string(data: &[u8]) -> String {
+    build_packet_string_with(data, MAX_STRING_PACKET_SIZE, false)
+}
+
+/// Dump the beginning of `data` for the connections trace logs, with its ASCII representation.
+pub fn build_trace_packet_string(data: &[u8]) -> String {
+    build_packet_string_with(data, MAX_TRACE_PACKET_SIZE, true)
+}
+
+/// Dump at most `max_bytes` of `data` in hexadecimal, followed by their ASCII representation if
+/// `ascii` is set (like `hexdump -C`, non-printable bytes are replaced by `.`).
+pub fn build_packet_string_with(data: &[u8], max_bytes: usize, ascii: bool) -> String {
     let mut s = String::new();
-    let limit = min(MAX_STRING_PACKET_SIZE, data.len());
+    let limit = min(max_bytes, data.len());
     for (i, &byte) in data.iter().take(limit).enumerate() {
         if i != 0 {
             let sep = if (i % 4) == 0 { "  " } else { " " };
@@ -36,6 +48,17 @@ pub fn build_packet_string(data: &[u8]) -> String {
         }
         write!(&mut s, "{:02X}", byte).unwrap();
     }
+    if ascii && limit != 0 {
+        s.push_str("  |");
+        s.extend(data[..limit].iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        s.push('|');
+    }
     if limit < data.len() {
         write!(&mut s, "  ... +{} bytes", data.len() - limit).unwrap();
     }
@@ -50,3 +73,31 @@ pub fn ptr_data_eq<T: ?Sized>(lhs: *const T, rhs: *const T) -> bool {
     // cast to thin pointers to ignore the vtable part
     lhs as *const () == rhs as *const ()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_short_packet_string() {
+        let data = b"\x45\x00GET /\n";
+        assert_eq!(
+            "45 00 47 45  54 20 2F 0A  |E.GET /.|",
+            build_packet_string_with(data, 16, true)
+        );
+        assert_eq!("45 00 47 45  54 20 2F 0A", build_packet_string(data));
+    }
+
+    #[test]
+    fn build_truncated_packet_string() {
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(
+            "00 01 02 03  04 05  ... +94 bytes",
+            build_packet_string_with(&data, 6, false)
+        );
+        assert_eq!(
+            "2E 2F 30 31  |./01|  ... +50 bytes",
+            build_packet_string_with(&data[46..], 4, true)
+        );
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::Ipv6Addr;
+
+/// Sum of the IPv4 pseudo-header used by TCP and UDP checksums (cf rfc793 section 3.1).
+pub fn ipv4_pseudo_header_sum(source: u32, destination: u32, protocol: u8, length: u16) -> u32 {
+    u32::from(protocol)
+        + (source >> 16)
+        + (source & 0xFFFF)
+        + (destination >> 16)
+        + (destination & 0xFFFF)
+        + u32::from(length)
+}
+
+/// Sum of the IPv6 pseudo-header used by upper-layer checksums (cf rfc8200 section 8.1).
+pub fn ipv6_pseudo_header_sum(
+    source: &Ipv6Addr,
+    destination: &Ipv6Addr,
+    next_header: u8,
+    length: u32,
+) -> u32 {
+    let addresses_sum = source
+        .segments()
+        .iter()
+        .chain(destination.segments().iter())
+        .map(|&segment| u32::from(segment))
+        .sum::<u32>();
+    addresses_sum + (length >> 16) + (length & 0xFFFF) + u32::from(next_header)
+}
+
+/// Sum of the 16-bit words of `raw` (the last odd byte, if any, is padded).
+pub fn sum(raw: &[u8]) -> u32 {
+    raw.chunks(2)
+        .map(|chunk| {
+            if chunk.len() == 2 {
+                u32::from(BigEndian::read_u16(chunk))
+            } else {
+                u32::from(chunk[0]) << 8
+            }
+        })
+        .sum::<u32>()
+}
+
+/// Update `checksum` after a 16-bit word of the data changed from `old` to `new` (cf rfc1624
+/// section 3, eqn. 3).
+pub fn update_incremental(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold(u32::from(!checksum) + u32::from(!old) + u32::from(new))
+}
+
+/// Fold a sum into its 16-bit one's complement representation.
+pub fn fold(mut sum: u32) -> u16 {
+    while (sum & !0xFFFF) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_carries() {
+        assert_eq!(0x0001, fold(0x0001_0000));
+        assert_eq!(0xFFFF, fold(0xFFFE + 0x0001));
+        assert_eq!(0x0003, fold(0x0001_FFFF + 0x0002));
+    }
+
+    #[test]
+    fn incremental_update() {
+        // checksum of [0x0001, 0x0002]
+        let checksum = !fold(0x0003);
+        // checksum of [0x0001, 0x1234]
+        assert_eq!(!fold(0x1235), update_incremental(checksum, 0x0002, 0x1234));
+    }
+
+    #[test]
+    fn ipv6_pseudo_header() {
+        let source = "fe80::1".parse().unwrap();
+        let destination = "fe80::2".parse().unwrap();
+        // 0xfe80 * 2 + 1 + 2 + length + next header
+        assert_eq!(
+            0xFE80 * 2 + 3 + 12 + 58,
+            ipv6_pseudo_header_sum(&source, &destination, 58, 12)
+        );
+    }
+}
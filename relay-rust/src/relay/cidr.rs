@@ -0,0 +1,178 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Range of IPv4 addresses, written `address/prefix_length` (a bare address is a /32).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: u32,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn new(address: Ipv4Addr, prefix_length: u8) -> Self {
+        assert!(
+            prefix_length <= 32,
+            "Invalid prefix length: {}",
+            prefix_length
+        );
+        Self {
+            network: u32::from(address) & Self::mask(prefix_length),
+            prefix_length,
+        }
+    }
+
+    fn mask(prefix_length: u8) -> u32 {
+        // a shift by 32 would overflow
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_length))
+            .unwrap_or(0)
+    }
+
+    fn first(&self) -> u32 {
+        self.network
+    }
+
+    fn last(&self) -> u32 {
+        self.network | !Self::mask(self.prefix_length)
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address & Self::mask(self.prefix_length) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CIDR range: {}", s);
+        let (address, prefix_length) = match s.find('/') {
+            Some(index) => {
+                let prefix_length = s[index + 1..].parse().map_err(|_| invalid())?;
+                (&s[..index], prefix_length)
+            }
+            None => (s, 32),
+        };
+        if prefix_length > 32 {
+            return Err(invalid());
+        }
+        let address = address.parse().map_err(|_| invalid())?;
+        Ok(Self::new(address, prefix_length))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.network), self.prefix_length)
+    }
+}
+
+/// Set of IPv4 ranges, parsed from a comma-separated list (e.g. `"10.0.0.0/8, 192.168.0.0/16"`).
+///
+/// The ranges are merged and sorted, so that an address is matched by a binary search, whatever
+/// the number of ranges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CidrSet {
+    // disjoint (first, last) bounds, in increasing order
+    ranges: Vec<(u32, u32)>,
+}
+
+impl CidrSet {
+    pub fn new<I: IntoIterator<Item = Cidr>>(cidrs: I) -> Self {
+        let mut bounds: Vec<_> = cidrs
+            .into_iter()
+            .map(|cidr| (cidr.first(), cidr.last()))
+            .collect();
+        bounds.sort_unstable();
+        let mut ranges: Vec<(u32, u32)> = Vec::with_capacity(bounds.len());
+        for (first, last) in bounds {
+            match ranges.last_mut() {
+                // overlapping or adjacent
+                Some(previous) if u64::from(first) <= u64::from(previous.1) + 1 => {
+                    previous.1 = previous.1.max(last);
+                }
+                _ => ranges.push((first, last)),
+            }
+        }
+        Self { ranges }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        // the range starting the closest before the address is the only candidate
+        let index = self.ranges.partition_point(|&(first, _)| first <= address);
+        index > 0 && address <= self.ranges[index - 1].1
+    }
+}
+
+impl FromStr for CidrSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cidrs = s
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Cidr>, _>>()?;
+        Ok(Self::new(cidrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!("10.0.0.0/8", cidr.to_string());
+        assert!(cidr.contains(0x0A010203));
+        assert!(!cidr.contains(0x0B000000));
+
+        let cidr: Cidr = "1.1.1.1".parse().unwrap();
+        assert_eq!("1.1.1.1/32", cidr.to_string());
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(u32::MAX));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn match_merged_ranges() {
+        let set: CidrSet = "192.168.1.0/24, 10.0.0.0/8, 192.168.0.0/24, 10.2.0.0/16"
+            .parse()
+            .unwrap();
+        // the overlapping and adjacent ranges are merged
+        assert_eq!(2, set.ranges.len());
+        assert!(set.contains(0x0A020304));
+        assert!(set.contains(0xC0A801FF));
+        assert!(!set.contains(0xC0A80200));
+        assert!(!set.contains(0x09FFFFFF));
+        assert!(!set.contains(0x01010101));
+
+        assert!("".parse::<CidrSet>().unwrap().is_empty());
+        assert!("10.0.0.0/8, nope".parse::<CidrSet>().is_err());
+    }
+}
@@ -16,59 +16,179 @@
 
 use log::*;
 use mio::net::TcpStream;
-use mio::{Event, PollOpt, Ready, Token};
+use mio::{Event, Evented, Poll, PollOpt, Ready, Token};
 use std::cell::RefCell;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::net::Shutdown;
 use std::rc::Rc;
+use std::time::Instant;
 
 use super::binary;
-use super::close_listener::CloseListener;
+use super::connection::{ConnectionId, ConnectionInfo, SharedConnectionCloseListener};
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::ipv4_packet_buffer::Ipv4PacketBuffer;
+use super::ipv6_packet::Ipv6Packet;
+use super::listener::Listener;
+use super::metrics::RelayMetrics;
+use super::packet_sink::PacketSink;
 use super::packet_source::PacketSource;
+use super::pcap::{self, SharedPcapWriter};
+use super::rate_limiter::RateLimiter;
+use super::relay_config::RelayConfig;
 use super::router::Router;
 use super::selector::Selector;
 use super::stream_buffer::StreamBuffer;
+#[cfg(target_os = "linux")]
+use super::tun_device::TunDevice;
 
 const TAG: &str = "Client";
 
+/// Client (a device) connected to the relay.
+///
+/// Each client owns its router, so its connections are isolated from those of the other clients,
+/// even for identical flows.
 pub struct Client {
     id: u32,
-    stream: TcpStream,
+    stream: ClientStream,
     interests: Ready,
     token: Token,
     client_to_network: Ipv4PacketBuffer,
     network_to_client: StreamBuffer,
     router: Router,
-    close_listener: Box<dyn CloseListener<Client>>,
+    close_listener: Box<dyn Listener<Client>>,
     closed: bool,
     pending_packet_sources: Vec<Rc<RefCell<dyn PacketSource>>>,
     // number of remaining bytes of "id" to send to the client before relaying any data
     pending_id_bytes: usize,
+    pcap_writer: Option<SharedPcapWriter>,
+    rate_limiter: Option<RateLimiter>,
+    packet_rate_limiter: Option<RateLimiter>,
+    packets_rate_limited: u64,
+    // set when the rate limiter retains the next packet, until enough bandwidth is available
+    throttled_until: Option<Instant>,
+    // set when the connection of the next packet cannot accept it, until it drains
+    blocked_on_network: bool,
+}
+
+/// Link the packets of a client are exchanged over.
+pub enum ClientStream {
+    /// Connection of a device, prefixing the relayed packets by the client id.
+    Tcp(TcpStream),
+    /// Local TUN interface, exchanging the packets of the host itself.
+    #[cfg(target_os = "linux")]
+    Tun(TunDevice),
+}
+
+impl ClientStream {
+    // only a device expects its client id
+    fn sends_id(&self) -> bool {
+        match self {
+            ClientStream::Tcp(_) => true,
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(_) => false,
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            // the interface is closed on drop
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(_) => Ok(()),
+        }
+    }
+
+    fn as_evented(&self) -> &dyn Evented {
+        match self {
+            ClientStream::Tcp(stream) => stream,
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(device) => device,
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(device) => device.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(device) => device.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(target_os = "linux")]
+            ClientStream::Tun(device) => device.flush(),
+        }
+    }
+}
+
+impl Evented for ClientStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.as_evented().register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.as_evented().reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.as_evented().deregister(poll)
+    }
 }
 
 /// Channel for connections to send back data immediately to the client
 pub struct ClientChannel<'a> {
     network_to_client: &'a mut StreamBuffer,
-    stream: &'a TcpStream,
+    stream: &'a dyn Evented,
     token: Token,
     interests: &'a mut Ready,
+    pcap_writer: Option<&'a SharedPcapWriter>,
+    // the client retains its next packet (throttled or blocked on network)
+    paused: bool,
 }
 
 impl<'a> ClientChannel<'a> {
-    fn new(
+    pub fn new(
         network_to_client: &'a mut StreamBuffer,
-        stream: &'a TcpStream,
+        stream: &'a dyn Evented,
         token: Token,
         interests: &'a mut Ready,
+        pcap_writer: Option<&'a SharedPcapWriter>,
+        paused: bool,
     ) -> Self {
         Self {
             network_to_client,
             stream,
             token,
             interests,
+            pcap_writer,
+            paused,
         }
     }
 
@@ -81,6 +201,7 @@ impl<'a> ClientChannel<'a> {
     ) -> io::Result<()> {
         if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
             self.network_to_client.read_from(ipv4_packet.raw());
+            pcap::capture(self.pcap_writer, ipv4_packet.raw());
             self.update_interests(selector);
             Ok(())
         } else {
@@ -93,11 +214,16 @@ impl<'a> ClientChannel<'a> {
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.network_to_client.is_empty() {
-            Ready::readable()
+        // do not read more packets from the client while the previous ones are retained, so that
+        // TCP flow control slows the client down
+        let mut ready = if self.paused {
+            Ready::empty()
         } else {
-            Ready::readable() | Ready::writable()
+            Ready::readable()
         };
+        if !self.network_to_client.is_empty() {
+            ready |= Ready::writable();
+        }
         if *self.interests != ready {
             // interests must be changed
             *self.interests = ready;
@@ -112,11 +238,25 @@ impl Client {
     pub fn create(
         id: u32,
         selector: &mut Selector,
-        stream: TcpStream,
-        close_listener: Box<dyn CloseListener<Client>>,
+        stream: ClientStream,
+        close_listener: Box<dyn Listener<Client>>,
+        config: Rc<RelayConfig>,
+        pcap_writer: Option<SharedPcapWriter>,
+        connection_close_listeners: Vec<SharedConnectionCloseListener>,
     ) -> io::Result<Rc<RefCell<Self>>> {
-        // on start, we are interested only in writing (we must first send the client id)
-        let interests = Ready::writable();
+        // on start, we are interested only in writing (we must first send the client id to a
+        // device)
+        let (interests, pending_id_bytes) = if stream.sends_id() {
+            (Ready::writable(), 4)
+        } else {
+            (Ready::readable(), 0)
+        };
+        let rate_limiter = config
+            .rate_limit()
+            .map(|rate| RateLimiter::new(rate, config.rate_limit_burst()));
+        let packet_rate_limiter = config
+            .packet_rate_limit()
+            .map(|rate| RateLimiter::new(rate, config.packet_rate_limit_burst()));
         let rc = Rc::new(RefCell::new(Self {
             id,
             stream,
@@ -124,17 +264,26 @@ impl Client {
             token: Token(0), // default value, will be set afterwards
             client_to_network: Ipv4PacketBuffer::new(),
             network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
-            router: Router::new(),
+            router: Router::new(config),
             closed: false,
             close_listener,
             pending_packet_sources: Vec::new(),
-            pending_id_bytes: 4,
+            pending_id_bytes,
+            pcap_writer,
+            rate_limiter,
+            packet_rate_limiter,
+            packets_rate_limited: 0,
+            throttled_until: None,
+            blocked_on_network: false,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
             // set client as router owner
             self_ref.router.set_client(Rc::downgrade(&rc));
+            for listener in connection_close_listeners {
+                self_ref.router.add_close_listener(listener);
+            }
 
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
@@ -155,24 +304,58 @@ impl Client {
         &mut self.router
     }
 
-    pub fn channel(&mut self) -> ClientChannel {
+    /// Close the UDP connection rejected by the ICMP error `message`, if any, and notify the
+    /// client.
+    pub fn close_unreachable_flow(&mut self, selector: &mut Selector, message: &[u8]) {
+        if let Some(mut raw) = self.router.close_unreachable_flow(selector, message) {
+            let error_packet = Ipv4Packet::parse(&mut raw);
+            if let Err(err) = self.send_to_client(selector, &error_packet) {
+                warn!(
+                    target: TAG,
+                    "Cannot send Port Unreachable to client: {}", err
+                );
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> RelayMetrics {
+        let mut metrics = self.router.metrics();
+        metrics.packets_rate_limited = self.packets_rate_limited;
+        metrics
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn connections_info(&self, now: Instant) -> Vec<ConnectionInfo> {
+        self.router.connections_info(now)
+    }
+
+    pub fn channel(&mut self) -> ClientChannel<'_> {
+        let paused = self.is_paused();
         ClientChannel::new(
             &mut self.network_to_client,
             &self.stream,
             self.token,
             &mut self.interests,
+            self.pcap_writer.as_ref(),
+            paused,
         )
     }
 
-    fn close(&mut self, selector: &mut Selector) {
+    pub fn close(&mut self, selector: &mut Selector) {
         self.closed = true;
         selector.deregister(&self.stream, self.token).unwrap();
         // shutdown only (there is no close), the socket will be closed on drop
-        if self.stream.shutdown(Shutdown::Both).is_err() {
+        if self.stream.shutdown().is_err() {
             warn!(target: TAG, "Cannot shutdown client socket");
         }
         self.router.clear(selector);
-        self.close_listener.on_closed(self);
+        info!(
+            target: TAG,
+            "Client #{} closed, {}",
+            self.id,
+            self.router.stats()
+        );
+        self.close_listener.on_event(self);
     }
 
     fn on_ready(&mut self, selector: &mut Selector, event: Event) {
@@ -193,7 +376,7 @@ impl Client {
             if ready.is_writable() {
                 self.process_send(selector)?;
             }
-            if !self.closed && ready.is_readable() {
+            if !self.closed && ready.is_readable() && !self.is_paused() {
                 self.process_receive(selector)?;
             }
             if !self.closed {
@@ -258,8 +441,21 @@ impl Client {
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
+        self.send_raw_to_client(selector, ipv4_packet.raw())
+    }
+
+    pub fn send_ipv6_to_client(
+        &mut self,
+        selector: &mut Selector,
+        ipv6_packet: &Ipv6Packet,
+    ) -> io::Result<()> {
+        self.send_raw_to_client(selector, ipv6_packet.raw())
+    }
+
+    fn send_raw_to_client(&mut self, selector: &mut Selector, raw: &[u8]) -> io::Result<()> {
+        if raw.len() <= self.network_to_client.remaining() {
+            self.network_to_client.read_from(raw);
+            pcap::capture(self.pcap_writer.as_ref(), raw);
             self.update_interests(selector);
             Ok(())
         } else {
@@ -297,19 +493,130 @@ impl Client {
     }
 
     fn push_to_network(&mut self, selector: &mut Selector) {
-        while self.push_one_packet_to_network(selector) {
+        while self.acquire_bandwidth() {
+            if !self.acquire_packet_token() {
+                self.client_to_network.next();
+                continue;
+            }
+            if !self.push_one_packet_to_network(selector) {
+                break;
+            }
             self.client_to_network.next();
         }
     }
 
+    // consult the packet rate limiter (if any), the next packet must be dropped if it returns false
+    fn acquire_packet_token(&mut self) -> bool {
+        // a packet blocked on network has already been accepted
+        if self.blocked_on_network || self.client_to_network.packet_length().is_none() {
+            return true;
+        }
+        if let Some(ref mut packet_rate_limiter) = self.packet_rate_limiter {
+            if !packet_rate_limiter.try_consume(1, Instant::now()) {
+                debug!(
+                    target: TAG,
+                    "Client #{}: packet rate exceeded, dropping packet", self.id
+                );
+                self.packets_rate_limited += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    // consult the rate limiter (if any) before pushing the next packet
+    fn acquire_bandwidth(&mut self) -> bool {
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            if let Some(length) = self.client_to_network.packet_length() {
+                let length = length as usize;
+                let now = Instant::now();
+                if !rate_limiter.try_consume(length, now) {
+                    let deadline = now + rate_limiter.delay(length, now);
+                    self.throttled_until = Some(deadline);
+                    return false;
+                }
+            }
+        }
+        self.throttled_until = None;
+        true
+    }
+
+    fn is_paused(&self) -> bool {
+        self.throttled_until.is_some() || self.blocked_on_network
+    }
+
+    /// Push the packet retained because its connection was full, if it drained meanwhile.
+    pub fn resume_blocked(&mut self, selector: &mut Selector) {
+        if self.blocked_on_network && !self.closed {
+            self.push_to_network(selector);
+            self.update_interests(selector);
+        }
+    }
+
+    pub fn throttled_until(&self) -> Option<Instant> {
+        self.throttled_until
+    }
+
+    /// Push the packets retained by the rate limiter, if the bandwidth is available again.
+    ///
+    /// Return `true` if the client was resumed.
+    pub fn resume_throttled(&mut self, selector: &mut Selector) -> bool {
+        match self.throttled_until {
+            Some(deadline) if !self.closed && Instant::now() >= deadline => {
+                self.push_to_network(selector);
+                self.update_interests(selector);
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> bool {
+        // a blocked packet is retried, it has already been captured
+        let retried = self.blocked_on_network;
+        match self.client_to_network.as_ipv6_packet() {
+            Some(Err(err)) => {
+                warn!(
+                    target: TAG,
+                    "Client #{}: dropping malformed IPv6 packet: {}", self.id, err
+                );
+                return true;
+            }
+            Some(Ok(packet)) => {
+                if !retried {
+                    pcap::capture(self.pcap_writer.as_ref(), packet.raw());
+                }
+                trace!(
+                    target: TAG,
+                    "push IPv6 packet to network: {}, packet length {}",
+                    self.id,
+                    packet.length()
+                );
+                let result = self.router.send_ipv6_to_network(selector, &packet);
+                return self.handle_backpressure(result);
+            }
+            None => (),
+        }
+        let paused = self.is_paused();
         match self.client_to_network.as_ipv4_packet() {
-            Some(ref packet) => {
+            Some(Err(err)) => {
+                warn!(
+                    target: TAG,
+                    "Client #{}: dropping malformed packet: {}", self.id, err
+                );
+                true
+            }
+            Some(Ok(mut packet)) => {
+                if !retried {
+                    pcap::capture(self.pcap_writer.as_ref(), packet.raw());
+                }
                 let mut client_channel = ClientChannel::new(
                     &mut self.network_to_client,
                     &self.stream,
                     self.token,
                     &mut self.interests,
+                    self.pcap_writer.as_ref(),
+                    paused,
                 );
                 trace!(
                     target: TAG,
@@ -317,37 +624,62 @@ impl Client {
                     self.id,
                     packet.length(),
                     packet.ipv4_header_data().header_length(),
-                    packet.transport_header().unwrap().header_length()
+                    packet
+                        .transport_header()
+                        .map_or(0, |transport_header| transport_header.header_length())
                 );
-                self.router
-                    .send_to_network(selector, &mut client_channel, packet);
-                true
+                let result =
+                    self.router
+                        .send_to_network(selector, &mut client_channel, &mut packet);
+                self.handle_backpressure(result)
             }
             None => false,
         }
     }
 
+    // return true if the packet was consumed, false if it must be retained until its connection
+    // drains
+    fn handle_backpressure(&mut self, result: io::Result<()>) -> bool {
+        match result {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if !self.blocked_on_network {
+                    debug!(target: TAG, "Client #{} blocked on network", self.id);
+                    self.blocked_on_network = true;
+                }
+                false
+            }
+            _ => {
+                self.blocked_on_network = false;
+                true
+            }
+        }
+    }
+
     fn process_pending(&mut self, selector: &mut Selector) {
         let mut vec = Vec::new();
         mem::swap(&mut self.pending_packet_sources, &mut vec);
         for pending in vec.into_iter() {
             let consumed = {
                 let mut source = pending.borrow_mut();
-                let result = {
-                    let ipv4_packet = source
-                        .get()
-                        .expect("Unexpected pending source with no packet");
-                    self.send_to_client(selector, &ipv4_packet)
-                };
-                #[allow(clippy::match_wild_err_arm)]
-                match result {
-                    Ok(_) => {
-                        source.next(selector);
-                        true
-                    }
-                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => false,
-                    Err(_) => {
-                        panic!("Cannot send packet to client for unknown reason");
+                loop {
+                    let result = {
+                        let ipv4_packet = source
+                            .get()
+                            .expect("Unexpected pending source with no packet");
+                        self.send_to_client(selector, &ipv4_packet)
+                    };
+                    #[allow(clippy::match_wild_err_arm)]
+                    match result {
+                        Ok(_) => {
+                            source.next(selector);
+                            if source.get().is_none() {
+                                break true;
+                            }
+                        }
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                        Err(_) => {
+                            panic!("Cannot send packet to client for unknown reason");
+                        }
                     }
                 }
             };
@@ -359,10 +691,1629 @@ impl Client {
     }
 
     pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        self.router.clean_expired_connections(selector);
+        // the connections may send packets to the client when their timers elapse
+        let (router, mut client_channel) = self.router_and_channel();
+        router.clean_expired_connections(selector, &mut client_channel);
+    }
+
+    /// Reset the connection identified by `id`, if any.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn reset_connection(&mut self, selector: &mut Selector, id: &ConnectionId) -> bool {
+        let (router, mut client_channel) = self.router_and_channel();
+        router.reset_connection(selector, &mut client_channel, id)
+    }
+
+    // borrow the router along with a channel to the client, for the router calls which may send
+    // packets to the client
+    fn router_and_channel(&mut self) -> (&mut Router, ClientChannel<'_>) {
+        let paused = self.is_paused();
+        let client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            self.pcap_writer.as_ref(),
+            paused,
+        );
+        (&mut self.router, client_channel)
+    }
+
+    /// Earliest instant when a connection of this client may expire, if any.
+    pub fn next_expiry_deadline(&self) -> Option<Instant> {
+        self.router.next_expiry_deadline()
     }
 
     fn must_send_id(&self) -> bool {
         self.pending_id_bytes > 0
     }
 }
+
+impl PacketSink for Client {
+    fn send_to_client(
+        &mut self,
+        selector: &mut Selector,
+        ipv4_packet: &Ipv4Packet,
+    ) -> io::Result<()> {
+        Client::send_to_client(self, selector, ipv4_packet)
+    }
+
+    fn close_unreachable_flow(&mut self, selector: &mut Selector, message: &[u8]) {
+        Client::close_unreachable_flow(self, selector, message)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::connection::{OpenStage, OpenedConnection};
+    use crate::relay::dns_blocklist::DnsBlocklist;
+    use crate::relay::dns_cache::DNS_PORT;
+    use crate::relay::icmp_error::{
+        self, CODE_ADMINISTRATIVELY_PROHIBITED, CODE_FRAGMENTATION_NEEDED,
+    };
+    use crate::relay::icmp_header::{
+        IcmpHeaderData, ICMP_HEADER_LENGTH, TYPE_DESTINATION_UNREACHABLE,
+    };
+    use crate::relay::icmp_socket::{IcmpSocket, IcmpSocketKind};
+    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::tcp_header::{
+        TcpHeaderData, FLAG_ACK, FLAG_FIN, FLAG_PSH, FLAG_RST, FLAG_SYN,
+    };
+    use crate::relay::transport_header::TransportHeaderData;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+    use mio::Events;
+    use std::io::Read;
+    use std::net::{self, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::time::Duration;
+
+    // a datagram from 10.0.0.2:1234 to localhost
+    pub fn create_udp_packet(destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28 + payload.len());
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28 + payload.len() as u16)
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7F000001).unwrap(); // destination address (localhost)
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(destination_port).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(8 + payload.len() as u16)
+            .unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.extend_from_slice(payload);
+
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    // connect a client over loopback, and return the device side of its stream
+    fn connect_client(id: u32, selector: &mut Selector) -> (Rc<RefCell<Client>>, net::TcpStream) {
+        connect_client_with_config(id, selector, RelayConfig::new(0))
+    }
+
+    fn connect_client_with_config(
+        id: u32,
+        selector: &mut Selector,
+        config: RelayConfig,
+    ) -> (Rc<RefCell<Client>>, net::TcpStream) {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let device = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        device
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stream = TcpStream::from_stream(stream).unwrap();
+        let close_listener = Box::new(|_: &Client| ());
+        let config = Rc::new(config);
+        let client = Client::create(
+            id,
+            selector,
+            ClientStream::Tcp(stream),
+            close_listener,
+            config,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        (client, device)
+    }
+
+    fn run_selector(selector: &mut Selector) {
+        for _ in 0..10 {
+            run_selector_once(selector);
+        }
+    }
+
+    fn run_selector_once(selector: &mut Selector) {
+        let mut events = Events::with_capacity(16);
+        selector
+            .poll(&mut events, Some(Duration::from_millis(20)))
+            .unwrap();
+        selector.run_handlers(&events);
+    }
+
+    // read the id, then the first packet sent to the device
+    fn read_packet(device: &mut net::TcpStream) -> (u32, Vec<u8>) {
+        let mut id = [0u8; 4];
+        device.read_exact(&mut id).unwrap();
+        (u32::from_be_bytes(id), read_next_packet(device))
+    }
+
+    fn read_next_packet(device: &mut net::TcpStream) -> Vec<u8> {
+        let mut header = [0u8; 4];
+        device.read_exact(&mut header).unwrap();
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut raw = header.to_vec();
+        raw.resize(length, 0);
+        device.read_exact(&mut raw[4..]).unwrap();
+        raw
+    }
+
+    #[test]
+    fn isolate_identical_flows_of_distinct_clients() {
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client1, mut device1) = connect_client(1, &mut selector);
+        let (client2, mut device2) = connect_client(2, &mut selector);
+
+        // both devices send a datagram with the same 4-tuple
+        device1.write_all(&create_udp_packet(port, b"one")).unwrap();
+        device2.write_all(&create_udp_packet(port, b"two")).unwrap();
+        run_selector(&mut selector);
+
+        assert_eq!(1, client1.borrow().metrics().active_udp_connections);
+        assert_eq!(1, client2.borrow().metrics().active_udp_connections);
+
+        // each client relays its flow through its own socket: echo every datagram
+        let mut sources: Vec<SocketAddr> = Vec::new();
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            let (r, source) = server.recv_from(&mut buf).unwrap();
+            sources.push(source);
+            let mut reply = b"re:".to_vec();
+            reply.extend_from_slice(&buf[..r]);
+            server.send_to(&reply, source).unwrap();
+        }
+        assert_ne!(sources[0], sources[1]);
+        run_selector(&mut selector);
+
+        // each reply is sent back to the client of the flow only
+        let (id, mut raw) = read_packet(&mut device1);
+        assert_eq!(1, id);
+        assert_eq!(b"re:one", Ipv4Packet::parse(&mut raw).payload().unwrap());
+        let (id, mut raw) = read_packet(&mut device2);
+        assert_eq!(2, id);
+        assert_eq!(b"re:two", Ipv4Packet::parse(&mut raw).payload().unwrap());
+
+        client1.borrow_mut().close(&mut selector);
+        client2.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn drop_packets_above_packet_rate() {
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_packet_rate_limit(Some(1));
+        config.set_packet_rate_limit_burst(3);
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        let mut burst = Vec::new();
+        for i in 0..5u8 {
+            burst.extend(create_udp_packet(port, &[i]));
+        }
+        device.write_all(&burst).unwrap();
+        run_selector(&mut selector);
+
+        // only the burst is relayed, the excess packets are dropped rather than delayed
+        let mut buf = [0u8; 16];
+        for i in 0..3u8 {
+            let (r, _) = server.recv_from(&mut buf).unwrap();
+            assert_eq!([i], buf[..r]);
+        }
+        assert!(server.recv_from(&mut buf).is_err());
+        assert_eq!(2, client.borrow().metrics().packets_rate_limited);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn strip_ip_options_of_relayed_packets() {
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_strip_ipv4_options(true);
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        // insert a Record Route option with a single slot, padded by End of Option List
+        let mut raw = create_udp_packet(port, b"options");
+        let option = [7, 7, 4, 0, 0, 0, 0, 0];
+        raw.splice(20..20, option.iter().cloned());
+        raw[0] = 4 << 4 | 7;
+        let total_length = raw.len() as u16;
+        BigEndian::write_u16(&mut raw[2..4], total_length);
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        device.write_all(&raw).unwrap();
+        run_selector(&mut selector);
+
+        let mut buf = [0u8; 16];
+        let (r, source) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(b"options", &buf[..r]);
+
+        server.send_to(b"reply", source).unwrap();
+        run_selector(&mut selector);
+
+        // the reply is built from the stripped header
+        let (_, mut raw) = read_packet(&mut device);
+        let packet = Ipv4Packet::parse(&mut raw);
+        let ipv4_header = packet.ipv4_header();
+        assert_eq!(20, ipv4_header.header_length());
+        assert!(ipv4_header.options().is_empty());
+        assert_eq!(33, ipv4_header.total_length());
+        assert!(ipv4_header.verify_checksum());
+        assert_eq!(b"reply", packet.payload().unwrap());
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn pause_reading_while_connection_is_full() {
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_udp_buffer_datagrams(1);
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        // the connection is created on the first datagram, and only writes it to its socket on
+        // the next poll, so its buffer is full when the second one is read
+        let mut raw = create_udp_packet(port, b"one");
+        raw.extend_from_slice(&create_udp_packet(port, b"two"));
+        device.write_all(&raw).unwrap();
+        for _ in 0..10 {
+            if client.borrow().blocked_on_network {
+                break;
+            }
+            run_selector_once(&mut selector);
+        }
+        assert!(client.borrow().blocked_on_network);
+        // the client stops reading from the device
+        assert!(!client.borrow().interests.is_readable());
+
+        // once the connection drained, the retained datagram is relayed
+        for _ in 0..10 {
+            run_selector_once(&mut selector);
+            client.borrow_mut().resume_blocked(&mut selector);
+        }
+        assert!(!client.borrow().blocked_on_network);
+        assert!(client.borrow().interests.is_readable());
+
+        let mut buf = [0u8; 16];
+        let r = server.recv(&mut buf).unwrap();
+        assert_eq!(b"one", &buf[..r]);
+        let r = server.recv(&mut buf).unwrap();
+        assert_eq!(b"two", &buf[..r]);
+        let metrics = client.borrow().metrics();
+        assert_eq!(2, metrics.stats.tx_packets);
+        assert_eq!(0, metrics.stats.tx_dropped);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn drop_packet_with_invalid_ipv4_checksum() {
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        let mut raw = create_udp_packet(1234, b"corrupt");
+        raw[10] ^= 0x01;
+        device.write_all(&raw).unwrap();
+        run_selector(&mut selector);
+        assert_eq!(0, client.borrow().metrics().active_udp_connections);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn reject_oversized_datagram_with_dont_fragment() {
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_outbound_mtu(Some(100));
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        let mut raw = create_udp_packet(port, &[0x42; 100]);
+        {
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            let mut ipv4_header = ipv4_packet.ipv4_header_mut();
+            ipv4_header.set_dont_fragment(true);
+            ipv4_header.update_checksum();
+        }
+        device.write_all(&raw).unwrap();
+        run_selector(&mut selector);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        match error_packet.transport_header_data() {
+            Some(TransportHeaderData::Icmp(icmp_header_data)) => {
+                assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+                assert_eq!(CODE_FRAGMENTATION_NEEDED, icmp_header_data.code());
+            }
+            _ => panic!("Expected ICMP error"),
+        }
+        let message = error_packet.payload().unwrap();
+        assert_eq!(100, BigEndian::read_u16(&message[6..8]));
+
+        let mut buf = [0u8; 256];
+        assert!(server.recv_from(&mut buf).is_err());
+        assert_eq!(0, client.borrow().metrics().active_udp_connections);
+
+        // without the DF flag, the datagram may be fragmented
+        device
+            .write_all(&create_udp_packet(port, &[0x42; 100]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (size, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(100, size);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    fn create_echo_request(destination: u32, identifier: u16) -> Vec<u8> {
+        create_echo_request_with_sequence(destination, identifier, 7)
+    }
+
+    fn create_echo_request_with_sequence(
+        destination: u32,
+        identifier: u16,
+        sequence_number: u16,
+    ) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(destination).unwrap(); // destination address
+        raw.write_u8(8).unwrap(); // type (echo request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(identifier).unwrap();
+        raw.write_u16::<BigEndian>(sequence_number).unwrap();
+        raw.extend_from_slice(b"ping");
+        let mut icmp_header_data = IcmpHeaderData::parse(&raw[20..]);
+        icmp_header_data.bind_mut(&mut raw[20..]).update_checksum();
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    #[test]
+    fn answer_echo_request_to_relay() {
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        // 10.0.2.2 is the relay
+        device
+            .write_all(&create_echo_request(0x0A000202, 0x4321))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let reply_packet = Ipv4Packet::parse(&mut raw);
+        assert!(reply_packet.ipv4_header().verify_checksum());
+        assert_eq!(0x0A000202, reply_packet.ipv4_header_data().source());
+        assert_eq!(0x0A000002, reply_packet.ipv4_header_data().destination());
+        let message = reply_packet.payload().unwrap();
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert!(icmp_header_data.is_echo_reply());
+        assert_eq!(Some(0x4321), icmp_header_data.identifier());
+        assert_eq!(Some(7), icmp_header_data.sequence_number());
+        assert_eq!(b"ping", &message[ICMP_HEADER_LENGTH..]);
+        assert!(icmp_header_data.bind(message).verify_checksum());
+
+        let metrics = client.borrow().metrics();
+        assert_eq!(0, metrics.icmp_sockets_opened);
+        assert_eq!(0, metrics.active_icmp_connections);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn answer_echo_request_to_relay_with_configured_ttl() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_local_reply_ttl(128);
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        device
+            .write_all(&create_echo_request(0x0A000202, 0x4321))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let reply_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(128, reply_packet.ipv4_header_data().ttl());
+        assert!(reply_packet.ipv4_header().verify_checksum());
+        assert!(IcmpHeaderData::parse(reply_packet.payload().unwrap()).is_echo_reply());
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn drop_icmp_if_not_relayed() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_relay_icmp(false);
+        config.set_reply_icmp_prohibited(true);
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        device
+            .write_all(&create_echo_request(0x7F000001, 0x1234))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let metrics = client.borrow().metrics();
+        assert_eq!(0, metrics.icmp_sockets_opened);
+        assert_eq!(0, metrics.active_icmp_connections);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x7F000001, error_packet.ipv4_header_data().source());
+        let icmp_header_data = IcmpHeaderData::parse(error_packet.payload().unwrap());
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+        assert_eq!(CODE_ADMINISTRATIVELY_PROHIBITED, icmp_header_data.code());
+
+        // the relay itself still answers
+        device
+            .write_all(&create_echo_request(0x0A000202, 0x4321))
+            .unwrap();
+        run_selector(&mut selector);
+        let mut raw = read_next_packet(&mut device);
+        let reply_packet = Ipv4Packet::parse(&mut raw);
+        assert!(IcmpHeaderData::parse(reply_packet.payload().unwrap()).is_echo_reply());
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn share_raw_icmp_socket_between_pings() {
+        match IcmpSocket::bind(IpAddr::V4(Ipv4Addr::UNSPECIFIED), None) {
+            Ok(ref socket) if socket.kind() == IcmpSocketKind::Raw => (),
+            // raw sockets require CAP_NET_RAW, there is nothing to share without them
+            _ => return,
+        }
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        // two concurrent pings of localhost
+        device
+            .write_all(&create_echo_request(0x7F000001, 0x1234))
+            .unwrap();
+        device
+            .write_all(&create_echo_request(0x7F000001, 0x4321))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let metrics = client.borrow().metrics();
+        assert_eq!(2, metrics.active_icmp_connections);
+        assert_eq!(1, metrics.icmp_sockets_opened);
+
+        // each reply is delivered once, by the connection having its identifier
+        let identifier_of = |mut raw: Vec<u8>| {
+            let reply_packet = Ipv4Packet::parse(&mut raw);
+            IcmpHeaderData::parse(reply_packet.payload().unwrap()).identifier()
+        };
+        let (_, raw) = read_packet(&mut device);
+        let mut identifiers = vec![
+            identifier_of(raw),
+            identifier_of(read_next_packet(&mut device)),
+        ];
+        identifiers.sort_unstable();
+        assert_eq!(vec![Some(0x1234), Some(0x4321)], identifiers);
+        assert_eq!(2, client.borrow().metrics().stats.rx_packets);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn deliver_deferred_echo_replies_in_order() {
+        if IcmpSocket::bind(IpAddr::V4(Ipv4Addr::UNSPECIFIED), None).is_err() {
+            // ICMP sockets are denied, nothing can be pinged
+            return;
+        }
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        run_selector(&mut selector);
+        let mut id = [0u8; 4];
+        device.read_exact(&mut id).unwrap();
+
+        // fill the client buffer, without polling for writability so that it is not drained yet
+        let mut filler_packets = 0;
+        {
+            let mut client = client.borrow_mut();
+            let filler = create_udp_packet(9, &[0; 60000]);
+            while client.network_to_client.remaining() >= filler.len() + 1000 {
+                client.network_to_client.read_from(&filler);
+                filler_packets += 1;
+            }
+            let length = client.network_to_client.remaining() - 4;
+            client
+                .network_to_client
+                .read_from(&create_udp_packet(9, &vec![0; length - 28]));
+            filler_packets += 1;
+
+            // two pings of localhost on the same connection
+            let mut raw = create_echo_request_with_sequence(0x7F000001, 0x1234, 1);
+            raw.extend(create_echo_request_with_sequence(0x7F000001, 0x1234, 2));
+            client.client_to_network.read_from(&mut &raw[..]).unwrap();
+            client.push_to_network(&mut selector);
+        }
+        run_selector(&mut selector);
+
+        // both replies are deferred by the connection, which is registered once
+        assert_eq!(1, client.borrow().metrics().active_icmp_connections);
+        assert_eq!(1, client.borrow().pending_packet_sources.len());
+        assert_eq!(0, client.borrow().metrics().stats.rx_packets);
+
+        // read the device side meanwhile, the buffered packets exceed the socket buffers
+        let reader = std::thread::spawn(move || {
+            for _ in 0..filler_packets {
+                read_next_packet(&mut device);
+            }
+            vec![read_next_packet(&mut device), read_next_packet(&mut device)]
+        });
+        client.borrow_mut().update_interests(&mut selector);
+        for _ in 0..100 {
+            run_selector_once(&mut selector);
+            // process_pending() pulls both replies at once, as soon as the buffer accepts them
+            let rx_packets = client.borrow().metrics().stats.rx_packets;
+            assert!(rx_packets == 0 || rx_packets == 2);
+            if client.borrow().network_to_client.is_empty() {
+                break;
+            }
+        }
+
+        let sequence_numbers: Vec<Option<u16>> = reader
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|mut raw| {
+                let reply_packet = Ipv4Packet::parse(&mut raw);
+                let icmp_header_data = IcmpHeaderData::parse(reply_packet.payload().unwrap());
+                assert!(icmp_header_data.is_echo_reply());
+                icmp_header_data.sequence_number()
+            })
+            .collect();
+        assert_eq!(vec![Some(1), Some(2)], sequence_numbers);
+        assert!(client.borrow().pending_packet_sources.is_empty());
+        assert_eq!(2, client.borrow().metrics().stats.rx_packets);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    fn create_gre_packet(payload: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(24 + payload.len() as u16)
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(47).unwrap(); // protocol (GRE)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7F000001).unwrap(); // destination address (localhost)
+        raw.write_u16::<BigEndian>(0).unwrap(); // GRE flags and version
+        raw.write_u16::<BigEndian>(0x0800).unwrap(); // GRE protocol type (IPv4)
+        raw.extend_from_slice(payload);
+        Ipv4Packet::parse(&mut raw)
+            .ipv4_header_mut()
+            .update_checksum();
+        raw
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn relay_gre_packet_through_raw_connection() {
+        use socket2::{Domain, Socket, Type};
+
+        // receive the GRE packets sent to localhost
+        let receiver = match Socket::new(Domain::IPV4, Type::RAW, Some(47.into())) {
+            Ok(receiver) => receiver,
+            // raw sockets require CAP_NET_RAW
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("Cannot open raw socket: {}", err),
+        };
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        device.write_all(&create_gre_packet(b"tunneled")).unwrap();
+        run_selector(&mut selector);
+
+        assert_eq!(1, client.borrow().metrics().stats.tx_packets);
+        // the payload is relayed verbatim, after the IPv4 header added by the kernel
+        let mut buffer = [0u8; 128];
+        let size = (&receiver).read(&mut buffer).unwrap();
+        assert_eq!(47, buffer[9]);
+        assert_eq!(&[0, 0, 0x08, 0x00], &buffer[20..24]);
+        assert_eq!(b"tunneled", &buffer[24..size]);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    fn create_tcp_packet(
+        destination_port: u16,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+        flags: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        create_tcp_packet_with_options(
+            destination_port,
+            sequence_number,
+            acknowledgement_number,
+            flags,
+            &[],
+            payload,
+        )
+    }
+
+    // the options length must be a multiple of 4
+    fn create_tcp_packet_with_options(
+        destination_port: u16,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+        flags: u16,
+        options: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let header_length = 40 + options.len() as u16;
+        let mut raw = Vec::with_capacity(header_length as usize + payload.len());
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(header_length + payload.len() as u16)
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7F000001).unwrap(); // destination address (localhost)
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(destination_port).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(sequence_number).unwrap();
+        raw.write_u32::<BigEndian>(acknowledgement_number).unwrap();
+        let data_offset = (header_length - 20) / 4;
+        raw.write_u16::<BigEndian>(data_offset << 12 | flags)
+            .unwrap(); // data offset and flags
+        raw.write_u16::<BigEndian>(0xFFFF).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+        raw.extend_from_slice(options);
+        raw.extend_from_slice(payload);
+
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    fn read_tcp_packet(raw: &mut [u8]) -> (TcpHeaderData, Vec<u8>) {
+        let packet = Ipv4Packet::parse(raw);
+        let tcp_header_data = match packet.transport_header_data() {
+            Some(TransportHeaderData::Tcp(tcp_header_data)) => tcp_header_data.clone(),
+            _ => panic!("Expected TCP packet"),
+        };
+        (tcp_header_data, packet.payload().unwrap().to_vec())
+    }
+
+    #[test]
+    fn keep_relaying_to_client_after_client_fin() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_SYN | FLAG_ACK, syn_ack.flags());
+        assert_eq!(1001, syn_ack.acknowledgement_number());
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+
+        // the client is done sending
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_FIN | FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let mut raw = read_next_packet(&mut device);
+        let (ack, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_ACK, ack.flags());
+        assert_eq!(1002, ack.acknowledgement_number());
+        // the half-close is propagated to the network
+        let mut buf = [0u8; 16];
+        assert_eq!(0, server.read(&mut buf).unwrap());
+
+        // the network may still send data to the client
+        server.write_all(b"response").unwrap();
+        run_selector(&mut selector);
+
+        let mut raw = read_next_packet(&mut device);
+        let (data, payload) = read_tcp_packet(&mut raw);
+        assert_eq!(relay_sequence_number, data.sequence_number());
+        assert_eq!(0, data.flags() & FLAG_FIN);
+        assert_eq!(b"response", &payload[..]);
+
+        // until it closes
+        drop(server);
+        run_selector(&mut selector);
+
+        let mut raw = read_next_packet(&mut device);
+        let (fin, _) = read_tcp_packet(&mut raw);
+        assert_ne!(0, fin.flags() & FLAG_FIN);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    // establish a TCP connection to the network, reset by the network once the client advertised a
+    // zero window or not
+    fn reset_connection_from_network(zero_window: bool) {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (server, _) = listener.accept().unwrap();
+
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        let mut ack = create_tcp_packet(port, 1001, relay_sequence_number, FLAG_ACK, &[]);
+        if zero_window {
+            // the relay does not read from the network while the client window is full
+            ack[34..36].copy_from_slice(&[0, 0]);
+            Ipv4Packet::parse(&mut ack).compute_checksums();
+        }
+        device.write_all(&ack).unwrap();
+        run_selector(&mut selector);
+        assert_eq!(1, client.borrow().metrics().active_tcp_connections);
+
+        // a zero linger timeout makes the socket send a RST on close
+        let server = socket2::Socket::from(server);
+        server.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(server);
+        run_selector(&mut selector);
+
+        let mut raw = read_next_packet(&mut device);
+        let (rst, _) = read_tcp_packet(&mut raw);
+        assert_ne!(0, rst.flags() & FLAG_RST);
+        assert_eq!(relay_sequence_number, rst.sequence_number());
+        assert_eq!(0, client.borrow().metrics().active_tcp_connections);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn retransmit_unacknowledged_data_from_snd_una() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_tcp_initial_rto(Duration::from_millis(100));
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (mut server, _) = listener.accept().unwrap();
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        server.write_all(b"hello").unwrap();
+        run_selector(&mut selector);
+        let mut raw = read_next_packet(&mut device);
+        let (header, payload) = read_tcp_packet(&mut raw);
+        assert_eq!(relay_sequence_number, header.sequence_number());
+        assert_eq!(b"hello", &payload[..]);
+
+        let retransmit = |selector: &mut Selector, device: &mut net::TcpStream| {
+            let deadline = client.borrow().next_expiry_deadline().unwrap();
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            client.borrow_mut().clean_expired_connections(selector);
+            run_selector_once(selector);
+            let mut raw = read_next_packet(device);
+            read_tcp_packet(&mut raw)
+        };
+
+        // the ACK is lost, the whole segment is resent
+        let (header, payload) = retransmit(&mut selector, &mut device);
+        assert_eq!(relay_sequence_number, header.sequence_number());
+        assert_eq!(b"hello", &payload[..]);
+
+        // only the first 2 bytes are acknowledged, the others are resent
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number.wrapping_add(2),
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+        let (header, payload) = retransmit(&mut selector, &mut device);
+        assert_eq!(
+            relay_sequence_number.wrapping_add(2),
+            header.sequence_number()
+        );
+        assert_eq!(b"llo", &payload[..]);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn stop_reading_network_at_unacknowledged_data_limit() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_tcp_buffer_high_watermark(10_000);
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        // the largest window scale, the client window is about 1 GiB
+        let syn = create_tcp_packet_with_options(port, 1000, 0, FLAG_SYN, &[1, 3, 3, 14], &[]);
+        device.write_all(&syn).unwrap();
+        run_selector(&mut selector);
+        let (mut server, _) = listener.accept().unwrap();
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        // the client never acknowledges the data
+        server.write_all(&[0x42; 100_000]).unwrap();
+        run_selector(&mut selector);
+        let mut relayed = 0;
+        while relayed < 10_000 {
+            let mut raw = read_next_packet(&mut device);
+            let (_, payload) = read_tcp_packet(&mut raw);
+            relayed += payload.len();
+        }
+        assert_eq!(10_000, relayed);
+        device
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(device.read(&mut [0u8; 1]).is_err());
+
+        // once acknowledged, the network is read again
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number.wrapping_add(10_000),
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+        let mut raw = read_next_packet(&mut device);
+        let (header, _) = read_tcp_packet(&mut raw);
+        assert_eq!(
+            relay_sequence_number.wrapping_add(10_000),
+            header.sequence_number()
+        );
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn reset_client_on_network_reset() {
+        reset_connection_from_network(false);
+    }
+
+    #[test]
+    fn reset_client_on_network_reset_while_not_reading() {
+        reset_connection_from_network(true);
+    }
+
+    // read the packets received on the raw TCP socket `sniffer` until the SYN to `port`
+    fn sniff_syn(sniffer: &mut socket2::Socket, port: u16, ignored_source_port: u16) -> Vec<u8> {
+        let mut buf = [0u8; 256];
+        loop {
+            let len = sniffer.read(&mut buf).unwrap();
+            let (tcp_header_data, _) = read_tcp_packet(&mut buf[..len]);
+            if tcp_header_data.destination_port() == port
+                && tcp_header_data.source_port() != ignored_source_port
+                && tcp_header_data.is_syn()
+            {
+                return buf[..len].to_vec();
+            }
+        }
+    }
+
+    #[test]
+    fn report_host_unreachable_on_asynchronous_connect_failure() {
+        use socket2::{Domain, Protocol as SocketProtocol, Socket, Type};
+
+        // the host unreachable error is simulated from raw sockets
+        let mut sniffer = match Socket::new(Domain::IPV4, Type::RAW, Some(SocketProtocol::TCP)) {
+            Ok(sniffer) => sniffer,
+            Err(_) => return,
+        };
+        sniffer
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let icmp_socket =
+            Socket::new(Domain::IPV4, Type::RAW, Some(SocketProtocol::ICMPV4)).unwrap();
+
+        // once its accept queue is full, the listener drops the SYNs without replying
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        listener.bind(&localhost.into()).unwrap();
+        listener.listen(0).unwrap();
+        let port = listener.local_addr().unwrap().as_socket().unwrap().port();
+        let queued = net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let queued_port = queued.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        let syn = create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]);
+        device.write_all(&syn).unwrap();
+        run_selector(&mut selector);
+        assert_eq!(1, client.borrow().metrics().active_tcp_connections);
+
+        // the network reports the destination host unreachable, the connection fails
+        // asynchronously
+        let mut relayed_syn = sniff_syn(&mut sniffer, port, queued_port);
+        let raw = icmp_error::build_destination_unreachable(
+            &Ipv4Packet::parse(&mut relayed_syn),
+            icmp_error::CODE_HOST_UNREACHABLE,
+        );
+        icmp_socket.send_to(&raw[20..], &localhost.into()).unwrap();
+        run_selector(&mut selector);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        match error_packet.transport_header_data() {
+            Some(TransportHeaderData::Icmp(icmp_header_data)) => {
+                assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+                assert_eq!(icmp_error::CODE_HOST_UNREACHABLE, icmp_header_data.code());
+            }
+            _ => panic!("Expected ICMP error"),
+        }
+        // the error refers to the SYN of the client (its TTL is decremented by the relay)
+        let message = error_packet.payload().unwrap();
+        let embedded = &message[ICMP_HEADER_LENGTH..];
+        assert_eq!(&syn[12..28], &embedded[12..28]);
+        assert_eq!(0, client.borrow().metrics().active_tcp_connections);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn connect_with_pooled_socket() {
+        use crate::relay::socket_pool::tests::create_counting_pool;
+
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        let (mut pool, created) = create_counting_pool(2);
+        pool.refill();
+        let pool = Rc::new(RefCell::new(pool));
+        client
+            .borrow_mut()
+            .router()
+            .set_tcp_socket_pool(Some(pool.clone()));
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        listener.accept().unwrap();
+
+        // the connection took a socket from the warm pool, without creating any
+        assert_eq!(2, created.get());
+        assert_eq!(1, pool.borrow().len());
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_SYN | FLAG_ACK, syn_ack.flags());
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn flush_pending_data_before_client_fin() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        // queue several segments and close immediately, before the relay had a chance to write
+        // them to the network
+        let mut burst = Vec::new();
+        let mut sequence_number = 1001;
+        for i in 0..4u8 {
+            let chunk = [b'a' + i; 1000];
+            burst.extend(create_tcp_packet(
+                port,
+                sequence_number,
+                relay_sequence_number,
+                FLAG_ACK | FLAG_PSH,
+                &chunk,
+            ));
+            sequence_number += chunk.len() as u32;
+        }
+        burst.extend(create_tcp_packet(
+            port,
+            sequence_number,
+            relay_sequence_number,
+            FLAG_FIN | FLAG_ACK,
+            &[],
+        ));
+        device.write_all(&burst).unwrap();
+        run_selector(&mut selector);
+
+        // all the bytes are received before the half-close
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(4000, received.len());
+        for (i, chunk) in received.chunks(1000).enumerate() {
+            assert!(chunk.iter().all(|&b| b == b'a' + i as u8));
+        }
+
+        // the data is acked before the FIN
+        let mut acked = 1001;
+        while acked != sequence_number + 1 {
+            let mut raw = read_next_packet(&mut device);
+            let (ack, _) = read_tcp_packet(&mut raw);
+            assert_eq!(FLAG_ACK, ack.flags());
+            assert!(ack.acknowledgement_number() > acked);
+            assert!(ack.acknowledgement_number() <= sequence_number + 1);
+            acked = ack.acknowledgement_number();
+        }
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn connect_through_socks5_proxy() {
+        let proxy = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let proxy_addr = match proxy.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => panic!("Expected IPv4 address"),
+        };
+
+        let mut config = RelayConfig::new(0);
+        config.set_socks5_proxy(Some(proxy_addr));
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        device
+            .write_all(&create_tcp_packet(4321, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (mut proxy_stream, _) = proxy.accept().unwrap();
+        proxy_stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut greeting = [0u8; 3];
+        proxy_stream.read_exact(&mut greeting).unwrap();
+        assert_eq!([5, 1, 0], greeting);
+        proxy_stream.write_all(&[5, 0]).unwrap();
+        run_selector(&mut selector);
+
+        // CONNECT to the original destination
+        let mut request = [0u8; 10];
+        proxy_stream.read_exact(&mut request).unwrap();
+        assert_eq!([5, 1, 0, 1, 127, 0, 0, 1, 0x10, 0xE1], request);
+
+        // the client is not connected until the proxy replies
+        let mut id = [0u8; 4];
+        device.read_exact(&mut id).unwrap();
+        let mut buf = [0u8; 1];
+        device
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(device.read(&mut buf).is_err());
+        device
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        proxy_stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x30, 0x39])
+            .unwrap();
+        run_selector(&mut selector);
+
+        let mut raw = read_next_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_SYN | FLAG_ACK, syn_ack.flags());
+        assert_eq!(1001, syn_ack.acknowledgement_number());
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+
+        device
+            .write_all(&create_tcp_packet(
+                4321,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        device
+            .write_all(&create_tcp_packet(
+                4321,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK | FLAG_PSH,
+                b"hello",
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+        let mut payload = [0u8; 5];
+        proxy_stream.read_exact(&mut payload).unwrap();
+        assert_eq!(b"hello", &payload);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn relay_segment_carrying_sack_blocks() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+
+        // NOP, NOP, SACK-permitted
+        let syn_options = [1, 1, 4, 2];
+        device
+            .write_all(&create_tcp_packet_with_options(
+                port,
+                1000,
+                0,
+                FLAG_SYN,
+                &syn_options,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_SYN | FLAG_ACK, syn_ack.flags());
+        // SACK is never negotiated with the client
+        assert_eq!(20, syn_ack.header_length());
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        // NOP, NOP, SACK with one block
+        let mut sack_options = vec![1, 1, 5, 10];
+        sack_options.extend_from_slice(&relay_sequence_number.wrapping_add(100).to_be_bytes());
+        sack_options.extend_from_slice(&relay_sequence_number.wrapping_add(200).to_be_bytes());
+        device
+            .write_all(&create_tcp_packet_with_options(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK | FLAG_PSH,
+                &sack_options,
+                b"hello",
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        // the options are not mistaken for payload
+        let mut payload = [0u8; 5];
+        server.read_exact(&mut payload).unwrap();
+        assert_eq!(b"hello", &payload);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn retransmit_unacknowledged_fin_with_backoff() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut config = RelayConfig::new(0);
+        config.set_tcp_initial_rto(Duration::from_millis(100));
+        config.set_tcp_max_retransmissions(2);
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let (server, _) = listener.accept().unwrap();
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        device
+            .write_all(&create_tcp_packet(
+                port,
+                1001,
+                relay_sequence_number,
+                FLAG_ACK,
+                &[],
+            ))
+            .unwrap();
+        run_selector(&mut selector);
+
+        // the network closes, the ACKs of the FIN are never received
+        let closed_at = Instant::now();
+        drop(server);
+        run_selector(&mut selector);
+        let mut raw = read_next_packet(&mut device);
+        let (fin, _) = read_tcp_packet(&mut raw);
+        assert_eq!(FLAG_FIN | FLAG_ACK, fin.flags());
+        // the FIN was sent in between
+        let mut sent_bounds = (closed_at, Instant::now());
+
+        let ms = Duration::from_millis;
+        for &(interval, flags) in &[
+            (ms(100), FLAG_FIN | FLAG_ACK),
+            (ms(200), FLAG_FIN | FLAG_ACK),
+            // the retransmissions are exhausted
+            (ms(400), FLAG_RST),
+        ] {
+            let deadline = client.borrow().next_expiry_deadline().unwrap();
+            assert!(deadline >= sent_bounds.0 + interval);
+            assert!(deadline <= sent_bounds.1 + interval);
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            client.borrow_mut().clean_expired_connections(&mut selector);
+            sent_bounds = (deadline, Instant::now());
+            run_selector_once(&mut selector);
+
+            let mut raw = read_next_packet(&mut device);
+            let (header, _) = read_tcp_packet(&mut raw);
+            assert_eq!(flags, header.flags() & (FLAG_FIN | FLAG_ACK | FLAG_RST));
+            if flags & FLAG_FIN != 0 {
+                assert_eq!(fin.sequence_number(), header.sequence_number());
+            }
+        }
+        assert_eq!(0, client.borrow().metrics().active_tcp_connections);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    fn create_dns_query(name: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // id
+        raw.write_u16::<BigEndian>(0x0100).unwrap(); // flags (recursion desired)
+        raw.write_u16::<BigEndian>(1).unwrap(); // question count
+        raw.extend_from_slice(&[0; 6]); // answer, authority and additional counts
+        raw.extend_from_slice(name);
+        raw.write_u16::<BigEndian>(1).unwrap(); // type A
+        raw.write_u16::<BigEndian>(1).unwrap(); // class IN
+        raw
+    }
+
+    #[test]
+    fn redirect_dns_query_to_upstream() {
+        let upstream = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let upstream_addr = match upstream.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            addr => panic!("Unexpected address: {}", addr),
+        };
+
+        let mut config = RelayConfig::new(0);
+        config.set_dns_upstream(Some(upstream_addr));
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client_with_config(1, &mut selector, config);
+
+        // query to 10.0.0.1:53
+        let query = create_dns_query(b"\x07example\x03com\x00");
+        let mut raw = create_udp_packet(DNS_PORT, &query);
+        BigEndian::write_u32(&mut raw[16..20], 0x0A000001);
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        device.write_all(&raw).unwrap();
+        run_selector(&mut selector);
+
+        let mut buf = [0u8; 512];
+        let (size, source) = upstream.recv_from(&mut buf).unwrap();
+        assert_eq!(query, &buf[..size]);
+        // answer with the query itself, only its origin matters
+        upstream.send_to(&buf[..size], source).unwrap();
+        run_selector(&mut selector);
+
+        let (_, mut raw) = read_packet(&mut device);
+        let response_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x0A000001, response_packet.ipv4_header_data().source());
+        match response_packet.transport_header_data() {
+            Some(TransportHeaderData::Udp(udp_header_data)) => {
+                assert_eq!(DNS_PORT, udp_header_data.source_port())
+            }
+            _ => panic!("Expected UDP response"),
+        }
+        assert_eq!(query, response_packet.payload().unwrap());
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn answer_blocked_dns_query() {
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        let dns_blocklist = DnsBlocklist::parse("*.ads.example");
+        client
+            .borrow_mut()
+            .router()
+            .set_dns_blocklist(Some(Rc::new(dns_blocklist)));
+
+        let query = create_dns_query(b"\x06banner\x03ads\x07example\x00");
+        device
+            .write_all(&create_udp_packet(DNS_PORT, &query))
+            .unwrap();
+        run_selector(&mut selector);
+
+        // answered by the relay, with a name error
+        let (_, mut raw) = read_packet(&mut device);
+        let response_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x7F000001, response_packet.ipv4_header_data().source());
+        let response = response_packet.payload().unwrap();
+        assert_eq!(&query[..2], &response[..2]);
+        assert_eq!(0x8183, BigEndian::read_u16(&response[2..4]));
+        assert_eq!(0, client.borrow().metrics().stats.tx_packets);
+
+        // the other queries are forwarded unchanged
+        let query = create_dns_query(b"\x07example\x03com\x00");
+        device
+            .write_all(&create_udp_packet(DNS_PORT, &query))
+            .unwrap();
+        run_selector(&mut selector);
+        let stats = client.borrow().metrics().stats;
+        assert_eq!(1, stats.tx_packets);
+        assert_eq!(query.len() as u64, stats.tx_bytes);
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn notify_opened_connections() {
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let (client, mut device) = connect_client(1, &mut selector);
+        let opened = Rc::new(RefCell::new(Vec::new()));
+        let recorded = opened.clone();
+        client.borrow_mut().router().add_open_listener(Rc::new(
+            move |opened: &OpenedConnection| {
+                recorded.borrow_mut().push(opened.clone());
+            },
+        ));
+
+        device
+            .write_all(&create_tcp_packet(port, 1000, 0, FLAG_SYN, &[]))
+            .unwrap();
+        run_selector(&mut selector);
+        let _server = listener.accept().unwrap();
+
+        let (_, mut raw) = read_packet(&mut device);
+        let (syn_ack, _) = read_tcp_packet(&mut raw);
+        let relay_sequence_number = syn_ack.sequence_number().wrapping_add(1);
+        // the SYN created the connection, but the handshake is not complete
+        assert_eq!(1, opened.borrow().len());
+        assert_eq!(OpenStage::Created, opened.borrow()[0].stage);
+
+        // only the first ACK completes the handshake
+        for _ in 0..2 {
+            device
+                .write_all(&create_tcp_packet(
+                    port,
+                    1001,
+                    relay_sequence_number,
+                    FLAG_ACK,
+                    &[],
+                ))
+                .unwrap();
+            run_selector(&mut selector);
+        }
+
+        let opened = opened.borrow();
+        assert_eq!(2, opened.len());
+        assert_eq!(OpenStage::Established, opened[1].stage);
+        for opened in opened.iter() {
+            assert_eq!(Protocol::Tcp, opened.id.protocol());
+            assert_eq!(
+                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234),
+                opened.id.source()
+            );
+            assert_eq!(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+                opened.id.destination()
+            );
+        }
+
+        client.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn relay_packets_of_tun_interface() {
+        use crate::relay::tun_device::tests::configure_interface;
+
+        let device = match TunDevice::open("gnirehtet%d") {
+            Ok(device) => device,
+            // creating an interface requires CAP_NET_ADMIN
+            Err(ref err)
+                if err.kind() == io::ErrorKind::PermissionDenied
+                    || err.kind() == io::ErrorKind::NotFound =>
+            {
+                return
+            }
+            Err(err) => panic!("Cannot open TUN interface: {}", err),
+        };
+        // the address of the emulator, so that 10.0.2.2 (routed through the interface) is
+        // redirected to localhost
+        let address = Ipv4Addr::new(10, 0, 2, 15);
+        configure_interface(device.name(), address).unwrap();
+
+        let upstream = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let close_listener = Box::new(|_: &Client| ());
+        let client = Client::create(
+            1,
+            &mut selector,
+            ClientStream::Tun(device),
+            close_listener,
+            Rc::new(RelayConfig::new(0)),
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+
+        let socket = net::UdpSocket::bind((address, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        socket
+            .send_to(b"hello", (Ipv4Addr::new(10, 0, 2, 2), upstream_port))
+            .unwrap();
+        run_selector(&mut selector);
+
+        let mut buf = [0u8; 16];
+        let (size, source) = upstream.recv_from(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        upstream.send_to(b"world", source).unwrap();
+        run_selector(&mut selector);
+
+        // the response is written to the interface, and delivered by the kernel
+        let (size, source) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(b"world", &buf[..size]);
+        assert_eq!(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 2)), upstream_port),
+            source
+        );
+
+        client.borrow_mut().close(&mut selector);
+    }
+}
@@ -0,0 +1,388 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::cidr::CidrSet;
+use super::relay_builder::RelayBuilder;
+use super::relay_config::RelayConfig;
+
+/// A value of the configuration file.
+///
+/// Only the subset of TOML needed for the settings is supported: top-level `key = value` pairs,
+/// whose values are booleans, numbers or basic strings.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Boolean(_) => "a boolean",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::String(_) => "a string",
+        }
+    }
+}
+
+/// Parse the content of a configuration file, and validate the resulting configuration.
+///
+/// The keys are named after the `RelayConfig` getters (e.g. `udp_idle_timeout`); durations are
+/// expressed in seconds. Unset keys keep their default value.
+pub fn parse(port: u16, content: &str) -> Result<RelayConfig, String> {
+    let mut config = RelayConfig::new(port);
+    let mut keys = HashSet::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        parse_line(&mut config, &mut keys, line)
+            .map_err(|err| format!("line {}: {}", index + 1, err))?;
+    }
+    RelayBuilder::from(config).build()
+}
+
+fn parse_line<'a>(
+    config: &mut RelayConfig,
+    keys: &mut HashSet<&'a str>,
+    line: &'a str,
+) -> Result<(), String> {
+    if line.starts_with('[') {
+        return Err("Tables are not supported".to_string());
+    }
+    let (key, value) = match line.find('=') {
+        Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+        None => return Err(format!("Expected \"key = value\": {}", line)),
+    };
+    let valid_key = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if key.is_empty() || !key.chars().all(valid_key) {
+        return Err(format!("Invalid key: {}", key));
+    }
+    if !keys.insert(key) {
+        return Err(format!("Duplicate key: {}", key));
+    }
+    let value = parse_value(value)?;
+    apply(config, key, value).map_err(|err| format!("{}: {}", key, err))
+}
+
+/// Remove the comment at the end of the line, if any (a `#` within a string is not a comment).
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(raw: &str) -> Result<Value, String> {
+    if let Some(quoted) = raw.strip_prefix('"') {
+        return parse_string(quoted).map(Value::String);
+    }
+    match raw {
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => {}
+    }
+    // underscores may separate the digits
+    let number = raw.replace('_', "");
+    if let Ok(integer) = number.parse() {
+        return Ok(Value::Integer(integer));
+    }
+    if number.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') {
+        if let Ok(float) = number.parse() {
+            return Ok(Value::Float(float));
+        }
+    }
+    Err(format!("Invalid value: {}", raw))
+}
+
+/// Decode a basic string, `quoted` being the raw value without its opening quote.
+fn parse_string(quoted: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if !chars.as_str().is_empty() {
+                    return Err(format!(
+                        "Unexpected characters after string: {}",
+                        chars.as_str()
+                    ));
+                }
+                return Ok(result);
+            }
+            '\\' => match chars.next() {
+                Some('"') => result.push('"'),
+                Some('\\') => result.push('\\'),
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(other) => return Err(format!("Invalid escape sequence: \\{}", other)),
+                None => break,
+            },
+            _ => result.push(c),
+        }
+    }
+    Err("Unterminated string".to_string())
+}
+
+fn apply(config: &mut RelayConfig, key: &str, value: Value) -> Result<(), String> {
+    match key {
+        "listen_address" => config.set_listen_address(parsed(value)?),
+        "bind_address" => config.set_bind_address(parsed(value)?),
+        "bind_device" => config.set_bind_device(Some(string(value)?)),
+        "tun_interface" => config.set_tun_interface(Some(string(value)?)),
+        "tcp_idle_timeout" => config.set_tcp_idle_timeout(Some(duration(value)?)),
+        "tcp_initial_rto" => config.set_tcp_initial_rto(duration(value)?),
+        "tcp_max_retransmissions" => config.set_tcp_max_retransmissions(integer(value)?),
+        "tcp_keepalive_idle" => config.set_tcp_keepalive_idle(Some(duration(value)?)),
+        "tcp_keepalive_interval" => config.set_tcp_keepalive_interval(duration(value)?),
+        "tcp_keepalive_count" => config.set_tcp_keepalive_count(integer(value)?),
+        "tcp_socket_pool_size" => config.set_tcp_socket_pool_size(integer(value)?),
+        "udp_idle_timeout" => config.set_udp_idle_timeout(duration(value)?),
+        "icmp_idle_timeout" => config.set_icmp_idle_timeout(duration(value)?),
+        "mtu" => config.set_mtu(integer(value)?),
+        "outbound_mtu" => config.set_outbound_mtu(Some(integer(value)?)),
+        "verify_ipv4_checksums" => config.set_verify_ipv4_checksums(boolean(value)?),
+        "verify_echo_payloads" => config.set_verify_echo_payloads(boolean(value)?),
+        "relay_icmp" => config.set_relay_icmp(boolean(value)?),
+        "reply_icmp_prohibited" => config.set_reply_icmp_prohibited(boolean(value)?),
+        "relay_raw" => config.set_relay_raw(boolean(value)?),
+        "unknown_protocol_policy" => config.set_unknown_protocol_policy(parsed(value)?),
+        "strip_ipv4_options" => config.set_strip_ipv4_options(boolean(value)?),
+        "preserve_ttl" => config.set_preserve_ttl(boolean(value)?),
+        "preserve_tos" => config.set_preserve_tos(boolean(value)?),
+        "remark_tos" => config.set_remark_tos(Some(integer(value)?)),
+        "dry_run" => config.set_dry_run(boolean(value)?),
+        "log_target_names" => config.set_log_target_names(boolean(value)?),
+        "tcp_buffer_high_watermark" => config.set_tcp_buffer_high_watermark(integer(value)?),
+        "tcp_buffer_low_watermark" => config.set_tcp_buffer_low_watermark(Some(integer(value)?)),
+        "udp_buffer_datagrams" => config.set_udp_buffer_datagrams(integer(value)?),
+        "icmp_buffer_datagrams" => config.set_icmp_buffer_datagrams(integer(value)?),
+        "icmp_max_payload" => config.set_icmp_max_payload(integer(value)?),
+        "datagram_queue_policy" => config.set_datagram_queue_policy(parsed(value)?),
+        "pcap_path" => config.set_pcap_path(Some(PathBuf::from(string(value)?))),
+        "connection_log_path" => {
+            config.set_connection_log_path(Some(PathBuf::from(string(value)?)))
+        }
+        "rate_limit" => config.set_rate_limit(Some(integer(value)?)),
+        "rate_limit_burst" => config.set_rate_limit_burst(integer(value)?),
+        "packet_rate_limit" => config.set_packet_rate_limit(Some(integer(value)?)),
+        "packet_rate_limit_burst" => config.set_packet_rate_limit_burst(integer(value)?),
+        "icmp_rate_limit" => config.set_icmp_rate_limit(Some(integer(value)?)),
+        "icmp_rate_limit_burst" => config.set_icmp_rate_limit_burst(integer(value)?),
+        "local_reply_ttl" => config.set_local_reply_ttl(integer(value)?),
+        "socket_receive_buffer_size" => {
+            config.set_socket_receive_buffer_size(Some(integer(value)?))
+        }
+        "socket_send_buffer_size" => config.set_socket_send_buffer_size(Some(integer(value)?)),
+        "dns_cache_entries" => config.set_dns_cache_entries(Some(integer(value)?)),
+        "dns_blocklist_path" => config.set_dns_blocklist_path(Some(PathBuf::from(string(value)?))),
+        "dns_upstream" => config.set_dns_upstream(Some(parsed(value)?)),
+        "socks5_proxy" => config.set_socks5_proxy(Some(parsed(value)?)),
+        "allowed_destinations" => config.set_allowed_destinations(cidr_set(value)?),
+        "denied_destinations" => config.set_denied_destinations(cidr_set(value)?),
+        "reply_denied_prohibited" => config.set_reply_denied_prohibited(boolean(value)?),
+        "max_connections" => config.set_max_connections(Some(integer(value)?)),
+        "max_tcp_connections" => config.set_max_tcp_connections(Some(integer(value)?)),
+        "max_udp_connections" => config.set_max_udp_connections(Some(integer(value)?)),
+        "max_icmp_connections" => config.set_max_icmp_connections(Some(integer(value)?)),
+        "metrics_address" => config.set_metrics_address(Some(parsed(value)?)),
+        _ => return Err("Unknown key".to_string()),
+    }
+    Ok(())
+}
+
+fn mismatch(expected: &str, value: &Value) -> String {
+    format!("Expected {}, found {}", expected, value.type_name())
+}
+
+fn boolean(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(boolean) => Ok(boolean),
+        _ => Err(mismatch("a boolean", &value)),
+    }
+}
+
+fn integer<T: TryFrom<i64>>(value: Value) -> Result<T, String> {
+    match value {
+        Value::Integer(integer) => {
+            T::try_from(integer).map_err(|_| format!("Value out of range: {}", integer))
+        }
+        _ => Err(mismatch("an integer", &value)),
+    }
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        _ => Err(mismatch("a string", &value)),
+    }
+}
+
+/// Parse a string value, typically an address.
+fn parsed<T: FromStr>(value: Value) -> Result<T, String> {
+    let string = string(value)?;
+    string
+        .parse()
+        .map_err(|_| format!("Invalid value: \"{}\"", string))
+}
+
+/// Parse a comma-separated list of CIDR ranges, reporting the invalid one.
+fn cidr_set(value: Value) -> Result<CidrSet, String> {
+    string(value)?.parse()
+}
+
+/// Read a duration, in (possibly fractional) seconds.
+fn duration(value: Value) -> Result<Duration, String> {
+    match value {
+        Value::Integer(secs) if secs >= 0 => Ok(Duration::from_secs(secs as u64)),
+        Value::Float(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        Value::Integer(_) | Value::Float(_) => Err("A duration may not be negative".to_string()),
+        _ => Err(mismatch("a number of seconds", &value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::relay_config::UnknownProtocolPolicy;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::Path;
+
+    const SAMPLE: &str = r#"
+# relay settings
+listen_address = "0.0.0.0"
+bind_address = "192.168.1.10"
+
+tcp_idle_timeout = 600
+tcp_initial_rto = 0.5
+udp_idle_timeout = 30  # seconds
+
+mtu = 1_400
+rate_limit = 1048576
+icmp_rate_limit = 5
+verify_echo_payloads = true
+dns_blocklist_path = "/etc/gnirehtet/#blocked"
+dns_upstream = "1.1.1.1:53"
+denied_destinations = "10.0.0.0/8, 192.168.0.0/16"
+unknown_protocol_policy = "reply"
+"#;
+
+    #[test]
+    fn load_sample_config_file() {
+        let path = std::env::temp_dir().join(format!("gnirehtet-{}.toml", std::process::id()));
+        fs::write(&path, SAMPLE).unwrap();
+        let config = RelayConfig::from_toml_file(1234, &path);
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(1234, config.port());
+        assert_eq!(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.listen_address());
+        assert_eq!(Ipv4Addr::new(192, 168, 1, 10), config.bind_address());
+        assert_eq!(Some(Duration::from_secs(600)), config.tcp_idle_timeout());
+        assert_eq!(Duration::from_millis(500), config.tcp_initial_rto());
+        assert_eq!(Duration::from_secs(30), config.udp_idle_timeout());
+        assert_eq!(1400, config.mtu());
+        assert_eq!(Some(1 << 20), config.rate_limit());
+        assert_eq!(Some(5), config.icmp_rate_limit());
+        assert!(config.verify_echo_payloads());
+        assert_eq!(
+            Some(Path::new("/etc/gnirehtet/#blocked")),
+            config.dns_blocklist_path()
+        );
+        assert_eq!(Some("1.1.1.1:53".parse().unwrap()), config.dns_upstream());
+        assert!(config.denied_destinations().contains(0xC0A80101));
+        assert_eq!(
+            UnknownProtocolPolicy::Reply,
+            config.unknown_protocol_policy()
+        );
+        // unset keys keep their default value
+        assert_eq!(
+            RelayConfig::new(0).icmp_idle_timeout(),
+            config.icmp_idle_timeout()
+        );
+    }
+
+    #[test]
+    fn report_malformed_config() {
+        let parse_err = |content| parse(0, content).unwrap_err();
+        assert_eq!(
+            "line 2: mtu_size: Unknown key",
+            parse_err("mtu = 1500\nmtu_size = 1500")
+        );
+        assert_eq!(
+            "line 1: mtu: Expected an integer, found a string",
+            parse_err("mtu = \"1500\"")
+        );
+        assert_eq!(
+            "line 1: mtu: Value out of range: 70000",
+            parse_err("mtu = 70000")
+        );
+        assert_eq!(
+            "line 2: Duplicate key: mtu",
+            parse_err("mtu = 1500\nmtu = 1400")
+        );
+        assert_eq!("line 1: Expected \"key = value\": mtu", parse_err("mtu"));
+        assert_eq!("line 1: Tables are not supported", parse_err("[tcp]"));
+        assert_eq!(
+            "line 1: Unterminated string",
+            parse_err("bind_device = \"eth0")
+        );
+        assert_eq!(
+            "line 1: udp_idle_timeout: A duration may not be negative",
+            parse_err("udp_idle_timeout = -1")
+        );
+        assert_eq!(
+            "line 1: bind_address: Invalid value: \"localhost\"",
+            parse_err("bind_address = \"localhost\"")
+        );
+        // the resulting configuration is validated
+        assert!(parse_err("mtu = 20").starts_with("MTU too small"));
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(Value::Boolean(false), parse_value("false").unwrap());
+        assert_eq!(Value::Integer(-42), parse_value("-42").unwrap());
+        assert_eq!(Value::Float(2.5), parse_value("2.5").unwrap());
+        assert_eq!(
+            Value::String("a \"b\"\t\\".to_string()),
+            parse_value(r#""a \"b\"\t\\""#).unwrap()
+        );
+        assert!(parse_value("yes").is_err());
+        assert!(parse_value("inf").is_err());
+        assert!(parse_value(r#""a" b"#).is_err());
+        assert_eq!("x = \"#\" ", strip_comment("x = \"#\" # comment"));
+    }
+}
@@ -15,18 +15,28 @@
  */
 
 use std::fmt;
-use std::net::SocketAddrV4;
+use std::hash::{Hash, Hasher};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::ops::AddAssign;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use super::client::ClientChannel;
 use super::ipv4_header::{Ipv4HeaderData, Protocol};
 use super::ipv4_packet::Ipv4Packet;
+use super::listener::Listener;
 use super::net;
 use super::selector::Selector;
 use super::transport_header::TransportHeaderData;
 
-const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
+/// Address redirected to the host running the relay.
+pub const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
 const LOCALHOST: u32 = 0x7F_00_00_01; // 127.0.0.1
 
+// shared by all the clients, so that a flow id is never reused while the relay runs
+static NEXT_FLOW_ID: AtomicU64 = AtomicU64::new(1);
+
 pub trait Connection {
     fn id(&self) -> &ConnectionId;
     fn send_to_network(
@@ -35,18 +45,249 @@ pub trait Connection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     );
+
+    /// Indicate whether the connection may accept `ipv4_packet` now.
+    ///
+    /// If not, the client keeps the packet and stops reading until the connection drains, so
+    /// that the device is slowed down instead of losing packets.
+    fn can_accept(&self, _: &Ipv4Packet) -> bool {
+        true
+    }
+
     fn close(&mut self, selector: &mut Selector);
     fn is_expired(&self) -> bool;
     fn is_closed(&self) -> bool;
+
+    /// Last time some traffic was relayed by this connection.
+    fn idle_since(&self) -> Instant;
+
+    /// Instant when the connection was opened.
+    fn created_at(&self) -> Instant;
+
+    /// Short description of the state of the connection, for diagnostics.
+    fn state(&self) -> &'static str {
+        if self.is_closed() {
+            "CLOSED"
+        } else {
+            "OPEN"
+        }
+    }
+
+    /// Indicate whether the handshake with the client is complete, for the protocols having one
+    /// (TCP).
+    fn is_established(&self) -> bool {
+        false
+    }
+
+    /// Instant when the connection expires if it stays idle, or when one of its timers elapses,
+    /// if any.
+    fn expiry(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Handle the timers elapsed, called by the router once `expiry()` is reached (unless the
+    /// connection expired).
+    ///
+    /// The connection may close itself, the router will remove it.
+    fn handle_timeout(&mut self, _: &mut Selector, _: &mut ClientChannel) {}
+
+    /// Abort the connection on request, notifying both ends if the protocol allows it.
+    ///
+    /// The router removes it once reset.
+    fn reset(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        self.close(selector);
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    /// Local port of the socket towards the network, to match the ICMP errors received for it.
+    fn local_port(&self) -> Option<u16> {
+        None
+    }
+
+    /// Destination of the socket towards the network, which may differ from the one requested by
+    /// the client.
+    fn network_destination(&self) -> SocketAddrV4 {
+        self.id().rewritten_destination()
+    }
+
+    /// Build the ICMP Port Unreachable error to send to the client once the network rejected
+    /// this connection, if it applies.
+    fn build_port_unreachable(&self) -> Option<Vec<u8>> {
+        None
+    }
 }
 
+/// Snapshot of a live connection, to render a netstat-like table.
 #[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub protocol: Protocol,
+    /// Address of the client side of the flow (port 0 for protocols without ports).
+    pub source: SocketAddr,
+    /// Destination requested by the client.
+    pub destination: SocketAddr,
+    pub state: &'static str,
+    pub age: Duration,
+    pub idle: Duration,
+    /// Time elapsed since the last traffic sent to the network, if any.
+    pub tx_idle: Option<Duration>,
+    /// Time elapsed since the last traffic received from the network, if any.
+    pub rx_idle: Option<Duration>,
+    pub stats: ConnectionStats,
+}
+
+impl ConnectionInfo {
+    pub fn of(connection: &dyn Connection, now: Instant) -> Self {
+        let id = connection.id();
+        let stats = connection.stats();
+        Self {
+            protocol: id.protocol(),
+            source: id.source().into(),
+            destination: id.destination().into(),
+            state: connection.state(),
+            age: now.saturating_duration_since(connection.created_at()),
+            idle: now.saturating_duration_since(connection.idle_since()),
+            tx_idle: stats.tx_idle(now),
+            rx_idle: stats.rx_idle(now),
+            stats,
+        }
+    }
+}
+
+/// Payload traffic relayed by a connection: "tx" to the network, "rx" from the network.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    // packets from the client which could not be sent to the network
+    pub tx_dropped: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    // instants of the last traffic in each direction, to diagnose the stalled flows
+    pub last_tx: Option<Instant>,
+    pub last_rx: Option<Instant>,
+    // round-trip times measured (only for ICMP echo), the last one and their sum
+    pub last_rtt: Option<Duration>,
+    pub rtt_samples: u64,
+    pub rtt_total: Duration,
+}
+
+impl ConnectionStats {
+    pub fn record_tx(&mut self, bytes: usize) {
+        self.tx_packets += 1;
+        self.tx_bytes += bytes as u64;
+        self.last_tx = Some(Instant::now());
+    }
+
+    pub fn record_tx_datagrams(&mut self, datagrams: usize, bytes: usize) {
+        self.tx_packets += datagrams as u64;
+        self.tx_bytes += bytes as u64;
+        self.last_tx = Some(Instant::now());
+    }
+
+    pub fn record_tx_dropped(&mut self) {
+        self.tx_dropped += 1;
+    }
+
+    // the datagrams evicted from a full queue to make room for newer ones
+    pub fn record_tx_evicted(&mut self, datagrams: usize) {
+        self.tx_dropped += datagrams as u64;
+    }
+
+    pub fn record_rx(&mut self, bytes: usize) {
+        self.rx_packets += 1;
+        self.rx_bytes += bytes as u64;
+        self.last_rx = Some(Instant::now());
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.last_rtt = Some(rtt);
+        self.rtt_samples += 1;
+        self.rtt_total += rtt;
+    }
+
+    pub fn tx_idle(&self, now: Instant) -> Option<Duration> {
+        self.last_tx
+            .map(|last_tx| now.saturating_duration_since(last_tx))
+    }
+
+    pub fn rx_idle(&self, now: Instant) -> Option<Duration> {
+        self.last_rx
+            .map(|last_rx| now.saturating_duration_since(last_rx))
+    }
+}
+
+impl AddAssign for ConnectionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.tx_packets += other.tx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.tx_dropped += other.tx_dropped;
+        self.rx_packets += other.rx_packets;
+        self.rx_bytes += other.rx_bytes;
+        self.last_tx = self.last_tx.max(other.last_tx);
+        self.last_rx = self.last_rx.max(other.last_rx);
+        self.last_rtt = other.last_rtt.or(self.last_rtt);
+        self.rtt_samples += other.rtt_samples;
+        self.rtt_total += other.rtt_total;
+    }
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tx: {} packets ({} bytes, {} dropped), rx: {} packets ({} bytes)",
+            self.tx_packets, self.tx_bytes, self.tx_dropped, self.rx_packets, self.rx_bytes
+        )
+    }
+}
+
+/// Connection removed from its router, as reported to the connection close listeners.
+#[derive(Clone, Debug)]
+pub struct ClosedConnection {
+    pub id: ConnectionId,
+    // final traffic of the connection
+    pub stats: ConnectionStats,
+    // closed because it stayed idle
+    pub expired: bool,
+}
+
+pub type SharedConnectionCloseListener = Rc<dyn Listener<ClosedConnection>>;
+
+/// Step of the opening of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenStage {
+    /// The connection is created on the first packet of its flow (for TCP, on the SYN, which is
+    /// answered once the network is connected).
+    Created,
+    /// The TCP handshake with the client is complete.
+    Established,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenedConnection {
+    pub id: ConnectionId,
+    pub stage: OpenStage,
+}
+
+pub type SharedConnectionOpenListener = Rc<dyn Listener<OpenedConnection>>;
+
+/// Identify a flow by its endpoints.
+///
+/// The id of a connection also carries the flow id assigned on its creation, which does not take
+/// part in the comparisons: the id built from any packet of the flow matches it.
+#[derive(Clone, Debug)]
 pub struct ConnectionId {
     protocol: Protocol,
     source_ip: u32,
     source_port: u16,
     destination_ip: u32,
     destination_port: u16,
+    // echo identifier, ICMP has no ports to demultiplex concurrent pings
+    icmp_identifier: Option<u16>,
+    flow_id: Option<u64>,
     id_string: String,
 }
 
@@ -59,17 +300,74 @@ impl ConnectionId {
         let source_port = transport_header_data.source_port();
         let destination_ip = ipv4_header_data.destination();
         let destination_port = transport_header_data.destination_port();
-        let id_string = format!(
-            "{} -> {}",
-            net::to_socket_addr(source_ip, source_port),
-            net::to_socket_addr(destination_ip, destination_port)
-        );
+        let icmp_identifier = match *transport_header_data {
+            TransportHeaderData::Icmp(ref icmp_header_data) => icmp_header_data.identifier(),
+            _ => None,
+        };
+        let id_string = if let Some(icmp_identifier) = icmp_identifier {
+            format!(
+                "{} -> {} (id={})",
+                net::to_addr(source_ip),
+                net::to_addr(destination_ip),
+                icmp_identifier
+            )
+        } else {
+            format!(
+                "{} -> {}",
+                net::to_socket_addr(source_ip, source_port),
+                net::to_socket_addr(destination_ip, destination_port)
+            )
+        };
         Self {
             protocol: ipv4_header_data.protocol(),
             source_ip,
             source_port,
             destination_ip,
             destination_port,
+            icmp_identifier,
+            flow_id: None,
+            id_string,
+        }
+    }
+
+    /// Identify a TCP or UDP flow by its endpoints, as if it were created from its packets.
+    pub fn from_endpoints(
+        protocol: Protocol,
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+    ) -> Self {
+        Self {
+            protocol,
+            source_ip: u32::from(*source.ip()),
+            source_port: source.port(),
+            destination_ip: u32::from(*destination.ip()),
+            destination_port: destination.port(),
+            icmp_identifier: None,
+            flow_id: None,
+            id_string: format!("{} -> {}", source, destination),
+        }
+    }
+
+    /// Identify the flow of a packet of a protocol without transport header support, by its
+    /// addresses and its protocol number only.
+    pub fn from_ipv4_header(ipv4_header_data: &Ipv4HeaderData) -> Self {
+        let source_ip = ipv4_header_data.source();
+        let destination_ip = ipv4_header_data.destination();
+        let protocol = ipv4_header_data.protocol();
+        let id_string = format!(
+            "{} -> {} ({:?})",
+            net::to_addr(source_ip),
+            net::to_addr(destination_ip),
+            protocol
+        );
+        Self {
+            protocol,
+            source_ip,
+            source_port: 0,
+            destination_ip,
+            destination_port: 0,
+            icmp_identifier: None,
+            flow_id: None,
             id_string,
         }
     }
@@ -78,6 +376,43 @@ impl ConnectionId {
         self.protocol
     }
 
+    pub fn icmp_identifier(&self) -> Option<u16> {
+        self.icmp_identifier
+    }
+
+    /// Assign a new flow id, to identify the connection in the logs even across reconnections
+    /// reusing the same endpoints.
+    pub fn with_new_flow_id(mut self) -> Self {
+        self.flow_id = Some(NEXT_FLOW_ID.fetch_add(1, Ordering::Relaxed));
+        self
+    }
+
+    /// Flow id of the connection, `None` for an id built from a packet.
+    pub fn flow_id(&self) -> Option<u64> {
+        self.flow_id
+    }
+
+    fn key(&self) -> (Protocol, u32, u16, u32, u16, Option<u16>) {
+        (
+            self.protocol,
+            self.source_ip,
+            self.source_port,
+            self.destination_ip,
+            self.destination_port,
+            self.icmp_identifier,
+        )
+    }
+
+    /// Address of the client side of the flow (port 0 for protocols without ports).
+    pub fn source(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.source_ip, self.source_port)
+    }
+
+    /// Destination requested by the client, before any rewriting.
+    pub fn destination(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.destination_ip, self.destination_port)
+    }
+
     pub fn rewritten_destination(&self) -> SocketAddrV4 {
         let ip = if self.destination_ip == LOCALHOST_FORWARD {
             LOCALHOST
@@ -88,12 +423,35 @@ impl ConnectionId {
     }
 }
 
+impl PartialEq for ConnectionId {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ConnectionId {}
+
+impl Hash for ConnectionId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
 impl fmt::Display for ConnectionId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.id_string)
+        match self.flow_id {
+            Some(flow_id) => write!(f, "#{} {}", flow_id, self.id_string),
+            None => write!(f, "{}", self.id_string),
+        }
     }
 }
 
+/// Whether a connection idle since `idle_since` must be considered expired.
+pub fn is_idle_expired(idle_since: Instant, idle_timeout: Duration) -> bool {
+    // expired as soon as its deadline is reached, the relay wakes up exactly at that time
+    idle_since.elapsed() >= idle_timeout
+}
+
 // macros to log connection id along with the message
 
 macro_rules! cx_format {
@@ -105,32 +463,210 @@ macro_rules! cx_format {
     };
 }
 
+// check the level of the target before formatting, the logger may filter per target
+macro_rules! cx_log {
+    ($level:expr, target: $target:expr, $id:expr, $($arg:tt)*) => {
+        if log::log_enabled!(target: $target, $level) {
+            log::log!(target: $target, $level, "{}", cx_format!($id, $($arg)+))
+        }
+    }
+}
+
 macro_rules! cx_trace {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::trace!(target: $target, "{}", cx_format!($id, $($arg)+));
+        cx_log!(log::Level::Trace, target: $target, $id, $($arg)*)
     }
 }
 
 macro_rules! cx_debug {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::debug!(target: $target, "{}", cx_format!($id, $($arg)+));
+        cx_log!(log::Level::Debug, target: $target, $id, $($arg)*)
     }
 }
 
 macro_rules! cx_info {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::info!(target: $target, "{}", cx_format!($id, $($arg)+));
+        cx_log!(log::Level::Info, target: $target, $id, $($arg)*)
     }
 }
 
 macro_rules! cx_warn {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::warn!(target: $target, "{}", cx_format!($id, $($arg)+));
+        cx_log!(log::Level::Warn, target: $target, $id, $($arg)*)
     }
 }
 
 macro_rules! cx_error {
     (target: $target:expr, $id:expr, $($arg:tt)*) => {
-        log::error!(target: $target, "{}", cx_format!($id, $($arg)+));
+        cx_log!(log::Level::Error, target: $target, $id, $($arg)*)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::log_filter::LogFilter;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn create_echo_request(identifier: u16, sequence_number: u16) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x01010101).unwrap(); // destination address
+
+        raw.write_u8(8).unwrap(); // type (Echo Request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(identifier).unwrap();
+        raw.write_u16::<BigEndian>(sequence_number).unwrap();
+
+        raw
+    }
+
+    fn connection_id_of(raw: &mut [u8]) -> ConnectionId {
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    #[test]
+    fn icmp_identifier_demux() {
+        let id1 = connection_id_of(&mut create_echo_request(0x1234, 1));
+        let id2 = connection_id_of(&mut create_echo_request(0x5678, 1));
+        let id1_next = connection_id_of(&mut create_echo_request(0x1234, 2));
+
+        assert_eq!(Some(0x1234), id1.icmp_identifier());
+        assert_eq!(Some(0x5678), id2.icmp_identifier());
+        assert_ne!(id1, id2);
+        // the sequence number does not take part in the connection key
+        assert_eq!(id1, id1_next);
+    }
+
+    #[test]
+    fn assign_distinct_flow_ids() {
+        let id = connection_id_of(&mut create_echo_request(0x1234, 1));
+        assert_eq!(None, id.flow_id());
+        let flow1 = id.clone().with_new_flow_id();
+        let flow2 = id.clone().with_new_flow_id();
+        let flow1_id = flow1.flow_id().unwrap();
+        assert!(flow2.flow_id().unwrap() > flow1_id);
+        // the flow id does not take part in the connection key
+        assert_eq!(id, flow1);
+        assert_eq!(flow1, flow2);
+
+        let line = cx_format!(flow1, "Open");
+        assert_eq!(
+            format!("#{} 10.0.0.2 -> 1.1.1.1 (id=4660) Open", flow1_id),
+            line
+        );
+    }
+
+    #[test]
+    fn record_stats() {
+        let mut stats = ConnectionStats::default();
+        stats.record_tx(12);
+        stats.record_tx(30);
+        stats.record_rx(7);
+        stats.record_tx_dropped();
+        assert_eq!(2, stats.tx_packets);
+        assert_eq!(42, stats.tx_bytes);
+        assert_eq!(1, stats.rx_packets);
+        assert_eq!(7, stats.rx_bytes);
+        assert_eq!(1, stats.tx_dropped);
+        assert!(stats.last_tx.is_some());
+        assert!(stats.last_rx >= stats.last_tx);
+        let later = stats.last_tx.unwrap() + Duration::from_secs(5);
+        assert!(stats.tx_idle(later).unwrap() >= Duration::from_secs(5));
+        assert!(ConnectionStats::default().rx_idle(later).is_none());
+        stats.record_rtt(Duration::from_millis(30));
+
+        let mut total = ConnectionStats::default();
+        total += stats;
+        total += stats;
+        assert_eq!(84, total.tx_bytes);
+        assert_eq!(2, total.rx_packets);
+        assert_eq!(2, total.tx_dropped);
+        assert_eq!(stats.last_rx, total.last_rx);
+        assert_eq!(Some(Duration::from_millis(30)), total.last_rtt);
+        assert_eq!(2, total.rtt_samples);
+        assert_eq!(Duration::from_millis(60), total.rtt_total);
+    }
+
+    #[test]
+    fn idle_expiration() {
+        let idle_since = Instant::now() - Duration::from_secs(3);
+        assert!(!is_idle_expired(idle_since, Duration::from_secs(10)));
+        assert!(is_idle_expired(idle_since, Duration::from_secs(2)));
+    }
+
+    // logger capturing the messages enabled by its filter
+    struct CaptureLogger {
+        filter: LogFilter,
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            self.filter.enabled(metadata.target(), metadata.level())
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                let message = format!("{}: {}", record.target(), record.args());
+                self.messages.lock().unwrap().push(message);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    // count how many times the id is formatted
+    struct CountingId(AtomicUsize);
+
+    impl fmt::Display for CountingId {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            write!(f, "[id]")
+        }
+    }
+
+    #[test]
+    fn filter_connection_logs_per_target() {
+        // the other targets are disabled, so that the other tests do not log concurrently
+        let filter = LogFilter::parse("off,QuietConnection=warn,LoudConnection=debug").unwrap();
+        let logger: &'static CaptureLogger = Box::leak(Box::new(CaptureLogger {
+            filter,
+            messages: Mutex::new(Vec::new()),
+        }));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let id = CountingId(AtomicUsize::new(0));
+        cx_debug!(target: "QuietConnection", id, "Suppressed {}", 1);
+        // the message is not even formatted
+        assert_eq!(0, id.0.load(Ordering::SeqCst));
+
+        cx_debug!(target: "LoudConnection", id, "Enabled {}", 2);
+        cx_warn!(target: "QuietConnection", id, "Warning");
+        assert_eq!(2, id.0.load(Ordering::SeqCst));
+
+        let messages = logger.messages.lock().unwrap();
+        assert_eq!(
+            vec![
+                "LoudConnection: [id] Enabled 2",
+                "QuietConnection: [id] Warning"
+            ],
+            *messages
+        );
     }
 }
@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::connection::{ClosedConnection, ConnectionId};
+use super::ipv4_header::Protocol;
+
+const TAG: &str = "ConnectionLog";
+
+pub type SharedConnectionLog = Rc<RefCell<ConnectionLog<LineWriter<File>>>>;
+
+/// Record the lifecycle events of the connections (open, close and expire), as one JSON object
+/// per line.
+pub struct ConnectionLog<W: Write> {
+    output: W,
+    // the connection ids are unique per client
+    opened: HashMap<(u32, ConnectionId), Instant>,
+}
+
+impl ConnectionLog<LineWriter<File>> {
+    /// Append the events to the file at `path`, flushed line by line.
+    pub fn open_shared(path: &Path) -> io::Result<SharedConnectionLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Rc::new(RefCell::new(ConnectionLog::new(LineWriter::new(
+            file,
+        )))))
+    }
+}
+
+impl<W: Write> ConnectionLog<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            opened: HashMap::new(),
+        }
+    }
+
+    pub fn on_opened(&mut self, client_id: u32, id: &ConnectionId) {
+        self.opened.insert((client_id, id.clone()), Instant::now());
+        let line = Self::format_event("open", client_id, id);
+        self.write_line(line);
+    }
+
+    pub fn on_closed(&mut self, client_id: u32, closed: &ClosedConnection) {
+        let duration = self
+            .opened
+            .remove(&(client_id, closed.id.clone()))
+            .map_or(0, |opened| opened.elapsed().as_millis());
+        let event = if closed.expired { "expire" } else { "close" };
+        let mut line = Self::format_event(event, client_id, &closed.id);
+        // replace the closing brace
+        line.pop();
+        line.push_str(&format!(
+            ",\"duration_ms\":{},\"tx_packets\":{},\"tx_bytes\":{},\"tx_dropped\":{},\
+             \"rx_packets\":{},\"rx_bytes\":{}}}",
+            duration,
+            closed.stats.tx_packets,
+            closed.stats.tx_bytes,
+            closed.stats.tx_dropped,
+            closed.stats.rx_packets,
+            closed.stats.rx_bytes
+        ));
+        self.write_line(line);
+    }
+
+    // the values are numbers or addresses, they never need to be escaped
+    fn format_event(event: &str, client_id: u32, id: &ConnectionId) -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let source = id.source();
+        let destination = id.destination();
+        let flow = id
+            .flow_id()
+            .map_or_else(String::new, |flow_id| format!(",\"flow\":{}", flow_id));
+        format!(
+            "{{\"event\":\"{}\",\"time_ms\":{},\"client\":{}{},\"protocol\":\"{}\",\
+             \"source_ip\":\"{}\",\"source_port\":{},\"destination_ip\":\"{}\",\
+             \"destination_port\":{}}}",
+            event,
+            time,
+            client_id,
+            flow,
+            Self::protocol_name(id.protocol()),
+            source.ip(),
+            source.port(),
+            destination.ip(),
+            destination.port()
+        )
+    }
+
+    fn protocol_name(protocol: Protocol) -> String {
+        match protocol {
+            Protocol::Tcp => "tcp".to_string(),
+            Protocol::Udp => "udp".to_string(),
+            Protocol::Icmp => "icmp".to_string(),
+            Protocol::Other(number) => number.to_string(),
+        }
+    }
+
+    fn write_line(&mut self, mut line: String) {
+        line.push('\n');
+        if let Err(err) = self.output.write_all(line.as_bytes()) {
+            error!(target: TAG, "Cannot write connection event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::connection::ConnectionStats;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn create_connection_id() -> ConnectionId {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x01020304).unwrap(); // destination address
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(8).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    // remove the timings, which change from one run to another
+    fn strip_timings(line: &str) -> String {
+        let mut line = line.to_string();
+        for field in &[",\"time_ms\":", ",\"duration_ms\":"] {
+            if let Some(start) = line.find(field) {
+                let end = start + line[start + 1..].find(',').unwrap() + 1;
+                line.replace_range(start..end, "");
+            }
+        }
+        line
+    }
+
+    #[test]
+    fn log_open_and_close_events() {
+        let id = create_connection_id();
+        assert_eq!(None, id.flow_id());
+        let mut stats = ConnectionStats::default();
+        stats.record_tx(10);
+        stats.record_rx(20);
+        stats.record_rx(30);
+
+        let mut connection_log = ConnectionLog::new(Vec::new());
+        connection_log.on_opened(3, &id);
+        connection_log.on_closed(
+            3,
+            &ClosedConnection {
+                id,
+                stats,
+                expired: false,
+            },
+        );
+
+        let output = String::from_utf8(connection_log.output).unwrap();
+        let lines: Vec<_> = output.lines().map(strip_timings).collect();
+        assert_eq!(
+            vec![
+                "{\"event\":\"open\",\"client\":3,\"protocol\":\"udp\",\
+                 \"source_ip\":\"10.0.0.2\",\"source_port\":1234,\
+                 \"destination_ip\":\"1.2.3.4\",\"destination_port\":53}",
+                "{\"event\":\"close\",\"client\":3,\"protocol\":\"udp\",\
+                 \"source_ip\":\"10.0.0.2\",\"source_port\":1234,\
+                 \"destination_ip\":\"1.2.3.4\",\"destination_port\":53,\
+                 \"tx_packets\":1,\"tx_bytes\":10,\"tx_dropped\":0,\
+                 \"rx_packets\":2,\"rx_bytes\":50}",
+            ],
+            lines
+        );
+        assert!(connection_log.opened.is_empty());
+    }
+
+    #[test]
+    fn log_flow_id() {
+        let id = create_connection_id().with_new_flow_id();
+        let mut connection_log = ConnectionLog::new(Vec::new());
+        connection_log.on_opened(3, &id);
+
+        let output = String::from_utf8(connection_log.output).unwrap();
+        let expected = format!("\"client\":3,\"flow\":{},", id.flow_id().unwrap());
+        assert!(output.contains(&expected));
+    }
+}
@@ -0,0 +1,90 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Index;
+use std::rc::Rc;
+use std::slice;
+
+use super::connection::{Connection, ConnectionId};
+
+/// Connections of a router, addressed by index or found by id in constant time.
+///
+/// The connections are stored contiguously, along with their ids (so that a connection currently
+/// borrowed may still be removed). Removing a connection moves the last one to its index.
+#[derive(Default)]
+pub struct ConnectionTable {
+    connections: Vec<Rc<RefCell<dyn Connection>>>,
+    ids: Vec<ConnectionId>,
+    // the id embeds the ICMP identifier, so the ICMP flows to the same host are distinct
+    indices: HashMap<ConnectionId, usize>,
+}
+
+impl ConnectionTable {
+    /// Add `connection`, whose id must not be in the table yet.
+    pub fn push(&mut self, connection: Rc<RefCell<dyn Connection>>) {
+        let id = connection.borrow().id().clone();
+        let previous = self.indices.insert(id.clone(), self.connections.len());
+        debug_assert!(previous.is_none(), "Duplicate connection: {}", id);
+        self.connections.push(connection);
+        self.ids.push(id);
+    }
+
+    pub fn find_index(&self, id: &ConnectionId) -> Option<usize> {
+        self.indices.get(id).copied()
+    }
+
+    /// Remove the connection at `index`, and move the last one in its place.
+    pub fn swap_remove(&mut self, index: usize) -> Rc<RefCell<dyn Connection>> {
+        let connection = self.connections.swap_remove(index);
+        let id = self.ids.swap_remove(index);
+        self.indices.remove(&id);
+        if let Some(moved_id) = self.ids.get(index) {
+            self.indices.insert(moved_id.clone(), index);
+        }
+        connection
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Rc<RefCell<dyn Connection>>> {
+        self.connections.iter()
+    }
+}
+
+impl Index<usize> for ConnectionTable {
+    type Output = Rc<RefCell<dyn Connection>>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.connections[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a ConnectionTable {
+    type Item = &'a Rc<RefCell<dyn Connection>>;
+    type IntoIter = slice::Iter<'a, Rc<RefCell<dyn Connection>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
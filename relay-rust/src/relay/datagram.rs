@@ -17,15 +17,91 @@
 use mio::net::UdpSocket;
 use std::cmp;
 use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::{AsRawFd, RawFd};
 
 pub const MAX_DATAGRAM_LENGTH: usize = 1 << 16;
 
+/// Maximum number of datagrams sent by a single `send_batch()` call.
+pub const MAX_BATCH_DATAGRAMS: usize = 32;
+
 pub trait DatagramSender {
     fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Send several datagrams (at most `MAX_BATCH_DATAGRAMS`), in a single syscall if possible.
+    ///
+    /// Return the number of datagrams sent, which may be less than `datagrams.len()`. An error is
+    /// returned only if the first one could not be sent.
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        for (i, datagram) in datagrams.iter().enumerate() {
+            match self.send(datagram) {
+                Ok(w) if w == datagram.len() => (),
+                Ok(_) if i == 0 => {
+                    return Err(io::Error::other("Cannot write the whole datagram"));
+                }
+                Err(err) if i == 0 => return Err(err),
+                _ => return Ok(i),
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+/// Send `datagrams` on the (connected) socket `fd` with a single `sendmmsg()` call.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send_mmsg(fd: RawFd, datagrams: &[&[u8]]) -> io::Result<usize> {
+    let count = cmp::min(datagrams.len(), MAX_BATCH_DATAGRAMS);
+    // iovec and mmsghdr are plain C structs, for which all-zero is a valid value
+    let mut iovecs: [libc::iovec; MAX_BATCH_DATAGRAMS] = unsafe { std::mem::zeroed() };
+    let mut messages: [libc::mmsghdr; MAX_BATCH_DATAGRAMS] = unsafe { std::mem::zeroed() };
+    for i in 0..count {
+        iovecs[i].iov_base = datagrams[i].as_ptr() as *mut libc::c_void;
+        iovecs[i].iov_len = datagrams[i].len();
+        // no msg_name, the socket is connected
+        messages[i].msg_hdr.msg_iov = &mut iovecs[i];
+        messages[i].msg_hdr.msg_iovlen = 1;
+    }
+    // the first count messages point to the datagrams, which outlive the call
+    let sent = unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), count as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written = messages[..sent as usize]
+        .iter()
+        .map(|message| message.msg_len as usize);
+    count_whole_datagrams(written, datagrams)
+}
+
+/// Count the datagrams sent entirely, given the number of bytes `written` for each of the first
+/// `datagrams`.
+///
+/// As for `send_batch()`, a datagram not written entirely is reported as not sent (nor the
+/// following ones), and an error is returned if it is the first one.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn count_whole_datagrams<I>(written: I, datagrams: &[&[u8]]) -> io::Result<usize>
+where
+    I: Iterator<Item = usize>,
+{
+    let mut count = 0;
+    for (length, datagram) in written.zip(datagrams) {
+        if length != datagram.len() {
+            if count == 0 {
+                return Err(io::Error::other("Cannot write the whole datagram"));
+            }
+            break;
+        }
+        count += 1;
+    }
+    Ok(count)
 }
 
 pub trait DatagramReceiver {
     fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// TTL of the IP packet which carried the last datagram received, if known.
+    fn received_ttl(&self) -> Option<u8> {
+        None
+    }
 }
 
 // Expose UdpSocket as DatagramSender
@@ -34,6 +110,11 @@ impl DatagramSender for UdpSocket {
         // call the Self implementation
         (self as &Self).send(buf)
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        send_mmsg(self.as_raw_fd(), datagrams)
+    }
 }
 
 // Expose UdpSocket as DatagramReceiver
@@ -51,6 +132,7 @@ where
 {
     read: &'a mut R,
     max_chunk_size: Option<usize>,
+    ttl: Option<u8>,
 }
 
 impl<'a, R> ReadAdapter<'a, R>
@@ -61,8 +143,15 @@ where
         Self {
             read,
             max_chunk_size,
+            ttl: None,
         }
     }
+
+    /// Report `ttl` as the TTL of the data read, received with its IP header stripped.
+    pub fn with_ttl(mut self, ttl: Option<u8>) -> Self {
+        self.ttl = ttl;
+        self
+    }
 }
 
 impl<'a, R> DatagramReceiver for ReadAdapter<'a, R>
@@ -77,11 +166,71 @@ where
         };
         self.read.read(&mut buf[..len])
     }
+
+    fn received_ttl(&self) -> Option<u8> {
+        self.ttl
+    }
+}
+
+/// Receiver of the datagrams of a socket on which `IP_RECVTTL` is enabled, which reports the TTL
+/// of the packet carrying each of them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub struct TtlReceiver<'a, S: AsRawFd> {
+    socket: &'a S,
+    ttl: Option<u8>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl<'a, S: AsRawFd> TtlReceiver<'a, S> {
+    pub fn new(socket: &'a S) -> Self {
+        Self { socket, ttl: None }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl<S: AsRawFd> DatagramReceiver for TtlReceiver<'_, S> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // room (properly aligned) for the control message holding the TTL
+        let mut control = [0u64; 4];
+        // msghdr is a plain C struct, for which all-zero is a valid value
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iovec;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = std::mem::size_of_val(&control) as _;
+        // the buffers outlive the call
+        let r = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut message, 0) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.ttl = None;
+        // the control messages have been written by the kernel within the control buffer
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_TTL {
+                    let data = libc::CMSG_DATA(cmsg) as *const libc::c_int;
+                    self.ttl = Some(std::ptr::read_unaligned(data) as u8);
+                }
+                cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+            }
+        }
+        Ok(r as usize)
+    }
+
+    fn received_ttl(&self) -> Option<u8> {
+        self.ttl
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use std::collections::VecDeque;
 
     // Mock datagram socket to be used in other tests
     pub struct MockDatagramSocket {
@@ -111,7 +260,7 @@ pub mod tests {
     impl DatagramSender for MockDatagramSocket {
         fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
             let len = cmp::min(self.buf.len(), buf.len());
-            &mut self.buf[..len].copy_from_slice(&buf[..len]);
+            self.buf[..len].copy_from_slice(&buf[..len]);
             self.len = len;
             Ok(len)
         }
@@ -120,11 +269,76 @@ pub mod tests {
     impl DatagramReceiver for MockDatagramSocket {
         fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let len = cmp::min(self.len, buf.len());
-            &mut buf[..len].copy_from_slice(&self.buf[..len]);
+            buf[..len].copy_from_slice(&self.buf[..len]);
+            Ok(len)
+        }
+    }
+
+    // Loopback datagram socket, receiving the datagrams sent to it in order (to be used in other
+    // tests to inject packets from the "network")
+    #[derive(Default)]
+    pub struct LoopbackDatagramSocket {
+        datagrams: VecDeque<Vec<u8>>,
+    }
+
+    impl LoopbackDatagramSocket {
+        pub fn pending(&self) -> usize {
+            self.datagrams.len()
+        }
+    }
+
+    impl DatagramSender for LoopbackDatagramSocket {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.datagrams.push_back(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    impl DatagramReceiver for LoopbackDatagramSocket {
+        fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let datagram = self
+                .datagrams
+                .pop_front()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+            // like a real socket, truncate the datagram if it does not fit
+            let len = cmp::min(datagram.len(), buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
             Ok(len)
         }
     }
 
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn count_whole_datagrams_sent() {
+        let datagrams: [&[u8]; 3] = [&[1, 2, 3], &[4, 5], &[6]];
+        let count = |written: &[usize]| count_whole_datagrams(written.iter().cloned(), &datagrams);
+        assert_eq!(3, count(&[3, 2, 1]).unwrap());
+        // sendmmsg() may send only the first datagrams
+        assert_eq!(2, count(&[3, 2]).unwrap());
+        // a short write stops the count
+        assert_eq!(1, count(&[3, 1, 1]).unwrap());
+        assert_eq!(2, count(&[3, 2, 0]).unwrap());
+        // unless it is the first datagram
+        let err = count(&[2, 2, 1]).unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+    }
+
+    #[test]
+    fn loopback_preserves_datagram_boundaries() {
+        let mut loopback = LoopbackDatagramSocket::default();
+        loopback.send(&[1, 2, 3]).unwrap();
+        loopback.send(&[4, 5]).unwrap();
+        assert_eq!(2, loopback.pending());
+
+        let mut buf = [0u8; 10];
+        assert_eq!(3, loopback.recv(&mut buf).unwrap());
+        assert_eq!([1, 2, 3], &buf[..3]);
+        assert_eq!(2, loopback.recv(&mut buf).unwrap());
+        assert_eq!([4, 5], &buf[..2]);
+        let err = loopback.recv(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+    }
+
     #[test]
     fn mock_send() {
         let mut mock = MockDatagramSocket::new();
@@ -179,4 +393,51 @@ pub mod tests {
             assert_eq!([4, 5], &buf[..2]);
         }
     }
+
+    #[test]
+    fn send_batch_on_udp_socket() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+
+        let datagrams: [&[u8]; 3] = [&[1, 2, 3], &[], &[4, 5]];
+        assert_eq!(3, socket.send_batch(&datagrams).unwrap());
+
+        let mut buf = [0u8; 16];
+        for datagram in &datagrams {
+            let r = server.recv(&mut buf).unwrap();
+            assert_eq!(*datagram, &buf[..r]);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn report_ttl_of_received_datagram() {
+        use socket2::{Domain, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        let address: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&address.into()).unwrap();
+        crate::relay::net::set_socket_option(&socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)
+            .unwrap();
+        let server: std::net::UdpSocket = socket.into();
+        server
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_ttl(42).unwrap();
+        client
+            .send_to(&[1, 2, 3], server.local_addr().unwrap())
+            .unwrap();
+
+        let mut receiver = TtlReceiver::new(&server);
+        let mut buf = [0u8; 16];
+        assert_eq!(3, receiver.recv(&mut buf).unwrap());
+        assert_eq!([1, 2, 3], buf[..3]);
+        assert_eq!(Some(42), receiver.received_ttl());
+    }
 }
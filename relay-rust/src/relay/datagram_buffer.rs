@@ -15,15 +15,47 @@
  */
 
 use byteorder::{BigEndian, ByteOrder};
-use log::*;
+use std::fmt;
 use std::io;
+use std::str::FromStr;
 
-use super::datagram::{DatagramSender, MAX_DATAGRAM_LENGTH};
+use super::datagram::{DatagramSender, MAX_BATCH_DATAGRAMS, MAX_DATAGRAM_LENGTH};
 
 const HEADER_LENGTH: usize = 2;
 const MAX_BLOCK_LENGTH: usize = HEADER_LENGTH + MAX_DATAGRAM_LENGTH;
 
-const TAG: &str = "DatagramBuffer";
+/// Datagram to drop when a datagram buffer is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Reject the new datagram.
+    #[default]
+    TailDrop,
+    /// Evict the oldest datagrams to make room for the new one, so that real-time traffic keeps a
+    /// low latency.
+    HeadDrop,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tail_drop" => Ok(QueuePolicy::TailDrop),
+            "head_drop" => Ok(QueuePolicy::HeadDrop),
+            _ => Err(format!("Invalid queue policy: \"{}\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            QueuePolicy::TailDrop => "tail_drop",
+            QueuePolicy::HeadDrop => "head_drop",
+        };
+        write!(f, "{}", name)
+    }
+}
 
 /// Circular buffer to store datagrams (preserving their boundaries).
 ///
@@ -42,6 +74,10 @@ pub struct DatagramBuffer {
     head: usize,
     tail: usize,
     circular_buffer_length: usize,
+    // head == tail when the buffer is either empty or full, so count the datagrams
+    datagrams: usize,
+    max_datagrams: usize,
+    policy: QueuePolicy,
 }
 
 impl DatagramBuffer {
@@ -51,14 +87,45 @@ impl DatagramBuffer {
             head: 0,
             tail: 0,
             circular_buffer_length: capacity + 1,
+            datagrams: 0,
+            max_datagrams: usize::MAX,
+            policy: QueuePolicy::TailDrop,
         }
     }
 
+    /// Create a buffer able to store up to `max_datagrams` datagrams of any length.
+    ///
+    /// Each datagram may be up to 64K, so this reserves `max_datagrams * 64K` bytes.
+    pub fn with_max_datagrams(max_datagrams: usize) -> Self {
+        Self {
+            max_datagrams,
+            ..Self::new(max_datagrams * MAX_DATAGRAM_LENGTH)
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: QueuePolicy) {
+        self.policy = policy;
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.head == self.tail
+        self.datagrams == 0
+    }
+
+    /// Indicate whether a datagram of `datagram_length` bytes may be read now, possibly by
+    /// evicting older datagrams.
+    pub fn accepts(&self, datagram_length: usize) -> bool {
+        // once empty, the buffer has the extra space for storing any datagram
+        self.policy == QueuePolicy::HeadDrop || self.has_enough_space_for(datagram_length)
     }
 
     pub fn has_enough_space_for(&self, datagram_length: usize) -> bool {
+        if self.datagrams >= self.max_datagrams {
+            return false;
+        }
+        if self.head == self.tail && !self.is_empty() {
+            // the head wrapped up to the tail
+            return false;
+        }
         if self.head >= self.tail {
             // there is at least the extra space for storing 1 packet
             return true;
@@ -67,43 +134,85 @@ impl DatagramBuffer {
         HEADER_LENGTH + datagram_length < remaining
     }
 
-    pub fn write_to<S: DatagramSender>(&mut self, destination: &mut S) -> io::Result<()> {
+    /// Send as many pending datagrams as possible (up to `MAX_BATCH_DATAGRAMS`) at once.
+    ///
+    /// Only the datagrams actually sent are removed from the buffer. Return the number of
+    /// datagrams and of bytes sent.
+    pub fn write_batch_to<S: DatagramSender>(
+        &mut self,
+        destination: &mut S,
+    ) -> io::Result<(usize, usize)> {
         assert!(
             !self.is_empty(),
-            "DatagramBuffer.write_to() called while empty"
+            "DatagramBuffer.write_batch_to() called while empty"
         );
+        let (sent, bytes) = {
+            let mut datagrams: [&[u8]; MAX_BATCH_DATAGRAMS] = [&[]; MAX_BATCH_DATAGRAMS];
+            let count = self.datagrams.min(MAX_BATCH_DATAGRAMS);
+            let mut tail = self.tail;
+            for datagram in datagrams.iter_mut().take(count) {
+                let length = BigEndian::read_u16(&self.buf[tail..tail + HEADER_LENGTH]) as usize;
+                tail += HEADER_LENGTH;
+                *datagram = &self.buf[tail..tail + length];
+                tail += length;
+                if tail >= self.circular_buffer_length {
+                    tail = 0;
+                }
+            }
+            let sent = destination.send_batch(&datagrams[..count])?;
+            // consuming more datagrams than sent would lose them
+            assert!(sent <= count, "More datagrams sent than requested");
+            let bytes = datagrams[..sent]
+                .iter()
+                .map(|datagram| datagram.len())
+                .sum();
+            (sent, bytes)
+        };
+        for _ in 0..sent {
+            self.consume_datagram();
+        }
+        Ok((sent, bytes))
+    }
+
+    /// Remove the next pending datagram without sending it, and return it.
+    pub fn pop(&mut self) -> Vec<u8> {
+        assert!(!self.is_empty(), "DatagramBuffer.pop() called while empty");
+        let (index, length) = self.consume_datagram();
+        self.buf[index..index + length].to_vec()
+    }
+
+    // remove the datagram at the tail, and return its (index, length) in buf
+    fn consume_datagram(&mut self) -> (usize, usize) {
         let length = self.read_length() as usize;
-        let source_slice = &self.buf[self.tail..self.tail + length];
+        let index = self.tail;
         self.tail += length;
         if self.tail >= self.circular_buffer_length {
             self.tail = 0;
         }
-        let w = destination.send(source_slice)?;
-        if w != length {
-            error!(
-                target: TAG,
-                "Cannot write the whole datagram to the buffer (only {}/{})", w, length
-            );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Cannot write the whole datagram",
-            ));
-        }
-        Ok(())
+        self.datagrams -= 1;
+        (index, length)
     }
 
-    pub fn read_from(&mut self, source: &[u8]) -> io::Result<()> {
+    /// Store the datagram `source`, to be sent later.
+    ///
+    /// If the buffer is full, the datagram is rejected or, with the head-drop policy, the oldest
+    /// datagrams are evicted. Return the number of datagrams evicted.
+    pub fn read_from(&mut self, source: &[u8]) -> io::Result<usize> {
         let length = source.len();
         assert!(
             length <= MAX_DATAGRAM_LENGTH,
             "Datagram length may not be greater than {} bytes",
             MAX_DATAGRAM_LENGTH
         );
+        let mut evicted = 0;
+        if self.policy == QueuePolicy::HeadDrop {
+            while !self.is_empty() && !self.has_enough_space_for(length) {
+                self.consume_datagram();
+                evicted += 1;
+            }
+        }
         if !self.has_enough_space_for(length) {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Datagram buffer is full",
-            ));
+            return Err(io::Error::other("Datagram buffer is full"));
         }
         self.write_length(length as u16);
         let target_slice = &mut self.buf[self.head..self.head + length];
@@ -112,7 +221,8 @@ impl DatagramBuffer {
         if self.head >= self.circular_buffer_length {
             self.head = 0;
         }
-        Ok(())
+        self.datagrams += 1;
+        Ok(evicted)
     }
 
     fn read_length(&mut self) -> u16 {
@@ -129,7 +239,52 @@ impl DatagramBuffer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::relay::datagram::tests::MockDatagramSocket;
+
+    // record the datagrams sent, accepting at most max_batch datagrams per call
+    struct BatchRecorder {
+        max_batch: usize,
+        batches: Vec<Vec<Vec<u8>>>,
+    }
+
+    impl BatchRecorder {
+        fn new(max_batch: usize) -> Self {
+            Self {
+                max_batch,
+                batches: Vec::new(),
+            }
+        }
+    }
+
+    impl DatagramSender for BatchRecorder {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.send_batch(&[buf]).map(|_| buf.len())
+        }
+
+        fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+            let count = datagrams.len().min(self.max_batch);
+            let batch = datagrams[..count].iter().map(|d| d.to_vec()).collect();
+            self.batches.push(batch);
+            Ok(count)
+        }
+    }
+
+    // write the first datagrams entirely, then only a part of the next one
+    struct ShortWriter {
+        complete_writes: usize,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl DatagramSender for ShortWriter {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.complete_writes == 0 {
+                self.written.push(buf[..buf.len() / 2].to_vec());
+                return Ok(buf.len() / 2);
+            }
+            self.complete_writes -= 1;
+            self.written.push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
 
     fn create_datagram(length: u8) -> Vec<u8> {
         (0..length).collect()
@@ -175,8 +330,8 @@ mod tests {
         datagram_buffer.read_from(&create_datagram(10)).unwrap();
         {
             // write and forget
-            let mut mock = MockDatagramSocket::new();
-            datagram_buffer.write_to(&mut mock).unwrap();
+            let mut recorder = BatchRecorder::new(1);
+            datagram_buffer.write_batch_to(&mut recorder).unwrap();
         }
 
         // DatagramBuffer is expected to store the whole datagram, even if it exceeds its "capacity"
@@ -187,9 +342,140 @@ mod tests {
         assert_eq!(read_datagram(&mut datagram_buffer), datagram3);
     }
 
+    #[test]
+    fn drop_when_max_datagrams_reached() {
+        let mut datagram_buffer = DatagramBuffer::with_max_datagrams(4);
+        for _ in 0..4 {
+            datagram_buffer.read_from(&create_datagram(100)).unwrap();
+        }
+        assert!(datagram_buffer.read_from(&create_datagram(1)).is_err());
+
+        // consuming one datagram makes room for another one
+        assert_eq!(read_datagram(&mut datagram_buffer), create_datagram(100));
+        datagram_buffer.read_from(&create_datagram(1)).unwrap();
+    }
+
+    #[test]
+    fn drop_according_to_queue_policy() {
+        let datagrams: Vec<_> = (1..=5).map(create_datagram).collect();
+
+        let mut tail_drop = DatagramBuffer::with_max_datagrams(4);
+        for datagram in &datagrams[..4] {
+            assert_eq!(0, tail_drop.read_from(datagram).unwrap());
+        }
+        assert!(!tail_drop.accepts(5));
+        assert!(tail_drop.read_from(&datagrams[4]).is_err());
+        for datagram in &datagrams[..4] {
+            assert_eq!(read_datagram(&mut tail_drop), *datagram);
+        }
+
+        let mut head_drop = DatagramBuffer::with_max_datagrams(4);
+        head_drop.set_policy(QueuePolicy::HeadDrop);
+        for datagram in &datagrams[..4] {
+            assert_eq!(0, head_drop.read_from(datagram).unwrap());
+        }
+        assert!(head_drop.accepts(5));
+        // the oldest datagram is evicted
+        assert_eq!(1, head_drop.read_from(&datagrams[4]).unwrap());
+        for datagram in &datagrams[1..] {
+            assert_eq!(read_datagram(&mut head_drop), *datagram);
+        }
+        assert!(head_drop.is_empty());
+    }
+
+    #[test]
+    fn full_is_not_empty() {
+        // the head wraps exactly on the tail
+        let mut datagram_buffer = DatagramBuffer::new(8);
+        datagram_buffer.read_from(&create_datagram(7)).unwrap();
+        assert!(!datagram_buffer.is_empty());
+        assert!(datagram_buffer.read_from(&create_datagram(1)).is_err());
+        assert_eq!(read_datagram(&mut datagram_buffer), create_datagram(7));
+        assert!(datagram_buffer.is_empty());
+    }
+
+    #[test]
+    fn send_all_datagrams_in_one_batch() {
+        let mut datagram_buffer = DatagramBuffer::new(64);
+        for length in 1..=5 {
+            datagram_buffer.read_from(&create_datagram(length)).unwrap();
+        }
+
+        let mut recorder = BatchRecorder::new(MAX_BATCH_DATAGRAMS);
+        let (datagrams, bytes) = datagram_buffer.write_batch_to(&mut recorder).unwrap();
+        assert_eq!(5, datagrams);
+        assert_eq!(1 + 2 + 3 + 4 + 5, bytes);
+        assert_eq!(1, recorder.batches.len());
+        let expected: Vec<_> = (1..=5).map(create_datagram).collect();
+        assert_eq!(expected, recorder.batches[0]);
+        assert!(datagram_buffer.is_empty());
+    }
+
+    #[test]
+    fn keep_datagrams_not_sent() {
+        let mut datagram_buffer = DatagramBuffer::new(14);
+        // the datagrams wrap around the end of the circular buffer
+        datagram_buffer.read_from(&create_datagram(10)).unwrap();
+        read_datagram(&mut datagram_buffer);
+        for length in 2..=4 {
+            datagram_buffer.read_from(&create_datagram(length)).unwrap();
+        }
+
+        let mut recorder = BatchRecorder::new(2);
+        assert_eq!(
+            (2, 5),
+            datagram_buffer.write_batch_to(&mut recorder).unwrap()
+        );
+        assert!(!datagram_buffer.is_empty());
+        assert_eq!(
+            (1, 4),
+            datagram_buffer.write_batch_to(&mut recorder).unwrap()
+        );
+        assert!(datagram_buffer.is_empty());
+
+        let expected = vec![
+            vec![create_datagram(2), create_datagram(3)],
+            vec![create_datagram(4)],
+        ];
+        assert_eq!(expected, recorder.batches);
+    }
+
+    #[test]
+    fn keep_datagrams_partially_written() {
+        let mut datagram_buffer = DatagramBuffer::new(14);
+        // the datagrams wrap around the end of the circular buffer
+        datagram_buffer.read_from(&create_datagram(10)).unwrap();
+        read_datagram(&mut datagram_buffer);
+        for length in 2..=4 {
+            datagram_buffer.read_from(&create_datagram(length)).unwrap();
+        }
+
+        // the second datagram is kept entirely, as well as the next one
+        let mut writer = ShortWriter {
+            complete_writes: 1,
+            written: Vec::new(),
+        };
+        assert_eq!((1, 2), datagram_buffer.write_batch_to(&mut writer).unwrap());
+        assert_eq!(create_datagram(2), writer.written[0]);
+
+        // a short write of the first datagram is an error, nothing is consumed
+        let mut writer = ShortWriter {
+            complete_writes: 0,
+            written: Vec::new(),
+        };
+        assert!(datagram_buffer.write_batch_to(&mut writer).is_err());
+
+        assert_eq!(read_datagram(&mut datagram_buffer), create_datagram(3));
+        assert_eq!(read_datagram(&mut datagram_buffer), create_datagram(4));
+        assert!(datagram_buffer.is_empty());
+        // the ring is still consistent for the next datagrams
+        datagram_buffer.read_from(&create_datagram(5)).unwrap();
+        assert_eq!(read_datagram(&mut datagram_buffer), create_datagram(5));
+    }
+
     fn read_datagram(datagram_buffer: &mut DatagramBuffer) -> Vec<u8> {
-        let mut mock = MockDatagramSocket::new();
-        datagram_buffer.write_to(&mut mock).unwrap();
-        mock.data().to_vec()
+        let mut recorder = BatchRecorder::new(1);
+        datagram_buffer.write_batch_to(&mut recorder).unwrap();
+        recorder.batches.remove(0).remove(0)
     }
 }
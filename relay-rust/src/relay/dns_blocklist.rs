@@ -0,0 +1,176 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use super::dns_cache::{self, FLAG_RECURSION_AVAILABLE, FLAG_RECURSION_DESIRED, FLAG_RESPONSE};
+
+const RCODE_NAME_ERROR: u16 = 3;
+
+pub type SharedDnsBlocklist = Rc<DnsBlocklist>;
+
+/// Domains for which the relay answers DNS queries by itself, with a "name error" (NXDOMAIN).
+///
+/// An entry like `example.com` blocks only this name, while `*.example.com` blocks all its
+/// subdomains. Names are case-insensitive.
+#[derive(Debug, Default)]
+pub struct DnsBlocklist {
+    names: HashSet<String>,
+    // the domains whose subdomains are blocked
+    wildcards: HashSet<String>,
+}
+
+impl DnsBlocklist {
+    /// Load the blocklist from a file listing one domain per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut blocklist = Self::default();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let domain = line.trim_end_matches('.').to_ascii_lowercase();
+            match domain.strip_prefix("*.") {
+                Some(parent) => blocklist.wildcards.insert(parent.to_string()),
+                None => blocklist.names.insert(domain),
+            };
+        }
+        blocklist
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len() + self.wildcards.len()
+    }
+
+    /// Indicate whether `name` (lowercased, without the trailing dot) is blocked.
+    pub fn is_blocked(&self, name: &str) -> bool {
+        if self.names.contains(name) {
+            return true;
+        }
+        // check every parent domain against the wildcards
+        name.match_indices('.')
+            .any(|(index, _)| self.wildcards.contains(&name[index + 1..]))
+    }
+
+    /// Return the response to the DNS `query` if its name is blocked.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let question = dns_cache::parse_query(query)?;
+        let name = question_name(&question)?;
+        if !self.is_blocked(&name) {
+            return None;
+        }
+        // keep the question as is (with its original case), but none of the other records
+        let mut response = query[..dns_cache::HEADER_LENGTH + question.len()].to_vec();
+        let flags = FLAG_RESPONSE
+            | (dns_cache::flags(query) & FLAG_RECURSION_DESIRED)
+            | FLAG_RECURSION_AVAILABLE
+            | RCODE_NAME_ERROR;
+        BigEndian::write_u16(&mut response[2..4], flags);
+        // answer, authority and additional counts
+        for count in response[6..12].iter_mut() {
+            *count = 0;
+        }
+        Some(response)
+    }
+}
+
+/// Decode the name of an (uncompressed) question in dotted notation, without the trailing dot.
+pub fn question_name(question: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut index = 0;
+    loop {
+        let length = *question.get(index)? as usize;
+        if length == 0 {
+            break;
+        }
+        let label = question.get(index + 1..index + 1 + length)?;
+        labels.push(std::str::from_utf8(label).ok()?);
+        index += 1 + length;
+    }
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn create_query(id: u16, name: &str) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(id).unwrap();
+        raw.write_u16::<BigEndian>(0x0100).unwrap(); // flags (recursion desired)
+        raw.write_u16::<BigEndian>(1).unwrap(); // question count
+        raw.write_u16::<BigEndian>(0).unwrap(); // answer count
+        raw.write_u16::<BigEndian>(0).unwrap(); // authority count
+        raw.write_u16::<BigEndian>(0).unwrap(); // additional count
+        for label in name.split('.') {
+            raw.write_u8(label.len() as u8).unwrap();
+            raw.extend_from_slice(label.as_bytes());
+        }
+        raw.write_u8(0).unwrap();
+        raw.write_u16::<BigEndian>(1).unwrap(); // type A
+        raw.write_u16::<BigEndian>(1).unwrap(); // class IN
+        raw
+    }
+
+    #[test]
+    fn match_names_and_wildcards() {
+        let blocklist = DnsBlocklist::parse(
+            "# trackers\n\
+             tracker.example.com\n\
+             \n\
+             *.Ads.Example.\n",
+        );
+        assert_eq!(2, blocklist.len());
+        assert!(blocklist.is_blocked("tracker.example.com"));
+        assert!(!blocklist.is_blocked("example.com"));
+        assert!(!blocklist.is_blocked("www.tracker.example.com"));
+
+        assert!(blocklist.is_blocked("banner.ads.example"));
+        assert!(blocklist.is_blocked("a.b.ads.example"));
+        assert!(!blocklist.is_blocked("ads.example"));
+        assert!(!blocklist.is_blocked("badads.example"));
+    }
+
+    #[test]
+    fn answer_blocked_query_with_name_error() {
+        let blocklist = DnsBlocklist::parse("*.ads.example");
+        let query = create_query(0x1234, "Banner.ADS.example");
+
+        let response = blocklist.answer(&query).expect("Expected a response");
+        // same id and question
+        assert_eq!(&query[..2], &response[..2]);
+        assert_eq!(&query[12..], &response[12..]);
+        assert_eq!(0x8183, BigEndian::read_u16(&response[2..4]));
+        assert_eq!(1, BigEndian::read_u16(&response[4..6]));
+        assert_eq!(&[0; 6], &response[6..12]);
+
+        assert!(blocklist
+            .answer(&create_query(0x1234, "example.com"))
+            .is_none());
+    }
+}
@@ -0,0 +1,378 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use log::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::ipv4_packet::Ipv4Packet;
+use super::transport_header::TransportHeaderMut;
+
+const TAG: &str = "DnsCache";
+
+pub const DNS_PORT: u16 = 53;
+
+// rfc1035 section 4.1.1
+pub const HEADER_LENGTH: usize = 12;
+pub const FLAG_RESPONSE: u16 = 0x8000;
+const OPCODE_MASK: u16 = 0x7800;
+const FLAG_TRUNCATED: u16 = 0x0200;
+pub const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+pub const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const RCODE_MASK: u16 = 0x000F;
+
+const LABEL_POINTER: u8 = 0xC0;
+
+pub type SharedDnsCache = Rc<RefCell<DnsCache>>;
+
+/// Cache of the DNS responses, to answer repeated queries without a network round trip.
+///
+/// Entries are keyed by question (name, type and class), and expire after the minimum TTL of
+/// their answer records. The least recently used entry is evicted when the cache is full.
+pub struct DnsCache {
+    max_entries: usize,
+    // ordered from the least to the most recently used
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    question: Vec<u8>,
+    response: Vec<u8>,
+    expiration: Instant,
+}
+
+impl DnsCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn new_shared(max_entries: usize) -> SharedDnsCache {
+        Rc::new(RefCell::new(Self::new(max_entries)))
+    }
+
+    /// Return the cached response to the DNS `query`, if any.
+    ///
+    /// The response gets the transaction id of the query.
+    pub fn lookup(&mut self, query: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let question = parse_query(query)?;
+        self.entries.retain(|entry| entry.expiration > now);
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.question == question)?;
+        let entry = self.entries.remove(index);
+        let mut response = entry.response.clone();
+        // most recently used
+        self.entries.push(entry);
+        response[0..2].copy_from_slice(&query[0..2]);
+        Some(response)
+    }
+
+    /// Store the DNS `response` if it is cacheable.
+    pub fn store(&mut self, response: &[u8], now: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let (question, ttl) = match parse_response(response) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+        self.entries.retain(|entry| entry.question != question);
+        if self.entries.len() >= self.max_entries {
+            self.entries.remove(0);
+        }
+        debug!(target: TAG, "Caching response for {} seconds", ttl);
+        self.entries.push(Entry {
+            question,
+            response: response.to_vec(),
+            expiration: now + Duration::from_secs(u64::from(ttl)),
+        });
+    }
+}
+
+/// Build the raw IPv4 packet answering `query` (a UDP packet) with the payload `response`.
+pub fn build_response_packet(query: &Ipv4Packet, response: &[u8]) -> Vec<u8> {
+    let ipv4_header_length = query.ipv4_header_data().header_length() as usize;
+    let headers_length = ipv4_header_length + 8;
+    let total_length = headers_length + response.len();
+
+    let mut raw = Vec::with_capacity(total_length);
+    raw.extend_from_slice(&query.raw()[..headers_length]);
+    raw.extend_from_slice(response);
+    // set the lengths before parsing, the packet would be truncated otherwise
+    BigEndian::write_u16(&mut raw[2..4], total_length as u16);
+    BigEndian::write_u16(
+        &mut raw[ipv4_header_length + 4..ipv4_header_length + 6],
+        (8 + response.len()) as u16,
+    );
+
+    {
+        let mut packet = Ipv4Packet::parse(&mut raw);
+        {
+            let (mut ipv4_header, transport) = packet.split_mut();
+            ipv4_header.swap_source_and_destination();
+            if let Some((TransportHeaderMut::Udp(mut udp_header), _)) = transport {
+                udp_header.swap_source_and_destination();
+            }
+        }
+        packet.compute_checksums();
+    }
+    raw
+}
+
+pub fn flags(message: &[u8]) -> u16 {
+    BigEndian::read_u16(&message[2..4])
+}
+
+fn count(message: &[u8], index: usize) -> u16 {
+    BigEndian::read_u16(&message[4 + 2 * index..6 + 2 * index])
+}
+
+/// Return the question of a standard query having a single question, lowercased.
+pub fn parse_query(query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LENGTH {
+        return None;
+    }
+    let flags = flags(query);
+    if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 || count(query, 0) != 1 {
+        return None;
+    }
+    let (question, _) = parse_question(query)?;
+    Some(question)
+}
+
+/// Return the question of a successful response, and the minimum TTL of its answers.
+fn parse_response(response: &[u8]) -> Option<(Vec<u8>, u32)> {
+    if response.len() < HEADER_LENGTH {
+        return None;
+    }
+    let flags = flags(response);
+    if flags & FLAG_RESPONSE == 0
+        || flags & (OPCODE_MASK | FLAG_TRUNCATED | RCODE_MASK) != 0
+        || count(response, 0) != 1
+    {
+        return None;
+    }
+    let answer_count = count(response, 1);
+    if answer_count == 0 {
+        return None;
+    }
+    let (question, mut index) = parse_question(response)?;
+    let mut min_ttl = u32::MAX;
+    for _ in 0..answer_count {
+        index = skip_name(response, index)?;
+        // type (2), class (2), TTL (4), data length (2)
+        let fixed = response.get(index..index + 10)?;
+        min_ttl = min_ttl.min(BigEndian::read_u32(&fixed[4..8]));
+        let data_length = BigEndian::read_u16(&fixed[8..10]) as usize;
+        index += 10 + data_length;
+        if index > response.len() {
+            return None;
+        }
+    }
+    Some((question, min_ttl))
+}
+
+/// Parse the (uncompressed) question following the header, and return it lowercased, along with
+/// the index following it.
+fn parse_question(message: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut index = HEADER_LENGTH;
+    loop {
+        let length = *message.get(index)?;
+        if length & LABEL_POINTER != 0 {
+            // the question is the first name, it may not be compressed
+            return None;
+        }
+        index += 1 + length as usize;
+        if length == 0 {
+            break;
+        }
+    }
+    // type (2), class (2)
+    let end = index + 4;
+    let mut question = message.get(HEADER_LENGTH..end)?.to_vec();
+    // names are case-insensitive, but type and class are not characters
+    let name_length = index - HEADER_LENGTH;
+    question[..name_length].make_ascii_lowercase();
+    Some((question, end))
+}
+
+/// Return the index following the (possibly compressed) name starting at `index`.
+fn skip_name(message: &[u8], mut index: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(index)?;
+        if length & LABEL_POINTER == LABEL_POINTER {
+            // a pointer ends the name
+            return Some(index + 2);
+        }
+        index += 1 + length as usize;
+        if length == 0 {
+            return Some(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    const QUESTION_NAME: &[u8] = b"\x07example\x03com\x00";
+
+    fn create_query(id: u16) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(id).unwrap();
+        raw.write_u16::<BigEndian>(0x0100).unwrap(); // flags (recursion desired)
+        raw.write_u16::<BigEndian>(1).unwrap(); // question count
+        raw.write_u16::<BigEndian>(0).unwrap(); // answer count
+        raw.write_u16::<BigEndian>(0).unwrap(); // authority count
+        raw.write_u16::<BigEndian>(0).unwrap(); // additional count
+        raw.extend_from_slice(QUESTION_NAME);
+        raw.write_u16::<BigEndian>(1).unwrap(); // type A
+        raw.write_u16::<BigEndian>(1).unwrap(); // class IN
+        raw
+    }
+
+    fn create_response(id: u16, ttls: &[u32]) -> Vec<u8> {
+        let mut raw = create_query(id);
+        BigEndian::write_u16(&mut raw[2..4], 0x8180); // flags (response, no error)
+        BigEndian::write_u16(&mut raw[6..8], ttls.len() as u16); // answer count
+        for (i, &ttl) in ttls.iter().enumerate() {
+            raw.write_u16::<BigEndian>(0xC000 | HEADER_LENGTH as u16)
+                .unwrap(); // name pointer
+            raw.write_u16::<BigEndian>(1).unwrap(); // type A
+            raw.write_u16::<BigEndian>(1).unwrap(); // class IN
+            raw.write_u32::<BigEndian>(ttl).unwrap();
+            raw.write_u16::<BigEndian>(4).unwrap(); // data length
+            raw.write_u32::<BigEndian>(0x5DB8D822 + i as u32).unwrap(); // address
+        }
+        raw
+    }
+
+    #[test]
+    fn serve_second_query_from_cache() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(16);
+
+        // the first query is not cached, so the response comes from the network
+        assert!(cache.lookup(&create_query(0x1111), now).is_none());
+        cache.store(&create_response(0x1111, &[300]), now);
+
+        let response = cache
+            .lookup(&create_query(0x2222), now)
+            .expect("Not cached");
+        assert_eq!(create_response(0x2222, &[300]), response);
+    }
+
+    #[test]
+    fn ignore_name_case() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(16);
+        cache.store(&create_response(0x1111, &[300]), now);
+
+        let mut query = create_query(0x2222);
+        query[HEADER_LENGTH + 1] = b'E';
+        assert!(cache.lookup(&query, now).is_some());
+    }
+
+    #[test]
+    fn expire_after_min_ttl() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(16);
+        cache.store(&create_response(0x1111, &[300, 60, 120]), now);
+
+        let query = create_query(0x2222);
+        assert!(cache
+            .lookup(&query, now + Duration::from_secs(59))
+            .is_some());
+        assert!(cache
+            .lookup(&query, now + Duration::from_secs(60))
+            .is_none());
+    }
+
+    #[test]
+    fn do_not_cache_errors() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(16);
+        let mut response = create_response(0x1111, &[300]);
+        BigEndian::write_u16(&mut response[2..4], 0x8183); // NXDOMAIN
+        cache.store(&response, now);
+        assert!(cache.lookup(&create_query(0x2222), now).is_none());
+    }
+
+    #[test]
+    fn build_response_to_query_packet() {
+        let query = create_query(0x1111);
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28 + query.len() as u16).unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x08080808).unwrap(); // destination address
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(DNS_PORT).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(8 + query.len() as u16).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.extend_from_slice(&query);
+        let query_packet = Ipv4Packet::parse(&mut raw);
+
+        let response = create_response(0x1111, &[300]);
+        let mut raw = build_response_packet(&query_packet, &response);
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x08080808, packet.ipv4_header_data().source());
+        assert_eq!(0x0A000002, packet.ipv4_header_data().destination());
+        let transport_header_data = packet.transport_header_data().unwrap();
+        assert_eq!(DNS_PORT, transport_header_data.source_port());
+        assert_eq!(1234, transport_header_data.destination_port());
+        assert_eq!(&response[..], packet.payload().unwrap());
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(2);
+
+        let mut other_response = create_response(0x1111, &[300]);
+        // type AAAA
+        let type_index = HEADER_LENGTH + QUESTION_NAME.len();
+        other_response[type_index + 1] = 28;
+        let mut other_query = create_query(0x2222);
+        other_query[type_index + 1] = 28;
+        let mut third_response = create_response(0x1111, &[300]);
+        third_response[type_index + 1] = 15; // type MX
+
+        cache.store(&create_response(0x1111, &[300]), now);
+        cache.store(&other_response, now);
+        // use the first one, so that the second one is the least recently used
+        assert!(cache.lookup(&create_query(0x2222), now).is_some());
+        cache.store(&third_response, now);
+
+        assert!(cache.lookup(&create_query(0x2222), now).is_some());
+        assert!(cache.lookup(&other_query, now).is_none());
+    }
+}
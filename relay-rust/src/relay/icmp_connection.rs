@@ -1,41 +1,321 @@
+use byteorder::{BigEndian, ByteOrder};
 use log::*;
 use mio::{Event, PollOpt};
 use mio::{Ready, Token};
 use std::cell::RefCell;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::rc::Rc;
 use std::rc::Weak;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::{
     binary,
     client::{Client, ClientChannel},
-    connection::Connection,
-    connection::ConnectionId,
-    icmp_socket::IcmpSocket,
+    connection::{self, Connection, ConnectionId, ConnectionStats},
+    datagram::{DatagramReceiver, DatagramSender, ReadAdapter},
+    datagram_buffer::DatagramBuffer,
+    icmp_dispatcher::{IcmpEndpoint, IcmpTransport, SharedIcmpDispatcher},
+    icmp_error,
+    icmp_header::{IcmpHeaderData, ICMP_HEADER_LENGTH, TYPE_DESTINATION_UNREACHABLE},
+    icmp_socket::{IcmpSocket, IcmpSocketKind},
     ipv4_header::Ipv4Header,
     ipv4_packet::Ipv4Packet,
-    ipv4_packet::MAX_PACKET_LENGTH,
-    packetizer::Packetizer,
+    net,
+    packet_sink::PacketSink,
+    packet_source::PacketSource,
+    packetizer::{PacketizeError, Packetizer},
+    relay_config::RelayConfig,
     selector::Selector,
-    stream_buffer::StreamBuffer,
     transport_header::TransportHeader,
 };
 
-const TAG: &'static str = "IcmpConnection";
-const IDLE_TIMEOUT_SECONDS: u64 = 2;
+const TAG: &str = "IcmpConnection";
+
+/// ICMP message read from the socket.
+enum IcmpReply<'a> {
+    /// Echo (or timestamp) reply to forward to the client.
+    EchoReply(Ipv4Packet<'a>),
+    /// Error possibly concerning another connection of the client (raw sockets only).
+    DestinationUnreachable(Ipv4Packet<'a>),
+    Ignored,
+}
+
+// the requests never replied are forgotten once this number of newer requests are pending
+const MAX_PENDING_ECHO_PAYLOADS: usize = 64;
+
+/// Hashes of the payloads of the pending echo requests, to verify that their replies echo them.
+struct EchoPayloads {
+    hashes: HashMap<(u16, u16), u64>,
+    // keys in insertion order, to forget the oldest requests first
+    order: VecDeque<(u16, u16)>,
+}
+
+impl EchoPayloads {
+    fn new() -> Self {
+        Self {
+            hashes: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // the timestamp messages do not echo their payload
+    fn key(icmp_message: &[u8]) -> Option<(u16, u16)> {
+        let icmp_header_data = IcmpHeaderData::parse(icmp_message);
+        if !icmp_header_data.is_echo_request() && !icmp_header_data.is_echo_reply() {
+            return None;
+        }
+        Some((
+            icmp_header_data.identifier()?,
+            icmp_header_data.sequence_number()?,
+        ))
+    }
+
+    fn hash(icmp_message: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        icmp_message[ICMP_HEADER_LENGTH..].hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Store the payload hash of the echo request `icmp_message`.
+    fn record(&mut self, icmp_message: &[u8]) {
+        let key = match Self::key(icmp_message) {
+            Some(key) => key,
+            None => return,
+        };
+        if self.hashes.insert(key, Self::hash(icmp_message)).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > MAX_PENDING_ECHO_PAYLOADS {
+                let oldest = self.order.pop_front().unwrap();
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+
+    /// Check the payload of the echo reply `icmp_message` against its request, if it is known.
+    ///
+    /// Return `Some(false)` on mismatch.
+    fn verify(&mut self, icmp_message: &[u8]) -> Option<bool> {
+        let key = Self::key(icmp_message)?;
+        let hash = self.hashes.remove(&key)?;
+        self.order.retain(|&pending| pending != key);
+        Some(hash == Self::hash(icmp_message))
+    }
+}
+
+/// Send instants of the pending echo (or timestamp) requests, to measure the round-trip time of
+/// their replies.
+struct EchoTimes {
+    sent: HashMap<(u16, u16), Instant>,
+    // keys in insertion order, to forget the oldest requests first
+    order: VecDeque<(u16, u16)>,
+}
+
+impl EchoTimes {
+    fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn key(icmp_message: &[u8]) -> Option<(u16, u16)> {
+        let icmp_header_data = IcmpHeaderData::parse(icmp_message);
+        if !icmp_header_data.is_echo_request()
+            && !icmp_header_data.is_echo_reply()
+            && !icmp_header_data.is_timestamp_request()
+            && !icmp_header_data.is_timestamp_reply()
+        {
+            return None;
+        }
+        Some((
+            icmp_header_data.identifier()?,
+            icmp_header_data.sequence_number()?,
+        ))
+    }
+
+    /// Store the instant the request `icmp_message` is sent.
+    fn record(&mut self, icmp_message: &[u8], now: Instant) {
+        let key = match Self::key(icmp_message) {
+            Some(key) => key,
+            None => return,
+        };
+        // a retransmitted request keeps its first send instant
+        if let Entry::Vacant(entry) = self.sent.entry(key) {
+            entry.insert(now);
+            self.order.push_back(key);
+            if self.order.len() > MAX_PENDING_ECHO_PAYLOADS {
+                let oldest = self.order.pop_front().unwrap();
+                self.sent.remove(&oldest);
+            }
+        }
+    }
+
+    /// Round-trip time of the reply `icmp_message` received at `now`, if its request is known.
+    fn measure(&mut self, icmp_message: &[u8], now: Instant) -> Option<Duration> {
+        let key = Self::key(icmp_message)?;
+        let sent = self.sent.remove(&key)?;
+        self.order.retain(|&pending| pending != key);
+        Some(now.saturating_duration_since(sent))
+    }
+}
+
+/// Requests queued to the network, each along with the IPv4 header it was received with, to
+/// report the requests too large for the path MTU.
+struct QueuedRequests {
+    datagrams: DatagramBuffer,
+    // in the order of the datagrams
+    ipv4_headers: VecDeque<Vec<u8>>,
+}
+
+impl QueuedRequests {
+    fn new(datagrams: DatagramBuffer) -> Self {
+        Self {
+            datagrams,
+            ipv4_headers: VecDeque::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    fn accepts(&self, datagram_length: usize) -> bool {
+        self.datagrams.accepts(datagram_length)
+    }
+
+    /// Queue the ICMP message of `ipv4_packet`, and return the number of older requests evicted.
+    fn push(&mut self, ipv4_packet: &Ipv4Packet) -> io::Result<usize> {
+        let evicted = self
+            .datagrams
+            .read_from(ipv4_packet.payload().expect("No payload"))?;
+        self.ipv4_headers.drain(..evicted);
+        self.ipv4_headers
+            .push_back(ipv4_packet.ipv4_header().raw().to_vec());
+        Ok(evicted)
+    }
+
+    /// Send a batch of requests to `destination`, and record in `echo_times` the instant the
+    /// requests actually sent are sent.
+    fn write_batch_to<S: DatagramSender>(
+        &mut self,
+        destination: &mut S,
+        echo_times: &mut EchoTimes,
+    ) -> io::Result<(usize, usize)> {
+        let mut sender = EchoTimesRecorder {
+            sender: destination,
+            echo_times,
+        };
+        let (datagrams, bytes) = self.datagrams.write_batch_to(&mut sender)?;
+        self.ipv4_headers.drain(..datagrams);
+        Ok((datagrams, bytes))
+    }
+
+    /// Remove the next request without sending it, and return its IPv4 header and its ICMP
+    /// message.
+    fn pop(&mut self) -> (Vec<u8>, Vec<u8>) {
+        let ipv4_header = self
+            .ipv4_headers
+            .pop_front()
+            .expect("pop() called without queued request");
+        (ipv4_header, self.datagrams.pop())
+    }
+}
+
+/// Sender recording the send instant of the requests it sends.
+struct EchoTimesRecorder<'a, S: DatagramSender> {
+    sender: &'a mut S,
+    echo_times: &'a mut EchoTimes,
+}
+
+impl<S: DatagramSender> DatagramSender for EchoTimesRecorder<'_, S> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let w = self.sender.send(buf)?;
+        if w == buf.len() {
+            self.echo_times.record(buf, Instant::now());
+        }
+        Ok(w)
+    }
+
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        let sent = self.sender.send_batch(datagrams)?;
+        let now = Instant::now();
+        for datagram in &datagrams[..sent] {
+            self.echo_times.record(datagram, now);
+        }
+        Ok(sent)
+    }
+}
+
+// the replies are dropped once this number of replies wait for the client to drain its buffer
+const MAX_PENDING_REPLIES: usize = 16;
+
+/// Replies the client could not accept yet, delivered once its buffer drains.
+struct PendingReplies {
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl PendingReplies {
+    fn new() -> Self {
+        Self {
+            packets: VecDeque::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Store a copy of `ipv4_packet`, `false` if too many replies are already pending.
+    fn push(&mut self, ipv4_packet: &Ipv4Packet) -> bool {
+        if self.packets.len() >= MAX_PENDING_REPLIES {
+            return false;
+        }
+        self.packets.push_back(ipv4_packet.raw().to_vec());
+        true
+    }
+
+    fn front(&mut self) -> Option<Ipv4Packet<'_>> {
+        self.packets.front_mut().map(|raw| Ipv4Packet::parse(raw))
+    }
+
+    /// Forget the oldest reply, once delivered, and return its payload length.
+    fn pop(&mut self) -> usize {
+        let mut raw = self
+            .packets
+            .pop_front()
+            .expect("pop() called without pending reply");
+        Ipv4Packet::parse(&mut raw).payload().map_or(0, <[u8]>::len)
+    }
+}
 
 pub struct IcmpConnection {
     id: ConnectionId,
+    self_weak: Weak<RefCell<Self>>,
     client: Weak<RefCell<Client>>,
     interests: Ready,
-    socket: IcmpSocket,
+    transport: IcmpTransport,
+    destination: SocketAddr,
     token: Token,
-    client_to_network: StreamBuffer,
+    client_to_network: QueuedRequests,
     network_to_client: Packetizer,
+    // DF flag of the last request, `None` until the first request
+    dont_fragment: Option<bool>,
+    // only if the echo payloads are verified
+    echo_payloads: Option<EchoPayloads>,
+    echo_times: EchoTimes,
+    pending_replies: PendingReplies,
+    max_echo_payload: usize,
+    // the last send failed with ENOBUFS, do not poll for writability until the retry timer
+    no_buffer_space: bool,
     closed: bool,
     idle_since: Instant,
+    created_at: Instant,
+    idle_timeout: Duration,
+    stats: ConnectionStats,
 }
 
 impl IcmpConnection {
@@ -45,47 +325,107 @@ impl IcmpConnection {
         client: Weak<RefCell<Client>>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
+        config: &RelayConfig,
+        icmp_dispatcher: &SharedIcmpDispatcher,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
 
+        let destination = id.rewritten_destination();
+        let transport = icmp_dispatcher
+            .borrow_mut()
+            .open(selector, config, &destination)?;
+        Self::with_transport(
+            selector,
+            id,
+            client,
+            ipv4_header,
+            transport_header,
+            config,
+            transport,
+        )
+    }
+
+    fn with_transport(
+        selector: &mut Selector,
+        id: ConnectionId,
+        client: Weak<RefCell<Client>>,
+        ipv4_header: Ipv4Header,
+        transport_header: TransportHeader,
+        config: &RelayConfig,
+        transport: IcmpTransport,
+    ) -> io::Result<Rc<RefCell<Self>>> {
         let interests = Ready::readable();
-        let packetizer = Packetizer::new(&ipv4_header, &transport_header);
-        let socket = Self::create_socket(&id)?;
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, config.mtu());
+        packetizer.set_preserve_ttl(config.preserve_ttl());
+        let destination = id.rewritten_destination();
 
+        let mut client_to_network =
+            DatagramBuffer::with_max_datagrams(config.icmp_buffer_datagrams());
+        client_to_network.set_policy(config.datagram_queue_policy());
+        let client_to_network = QueuedRequests::new(client_to_network);
         let rc = Rc::new(RefCell::new(Self {
             id,
+            self_weak: Weak::new(),
             client,
             interests,
-            socket,
-            token: Token(0),
-            client_to_network: StreamBuffer::new(MAX_PACKET_LENGTH),
+            transport,
+            destination: destination.into(),
+            token: Token(0), // default value, will be set afterwards (if the socket is not shared)
+            client_to_network,
             network_to_client: packetizer,
+            dont_fragment: None,
+            echo_payloads: if config.verify_echo_payloads() {
+                Some(EchoPayloads::new())
+            } else {
+                None
+            },
+            echo_times: EchoTimes::new(),
+            pending_replies: PendingReplies::new(),
+            max_echo_payload: config.icmp_max_payload(),
+            no_buffer_space: false,
             closed: false,
             idle_since: Instant::now(),
+            created_at: Instant::now(),
+            idle_timeout: config.icmp_idle_timeout(),
+            stats: ConnectionStats::default(),
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
+            self_ref.self_weak = Rc::downgrade(&rc);
 
-            let rc2 = rc.clone();
-            // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
-            let handler =
-                move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
-            let token =
-                selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
-            self_ref.token = token;
+            let token = match self_ref.transport {
+                IcmpTransport::Dedicated(ref socket) => {
+                    let rc2 = rc.clone();
+                    // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
+                    let handler = move |selector: &mut Selector, event| {
+                        rc2.borrow_mut().on_ready(selector, event)
+                    };
+                    Some(selector.register(socket, handler, interests, PollOpt::level())?)
+                }
+                IcmpTransport::Shared(_, ref dispatcher) => {
+                    // only the echo requests get replies to dispatch
+                    if let Some(identifier) = self_ref.id.icmp_identifier() {
+                        let weak: Weak<RefCell<Self>> = Rc::downgrade(&rc);
+                        dispatcher
+                            .borrow_mut()
+                            .register(*destination.ip(), identifier, weak);
+                    }
+                    None
+                }
+            };
+            if let Some(token) = token {
+                self_ref.token = token;
+            }
         }
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<IcmpSocket> {
-        let socket = IcmpSocket::bind(
-            "0.0.0.0"
-                .parse::<IpAddr>()
-                .expect("IP address parse failed"),
-        )?;
-        socket.connect(&id.rewritten_destination().into())?;
-        Ok(socket)
+    fn socket(&self) -> &IcmpSocket {
+        match self.transport {
+            IcmpTransport::Dedicated(ref socket) => socket,
+            IcmpTransport::Shared(ref socket, _) => socket,
+        }
     }
 
     fn remove_from_router(&self) {
@@ -119,6 +459,9 @@ impl IcmpConnection {
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     self.process_send(selector)?;
+                    if self.no_buffer_space {
+                        self.schedule_send_retry();
+                    }
                 }
                 if !self.closed && ready.is_readable() {
                     self.process_receive(selector)?;
@@ -143,6 +486,14 @@ impl IcmpConnection {
                 cx_debug!(target: TAG, self.id, "Spurious event, ignoring");
                 return Err(err);
             }
+            Err(ref err) if net::is_message_too_long(err) => {
+                self.report_fragmentation_needed(selector);
+            }
+            Err(ref err) if net::is_no_buffer_space(err) => {
+                // the datagrams are kept, and retried once the retry timer elapses
+                cx_debug!(target: TAG, self.id, "No buffer space, retrying later");
+                self.no_buffer_space = true;
+            }
             Err(ref err) => {
                 cx_error!(
                     target: TAG,
@@ -157,6 +508,49 @@ impl IcmpConnection {
         Ok(())
     }
 
+    // the request having the DF flag does not fit the path MTU: drop it, and tell the client
+    fn report_fragmentation_needed(&mut self, selector: &mut Selector) {
+        let (ipv4_header_raw, datagram) = self.client_to_network.pop();
+        self.stats.record_tx_dropped();
+        // unknown (0) if the socket is shared, the kernel only tracks it for connected sockets
+        let mtu = self.socket().path_mtu().unwrap_or(0);
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Request ({} bytes) too large for the path MTU ({})",
+            datagram.len(),
+            mtu
+        );
+        let mut raw = Self::build_fragmentation_needed(&ipv4_header_raw, &datagram, mtu);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        if client_rc
+            .borrow_mut()
+            .send_to_client(selector, &ipv4_packet)
+            .is_err()
+        {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send Fragmentation Needed to client, drop packet"
+            );
+        }
+    }
+
+    /// Rebuild the request from its IPv4 header and its ICMP message `datagram`, and build the
+    /// ICMP Fragmentation Needed error to reply to the client.
+    fn build_fragmentation_needed(ipv4_header_raw: &[u8], datagram: &[u8], mtu: u16) -> Vec<u8> {
+        let mut original_raw = Vec::with_capacity(ipv4_header_raw.len() + datagram.len());
+        original_raw.extend_from_slice(ipv4_header_raw);
+        original_raw.extend_from_slice(datagram);
+        let total_length = original_raw.len() as u16;
+        // the packet may not be parsed before its length matches the datagram
+        BigEndian::write_u16(&mut original_raw[2..4], total_length);
+        let mut original = Ipv4Packet::parse(&mut original_raw);
+        original.ipv4_header_mut().update_checksum();
+        icmp_error::build_fragmentation_needed(&original, mtu)
+    }
+
     fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
         match self.read(selector) {
             Ok(_) => (),
@@ -178,44 +572,247 @@ impl IcmpConnection {
     }
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self
-            .network_to_client
-            .packetize_read(&mut self.socket, None)?
-            .expect("Packetzer reader failed");
         let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        let socket = match self.transport {
+            IcmpTransport::Dedicated(ref mut socket) => socket,
+            // the shared socket is read by the dispatcher
+            IcmpTransport::Shared(..) => panic!("Shared ICMP socket read by a connection"),
+        };
+        let kind = socket.kind();
+        let was_pending = !self.pending_replies.is_empty();
+        let result = Self::relay_reply(
+            &self.id,
+            &mut self.network_to_client,
+            &mut self.stats,
+            self.echo_payloads.as_mut(),
+            &mut self.echo_times,
+            &mut self.pending_replies,
+            socket,
+            kind,
+            selector,
+            &mut *client,
+        );
+        self.register_pending_replies(&mut client, was_pending);
+        result
+    }
 
-        match client_rc
-            .borrow_mut()
-            .send_to_client(selector, &ipv4_packet)
-        {
+    // ask the client to pull the deferred replies once its buffer is not full
+    fn register_pending_replies(&self, client: &mut Client, was_pending: bool) {
+        if !was_pending && !self.pending_replies.is_empty() {
+            let self_rc = self.self_weak.upgrade().unwrap();
+            client.register_pending_packet_source(self_rc);
+        }
+    }
+
+    /// Read an ICMP message from `source`, and deliver it to `sink` (the client).
+    ///
+    /// If `echo_payloads` is provided, the payload of an echo reply is verified against its
+    /// request. The round-trip time of the replies to the requests in `echo_times` is recorded.
+    ///
+    /// A reply the client cannot accept yet is deferred to `pending_replies`, as well as the
+    /// following ones, so that they are delivered in order.
+    #[allow(clippy::too_many_arguments)]
+    fn relay_reply<R: DatagramReceiver, S: PacketSink>(
+        id: &ConnectionId,
+        packetizer: &mut Packetizer,
+        stats: &mut ConnectionStats,
+        echo_payloads: Option<&mut EchoPayloads>,
+        echo_times: &mut EchoTimes,
+        pending_replies: &mut PendingReplies,
+        source: &mut R,
+        kind: IcmpSocketKind,
+        selector: &mut Selector,
+        sink: &mut S,
+    ) -> io::Result<()> {
+        let reply = match Self::packetize_reply(id, packetizer, source, kind) {
+            Ok(reply) => reply,
+            Err(PacketizeError::Io(err)) => return Err(err),
+            Err(err) => {
+                // a malformed message does not concern the socket, keep the connection open
+                cx_warn!(target: TAG, id, "Dropping ICMP message: {}", err);
+                return Ok(());
+            }
+        };
+        let ipv4_packet = match reply {
+            IcmpReply::EchoReply(ipv4_packet) => ipv4_packet,
+            IcmpReply::DestinationUnreachable(ipv4_packet) => {
+                let message = ipv4_packet.payload().expect("No payload");
+                sink.close_unreachable_flow(selector, message);
+                return Ok(());
+            }
+            IcmpReply::Ignored => return Ok(()),
+        };
+        let message = ipv4_packet.payload().expect("No payload");
+        if let Some(rtt) = echo_times.measure(message, Instant::now()) {
+            cx_debug!(target: TAG, id, "Round-trip time: {:?}", rtt);
+            stats.record_rtt(rtt);
+        }
+        if let Some(echo_payloads) = echo_payloads {
+            let message = ipv4_packet.payload().expect("No payload");
+            if echo_payloads.verify(message) == Some(false) {
+                cx_warn!(
+                    target: TAG,
+                    id,
+                    "Echo reply payload differs from its request (seq={:?})",
+                    IcmpHeaderData::parse(message).sequence_number()
+                );
+            }
+        }
+
+        let result = if pending_replies.is_empty() {
+            sink.send_to_client(selector, &ipv4_packet)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Replies already pending",
+            ))
+        };
+        match result {
             Ok(_) => {
+                stats.record_rx(ipv4_packet.payload().map_or(0, <[u8]>::len));
                 cx_debug!(
                     target: TAG,
-                    self.id,
+                    id,
                     "Packet ({} bytes) send to client",
                     ipv4_packet.length()
                 );
                 if log_enabled!(target: TAG, Level::Trace) {
                     cx_trace!(
                         target: TAG,
-                        self.id,
+                        id,
                         "send to client: {}",
-                        binary::build_packet_string(ipv4_packet.raw())
+                        binary::build_trace_packet_string(ipv4_packet.raw())
                     );
                 }
             }
-            Err(_) => cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet"),
+            Err(_) => {
+                if pending_replies.push(&ipv4_packet) {
+                    cx_debug!(target: TAG, id, "Client buffer full, reply deferred");
+                } else {
+                    cx_warn!(target: TAG, id, "Cannot send to client, drop packet");
+                }
+            }
         }
         Ok(())
     }
 
+    /// Read an ICMP message from `source` and packetize it.
+    ///
+    /// Truncated, oversized messages and messages with an invalid checksum are reported by the
+    /// packetizer.
+    ///
+    /// The raw socket may receive ICMP messages unrelated to the relayed request (redirects,
+    /// router advertisements, replies to other pings…), so ignore anything but an echo or
+    /// timestamp reply carrying the identifier of this connection. Destination Unreachable errors
+    /// are returned apart, the relayed UDP flows they reject must be closed.
+    ///
+    /// The routing control messages are dropped first, whatever their content: they concern the
+    /// relay host, and would confuse the routing of the client.
+    ///
+    /// A datagram socket only receives replies to its own requests, but carrying the identifier
+    /// chosen by the kernel, so the one of the client is restored.
+    fn packetize_reply<'a, R: DatagramReceiver>(
+        id: &ConnectionId,
+        packetizer: &'a mut Packetizer,
+        source: &mut R,
+        kind: IcmpSocketKind,
+    ) -> Result<IcmpReply<'a>, PacketizeError> {
+        let mut ipv4_packet = packetizer.packetize(source)?;
+        let payload = ipv4_packet.payload().expect("No payload");
+        let mut icmp_header_data = IcmpHeaderData::parse(payload);
+        if icmp_header_data.is_routing_control() {
+            cx_debug!(
+                target: TAG,
+                id,
+                "Dropping ICMP routing control message (type={}, code={})",
+                icmp_header_data.icmp_type(),
+                icmp_header_data.code()
+            );
+            return Ok(IcmpReply::Ignored);
+        }
+        if kind == IcmpSocketKind::Raw
+            && icmp_header_data.icmp_type() == TYPE_DESTINATION_UNREACHABLE
+        {
+            return Ok(IcmpReply::DestinationUnreachable(ipv4_packet));
+        }
+        let identifier_matches = match kind {
+            IcmpSocketKind::Raw => icmp_header_data.identifier() == id.icmp_identifier(),
+            IcmpSocketKind::Dgram => true,
+        };
+        let is_reply = icmp_header_data.is_echo_reply() || icmp_header_data.is_timestamp_reply();
+        if !is_reply || !identifier_matches {
+            cx_debug!(
+                target: TAG,
+                id,
+                "Ignoring ICMP message (type={}, code={}, id={:?})",
+                icmp_header_data.icmp_type(),
+                icmp_header_data.code(),
+                icmp_header_data.identifier()
+            );
+            return Ok(IcmpReply::Ignored);
+        }
+        if let (IcmpSocketKind::Dgram, Some(identifier)) = (kind, id.icmp_identifier()) {
+            if let (_, Some((_, payload))) = ipv4_packet.split_mut() {
+                icmp_header_data
+                    .bind_mut(payload)
+                    .set_identifier(identifier);
+            }
+        }
+        Ok(IcmpReply::EchoReply(ipv4_packet))
+    }
+
     fn write(&mut self) -> io::Result<()> {
-        self.client_to_network.write_to(&mut self.socket)?;
+        if let Some(dont_fragment) = self.dont_fragment {
+            // a shared socket may have been set to another mode by another connection
+            if let Err(err) = self.socket().set_dont_fragment(dont_fragment) {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot set DF flag to {}: {}",
+                    dont_fragment,
+                    err
+                );
+            }
+        }
+        let (datagrams, bytes) = match self.transport {
+            IcmpTransport::Dedicated(ref mut socket) => self
+                .client_to_network
+                .write_batch_to(socket, &mut self.echo_times)?,
+            IcmpTransport::Shared(ref socket, _) => self.client_to_network.write_batch_to(
+                &mut socket.sender_to(self.destination),
+                &mut self.echo_times,
+            )?,
+        };
+        self.stats.record_tx_datagrams(datagrams, bytes);
         Ok(())
     }
 
+    /// Poll for writability again once `NO_BUFFER_SPACE_RETRY_DELAY` elapsed.
+    ///
+    /// To be used if called by on_ready() or on_writable() (so the client is not borrowed yet).
+    fn schedule_send_retry(&self) {
+        let deadline = Instant::now() + net::NO_BUFFER_SPACE_RETRY_DELAY;
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let connection: Weak<RefCell<dyn Connection>> = self.self_weak.clone();
+        client_rc
+            .borrow_mut()
+            .router()
+            .schedule_timeout(deadline, connection);
+    }
+
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
+        let socket = match self.transport {
+            IcmpTransport::Dedicated(ref socket) => socket,
+            IcmpTransport::Shared(_, ref dispatcher) => {
+                if !self.client_to_network.is_empty() && !self.no_buffer_space {
+                    let weak: Weak<RefCell<Self>> = self.self_weak.clone();
+                    dispatcher.borrow_mut().request_write(selector, weak);
+                }
+                return;
+            }
+        };
+        let ready = if self.client_to_network.is_empty() || self.no_buffer_space {
             Ready::readable()
         } else {
             Ready::readable() | Ready::writable()
@@ -230,7 +827,7 @@ impl IcmpConnection {
             );
             self.interests = ready;
             selector
-                .reregister(&self.socket, self.token, ready, PollOpt::level())
+                .reregister(socket, self.token, ready, PollOpt::level())
                 .expect("Cannot register on poll");
         }
     }
@@ -238,6 +835,68 @@ impl IcmpConnection {
     fn touch(&mut self) {
         self.idle_since = Instant::now();
     }
+
+    /// Indicate whether `icmp_message` is an echo request carrying more than `max_payload` bytes.
+    fn is_oversized_echo_request(icmp_message: &[u8], max_payload: usize) -> bool {
+        IcmpHeaderData::parse(icmp_message).is_echo_request()
+            && icmp_message.len() - ICMP_HEADER_LENGTH > max_payload
+    }
+
+    // preserve the DF flag of the request, so that path MTU probing works through the relay
+    fn update_dont_fragment(&mut self, ipv4_packet: &Ipv4Packet) {
+        // applied to the socket on write
+        self.dont_fragment = Some(ipv4_packet.ipv4_header_data().dont_fragment());
+    }
+}
+
+impl IcmpEndpoint for IcmpConnection {
+    fn on_message(&mut self, selector: &mut Selector, message: &[u8], ttl: Option<u8>) {
+        if self.closed {
+            return;
+        }
+        self.touch();
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        let mut source = message;
+        let was_pending = !self.pending_replies.is_empty();
+        if let Err(err) = Self::relay_reply(
+            &self.id,
+            &mut self.network_to_client,
+            &mut self.stats,
+            self.echo_payloads.as_mut(),
+            &mut self.echo_times,
+            &mut self.pending_replies,
+            &mut ReadAdapter::new(&mut source, None).with_ttl(ttl),
+            IcmpSocketKind::Raw,
+            selector,
+            &mut *client,
+        ) {
+            cx_warn!(target: TAG, self.id, "Cannot relay ICMP message: {}", err);
+        }
+        self.register_pending_replies(&mut client, was_pending);
+    }
+
+    fn on_writable(&mut self, selector: &mut Selector) -> bool {
+        if self.closed || self.client_to_network.is_empty() {
+            return false;
+        }
+        self.touch();
+        if self.process_send(selector).is_err() {
+            // spurious event, retry on the next one
+            return true;
+        }
+        if self.closed {
+            // on_writable is not called from the router, so the connection must remove itself
+            self.remove_from_router();
+            return false;
+        }
+        if self.no_buffer_space {
+            // the write is requested again once the retry timer elapses
+            self.schedule_send_retry();
+            return false;
+        }
+        !self.client_to_network.is_empty()
+    }
 }
 
 impl Connection for IcmpConnection {
@@ -251,39 +910,820 @@ impl Connection for IcmpConnection {
         _: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
-        if ipv4_packet.length() as usize <= self.client_to_network.remaining() {
-            let payload = ipv4_packet.payload().expect("No Payload");
-            cx_trace!(
+        let payload = ipv4_packet.payload().expect("No Payload");
+        cx_trace!(
+            target: TAG,
+            self.id,
+            "send to network {}",
+            binary::build_trace_packet_string(payload)
+        );
+        if self.socket().kind() == IcmpSocketKind::Dgram
+            && !IcmpHeaderData::parse(payload).is_echo_request()
+        {
+            // the kernel only accepts echo requests on datagram ICMP sockets
+            cx_warn!(
                 target: TAG,
                 self.id,
-                "send to network {}",
-                binary::build_packet_string(payload)
+                "Cannot send non-echo message without raw socket, drop packet"
             );
-            self.client_to_network.read_from(payload);
-            self.update_interests(selector);
-        } else {
-            cx_warn!(target: TAG, self.id, "Cannot send to network, drop packet")
+            self.stats.record_tx_dropped();
+            return;
         }
+        if Self::is_oversized_echo_request(payload, self.max_echo_payload) {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Echo request payload exceeds {} bytes, drop packet",
+                self.max_echo_payload
+            );
+            self.stats.record_tx_dropped();
+            return;
+        }
+        self.update_dont_fragment(ipv4_packet);
+        if let Some(echo_payloads) = self.echo_payloads.as_mut() {
+            echo_payloads.record(payload);
+        }
+        match self.client_to_network.push(ipv4_packet) {
+            Ok(evicted) => {
+                self.stats.record_tx_evicted(evicted);
+                self.update_interests(selector);
+            }
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.stats.record_tx_dropped();
+            }
+        }
+    }
+
+    fn can_accept(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let length = ipv4_packet.payload().map_or(0, <[u8]>::len);
+        self.client_to_network.accepts(length)
     }
 
     fn close(&mut self, selector: &mut Selector) {
         cx_info!(target: TAG, self.id, "Close");
         self.closed = true;
-        if let Err(err) = selector.deregister(&self.socket, self.token) {
-            cx_warn!(
-                target: TAG,
-                self.id,
-                "Fail to deregister ICMP stream: {}",
-                err
-            );
+        // the dispatcher forgets the connection once dropped
+        if let IcmpTransport::Dedicated(ref socket) = self.transport {
+            if let Err(err) = selector.deregister(socket, self.token) {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Fail to deregister ICMP stream: {}",
+                    err
+                );
+            }
         }
     }
 
     fn is_expired(&self) -> bool {
-        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+        connection::is_idle_expired(self.idle_since, self.idle_timeout)
+    }
+
+    fn expiry(&self) -> Option<Instant> {
+        Some(self.idle_since + self.idle_timeout)
+    }
+
+    fn handle_timeout(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        if self.no_buffer_space {
+            self.no_buffer_space = false;
+            self.update_interests(selector);
+        }
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn idle_since(&self) -> Instant {
+        self.idle_since
+    }
+
+    fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+}
+
+impl PacketSource for IcmpConnection {
+    fn get(&mut self) -> Option<Ipv4Packet<'_>> {
+        self.pending_replies.front()
+    }
+
+    fn next(&mut self, _: &mut Selector) {
+        let payload_length = self.pending_replies.pop();
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Deferred packet ({} bytes) sent to client",
+            payload_length
+        );
+        self.stats.record_rx(payload_length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::datagram::tests::{LoopbackDatagramSocket, MockDatagramSocket};
+    use crate::relay::datagram::DatagramSender;
+    use crate::relay::datagram_buffer::QueuePolicy;
+    use crate::relay::icmp_header::{
+        TYPE_ECHO_REPLY, TYPE_ECHO_REQUEST, TYPE_REDIRECT, TYPE_ROUTER_ADVERTISEMENT,
+        TYPE_ROUTER_SOLICITATION,
+    };
+    use crate::relay::ipv4_packet::MTU;
+    use crate::relay::packet_sink::tests::PacketCapture;
+    use crate::relay::router::tests::FakeClient;
+    use crate::relay::selector::tests::MockSelector;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+    impl<'a> IcmpReply<'a> {
+        fn echo_reply(self) -> Option<Ipv4Packet<'a>> {
+            match self {
+                IcmpReply::EchoReply(ipv4_packet) => Some(ipv4_packet),
+                _ => None,
+            }
+        }
+    }
+
+    fn create_echo_request() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(32);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x01010101).unwrap(); // destination address
+
+        raw.write_u8(8).unwrap(); // type (Echo Request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // identifier
+        raw.write_u16::<BigEndian>(1).unwrap(); // sequence number
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
+    fn create_icmp_message(icmp_type: u8, code: u8, identifier: u16) -> Vec<u8> {
+        create_icmp_message_with_payload(icmp_type, code, identifier, &[0x11, 0x22, 0x33, 0x44])
+    }
+
+    fn create_icmp_message_with_payload(
+        icmp_type: u8,
+        code: u8,
+        identifier: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(8 + payload.len());
+        raw.write_u8(icmp_type).unwrap();
+        raw.write_u8(code).unwrap();
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(identifier).unwrap();
+        raw.write_u16::<BigEndian>(1).unwrap(); // sequence number
+        raw.extend_from_slice(payload);
+        let mut icmp_header_data = IcmpHeaderData::parse(&raw);
+        icmp_header_data.bind_mut(&mut raw).update_checksum();
+        raw
+    }
+
+    // the socket is reported as a datagram ICMP socket, but nothing is sent on it
+    fn create_dgram_connection(
+        selector: &mut Selector,
+        config: &RelayConfig,
+        raw: &mut [u8],
+    ) -> Rc<RefCell<IcmpConnection>> {
+        let reference_packet = Ipv4Packet::parse(raw);
+        let (ipv4_header_data, transport_header_data) = reference_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let (ipv4_header, transport_header) = reference_packet.headers();
+        let transport = IcmpTransport::Dedicated(IcmpSocket::fake(IcmpSocketKind::Dgram));
+        IcmpConnection::with_transport(
+            selector,
+            id,
+            Weak::new(),
+            ipv4_header,
+            transport_header.unwrap(),
+            config,
+            transport,
+        )
+        .unwrap()
+    }
+
+    fn create_packetizer(raw: &mut [u8]) -> (ConnectionId, Packetizer) {
+        let reference_packet = Ipv4Packet::parse(raw);
+        let (ipv4_header_data, transport_header_data) = reference_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let (ipv4_header, transport_header) = reference_packet.headers();
+        let packetizer = Packetizer::new(&ipv4_header, &transport_header.unwrap(), MTU);
+        (id, packetizer)
+    }
+
+    #[test]
+    fn forward_echo_reply() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        let message = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let packet =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw)
+                .unwrap()
+                .echo_reply()
+                .expect("Echo reply not forwarded");
+        assert_eq!(&message[..], packet.payload().unwrap());
+    }
+
+    #[test]
+    fn relay_echo_reply_to_client() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+        let mut stats = ConnectionStats::default();
+        let mut selector = Selector::create().unwrap();
+        let mut capture = PacketCapture::default();
+        let mut echo_times = EchoTimes::new();
+        let mut pending_replies = PendingReplies::new();
+        let request = create_icmp_message(TYPE_ECHO_REQUEST, 0, 0x1234);
+        echo_times.record(&request, Instant::now());
+
+        // the network replies to the request, then sends an unrelated message
+        let mut network = LoopbackDatagramSocket::default();
+        let reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        network.send(&reply).unwrap();
+        network
+            .send(&create_icmp_message(TYPE_ECHO_REPLY, 0, 0x4321))
+            .unwrap();
+
+        for _ in 0..2 {
+            IcmpConnection::relay_reply(
+                &id,
+                &mut packetizer,
+                &mut stats,
+                None,
+                &mut echo_times,
+                &mut pending_replies,
+                &mut network,
+                IcmpSocketKind::Raw,
+                &mut selector,
+                &mut capture,
+            )
+            .unwrap();
+        }
+        let err = IcmpConnection::relay_reply(
+            &id,
+            &mut packetizer,
+            &mut stats,
+            None,
+            &mut echo_times,
+            &mut pending_replies,
+            &mut network,
+            IcmpSocketKind::Raw,
+            &mut selector,
+            &mut capture,
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        // only the echo reply is sent to the client, from the pinged host
+        assert_eq!(1, capture.packets.len());
+        let packet = Ipv4Packet::parse(&mut capture.packets[0]);
+        assert_eq!(0x01010101, packet.ipv4_header_data().source());
+        assert_eq!(0x0A000002, packet.ipv4_header_data().destination());
+        assert_eq!(&reply[..], packet.payload().unwrap());
+        assert_eq!(1, stats.rx_packets);
+        assert_eq!(reply.len() as u64, stats.rx_bytes);
+        assert_eq!(1, stats.rtt_samples);
+        assert!(capture.unreachable_messages.is_empty());
+        assert!(pending_replies.is_empty());
+    }
+
+    // the reading side of a connection, driven by the selector events
+    struct ReplyReader {
+        id: ConnectionId,
+        packetizer: Packetizer,
+        stats: ConnectionStats,
+        echo_times: EchoTimes,
+        pending_replies: PendingReplies,
+        network: LoopbackDatagramSocket,
+        capture: PacketCapture,
+    }
+
+    impl ReplyReader {
+        fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+            assert!(event.readiness().is_readable());
+            IcmpConnection::relay_reply(
+                &self.id,
+                &mut self.packetizer,
+                &mut self.stats,
+                None,
+                &mut self.echo_times,
+                &mut self.pending_replies,
+                &mut self.network,
+                IcmpSocketKind::Raw,
+                selector,
+                &mut self.capture,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn relay_echo_reply_on_readable_event() {
+        let (id, packetizer) = create_packetizer(&mut create_echo_request());
+        let reader = Rc::new(RefCell::new(ReplyReader {
+            id,
+            packetizer,
+            stats: ConnectionStats::default(),
+            echo_times: EchoTimes::new(),
+            pending_replies: PendingReplies::new(),
+            network: LoopbackDatagramSocket::default(),
+            capture: PacketCapture::default(),
+        }));
+        let mut selector = MockSelector::new();
+        let reader2 = reader.clone();
+        let token = selector.register(
+            move |selector: &mut Selector, event| reader2.borrow_mut().on_ready(selector, event),
+            Ready::readable(),
+        );
+
+        let reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        reader.borrow_mut().network.send(&reply).unwrap();
+        assert_eq!(1, selector.trigger(token, Ready::readable()));
+
+        let reader = reader.borrow();
+        assert_eq!(1, reader.capture.packets.len());
+        let mut raw = reader.capture.packets[0].clone();
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x01010101, packet.ipv4_header_data().source());
+        assert_eq!(0x0A000002, packet.ipv4_header_data().destination());
+        assert_eq!(&reply[..], packet.payload().unwrap());
+        assert_eq!(1, reader.stats.rx_packets);
+    }
+
+    // client whose buffer is full
+    struct BlockedClient;
+
+    impl PacketSink for BlockedClient {
+        fn send_to_client(&mut self, _: &mut Selector, _: &Ipv4Packet) -> io::Result<()> {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Client buffer full",
+            ))
+        }
+
+        fn close_unreachable_flow(&mut self, _: &mut Selector, _: &[u8]) {}
+    }
+
+    #[test]
+    fn defer_replies_while_client_blocked() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+        let mut stats = ConnectionStats::default();
+        let mut selector = Selector::create().unwrap();
+        let mut echo_times = EchoTimes::new();
+        let mut pending_replies = PendingReplies::new();
+
+        let mut network = LoopbackDatagramSocket::default();
+        let first = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &[1]);
+        let second = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &[2]);
+        network.send(&first).unwrap();
+        network.send(&second).unwrap();
+
+        IcmpConnection::relay_reply(
+            &id,
+            &mut packetizer,
+            &mut stats,
+            None,
+            &mut echo_times,
+            &mut pending_replies,
+            &mut network,
+            IcmpSocketKind::Raw,
+            &mut selector,
+            &mut BlockedClient,
+        )
+        .unwrap();
+        // the client accepts packets again, but the second reply must not overtake the first
+        let mut capture = PacketCapture::default();
+        IcmpConnection::relay_reply(
+            &id,
+            &mut packetizer,
+            &mut stats,
+            None,
+            &mut echo_times,
+            &mut pending_replies,
+            &mut network,
+            IcmpSocketKind::Raw,
+            &mut selector,
+            &mut capture,
+        )
+        .unwrap();
+        assert!(capture.packets.is_empty());
+        assert_eq!(0, stats.rx_packets);
+
+        // delivered in order once the client pulls them
+        let mut delivered = Vec::new();
+        while let Some(packet) = pending_replies.front() {
+            delivered.push(packet.payload().unwrap().to_vec());
+            stats.record_rx(pending_replies.pop());
+        }
+        assert_eq!(vec![first.clone(), second.clone()], delivered);
+        assert_eq!(2, stats.rx_packets);
+        assert_eq!((first.len() + second.len()) as u64, stats.rx_bytes);
+    }
+
+    #[test]
+    fn drop_oversized_echo_requests() {
+        let max_payload = RelayConfig::new(0).icmp_max_payload();
+        let payload = vec![0x42; max_payload + 1];
+        let oversized = create_icmp_message_with_payload(TYPE_ECHO_REQUEST, 0, 0x1234, &payload);
+        assert!(IcmpConnection::is_oversized_echo_request(
+            &oversized,
+            max_payload
+        ));
+        let request = create_icmp_message_with_payload(TYPE_ECHO_REQUEST, 0, 0x1234, &payload[1..]);
+        assert!(!IcmpConnection::is_oversized_echo_request(
+            &request,
+            max_payload
+        ));
+        // the cap is configurable
+        assert!(IcmpConnection::is_oversized_echo_request(&request, 64));
+        // only the echo requests are capped
+        let reply = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &payload);
+        assert!(!IcmpConnection::is_oversized_echo_request(
+            &reply,
+            max_payload
+        ));
+    }
+
+    #[test]
+    fn drop_oversized_echo_requests_before_queuing() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = FakeClient::new(&mut selector);
+        let mut config = RelayConfig::new(0);
+        config.set_icmp_max_payload(64);
+        let mut raw = create_echo_request_with_payload(&[0x42; 65]);
+        let connection = create_dgram_connection(&mut selector, &config, &mut raw);
+
+        let mut connection = connection.borrow_mut();
+        connection.send_to_network(
+            &mut selector,
+            &mut client.channel(),
+            &Ipv4Packet::parse(&mut raw),
+        );
+        assert!(connection.client_to_network.is_empty());
+        assert_eq!(1, connection.stats.tx_dropped);
+
+        // a request within the cap is queued
+        let mut raw = create_echo_request_with_payload(&[0x42; 64]);
+        connection.send_to_network(
+            &mut selector,
+            &mut client.channel(),
+            &Ipv4Packet::parse(&mut raw),
+        );
+        assert!(!connection.client_to_network.is_empty());
+        assert_eq!(1, connection.stats.tx_dropped);
+    }
+
+    #[test]
+    fn bound_pending_replies() {
+        let mut raw = create_echo_request();
+        let packet = Ipv4Packet::parse(&mut raw);
+        let mut pending_replies = PendingReplies::new();
+        for _ in 0..MAX_PENDING_REPLIES {
+            assert!(pending_replies.push(&packet));
+        }
+        assert!(!pending_replies.push(&packet));
+    }
+
+    #[test]
+    fn measure_echo_rtt() {
+        let mut echo_times = EchoTimes::new();
+        let t0 = Instant::now();
+        let request = create_icmp_message(TYPE_ECHO_REQUEST, 0, 0x1234);
+        echo_times.record(&request, t0);
+        // a retransmission does not restart the measure
+        echo_times.record(&request, t0 + Duration::from_millis(20));
+
+        let reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        let other_reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x4321);
+        let t1 = t0 + Duration::from_millis(50);
+        assert_eq!(None, echo_times.measure(&other_reply, t1));
+        assert_eq!(
+            Some(Duration::from_millis(50)),
+            echo_times.measure(&reply, t1)
+        );
+        // the request is forgotten once replied
+        assert_eq!(None, echo_times.measure(&reply, t1));
+        assert!(echo_times.order.is_empty());
+    }
+
+    // accept a single datagram per batch
+    struct OneByOneSender;
+
+    impl DatagramSender for OneByOneSender {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn send_batch(&mut self, _datagrams: &[&[u8]]) -> io::Result<usize> {
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn record_echo_time_once_written() {
+        let mut first = create_echo_request_with_payload(&[0x42; 4]);
+        let mut second = create_echo_request_with_payload(&[0x11; 4]);
+        BigEndian::write_u16(&mut second[24..26], 0x4321); // identifier
+        let mut requests = QueuedRequests::new(DatagramBuffer::with_max_datagrams(4));
+        requests.push(&Ipv4Packet::parse(&mut first)).unwrap();
+        requests.push(&Ipv4Packet::parse(&mut second)).unwrap();
+        let mut echo_times = EchoTimes::new();
+
+        // only the request actually sent is recorded
+        let sent = requests
+            .write_batch_to(&mut OneByOneSender, &mut echo_times)
+            .unwrap();
+        assert_eq!((1, first.len() - 20), sent);
+        let first_reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        let second_reply = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x4321);
+        assert!(echo_times.measure(&first_reply, Instant::now()).is_some());
+        assert!(echo_times.measure(&second_reply, Instant::now()).is_none());
+
+        requests
+            .write_batch_to(&mut OneByOneSender, &mut echo_times)
+            .unwrap();
+        assert!(echo_times.measure(&second_reply, Instant::now()).is_some());
+    }
+
+    #[test]
+    fn detect_echo_payload_mismatch() {
+        let mut echo_payloads = EchoPayloads::new();
+        let request = create_icmp_message_with_payload(8, 0, 0x1234, &[1, 2, 3, 4]);
+        echo_payloads.record(&request);
+
+        let reply = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &[1, 2, 3, 5]);
+        assert_eq!(Some(false), echo_payloads.verify(&reply));
+        // the request is forgotten once replied
+        assert_eq!(None, echo_payloads.verify(&reply));
+
+        echo_payloads.record(&request);
+        let reply = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &[1, 2, 3, 4]);
+        assert_eq!(Some(true), echo_payloads.verify(&reply));
+        assert!(echo_payloads.order.is_empty());
+    }
+
+    #[test]
+    fn drop_destination_unreachable() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        // Destination Unreachable, Host Unreachable
+        let message = create_icmp_message(3, 1, 0);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let result =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw)
+                .unwrap();
+        assert!(result.echo_reply().is_none());
+    }
+
+    #[test]
+    fn report_destination_unreachable() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        // Destination Unreachable, Port Unreachable
+        let message = create_icmp_message(3, 3, 0);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let result =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw)
+                .unwrap();
+        match result {
+            IcmpReply::DestinationUnreachable(packet) => {
+                assert_eq!(&message[..], packet.payload().unwrap())
+            }
+            _ => panic!("Destination Unreachable not reported"),
+        }
+    }
+
+    #[test]
+    fn drop_routing_control_messages() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        // Redirect for Host, whose gateway address matches the identifier of the connection
+        let redirect = create_icmp_message(TYPE_REDIRECT, 1, 0x1234);
+        let router_advertisement = create_icmp_message(TYPE_ROUTER_ADVERTISEMENT, 0, 0x1234);
+        let router_solicitation = create_icmp_message(TYPE_ROUTER_SOLICITATION, 0, 0);
+        for message in &[redirect, router_advertisement, router_solicitation] {
+            for &kind in &[IcmpSocketKind::Raw, IcmpSocketKind::Dgram] {
+                let mut source = MockDatagramSocket::from_data(message);
+                let result =
+                    IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, kind)
+                        .unwrap();
+                assert!(matches!(result, IcmpReply::Ignored));
+            }
+        }
+    }
+
+    #[test]
+    fn drop_echo_reply_of_other_identifier() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        let message = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x4321);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let result =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw)
+                .unwrap();
+        assert!(result.echo_reply().is_none());
+    }
+
+    #[test]
+    fn restore_identifier_of_dgram_reply() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        // the kernel replaced the identifier of the request by its own
+        let message = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x4321);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let packet = IcmpConnection::packetize_reply(
+            &id,
+            &mut packetizer,
+            &mut source,
+            IcmpSocketKind::Dgram,
+        )
+        .unwrap()
+        .echo_reply()
+        .expect("Echo reply not forwarded");
+        let icmp_header_data = IcmpHeaderData::parse(packet.payload().unwrap());
+        assert_eq!(Some(0x1234), icmp_header_data.identifier());
+    }
+
+    #[test]
+    fn drop_corrupt_echo_reply() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        let mut message = create_icmp_message(TYPE_ECHO_REPLY, 0, 0x1234);
+        message[10] ^= 0x01;
+        let mut source = MockDatagramSocket::from_data(&message);
+        let result =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw);
+        assert!(matches!(result, Err(PacketizeError::BadChecksum)));
+    }
+
+    #[test]
+    fn drop_oversized_echo_reply() {
+        let raw = &mut create_echo_request()[..];
+        let (id, mut packetizer) = create_packetizer(raw);
+
+        let payload = vec![0x42; packetizer.max_payload_length()];
+        let message = create_icmp_message_with_payload(TYPE_ECHO_REPLY, 0, 0x1234, &payload);
+        let mut source = MockDatagramSocket::from_data(&message);
+        let result =
+            IcmpConnection::packetize_reply(&id, &mut packetizer, &mut source, IcmpSocketKind::Raw);
+        assert!(matches!(result, Err(PacketizeError::Oversized)));
+    }
+
+    #[test]
+    fn report_fragmentation_needed_for_oversized_df_request() {
+        let mut raw = create_echo_request();
+        raw[6] = 0x40; // DF flag
+        let ipv4_header_raw = raw[..20].to_vec();
+        assert!(Ipv4Packet::parse(&mut raw)
+            .ipv4_header_data()
+            .dont_fragment());
+
+        // the ICMP message of a request too large for the path MTU
+        let datagram = create_icmp_message_with_payload(8, 0, 0x1234, &[0x42; 2000]);
+        let mut raw = IcmpConnection::build_fragmentation_needed(&ipv4_header_raw, &datagram, 1400);
+
+        let packet = Ipv4Packet::parse(&mut raw);
+        // the error is sent from the destination of the request to the client
+        assert_eq!(0x01010101, packet.ipv4_header_data().source());
+        assert_eq!(0x0A000002, packet.ipv4_header_data().destination());
+        let message = packet.payload().unwrap();
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+        assert_eq!(
+            icmp_error::CODE_FRAGMENTATION_NEEDED,
+            icmp_header_data.code()
+        );
+        assert_eq!(1400, BigEndian::read_u16(&message[6..8]));
+        assert!(icmp_header_data.bind(message).verify_checksum());
+
+        // the embedded header describes the whole oversized request
+        let embedded = &message[ICMP_HEADER_LENGTH..];
+        assert_eq!(20 + 8, embedded.len());
+        assert_eq!(20 + 2008, BigEndian::read_u16(&embedded[2..4]));
+        assert_eq!(0x40, embedded[6]);
+        assert_eq!(&datagram[..8], &embedded[20..]);
+    }
+
+    fn create_echo_request_with_payload(payload: &[u8]) -> Vec<u8> {
+        let mut raw = create_echo_request()[..20].to_vec();
+        raw.extend_from_slice(&create_icmp_message_with_payload(
+            TYPE_ECHO_REQUEST,
+            0,
+            0x1234,
+            payload,
+        ));
+        let total_length = raw.len() as u16;
+        BigEndian::write_u16(&mut raw[2..4], total_length);
+        raw
+    }
+
+    #[test]
+    fn queue_dgram_requests_unmodified() {
+        let mut selector = Selector::create().unwrap();
+        let mut client = FakeClient::new(&mut selector);
+        let mut raw = create_echo_request();
+        // an invalid ICMP checksum, which would be recomputed if the relay computed it
+        BigEndian::write_u16(&mut raw[22..24], 0xDEAD);
+        let connection = create_dgram_connection(&mut selector, &RelayConfig::new(0), &mut raw);
+
+        let mut request = raw.clone();
+        connection.borrow_mut().send_to_network(
+            &mut selector,
+            &mut client.channel(),
+            &Ipv4Packet::parse(&mut request),
+        );
+        // the kernel computes the checksum and sets the identifier of the datagram sockets
+        let (_, datagram) = connection.borrow_mut().client_to_network.pop();
+        assert_eq!(&raw[20..], &datagram[..]);
+        assert_eq!(0xDEAD, BigEndian::read_u16(&datagram[2..4]));
+        assert_eq!(0x1234, BigEndian::read_u16(&datagram[4..6]));
+    }
+
+    #[test]
+    fn keep_the_header_of_each_queued_request() {
+        let mut large = create_echo_request_with_payload(&[0x42; 2000]);
+        large[6] = 0x40; // DF flag
+        let mut small = create_echo_request_with_payload(&[0x11; 4]);
+        let mut requests = QueuedRequests::new(DatagramBuffer::with_max_datagrams(4));
+        requests.push(&Ipv4Packet::parse(&mut small)).unwrap();
+        requests.push(&Ipv4Packet::parse(&mut large)).unwrap();
+
+        // the small request does not get the header of the last (large) one
+        let (ipv4_header_raw, datagram) = requests.pop();
+        assert_eq!(&small[..20], &ipv4_header_raw[..]);
+        assert_eq!(&small[20..], &datagram[..]);
+        let (ipv4_header_raw, datagram) = requests.pop();
+        assert_eq!(&large[..20], &ipv4_header_raw[..]);
+        assert_eq!(&large[20..], &datagram[..]);
+        assert!(requests.is_empty());
+
+        // even with a header describing a longer datagram, the embedded length is the actual one
+        let mut raw = IcmpConnection::build_fragmentation_needed(&large[..20], &small[20..], 1400);
+        let packet = Ipv4Packet::parse(&mut raw);
+        let embedded = &packet.payload().unwrap()[ICMP_HEADER_LENGTH..];
+        assert_eq!(small.len() as u16, BigEndian::read_u16(&embedded[2..4]));
+    }
+
+    #[test]
+    fn forget_the_headers_of_evicted_requests() {
+        let mut first = create_echo_request_with_payload(&[0x42; 2000]);
+        let mut second = create_echo_request_with_payload(&[0x11; 4]);
+        let mut datagrams = DatagramBuffer::with_max_datagrams(1);
+        datagrams.set_policy(QueuePolicy::HeadDrop);
+        let mut requests = QueuedRequests::new(datagrams);
+        assert_eq!(0, requests.push(&Ipv4Packet::parse(&mut first)).unwrap());
+        assert_eq!(1, requests.push(&Ipv4Packet::parse(&mut second)).unwrap());
+
+        let (ipv4_header_raw, datagram) = requests.pop();
+        assert_eq!(&second[..20], &ipv4_header_raw[..]);
+        assert_eq!(&second[20..], &datagram[..]);
+    }
+
+    #[test]
+    fn expire_after_configured_idle_timeout() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_icmp_idle_timeout(Duration::from_secs(10));
+        let mut raw = create_echo_request();
+        let connection = create_dgram_connection(&mut selector, &config, &mut raw);
+
+        let mut connection = connection.borrow_mut();
+        // idle for longer than the default timeout, but not than the configured one
+        connection.idle_since = Instant::now() - Duration::from_secs(3);
+        assert!(!connection.is_expired());
+
+        connection.idle_since = Instant::now() - Duration::from_secs(11);
+        assert!(connection.is_expired());
+    }
 }
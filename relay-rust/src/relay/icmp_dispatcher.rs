@@ -0,0 +1,376 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::{Event, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::rc::{Rc, Weak};
+
+use super::datagram::DatagramReceiver;
+use super::icmp_header::{IcmpHeaderData, ICMP_HEADER_LENGTH, TYPE_DESTINATION_UNREACHABLE};
+use super::icmp_socket::{IcmpSocket, IcmpSocketKind};
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+use super::relay_config::RelayConfig;
+use super::selector::Selector;
+
+const TAG: &str = "IcmpDispatcher";
+
+pub type SharedIcmpDispatcher = Rc<RefCell<IcmpDispatcher>>;
+
+/// Connection receiving its ICMP messages from an `IcmpDispatcher`.
+pub trait IcmpEndpoint {
+    /// Handle the ICMP `message` (without its IP header) routed to this endpoint.
+    ///
+    /// The `ttl` of the packet carrying the message is provided if its IP header was received.
+    fn on_message(&mut self, selector: &mut Selector, message: &[u8], ttl: Option<u8>);
+
+    /// Send the pending messages, now that the shared socket is writable.
+    ///
+    /// Return `true` if some messages are still pending.
+    fn on_writable(&mut self, selector: &mut Selector) -> bool;
+}
+
+pub type WeakIcmpEndpoint = Weak<RefCell<dyn IcmpEndpoint>>;
+
+/// Socket on which a connection sends its ICMP messages.
+pub enum IcmpTransport {
+    /// Socket connected to the destination, owned by the connection.
+    Dedicated(IcmpSocket),
+    /// Raw socket shared by the connections of the client, read by the dispatcher.
+    Shared(Rc<IcmpSocket>, SharedIcmpDispatcher),
+}
+
+/// Raw ICMP socket shared by the ICMP connections of a client.
+///
+/// Opening a raw socket per connection is expensive, and may hit the file descriptor limits on
+/// ping floods. Instead, the echo requests are all sent over a single raw socket, and the replies
+/// are routed to the connection having the same destination and identifier.
+///
+/// If raw sockets are not permitted, every connection gets its own datagram socket (the kernel
+/// replaces the identifier by one of its own, so they could not be demultiplexed anyway).
+pub struct IcmpDispatcher {
+    self_weak: Weak<RefCell<Self>>,
+    socket: Option<Rc<IcmpSocket>>,
+    token: Token,
+    interests: Ready,
+    // connections by (destination, identifier)
+    endpoints: HashMap<(Ipv4Addr, u16), WeakIcmpEndpoint>,
+    // connections waiting for the socket to be writable
+    writers: Vec<WeakIcmpEndpoint>,
+    buffer: Box<[u8; MAX_PACKET_LENGTH]>,
+    sockets_opened: u64,
+}
+
+impl IcmpDispatcher {
+    pub fn new_shared() -> SharedIcmpDispatcher {
+        let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
+            socket: None,
+            token: Token(0), // default value, will be set afterwards
+            interests: Ready::readable(),
+            endpoints: HashMap::new(),
+            writers: Vec::new(),
+            buffer: Box::new([0; MAX_PACKET_LENGTH]),
+            sockets_opened: 0,
+        }));
+        // keep a shared reference to this
+        rc.borrow_mut().self_weak = Rc::downgrade(&rc);
+        rc
+    }
+
+    /// Number of ICMP sockets opened so far, shared or not.
+    pub fn sockets_opened(&self) -> u64 {
+        self.sockets_opened
+    }
+
+    /// Provide the socket to send the messages to `destination`: the shared raw socket if
+    /// possible, or a new socket connected to `destination` otherwise.
+    pub fn open(
+        &mut self,
+        selector: &mut Selector,
+        config: &RelayConfig,
+        destination: &SocketAddrV4,
+    ) -> io::Result<IcmpTransport> {
+        if let Some(ref socket) = self.socket {
+            return Ok(IcmpTransport::Shared(socket.clone(), self.self_rc()));
+        }
+        let socket = IcmpSocket::bind(IpAddr::V4(config.bind_address()), config.bind_device())?;
+        socket.set_buffer_sizes(config)?;
+        self.sockets_opened += 1;
+        if socket.kind() == IcmpSocketKind::Dgram {
+            socket.connect(&SocketAddr::V4(*destination))?;
+            return Ok(IcmpTransport::Dedicated(socket));
+        }
+
+        debug!(target: TAG, "Open shared raw ICMP socket");
+        let socket = Rc::new(socket);
+        let rc = self.self_rc();
+        // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc.borrow_mut().on_ready(selector, event);
+        self.interests = Ready::readable();
+        self.token = selector.register(&*socket, handler, self.interests, PollOpt::level())?;
+        self.socket = Some(socket.clone());
+        Ok(IcmpTransport::Shared(socket, self.self_rc()))
+    }
+
+    fn self_rc(&self) -> SharedIcmpDispatcher {
+        self.self_weak
+            .upgrade()
+            .expect("Expected dispatcher not found")
+    }
+
+    /// Route the echo replies from `destination` carrying `identifier` to `endpoint`.
+    pub fn register(&mut self, destination: Ipv4Addr, identifier: u16, endpoint: WeakIcmpEndpoint) {
+        // forget the connections already dropped
+        self.endpoints
+            .retain(|_, endpoint| endpoint.strong_count() > 0);
+        self.endpoints.insert((destination, identifier), endpoint);
+    }
+
+    /// Call `endpoint.on_writable()` once the shared socket is writable.
+    pub fn request_write(&mut self, selector: &mut Selector, endpoint: WeakIcmpEndpoint) {
+        if !self.writers.iter().any(|writer| writer.ptr_eq(&endpoint)) {
+            self.writers.push(endpoint);
+        }
+        self.update_interests(selector);
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        let ready = event.readiness();
+        if ready.is_readable() {
+            self.process_receive(selector);
+        }
+        if ready.is_writable() {
+            self.process_send(selector);
+        }
+        if self.socket.is_some() {
+            self.update_interests(selector);
+        }
+    }
+
+    fn process_receive(&mut self, selector: &mut Selector) {
+        let socket = match self.socket {
+            Some(ref socket) => socket.clone(),
+            None => return,
+        };
+        match socket.recv_from(&mut self.buffer[..]) {
+            Ok((size, source)) => match source.as_socket_ipv4() {
+                Some(source) => self.dispatch(
+                    selector,
+                    *source.ip(),
+                    &self.buffer[..size],
+                    socket.received_ttl(),
+                ),
+                None => warn!(target: TAG, "Ignoring ICMP message from {:?}", source),
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!(target: TAG, "Spurious event, ignoring")
+            }
+            Err(err) => warn!(target: TAG, "Cannot read: [{:?}] {}", err.kind(), err),
+        }
+    }
+
+    /// Route the ICMP `message` received from `source` to the endpoint it concerns.
+    ///
+    /// Destination Unreachable errors may concern any relayed UDP flow of the client, so they are
+    /// handed to any endpoint, which reports them to the client.
+    fn dispatch(&self, selector: &mut Selector, source: Ipv4Addr, message: &[u8], ttl: Option<u8>) {
+        if message.len() < ICMP_HEADER_LENGTH {
+            debug!(target: TAG, "Ignoring truncated ICMP message from {}", source);
+            return;
+        }
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        let endpoint = if icmp_header_data.icmp_type() == TYPE_DESTINATION_UNREACHABLE {
+            self.endpoints.values().find_map(Weak::upgrade)
+        } else if let (true, Some(identifier)) = (
+            icmp_header_data.is_echo_reply() || icmp_header_data.is_timestamp_reply(),
+            icmp_header_data.identifier(),
+        ) {
+            self.endpoints
+                .get(&(source, identifier))
+                .and_then(Weak::upgrade)
+        } else {
+            None
+        };
+        match endpoint {
+            Some(endpoint) => endpoint.borrow_mut().on_message(selector, message, ttl),
+            None => debug!(
+                target: TAG,
+                "Ignoring ICMP message from {} (type={}, code={}, id={:?})",
+                source,
+                icmp_header_data.icmp_type(),
+                icmp_header_data.code(),
+                icmp_header_data.identifier()
+            ),
+        }
+    }
+
+    fn process_send(&mut self, selector: &mut Selector) {
+        for writer in mem::take(&mut self.writers) {
+            if let Some(endpoint) = writer.upgrade() {
+                if endpoint.borrow_mut().on_writable(selector) {
+                    self.writers.push(writer);
+                }
+            }
+        }
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let socket = match self.socket {
+            Some(ref socket) => socket,
+            None => return,
+        };
+        let ready = if self.writers.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        if self.interests != ready {
+            debug!(target: TAG, "interests: {:?}", ready);
+            self.interests = ready;
+            selector
+                .reregister(&**socket, self.token, ready, PollOpt::level())
+                .expect("Cannot register on poll");
+        }
+    }
+
+    /// Close the shared socket if no connection uses it anymore, so that the client is not woken
+    /// up by all the ICMP traffic of the host.
+    pub fn release_if_unused(&mut self, selector: &mut Selector) {
+        self.endpoints
+            .retain(|_, endpoint| endpoint.strong_count() > 0);
+        if self.endpoints.is_empty() {
+            self.close(selector);
+        }
+    }
+
+    pub fn close(&mut self, selector: &mut Selector) {
+        if let Some(socket) = self.socket.take() {
+            debug!(target: TAG, "Close shared raw ICMP socket");
+            if let Err(err) = selector.deregister(&*socket, self.token) {
+                warn!(target: TAG, "Fail to deregister ICMP socket: {}", err);
+            }
+        }
+        self.endpoints.clear();
+        self.writers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::icmp_header::TYPE_ECHO_REPLY;
+
+    #[derive(Default)]
+    struct FakeEndpoint {
+        messages: Vec<Vec<u8>>,
+    }
+
+    impl IcmpEndpoint for FakeEndpoint {
+        fn on_message(&mut self, _: &mut Selector, message: &[u8], _: Option<u8>) {
+            self.messages.push(message.to_vec());
+        }
+
+        fn on_writable(&mut self, _: &mut Selector) -> bool {
+            false
+        }
+    }
+
+    fn create_icmp_message(icmp_type: u8, identifier: u16) -> Vec<u8> {
+        let mut raw = vec![icmp_type, 0, 0, 0, 0, 0, 0, 1, 0x11, 0x22];
+        raw[4..6].copy_from_slice(&identifier.to_be_bytes());
+        raw
+    }
+
+    #[test]
+    fn share_socket_between_connections() {
+        let mut selector = Selector::create().unwrap();
+        let config = RelayConfig::new(0);
+        let dispatcher = IcmpDispatcher::new_shared();
+        let first =
+            dispatcher
+                .borrow_mut()
+                .open(&mut selector, &config, &"1.1.1.1:0".parse().unwrap());
+        let second =
+            dispatcher
+                .borrow_mut()
+                .open(&mut selector, &config, &"8.8.8.8:0".parse().unwrap());
+        match (first, second) {
+            (Ok(IcmpTransport::Shared(first, _)), Ok(IcmpTransport::Shared(second, _))) => {
+                assert!(Rc::ptr_eq(&first, &second));
+                assert_eq!(1, dispatcher.borrow().sockets_opened());
+            }
+            // raw sockets require CAP_NET_RAW, there is nothing to share without them
+            (Ok(IcmpTransport::Dedicated(_)), Ok(IcmpTransport::Dedicated(_))) | (Err(_), _) => {
+                return;
+            }
+            _ => panic!("Only one connection got the shared socket"),
+        }
+        dispatcher.borrow_mut().close(&mut selector);
+    }
+
+    #[test]
+    fn demux_replies_by_identifier() {
+        let mut selector = Selector::create().unwrap();
+        let dispatcher = IcmpDispatcher::new_shared();
+        let host = Ipv4Addr::new(1, 1, 1, 1);
+        let first = Rc::new(RefCell::new(FakeEndpoint::default()));
+        let second = Rc::new(RefCell::new(FakeEndpoint::default()));
+        let other_host = Rc::new(RefCell::new(FakeEndpoint::default()));
+        {
+            let mut dispatcher = dispatcher.borrow_mut();
+            dispatcher.register(host, 0x1234, Rc::downgrade(&first) as WeakIcmpEndpoint);
+            dispatcher.register(host, 0x4321, Rc::downgrade(&second) as WeakIcmpEndpoint);
+            dispatcher.register(
+                Ipv4Addr::new(8, 8, 8, 8),
+                0x1234,
+                Rc::downgrade(&other_host) as WeakIcmpEndpoint,
+            );
+        }
+
+        let reply1 = create_icmp_message(TYPE_ECHO_REPLY, 0x1234);
+        let reply2 = create_icmp_message(TYPE_ECHO_REPLY, 0x4321);
+        let dispatcher = dispatcher.borrow();
+        dispatcher.dispatch(&mut selector, host, &reply2, None);
+        dispatcher.dispatch(&mut selector, host, &reply1, None);
+        // no connection for this identifier, or not an echo reply
+        dispatcher.dispatch(
+            &mut selector,
+            host,
+            &create_icmp_message(TYPE_ECHO_REPLY, 0x9999),
+            None,
+        );
+        dispatcher.dispatch(&mut selector, host, &create_icmp_message(8, 0x1234), None);
+
+        assert_eq!(vec![reply1], first.borrow().messages);
+        assert_eq!(vec![reply2], second.borrow().messages);
+        assert!(other_host.borrow().messages.is_empty());
+
+        // a Destination Unreachable error is handled once
+        let error = create_icmp_message(TYPE_DESTINATION_UNREACHABLE, 0);
+        dispatcher.dispatch(&mut selector, Ipv4Addr::new(10, 0, 0, 1), &error, None);
+        let received = [&first, &second, &other_host]
+            .iter()
+            .filter(|endpoint| endpoint.borrow().messages.contains(&error))
+            .count();
+        assert_eq!(1, received);
+    }
+}
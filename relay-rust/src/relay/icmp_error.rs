@@ -0,0 +1,472 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::connection::ConnectionId;
+use super::icmp_header::{
+    IcmpHeaderData, ICMP_HEADER_LENGTH, TIMESTAMP_MESSAGE_LENGTH, TYPE_DESTINATION_UNREACHABLE,
+    TYPE_ECHO_REPLY, TYPE_PARAMETER_PROBLEM, TYPE_TIMESTAMP_REPLY, TYPE_TIME_EXCEEDED,
+};
+use super::ipv4_header::{Ipv4HeaderData, Protocol};
+use super::ipv4_packet::Ipv4Packet;
+use super::net;
+use super::transport_header::TransportHeaderData;
+
+pub const CODE_NET_UNREACHABLE: u8 = 0;
+pub const CODE_HOST_UNREACHABLE: u8 = 1;
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const CODE_ADMINISTRATIVELY_PROHIBITED: u8 = 13;
+
+pub const CODE_TTL_EXCEEDED: u8 = 0;
+
+// rfc792: the internet header plus the first 64 bits of the original datagram's data
+const ORIGINAL_DATA_LENGTH: usize = 8;
+
+const IPV4_HEADER_LENGTH: usize = 20;
+const ICMP_PROTOCOL: u8 = 1;
+const TTL: u8 = 64;
+const MILLIS_PER_DAY: u128 = 24 * 60 * 60 * 1000;
+
+/// Return the Destination Unreachable code matching an error on connection, if any.
+pub fn destination_unreachable_code(err: &io::Error) -> Option<u8> {
+    match err.kind() {
+        io::ErrorKind::NetworkUnreachable => Some(CODE_NET_UNREACHABLE),
+        io::ErrorKind::HostUnreachable => Some(CODE_HOST_UNREACHABLE),
+        io::ErrorKind::ConnectionRefused => Some(CODE_PORT_UNREACHABLE),
+        _ => None,
+    }
+}
+
+/// Indicate whether an ICMP error may be sent in response to `original`.
+///
+/// No error must be sent about an ICMP error message (rfc1122 section 3.2.2), so among ICMP
+/// messages, only echo requests are accepted.
+pub fn may_reply_with_error(original: &Ipv4Packet) -> bool {
+    if original.ipv4_header_data().protocol() != Protocol::Icmp {
+        return true;
+    }
+    match original.payload() {
+        Some(message) if message.len() >= ICMP_HEADER_LENGTH => {
+            IcmpHeaderData::parse(message).is_echo_request()
+        }
+        _ => false,
+    }
+}
+
+/// Build the raw IPv4 packet of an ICMP Destination Unreachable error in response to `original`.
+///
+/// Parse the result with `Ipv4Packet::parse()` to send it to the client.
+pub fn build_destination_unreachable(original: &Ipv4Packet, code: u8) -> Vec<u8> {
+    build_error(original, TYPE_DESTINATION_UNREACHABLE, code, 0)
+}
+
+/// Build the raw IPv4 packet of an ICMP Fragmentation Needed error in response to `original`,
+/// which has the DF flag but does not fit the path MTU (rfc1191 section 4).
+pub fn build_fragmentation_needed(original: &Ipv4Packet, next_hop_mtu: u16) -> Vec<u8> {
+    build_error(
+        original,
+        TYPE_DESTINATION_UNREACHABLE,
+        CODE_FRAGMENTATION_NEEDED,
+        next_hop_mtu,
+    )
+}
+
+/// Build the raw IPv4 packet of an ICMP Time Exceeded error in response to `original`, whose TTL
+/// expired.
+pub fn build_time_exceeded(original: &Ipv4Packet) -> Vec<u8> {
+    build_error(original, TYPE_TIME_EXCEEDED, CODE_TTL_EXCEEDED, 0)
+}
+
+/// Build the raw IPv4 packet of the ICMP echo reply to the echo request `request`, as if sent by
+/// its destination.
+///
+/// The identifier, sequence number and data of the request are echoed.
+pub fn build_echo_reply(request: &Ipv4Packet) -> Vec<u8> {
+    let request_message = request.payload().expect("ICMP packet without payload");
+    let mut raw = new_reply_packet(request, request_message.len());
+    {
+        let message = &mut raw[IPV4_HEADER_LENGTH..];
+        message.copy_from_slice(request_message);
+        message[0] = TYPE_ECHO_REPLY;
+        message[1] = 0; // code
+        let mut icmp_header_data = IcmpHeaderData::parse(message);
+        icmp_header_data.bind_mut(message).update_checksum();
+    }
+    Ipv4Packet::parse(&mut raw).compute_checksums();
+    raw
+}
+
+/// Return the current time in milliseconds since midnight UT, as carried by the ICMP timestamp
+/// messages (rfc792).
+pub fn timestamp_now() -> u32 {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    (millis % MILLIS_PER_DAY) as u32
+}
+
+/// Build the raw IPv4 packet of the ICMP timestamp reply to the timestamp request `request`, as if
+/// sent by its destination.
+///
+/// The identifier, sequence number and originate timestamp of the request are echoed, the request
+/// is considered received and the reply transmitted at `timestamp`.
+pub fn build_timestamp_reply(request: &Ipv4Packet, timestamp: u32) -> Vec<u8> {
+    let request_message = request.payload().expect("ICMP packet without payload");
+    let mut raw = new_reply_packet(request, TIMESTAMP_MESSAGE_LENGTH);
+    {
+        let message = &mut raw[IPV4_HEADER_LENGTH..];
+        // the header and the originate timestamp
+        message[..12].copy_from_slice(&request_message[..12]);
+        message[0] = TYPE_TIMESTAMP_REPLY;
+        message[1] = 0; // code
+        BigEndian::write_u32(&mut message[12..16], timestamp); // receive timestamp
+        BigEndian::write_u32(&mut message[16..20], timestamp); // transmit timestamp
+        let mut icmp_header_data = IcmpHeaderData::parse(message);
+        icmp_header_data.bind_mut(message).update_checksum();
+    }
+    Ipv4Packet::parse(&mut raw).compute_checksums();
+    raw
+}
+
+/// Return the flow (source, destination) of the UDP datagram embedded in `message`, if it is an
+/// ICMP Port Unreachable error.
+///
+/// The embedded datagram is the one sent to the network, so the source is the local socket.
+pub fn port_unreachable_udp_flow(message: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4)> {
+    let icmp_header_data = IcmpHeaderData::try_parse(message)?;
+    if icmp_header_data.icmp_type() != TYPE_DESTINATION_UNREACHABLE
+        || icmp_header_data.code() != CODE_PORT_UNREACHABLE
+    {
+        return None;
+    }
+    let id = embedded_connection_id(message)?;
+    if id.protocol() != Protocol::Udp {
+        return None;
+    }
+    Some((id.source(), id.destination()))
+}
+
+/// Reconstruct the id of the flow of the datagram embedded in the ICMP error `message`
+/// (Destination Unreachable, Time Exceeded or Parameter Problem), if any.
+///
+/// The id is the one of the embedded datagram as it was sent, so the flow of an error received
+/// from the network has the local socket as source.
+pub fn embedded_connection_id(message: &[u8]) -> Option<ConnectionId> {
+    let icmp_header_data = IcmpHeaderData::try_parse(message)?;
+    match icmp_header_data.icmp_type() {
+        TYPE_DESTINATION_UNREACHABLE | TYPE_TIME_EXCEEDED | TYPE_PARAMETER_PROBLEM => (),
+        _ => return None,
+    }
+    let original = &message[ICMP_HEADER_LENGTH..];
+    if original.len() < IPV4_HEADER_LENGTH {
+        return None;
+    }
+    // the embedded datagram is truncated, its total length cannot be checked
+    let ipv4_header_data = Ipv4HeaderData::parse(original);
+    let header_length = ipv4_header_data.header_length() as usize;
+    if ipv4_header_data.version() != 4 || header_length < IPV4_HEADER_LENGTH {
+        return None;
+    }
+    let transport = &original[cmp::min(header_length, original.len())..];
+    match ipv4_header_data.protocol() {
+        Protocol::Tcp | Protocol::Udp => {
+            // only the ports are needed, not the whole transport header
+            if transport.len() < 4 {
+                return None;
+            }
+            let source_port = BigEndian::read_u16(&transport[0..2]);
+            let destination_port = BigEndian::read_u16(&transport[2..4]);
+            Some(ConnectionId::from_endpoints(
+                ipv4_header_data.protocol(),
+                net::to_socket_addr(ipv4_header_data.source(), source_port),
+                net::to_socket_addr(ipv4_header_data.destination(), destination_port),
+            ))
+        }
+        Protocol::Icmp => {
+            // the first 64 bits of the data are exactly the ICMP header
+            let embedded_icmp_header_data = IcmpHeaderData::try_parse(transport)?;
+            Some(ConnectionId::from_headers(
+                &ipv4_header_data,
+                &TransportHeaderData::Icmp(embedded_icmp_header_data),
+            ))
+        }
+        Protocol::Other(_) => Some(ConnectionId::from_ipv4_header(&ipv4_header_data)),
+    }
+}
+
+// next_hop_mtu is stored in the low-order 16 bits of the "unused" field (0 if not applicable)
+fn build_error(original: &Ipv4Packet, icmp_type: u8, code: u8, next_hop_mtu: u16) -> Vec<u8> {
+    let original_raw = original.raw();
+    let original_header_length = original.ipv4_header_data().header_length() as usize;
+    let embedded_length = cmp::min(
+        original_raw.len(),
+        original_header_length + ORIGINAL_DATA_LENGTH,
+    );
+    let mut raw = new_reply_packet(original, ICMP_HEADER_LENGTH + embedded_length);
+
+    {
+        let message = &mut raw[IPV4_HEADER_LENGTH..];
+        message[0] = icmp_type;
+        message[1] = code;
+        // bytes 4..6 are unused
+        BigEndian::write_u16(&mut message[6..8], next_hop_mtu);
+        message[ICMP_HEADER_LENGTH..].copy_from_slice(&original_raw[..embedded_length]);
+        let mut icmp_header_data = IcmpHeaderData::parse(message);
+        icmp_header_data.bind_mut(message).update_checksum();
+    }
+
+    Ipv4Packet::parse(&mut raw).compute_checksums();
+    raw
+}
+
+/// Create the raw IPv4 packet of an ICMP message of `message_length` bytes (left blank) from the
+/// destination of `original` to its source.
+fn new_reply_packet(original: &Ipv4Packet, message_length: usize) -> Vec<u8> {
+    let original_raw = original.raw();
+    let total_length = IPV4_HEADER_LENGTH + message_length;
+    let mut raw = vec![0u8; total_length];
+    raw[0] = 4 << 4 | (IPV4_HEADER_LENGTH / 4) as u8; // version_and_ihl
+    BigEndian::write_u16(&mut raw[2..4], total_length as u16);
+    raw[8] = TTL;
+    raw[9] = ICMP_PROTOCOL;
+    // the reply comes from the original destination
+    raw[12..16].copy_from_slice(&original_raw[16..20]);
+    raw[16..20].copy_from_slice(&original_raw[12..16]);
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_header::Protocol;
+    use byteorder::WriteBytesExt;
+
+    fn create_udp_packet() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(32);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
+    #[test]
+    fn build_port_unreachable() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_destination_unreachable(&original, CODE_PORT_UNREACHABLE);
+
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(Protocol::Icmp, packet.ipv4_header_data().protocol());
+        assert_eq!(0x42424242, packet.ipv4_header_data().source());
+        assert_eq!(0x12345678, packet.ipv4_header_data().destination());
+
+        let message = packet.payload().unwrap();
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+        assert_eq!(CODE_PORT_UNREACHABLE, icmp_header_data.code());
+        // the IPv4 header and the UDP header, but not the UDP payload
+        assert_eq!(&original_raw[..28], &message[ICMP_HEADER_LENGTH..]);
+
+        let mut checked = message.to_vec();
+        let mut checked_header_data = icmp_header_data.clone();
+        checked_header_data.bind_mut(&mut checked).update_checksum();
+        assert_eq!(message, &checked[..]);
+    }
+
+    #[test]
+    fn swap_addresses_of_errors() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let errors = vec![
+            build_destination_unreachable(&original, CODE_HOST_UNREACHABLE),
+            build_fragmentation_needed(&original, 1400),
+            build_time_exceeded(&original),
+        ];
+        for mut raw in errors {
+            // sent by the original destination, back to the original source
+            let packet = Ipv4Packet::parse(&mut raw);
+            assert_eq!(0x42424242, packet.ipv4_header_data().source());
+            assert_eq!(0x12345678, packet.ipv4_header_data().destination());
+            assert!(packet.ipv4_header().verify_checksum());
+            assert!(packet.is_valid());
+        }
+    }
+
+    #[test]
+    fn build_ttl_exceeded() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_time_exceeded(&original);
+
+        let packet = Ipv4Packet::parse(&mut raw);
+        let message = packet.payload().unwrap();
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert_eq!(TYPE_TIME_EXCEEDED, icmp_header_data.icmp_type());
+        assert_eq!(CODE_TTL_EXCEEDED, icmp_header_data.code());
+        assert_eq!(&original_raw[..28], &message[ICMP_HEADER_LENGTH..]);
+    }
+
+    #[test]
+    fn build_fragmentation_needed_with_mtu() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_fragmentation_needed(&original, 1400);
+
+        let packet = Ipv4Packet::parse(&mut raw);
+        let message = packet.payload().unwrap();
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+        assert_eq!(CODE_FRAGMENTATION_NEEDED, icmp_header_data.code());
+        assert_eq!(&[0, 0], &message[4..6]);
+        assert_eq!(1400, BigEndian::read_u16(&message[6..8]));
+        assert!(icmp_header_data.bind(message).verify_checksum());
+    }
+
+    fn create_timestamp_request() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(40);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(40).unwrap(); // total length 20 + 20
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u8(13).unwrap(); // type (Timestamp Request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // identifier
+        raw.write_u16::<BigEndian>(7).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(1_000_000).unwrap(); // originate timestamp
+        raw.write_u32::<BigEndian>(0).unwrap(); // receive timestamp
+        raw.write_u32::<BigEndian>(0).unwrap(); // transmit timestamp
+
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    #[test]
+    fn build_timestamp_reply_to_request() {
+        let mut request_raw = create_timestamp_request();
+        let request = Ipv4Packet::parse(&mut request_raw);
+        let timestamp = timestamp_now();
+        assert!(u128::from(timestamp) < MILLIS_PER_DAY);
+        let mut raw = build_timestamp_reply(&request, timestamp);
+
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0x42424242, packet.ipv4_header_data().source());
+        assert_eq!(0x12345678, packet.ipv4_header_data().destination());
+        let message = packet.payload().unwrap();
+        assert_eq!(TIMESTAMP_MESSAGE_LENGTH, message.len());
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        assert!(icmp_header_data.is_timestamp_reply());
+        assert_eq!(Some(0x1234), icmp_header_data.identifier());
+        assert_eq!(Some(7), icmp_header_data.sequence_number());
+        assert_eq!(1_000_000, BigEndian::read_u32(&message[8..12]));
+        assert_eq!(timestamp, BigEndian::read_u32(&message[12..16]));
+        assert_eq!(timestamp, BigEndian::read_u32(&message[16..20]));
+        assert!(icmp_header_data.bind(message).verify_checksum());
+    }
+
+    #[test]
+    fn parse_port_unreachable_flow() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_destination_unreachable(&original, CODE_PORT_UNREACHABLE);
+        let packet = Ipv4Packet::parse(&mut raw);
+
+        let (source, destination) = port_unreachable_udp_flow(packet.payload().unwrap()).unwrap();
+        assert_eq!(net::to_socket_addr(0x12345678, 1234), source);
+        assert_eq!(net::to_socket_addr(0x42424242, 5678), destination);
+    }
+
+    #[test]
+    fn ignore_other_unreachable_flow() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_destination_unreachable(&original, CODE_HOST_UNREACHABLE);
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert!(port_unreachable_udp_flow(packet.payload().unwrap()).is_none());
+    }
+
+    #[test]
+    fn reconstruct_embedded_udp_flow() {
+        let mut original_raw = create_udp_packet();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_destination_unreachable(&original, CODE_PORT_UNREACHABLE);
+        let packet = Ipv4Packet::parse(&mut raw);
+
+        let id = embedded_connection_id(packet.payload().unwrap()).unwrap();
+        assert_eq!(Protocol::Udp, id.protocol());
+        assert_eq!(net::to_socket_addr(0x12345678, 1234), id.source());
+        assert_eq!(net::to_socket_addr(0x42424242, 5678), id.destination());
+        assert_eq!(None, id.icmp_identifier());
+    }
+
+    #[test]
+    fn reconstruct_embedded_echo_flow() {
+        let mut original_raw = create_timestamp_request();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        let mut raw = build_time_exceeded(&original);
+        let packet = Ipv4Packet::parse(&mut raw);
+
+        let id = embedded_connection_id(packet.payload().unwrap()).unwrap();
+        assert_eq!(Protocol::Icmp, id.protocol());
+        assert_eq!(Some(0x1234), id.icmp_identifier());
+
+        // a reply embeds no datagram
+        let mut reply = build_timestamp_reply(&original, 0);
+        let packet = Ipv4Packet::parse(&mut reply);
+        assert!(embedded_connection_id(packet.payload().unwrap()).is_none());
+    }
+
+    #[test]
+    fn map_error_to_code() {
+        let err = io::Error::from(io::ErrorKind::HostUnreachable);
+        assert_eq!(
+            Some(CODE_HOST_UNREACHABLE),
+            destination_unreachable_code(&err)
+        );
+        let err = io::Error::from(io::ErrorKind::OutOfMemory);
+        assert_eq!(None, destination_unreachable_code(&err));
+    }
+}
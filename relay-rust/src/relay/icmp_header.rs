@@ -1,3 +1,30 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::net::Ipv6Addr;
+
+use super::checksum;
+
+// the ICMP header is relayed as part of the payload (see TransportHeaderData::header_length())
+pub const ICMP_HEADER_LENGTH: usize = 8;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_REDIRECT: u8 = 5;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_ROUTER_ADVERTISEMENT: u8 = 9;
+pub const TYPE_ROUTER_SOLICITATION: u8 = 10;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+pub const TYPE_PARAMETER_PROBLEM: u8 = 12;
+pub const TYPE_TIMESTAMP_REQUEST: u8 = 13;
+pub const TYPE_TIMESTAMP_REPLY: u8 = 14;
+
+// the header followed by the originate, receive and transmit timestamps
+pub const TIMESTAMP_MESSAGE_LENGTH: usize = ICMP_HEADER_LENGTH + 12;
+
+pub const TYPE_ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const TYPE_ICMPV6_ECHO_REPLY: u8 = 129;
+// IPv6 next header value of ICMPv6
+const ICMPV6_NEXT_HEADER: u8 = 58;
+
 #[derive(Debug)]
 pub struct IcmpHeader<'a> {
     raw: &'a [u8],
@@ -11,12 +38,40 @@ pub struct IcmpHeaderMut<'a> {
 }
 
 #[derive(Clone, Debug)]
-pub struct IcmpHeaderData {}
+pub struct IcmpHeaderData {
+    icmp_type: u8,
+    code: u8,
+    // only echo and timestamp messages carry an identifier and a sequence number
+    identifier: Option<u16>,
+    sequence_number: Option<u16>,
+}
 
 #[allow(dead_code)]
 impl IcmpHeaderData {
-    pub fn parse(_: &[u8]) -> Self {
-        Self {}
+    pub fn parse(raw: &[u8]) -> Self {
+        let icmp_type = raw[0];
+        let (identifier, sequence_number) = if has_identifier(icmp_type) {
+            (
+                Some(BigEndian::read_u16(&raw[4..6])),
+                Some(BigEndian::read_u16(&raw[6..8])),
+            )
+        } else {
+            (None, None)
+        };
+        Self {
+            icmp_type,
+            code: raw[1],
+            identifier,
+            sequence_number,
+        }
+    }
+
+    /// Parse a header received from the outside, `None` if it is truncated.
+    pub fn try_parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < ICMP_HEADER_LENGTH {
+            return None;
+        }
+        Some(Self::parse(raw))
     }
 
     #[inline]
@@ -28,6 +83,82 @@ impl IcmpHeaderData {
     pub fn bind_mut<'c, 'a: 'c, 'b: 'c>(&'a mut self, raw: &'b mut [u8]) -> IcmpHeaderMut<'c> {
         IcmpHeaderMut::new(raw, self)
     }
+
+    #[inline]
+    pub fn icmp_type(&self) -> u8 {
+        self.icmp_type
+    }
+
+    #[inline]
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    /// Echo identifier, `None` if the message is not an echo request or reply.
+    #[inline]
+    pub fn identifier(&self) -> Option<u16> {
+        self.identifier
+    }
+
+    /// Echo sequence number, `None` if the message is not an echo request or reply.
+    #[inline]
+    pub fn sequence_number(&self) -> Option<u16> {
+        self.sequence_number
+    }
+
+    #[inline]
+    pub fn is_echo_request(&self) -> bool {
+        self.icmp_type == TYPE_ECHO_REQUEST && self.code == 0
+    }
+
+    #[inline]
+    pub fn is_echo_reply(&self) -> bool {
+        self.icmp_type == TYPE_ECHO_REPLY && self.code == 0
+    }
+
+    #[inline]
+    pub fn is_timestamp_request(&self) -> bool {
+        self.icmp_type == TYPE_TIMESTAMP_REQUEST && self.code == 0
+    }
+
+    #[inline]
+    pub fn is_timestamp_reply(&self) -> bool {
+        self.icmp_type == TYPE_TIMESTAMP_REPLY && self.code == 0
+    }
+
+    /// Indicate whether this message concerns the routing of the relay host (a redirect or a
+    /// router advertisement or solicitation), which must never reach the client.
+    #[inline]
+    pub fn is_routing_control(&self) -> bool {
+        matches!(
+            self.icmp_type,
+            TYPE_REDIRECT | TYPE_ROUTER_ADVERTISEMENT | TYPE_ROUTER_SOLICITATION
+        )
+    }
+
+    #[inline]
+    pub fn is_icmpv6_echo_request(&self) -> bool {
+        self.icmp_type == TYPE_ICMPV6_ECHO_REQUEST && self.code == 0
+    }
+
+    #[inline]
+    pub fn is_icmpv6_echo_reply(&self) -> bool {
+        self.icmp_type == TYPE_ICMPV6_ECHO_REPLY && self.code == 0
+    }
+}
+
+// the echo request and reply, of ICMP or ICMPv6, and the timestamp request and reply share the
+// same header layout
+fn has_identifier(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        TYPE_ECHO_REQUEST
+            | TYPE_ECHO_REPLY
+            | TYPE_TIMESTAMP_REQUEST
+            | TYPE_TIMESTAMP_REPLY
+            | TYPE_ICMPV6_ECHO_REQUEST
+            | TYPE_ICMPV6_ECHO_REPLY
+    )
 }
 
 macro_rules! icmp_header_common {
@@ -47,6 +178,26 @@ macro_rules! icmp_header_common {
             pub fn data(&self) -> &IcmpHeaderData {
                 self.data
             }
+
+            #[inline]
+            pub fn icmp_type(&self) -> u8 {
+                self.data.icmp_type
+            }
+
+            #[inline]
+            pub fn code(&self) -> u8 {
+                self.data.code
+            }
+
+            #[inline]
+            pub fn identifier(&self) -> Option<u16> {
+                self.data.identifier
+            }
+
+            #[inline]
+            pub fn sequence_number(&self) -> Option<u16> {
+                self.data.sequence_number
+            }
         }
     };
 }
@@ -54,6 +205,20 @@ macro_rules! icmp_header_common {
 icmp_header_common!(IcmpHeader, &'a [u8], &'a IcmpHeaderData);
 icmp_header_common!(IcmpHeaderMut, &'a mut [u8], &'a mut IcmpHeaderData);
 
+#[allow(dead_code)]
+impl<'a> IcmpHeader<'a> {
+    #[inline]
+    pub fn checksum(&self) -> u16 {
+        BigEndian::read_u16(&self.raw[2..4])
+    }
+
+    /// Check the checksum of the whole ICMP message, so `raw` must span the message.
+    pub fn verify_checksum(&self) -> bool {
+        // the sum including the checksum field is 0xFFFF for a valid message
+        checksum::fold(checksum::sum(self.raw)) == 0xFFFF
+    }
+}
+
 #[allow(dead_code)]
 impl<'a> IcmpHeaderMut<'a> {
     #[inline]
@@ -65,4 +230,276 @@ impl<'a> IcmpHeaderMut<'a> {
     pub fn data_mut(&mut self) -> &mut IcmpHeaderData {
         self.data
     }
+
+    fn set_checksum(&mut self, checksum: u16) {
+        BigEndian::write_u16(&mut self.raw[2..4], checksum);
+    }
+
+    /// Compute the checksum over the whole ICMP message, so `raw` must span the message.
+    pub fn update_checksum(&mut self) {
+        // reset checksum field, so that it can be added with other bytes
+        self.set_checksum(0);
+        let sum = checksum::sum(self.raw);
+        self.set_checksum(!checksum::fold(sum));
+    }
+
+    /// Compute the ICMPv6 checksum, which also covers the IPv6 pseudo-header (cf rfc4443
+    /// section 2.3), so `raw` must span the whole message.
+    pub fn update_icmpv6_checksum(&mut self, source: &Ipv6Addr, destination: &Ipv6Addr) {
+        self.set_checksum(0);
+        let length = self.raw.len() as u32;
+        let sum = checksum::ipv6_pseudo_header_sum(source, destination, ICMPV6_NEXT_HEADER, length)
+            + checksum::sum(self.raw);
+        self.set_checksum(!checksum::fold(sum));
+    }
+
+    /// Replace the echo identifier, adjusting the checksum incrementally (cf rfc1624).
+    ///
+    /// The message must be an echo request or reply.
+    pub fn set_identifier(&mut self, identifier: u16) {
+        let old_identifier = self
+            .data
+            .identifier
+            .expect("Setting the identifier of a non-echo message");
+        self.data.identifier = Some(identifier);
+        BigEndian::write_u16(&mut self.raw[4..6], identifier);
+
+        self.update_checksum_incremental(old_identifier, identifier);
+    }
+
+    /// Replace the echo sequence number, adjusting the checksum incrementally.
+    ///
+    /// The message must be an echo request or reply.
+    pub fn set_sequence_number(&mut self, sequence_number: u16) {
+        let old_sequence_number = self
+            .data
+            .sequence_number
+            .expect("Setting the sequence number of a non-echo message");
+        self.data.sequence_number = Some(sequence_number);
+        BigEndian::write_u16(&mut self.raw[6..8], sequence_number);
+
+        self.update_checksum_incremental(old_sequence_number, sequence_number);
+    }
+
+    /// Adjust the checksum after a 16-bit word of the message changed from `old_field` to
+    /// `new_field`, without summing the whole message again.
+    pub fn update_checksum_incremental(&mut self, old_field: u16, new_field: u16) {
+        let checksum = BigEndian::read_u16(&self.raw[2..4]);
+        self.set_checksum(checksum::update_incremental(checksum, old_field, new_field));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn parse_header() {
+        // Destination Unreachable, Port Unreachable
+        let raw = [3, 3, 0x12, 0x34, 0, 0, 0, 0];
+        let data = IcmpHeaderData::parse(&raw);
+        assert_eq!(3, data.icmp_type());
+        assert_eq!(3, data.code());
+        assert!(!data.is_echo_reply());
+
+        let raw = [TYPE_ECHO_REPLY, 0, 0x12, 0x34, 0, 1, 0, 1];
+        let data = IcmpHeaderData::parse(&raw);
+        assert!(data.is_echo_reply());
+    }
+
+    #[test]
+    fn compute_checksum() {
+        // odd length, the last byte is padded
+        let mut raw = [
+            TYPE_ECHO_REQUEST,
+            0,
+            0x12,
+            0x34,
+            0x00,
+            0x01,
+            0x00,
+            0x02,
+            0x7F,
+        ];
+        let mut data = IcmpHeaderData::parse(&raw);
+        data.bind_mut(&mut raw).update_checksum();
+        // 0x0800 + 0x0001 + 0x0002 + 0x7F00 = 0x8703
+        assert_eq!(0x78FC, BigEndian::read_u16(&raw[2..4]));
+    }
+
+    #[test]
+    fn compute_checksum_of_odd_payload() {
+        // echo request with a 5-byte payload ("hello")
+        let mut raw = [
+            TYPE_ECHO_REQUEST,
+            0,
+            0,
+            0,
+            0x12,
+            0x34,
+            0x00,
+            0x01,
+            b'h',
+            b'e',
+            b'l',
+            b'l',
+            b'o',
+        ];
+        let mut data = IcmpHeaderData::parse(&raw);
+        data.bind_mut(&mut raw).update_checksum();
+        // reference value, the last byte being padded with a zero low-order byte
+        assert_eq!(0xA1F8, BigEndian::read_u16(&raw[2..4]));
+        assert!(data.bind(&raw).verify_checksum());
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let mut raw = [
+            TYPE_ECHO_REPLY,
+            0,
+            0x00,
+            0x00,
+            0x12,
+            0x34,
+            0x00,
+            0x01,
+            0xCA,
+            0xFE,
+        ];
+        let mut data = IcmpHeaderData::parse(&raw);
+        data.bind_mut(&mut raw).update_checksum();
+        assert!(data.bind(&raw).verify_checksum());
+
+        // flip a bit of the payload
+        raw[9] ^= 0x10;
+        assert!(!data.bind(&raw).verify_checksum());
+    }
+
+    #[test]
+    fn compute_icmpv6_checksum() {
+        let source = "fe80::1".parse().unwrap();
+        let destination = "fe80::2".parse().unwrap();
+        let mut raw = [
+            TYPE_ICMPV6_ECHO_REQUEST,
+            0,
+            0,
+            0,
+            0x12,
+            0x34,
+            0x00,
+            0x01,
+            b'a',
+            b'b',
+            b'c',
+            b'd',
+        ];
+        let mut data = IcmpHeaderData::parse(&raw);
+        assert!(data.is_icmpv6_echo_request());
+        data.bind_mut(&mut raw)
+            .update_icmpv6_checksum(&source, &destination);
+        assert_eq!(0xABB8, BigEndian::read_u16(&raw[2..4]));
+    }
+
+    #[test]
+    fn set_identifier_updates_checksum() {
+        // echo reply, checksum computed over the whole message
+        let mut raw = [TYPE_ECHO_REPLY, 0, 0xFF, 0xFD, 0x00, 0x01, 0x00, 0x01];
+        let mut data = IcmpHeaderData::parse(&raw);
+        data.bind_mut(&mut raw).set_identifier(0x1234);
+        assert_eq!(0x1234, BigEndian::read_u16(&raw[4..6]));
+        assert_eq!(0xEDCA, BigEndian::read_u16(&raw[2..4]));
+    }
+
+    #[test]
+    fn incremental_checksum_matches_full_recompute() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..1000 {
+            let length = rng.gen_range(ICMP_HEADER_LENGTH, 64);
+            let mut raw: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            let mut data = IcmpHeaderData::parse(&raw);
+            data.bind_mut(&mut raw).update_checksum();
+
+            // mutate any 16-bit word but the checksum itself
+            let word = loop {
+                let word = rng.gen_range(0, length / 2);
+                if word != 1 {
+                    break word;
+                }
+            };
+            let range = 2 * word..2 * word + 2;
+            let old_field = BigEndian::read_u16(&raw[range.clone()]);
+            let new_field = rng.gen();
+            BigEndian::write_u16(&mut raw[range], new_field);
+            data.bind_mut(&mut raw)
+                .update_checksum_incremental(old_field, new_field);
+            let incremental = BigEndian::read_u16(&raw[2..4]);
+
+            data.bind_mut(&mut raw).update_checksum();
+            assert_eq!(BigEndian::read_u16(&raw[2..4]), incremental);
+        }
+    }
+
+    #[test]
+    fn parse_timestamp_header() {
+        let raw = [TYPE_TIMESTAMP_REPLY, 0, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x2A];
+        let data = IcmpHeaderData::parse(&raw);
+        assert!(data.is_timestamp_reply());
+        assert!(!data.is_echo_reply());
+        assert_eq!(Some(0xABCD), data.identifier());
+        assert_eq!(Some(42), data.sequence_number());
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        assert!(IcmpHeaderData::try_parse(&[]).is_none());
+        assert!(IcmpHeaderData::try_parse(&[TYPE_ECHO_REQUEST]).is_none());
+        let raw = [TYPE_ECHO_REQUEST, 0, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x2A];
+        assert!(IcmpHeaderData::try_parse(&raw[..7]).is_none());
+        assert!(IcmpHeaderData::try_parse(&raw).is_some());
+    }
+
+    #[test]
+    fn parse_echo_header() {
+        let raw = [TYPE_ECHO_REQUEST, 0, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x2A];
+        let data = IcmpHeaderData::parse(&raw);
+        assert!(data.is_echo_request());
+        assert_eq!(Some(0xABCD), data.identifier());
+        assert_eq!(Some(42), data.sequence_number());
+
+        let raw = [
+            TYPE_ICMPV6_ECHO_REPLY,
+            0,
+            0x12,
+            0x34,
+            0x00,
+            0x01,
+            0xFF,
+            0xFE,
+        ];
+        let data = IcmpHeaderData::parse(&raw);
+        assert_eq!(Some(1), data.bind(&raw).identifier());
+        assert_eq!(Some(0xFFFE), data.bind(&raw).sequence_number());
+    }
+
+    #[test]
+    fn no_echo_fields_for_other_types() {
+        // Time Exceeded, the "unused" field must not be read as an identifier
+        let raw = [TYPE_TIME_EXCEEDED, 0, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x2A];
+        let data = IcmpHeaderData::parse(&raw);
+        assert_eq!(None, data.identifier());
+        assert_eq!(None, data.sequence_number());
+    }
+
+    #[test]
+    fn set_sequence_number_updates_checksum() {
+        let mut raw = [TYPE_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0x00, 0x01];
+        let mut data = IcmpHeaderData::parse(&raw);
+        data.bind_mut(&mut raw).update_checksum();
+        data.bind_mut(&mut raw).set_sequence_number(0x0203);
+        assert_eq!(Some(0x0203), data.sequence_number());
+        assert_eq!(0x0203, BigEndian::read_u16(&raw[6..8]));
+        assert!(data.bind(&raw).verify_checksum());
+    }
 }
@@ -1,53 +1,317 @@
-use std::borrow::BorrowMut;
-use std::intrinsics::transmute;
 /**
  * The ICMP socket
  *
  * only impl ICMP_ECHO and ICMP_ECHO_REPLY methods .
  *
  */
+use std::cell::Cell;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::mem::{transmute, MaybeUninit};
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
 
+use log::*;
 use mio::Evented;
+
+use super::datagram::{self, DatagramReceiver, DatagramSender};
+use super::net;
+use super::relay_config::RelayConfig;
 use socket2::Domain;
 use socket2::Protocol;
+use socket2::SockAddr;
 use socket2::Socket;
 use socket2::Type;
 
-pub struct IcmpSocket(Socket, SelectorId);
+const TAG: &str = "IcmpSocket";
+const IPV4_HEADER_LENGTH: usize = 20;
+
+static LOG_KIND: Once = Once::new();
+static LOG_CAPABILITY: Once = Once::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpSocketKind {
+    /// Raw socket, requires CAP_NET_RAW
+    Raw,
+    /// Unprivileged "ping" socket (allowed by `net.ipv4.ping_group_range` on Linux)
+    ///
+    /// The kernel replaces the echo identifier by its own, so replies must be mapped back.
+    Dgram,
+}
+
+// the fourth field is the DF mode of the socket, `None` until it is set; the last one is the TTL
+// of the last message received, if its IP header was available
+pub struct IcmpSocket(
+    Socket,
+    SelectorId,
+    IcmpSocketKind,
+    Cell<Option<bool>>,
+    Cell<Option<u8>>,
+);
 
 impl IcmpSocket {
-    pub fn bind(ip: IpAddr) -> io::Result<IcmpSocket> {
+    pub fn bind(ip: IpAddr, device: Option<&str>) -> io::Result<IcmpSocket> {
         let protocol = match ip {
             IpAddr::V4(_) => Some(Protocol::ICMPV4),
             IpAddr::V6(_) => Some(Protocol::ICMPV6),
         };
-        Socket::new(
-            Domain::for_address(SocketAddr::new(ip, 0)),
-            Type::RAW,
-            protocol,
+        let domain = Domain::for_address(SocketAddr::new(ip, 0));
+        let (socket, kind) =
+            Self::open_with_fallback(|socket_type| Socket::new(domain, socket_type, protocol))?;
+        net::bind_outbound(&socket, ip, device)?;
+        LOG_KIND.call_once(|| info!(target: TAG, "Using {:?} ICMP sockets", kind));
+        socket
+            .set_nonblocking(true)
+            .expect("socket set non blocking failed");
+        Ok(IcmpSocket(
+            socket,
+            SelectorId::new(),
+            kind,
+            Cell::new(None),
+            Cell::new(None),
+        ))
+    }
+
+    /// Open a UDP socket reported as an ICMP socket of `kind`, which requires no privilege (for
+    /// the tests which never send on it).
+    #[cfg(test)]
+    pub fn fake(kind: IcmpSocketKind) -> IcmpSocket {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        IcmpSocket(
+            socket,
+            SelectorId::new(),
+            kind,
+            Cell::new(None),
+            Cell::new(None),
+        )
+    }
+
+    /// Open a raw socket, or an unprivileged datagram socket if raw sockets are not permitted.
+    fn open_with_fallback<T, F>(mut open: F) -> io::Result<(T, IcmpSocketKind)>
+    where
+        F: FnMut(Type) -> io::Result<T>,
+    {
+        match open(Type::RAW) {
+            Ok(socket) => Ok((socket, IcmpSocketKind::Raw)),
+            // both EPERM and EACCES are reported as PermissionDenied
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                debug!(target: TAG, "Cannot open raw ICMP socket ({}), use DGRAM", err);
+                Ok((open(Type::DGRAM)?, IcmpSocketKind::Dgram))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check on startup the kind of ICMP sockets the relay may open, and explain how to permit
+    /// raw sockets if they are denied.
+    ///
+    /// Return `None` if no ICMP socket may be opened at all.
+    pub fn check_capability() -> Option<IcmpSocketKind> {
+        Self::check_capability_with(
+            |socket_type| Socket::new(Domain::IPV4, socket_type, Some(Protocol::ICMPV4)),
+            &LOG_CAPABILITY,
+            |level, advice| log!(target: TAG, level, "{}", advice),
         )
-        .map(|socket| {
-            socket
-                .set_nonblocking(true)
-                .expect("socket set non blocking failed");
-            IcmpSocket(socket, SelectorId::new())
-        })
+    }
+
+    /// Check the kind of ICMP sockets `open` may open, and `report` the advice about the
+    /// capability, at most once for a given `reported`.
+    ///
+    /// The result is reported only once, even if several relays are started by the same process.
+    pub fn check_capability_with<T, F, R>(
+        open: F,
+        reported: &Once,
+        report: R,
+    ) -> Option<IcmpSocketKind>
+    where
+        F: FnMut(Type) -> io::Result<T>,
+        R: FnOnce(Level, &'static str),
+    {
+        let kind = match Self::open_with_fallback(open) {
+            Ok((_, kind)) => Some(kind),
+            Err(err) => {
+                debug!(target: TAG, "Cannot open ICMP socket: {}", err);
+                None
+            }
+        };
+        reported.call_once(|| match Self::capability_advice(kind) {
+            Some(advice) if kind.is_none() => report(Level::Warn, advice),
+            Some(advice) => report(Level::Info, advice),
+            None => (),
+        });
+        kind
+    }
+
+    fn capability_advice(kind: Option<IcmpSocketKind>) -> Option<&'static str> {
+        match kind {
+            Some(IcmpSocketKind::Raw) => None,
+            Some(IcmpSocketKind::Dgram) => Some(
+                "Raw ICMP sockets are denied, only echo requests are relayed (grant CAP_NET_RAW \
+                 to relay the other ICMP messages: sudo setcap cap_net_raw+ep <relay>)",
+            ),
+            None => Some(
+                "ICMP sockets are denied, ICMP is not relayed (grant CAP_NET_RAW: sudo setcap \
+                 cap_net_raw+ep <relay>, or enable unprivileged ping: sudo sysctl -w \
+                 net.ipv4.ping_group_range=\"0 2147483647\")",
+            ),
+        }
+    }
+
+    /// Apply the socket buffer sizes configured for the relay (if any).
+    pub fn set_buffer_sizes(&self, config: &RelayConfig) -> io::Result<()> {
+        net::set_buffer_sizes(&self.0, config)
     }
 
     pub fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
         self.0.connect(&(*addr).into())
     }
+
+    pub fn kind(&self) -> IcmpSocketKind {
+        self.2
+    }
+
+    /// Send `buf` to `addr`, on a socket which is not connected.
+    pub fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        retry_on_intr!(self.0.send_to(buf, &(*addr).into()))
+    }
+
+    /// Sender of the messages to `destination`, on a socket which is not connected.
+    pub fn sender_to(&self, destination: SocketAddr) -> IcmpSocketSender<'_> {
+        IcmpSocketSender {
+            socket: self,
+            destination,
+        }
+    }
+
+    /// Read an ICMP message, and return its length and the address it comes from.
+    ///
+    /// The address is reported by the kernel, so that it is known even if the IP header is
+    /// stripped (by a datagram socket on Linux).
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SockAddr)> {
+        // the buffer is already initialized, and is only written to
+        let uninit_buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        let (size, source) = retry_on_intr!(self.0.recv_from(uninit_buf))?;
+        let (size, ttl) = Self::strip_header(buf, size, self.has_ip_header())?;
+        self.4.set(ttl);
+        Ok((size, source))
+    }
+
+    /// Set the DF flag on the outgoing packets (and never fragment them locally), or not.
+    ///
+    /// With the DF flag, sending a packet larger than the path MTU fails with `EMSGSIZE`.
+    ///
+    /// Nothing is done if the socket is already in the requested mode. On failure, the mode is
+    /// considered set anyway, so that it is not retried for every packet.
+    pub fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        if self.3.replace(Some(dont_fragment)) == Some(dont_fragment) {
+            return Ok(());
+        }
+        self.apply_dont_fragment(dont_fragment)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn apply_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        let mode = if dont_fragment {
+            libc::IP_PMTUDISC_DO
+        } else {
+            libc::IP_PMTUDISC_DONT
+        };
+        net::set_socket_option(&self.0, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn apply_dont_fragment(&self, _: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Cannot set the DF flag: unsupported platform",
+        ))
+    }
+
+    /// Path MTU currently known by the kernel for the connected destination.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn path_mtu(&self) -> io::Result<u16> {
+        let mtu = net::socket_option(&self.0, libc::IPPROTO_IP, libc::IP_MTU)?;
+        Ok(mtu as u16)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn path_mtu(&self) -> io::Result<u16> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Cannot read the path MTU: unsupported platform",
+        ))
+    }
+
+    fn has_ip_header(&self) -> bool {
+        match self.2 {
+            IcmpSocketKind::Raw => true,
+            // Linux strips the IP header from datagram ICMP sockets, BSD-like systems do not
+            IcmpSocketKind::Dgram => !cfg!(any(target_os = "linux", target_os = "android")),
+        }
+    }
+
+    /// Length of the IPv4 header (including its options) preceding the ICMP message in `raw`.
+    ///
+    /// A malformed header is reported as `InvalidData`.
+    fn ipv4_header_length(raw: &[u8]) -> io::Result<usize> {
+        if raw.len() < IPV4_HEADER_LENGTH || raw[0] >> 4 != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Malformed IPv4 header",
+            ));
+        }
+        let header_length = ((raw[0] & 0xf) << 2) as usize;
+        if header_length < IPV4_HEADER_LENGTH || header_length > raw.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid IPv4 header length",
+            ));
+        }
+        Ok(header_length)
+    }
+
+    // a signal may interrupt the syscall before any data is transferred: retry, like std does
+    fn read_message<R: Read>(
+        source: &mut R,
+        buf: &mut [u8],
+        has_ip_header: bool,
+    ) -> io::Result<(usize, Option<u8>)> {
+        let size = retry_on_intr!(source.read(buf))?;
+        Self::strip_header(buf, size, has_ip_header)
+    }
+
+    /// Drop the IPv4 header (if any) of the packet of `size` bytes in `buf`, and return the
+    /// length of the ICMP message and the TTL read from the header.
+    fn strip_header(
+        buf: &mut [u8],
+        size: usize,
+        has_ip_header: bool,
+    ) -> io::Result<(usize, Option<u8>)> {
+        if !has_ip_header {
+            return Ok((size, None));
+        }
+        // the header is overwritten by the message
+        let ttl = buf[..size].get(8).copied();
+        let size = Self::strip_ipv4_header(buf, size)?;
+        Ok((size, ttl))
+    }
+
+    /// Drop the IPv4 header of the packet of `size` bytes in `buf`, and return the length of the
+    /// ICMP message.
+    fn strip_ipv4_header(buf: &mut [u8], size: usize) -> io::Result<usize> {
+        let ip_header_length = Self::ipv4_header_length(&buf[..size])?;
+        buf.copy_within(ip_header_length..size, 0);
+        Ok(size - ip_header_length)
+    }
 }
 
 impl Write for IcmpSocket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        retry_on_intr!(self.0.write(buf))
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -55,16 +319,49 @@ impl Write for IcmpSocket {
     }
 }
 
+impl DatagramSender for IcmpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        retry_on_intr!(self.0.send(buf))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send_batch(&mut self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        retry_on_intr!(datagram::send_mmsg(self.0.as_raw_fd(), datagrams))
+    }
+}
+
+/// Sender of the messages to a single destination over a socket shared by several destinations.
+pub struct IcmpSocketSender<'a> {
+    socket: &'a IcmpSocket,
+    destination: SocketAddr,
+}
+
+impl DatagramSender for IcmpSocketSender<'_> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, &self.destination)
+    }
+}
+
 impl Read for IcmpSocket {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut bytes = vec![0u8; 512];
-        let mut size = self.0.read(&mut bytes)?;
-        // Drop IPV4 Header
-        size = buf.borrow_mut().write(&bytes[20..size])?;
+        let has_ip_header = self.has_ip_header();
+        let (size, ttl) = Self::read_message(&mut self.0, buf, has_ip_header)?;
+        self.4.set(ttl);
         Ok(size)
     }
 }
 
+impl DatagramReceiver for IcmpSocket {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // one read is one ICMP message
+        self.read(buf)
+    }
+
+    fn received_ttl(&self) -> Option<u8> {
+        self.4.get()
+    }
+}
+
 #[cfg(unix)]
 use mio::unix::EventedFd;
 use mio::Poll;
@@ -104,7 +401,6 @@ impl Evented for IcmpSocket {
 }
 
 #[cfg(any(
-    target_os = "bitrig",
     target_os = "dragonfly",
     target_os = "freebsd",
     target_os = "ios",
@@ -112,7 +408,6 @@ impl Evented for IcmpSocket {
     target_os = "netbsd",
     target_os = "openbsd"
 ))]
-
 #[allow(dead_code)]
 struct Selector {
     id: usize,
@@ -181,10 +476,7 @@ impl SelectorId {
         let selector_id = self.id.load(Ordering::SeqCst);
         let poll_exposed: &ExPoll = unsafe { transmute(poll) };
         if selector_id != 0 && selector_id != poll_exposed.selector.id() {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "socket already registered",
-            ))
+            Err(io::Error::other("socket already registered"))
         } else {
             self.id.store(poll_exposed.selector.id(), Ordering::SeqCst);
             Ok(())
@@ -199,3 +491,186 @@ impl Clone for SelectorId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::checksum;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    #[test]
+    fn fallback_to_dgram_when_raw_is_denied() {
+        let mut requested = Vec::new();
+        let (_, kind) = IcmpSocket::open_with_fallback(|socket_type| {
+            requested.push(socket_type);
+            if socket_type == Type::RAW {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!(IcmpSocketKind::Dgram, kind);
+        assert_eq!(vec![Type::RAW, Type::DGRAM], requested);
+    }
+
+    #[test]
+    fn no_fallback_on_other_errors() {
+        let result = IcmpSocket::open_with_fallback(|socket_type| {
+            assert_eq!(Type::RAW, socket_type);
+            Err::<(), _>(io::Error::from(io::ErrorKind::AddrNotAvailable))
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn report_denied_icmp_sockets_once() {
+        let reported = Once::new();
+        let mut messages = Vec::new();
+        let kind = IcmpSocket::check_capability_with(
+            |_| Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied)),
+            &reported,
+            |level, advice| messages.push((level, advice)),
+        );
+        assert_eq!(None, kind);
+        assert_eq!(1, messages.len());
+        let (level, advice) = messages[0];
+        assert_eq!(Level::Warn, level);
+        assert!(advice.contains("CAP_NET_RAW"));
+        assert!(advice.contains("ping_group_range"));
+
+        // already reported, the capability is still checked
+        let kind = IcmpSocket::check_capability_with(
+            |socket_type| {
+                if socket_type == Type::RAW {
+                    Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                } else {
+                    Ok(())
+                }
+            },
+            &reported,
+            |level, advice| messages.push((level, advice)),
+        );
+        assert_eq!(Some(IcmpSocketKind::Dgram), kind);
+        assert_eq!(1, messages.len());
+    }
+
+    #[test]
+    fn report_dgram_fallback_as_info() {
+        let mut messages = Vec::new();
+        let kind = IcmpSocket::check_capability_with(
+            |socket_type| {
+                if socket_type == Type::RAW {
+                    Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                } else {
+                    Ok(())
+                }
+            },
+            &Once::new(),
+            |level, advice| messages.push((level, advice)),
+        );
+        assert_eq!(Some(IcmpSocketKind::Dgram), kind);
+        assert_eq!(1, messages.len());
+        assert_eq!(Level::Info, messages[0].0);
+        assert!(messages[0].1.contains("CAP_NET_RAW"));
+
+        // nothing to advise with raw sockets
+        let mut messages = Vec::new();
+        let kind = IcmpSocket::check_capability_with(
+            |_| Ok(()),
+            &Once::new(),
+            |level, advice| messages.push((level, advice)),
+        );
+        assert_eq!(Some(IcmpSocketKind::Raw), kind);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn ipv4_header_length_with_options() {
+        let mut raw = [0u8; 32];
+        raw[0] = 4 << 4 | 6; // one word of options
+        assert_eq!(24, IcmpSocket::ipv4_header_length(&raw).unwrap());
+    }
+
+    #[test]
+    fn reject_malformed_ipv4_header() {
+        let mut raw = [0u8; 32];
+        raw[0] = 6 << 4 | 5; // not IPv4
+        let err = IcmpSocket::ipv4_header_length(&raw).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        raw[0] = 4 << 4 | 15; // longer than the message
+        let err = IcmpSocket::ipv4_header_length(&raw).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let err = IcmpSocket::ipv4_header_length(&raw[..12]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    /// Reader failing once with `Interrupted`, as if a signal was received during the syscall.
+    struct InterruptedOnce {
+        interrupted: bool,
+        data: Vec<u8>,
+    }
+
+    impl Read for InterruptedOnce {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            (&self.data[..]).read(buf)
+        }
+    }
+
+    #[test]
+    fn report_source_address_of_received_message() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let socket = match IcmpSocket::bind(IpAddr::V4(localhost), None) {
+            Ok(socket) => socket,
+            // neither raw nor ping sockets may be permitted
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("Cannot open ICMP socket: {}", err),
+        };
+        socket.0.set_nonblocking(false).unwrap();
+        socket
+            .0
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // echo request to localhost, answered by the kernel
+        let mut request = [8, 0, 0, 0, 0x12, 0x34, 0, 1];
+        let checksum = !checksum::fold(checksum::sum(&request));
+        request[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let destination = SocketAddr::new(IpAddr::V4(localhost), 0);
+        socket.send_to(&request, &destination).unwrap();
+
+        let mut buf = [0u8; 64];
+        let (size, source) = socket.recv_from(&mut buf).unwrap();
+        // a raw socket also receives the request itself
+        assert_eq!(request.len(), size);
+        assert_eq!(
+            Some(SocketAddrV4::new(localhost, 0)),
+            source.as_socket_ipv4()
+        );
+    }
+
+    #[test]
+    fn retry_interrupted_read() {
+        let mut data = vec![0u8; IPV4_HEADER_LENGTH];
+        data[0] = 4 << 4 | 5;
+        data[8] = 42; // TTL
+        data.extend_from_slice(&[0, 0, 0x12, 0x34]);
+        let mut source = InterruptedOnce {
+            interrupted: false,
+            data,
+        };
+
+        let mut buf = [0u8; 64];
+        let (size, ttl) = IcmpSocket::read_message(&mut source, &mut buf, true).unwrap();
+        assert!(source.interrupted);
+        assert_eq!(&[0, 0, 0x12, 0x34], &buf[..size]);
+        assert_eq!(Some(42), ttl);
+    }
+}
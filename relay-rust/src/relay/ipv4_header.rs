@@ -15,8 +15,18 @@
  */
 
 use byteorder::{BigEndian, ByteOrder};
+use std::io;
 use std::mem;
 
+use super::checksum;
+
+// length of the header without options
+pub const MIN_HEADER_LENGTH: usize = 20;
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
 pub struct Ipv4Header<'a> {
     raw: &'a [u8],
     data: &'a Ipv4HeaderData,
@@ -31,18 +41,23 @@ pub struct Ipv4HeaderMut<'a> {
 pub struct Ipv4HeaderData {
     version: u8,
     header_length: u8,
+    tos: u8,
     total_length: u16,
+    identification: u16,
+    flags_fragment_offset: u16,
+    ttl: u8,
     protocol: Protocol,
     source: u32,
     destination: u32,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Protocol {
     Tcp,
     Udp,
     Icmp,
-    Other,
+    /// Any other protocol, by its number.
+    Other(u8),
 }
 
 #[allow(dead_code)]
@@ -51,18 +66,46 @@ impl Ipv4HeaderData {
         Self {
             version: raw[0] >> 4,
             header_length: (raw[0] & 0xf) << 2,
+            tos: raw[1],
             total_length: BigEndian::read_u16(&raw[2..4]),
+            identification: BigEndian::read_u16(&raw[4..6]),
+            flags_fragment_offset: BigEndian::read_u16(&raw[6..8]),
+            ttl: raw[8],
             protocol: match raw[9] {
                 1 => Protocol::Icmp,
                 6 => Protocol::Tcp,
                 17 => Protocol::Udp,
-                _ => Protocol::Other,
+                number => Protocol::Other(number),
             },
             source: BigEndian::read_u32(&raw[12..16]),
             destination: BigEndian::read_u32(&raw[16..20]),
         }
     }
 
+    /// Parse a header received from the outside, checking that its lengths are consistent with
+    /// `raw`, so that they may be used to split the packet.
+    pub fn try_parse(raw: &[u8]) -> io::Result<Self> {
+        if raw.len() < MIN_HEADER_LENGTH {
+            return Err(Self::invalid("Truncated IPv4 header"));
+        }
+        let data = Self::parse(raw);
+        let header_length = u16::from(data.header_length);
+        if header_length < MIN_HEADER_LENGTH as u16 {
+            return Err(Self::invalid("IPv4 header length too small"));
+        }
+        if data.total_length < header_length {
+            return Err(Self::invalid("IPv4 total length smaller than its header"));
+        }
+        if data.total_length as usize > raw.len() {
+            return Err(Self::invalid("IPv4 total length exceeds the packet"));
+        }
+        Ok(data)
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
     pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> Ipv4Header<'c> {
         Ipv4Header::new(raw, self)
     }
@@ -71,14 +114,54 @@ impl Ipv4HeaderData {
         Ipv4HeaderMut::new(raw, self)
     }
 
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn header_length(&self) -> u8 {
         self.header_length
     }
 
+    /// Type of service byte, made of the DSCP (6 bits) and the ECN (2 bits) fields.
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    /// Differentiated services code point, the QoS marking of the packet (rfc2474).
+    pub fn dscp(&self) -> u8 {
+        self.tos >> 2
+    }
+
     pub fn total_length(&self) -> u16 {
         self.total_length
     }
 
+    pub fn identification(&self) -> u16 {
+        self.identification
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_fragment_offset & FLAG_DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment_offset & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Offset of the fragment in the original datagram, in bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        (self.flags_fragment_offset & FRAGMENT_OFFSET_MASK) << 3
+    }
+
+    /// Indicate whether this packet is a fragment of a larger datagram.
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
@@ -112,8 +195,8 @@ macro_rules! ipv4_header_common {
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
                 Self {
-                    raw: raw,
-                    data: data,
+                    raw,
+                    data,
                 }
             }
 
@@ -129,10 +212,20 @@ macro_rules! ipv4_header_common {
                 self.data.header_length
             }
 
+            /// Raw IP options, between the fixed header and the payload (empty if none).
+            pub fn options(&self) -> &[u8] {
+                let header_length = self.data.header_length as usize;
+                &self.raw[MIN_HEADER_LENGTH.min(header_length)..header_length]
+            }
+
             pub fn total_length(&self) -> u16 {
                 self.data.total_length
             }
 
+            pub fn ttl(&self) -> u8 {
+                self.data.ttl
+            }
+
             pub fn protocol(&self) -> Protocol {
                 self.data.protocol
             }
@@ -144,6 +237,12 @@ macro_rules! ipv4_header_common {
             pub fn destination(&self) -> u32 {
                 self.data.destination
             }
+
+            /// Check the header checksum, so `raw` must span the whole header.
+            pub fn verify_checksum(&self) -> bool {
+                // the sum including the checksum field is 0xFFFF for a valid header
+                checksum::fold(checksum::sum(self.raw)) == 0xFFFF
+            }
         }
     };
 }
@@ -162,11 +261,48 @@ impl<'a> Ipv4HeaderMut<'a> {
         self.data
     }
 
+    /// Set the header length, in bytes (a multiple of 4).
+    pub fn set_header_length(&mut self, header_length: u8) {
+        self.data.header_length = header_length;
+        self.raw[0] = (self.raw[0] & 0xf0) | (header_length >> 2);
+    }
+
     pub fn set_total_length(&mut self, total_length: u16) {
         self.data.total_length = total_length;
         BigEndian::write_u16(&mut self.raw[2..4], total_length);
     }
 
+    pub fn set_identification(&mut self, identification: u16) {
+        self.data.identification = identification;
+        BigEndian::write_u16(&mut self.raw[4..6], identification);
+    }
+
+    /// Set the TTL, and update the header checksum accordingly.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        // the TTL is the high byte of the 16-bit word shared with the protocol
+        let old = BigEndian::read_u16(&self.raw[8..10]);
+        self.data.ttl = ttl;
+        self.raw[8] = ttl;
+        let new = BigEndian::read_u16(&self.raw[8..10]);
+        let checksum = checksum::update_incremental(self.checksum(), old, new);
+        self.set_checksum(checksum);
+    }
+
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) {
+        if dont_fragment {
+            self.data.flags_fragment_offset |= FLAG_DONT_FRAGMENT;
+        } else {
+            self.data.flags_fragment_offset &= !FLAG_DONT_FRAGMENT;
+        }
+        BigEndian::write_u16(&mut self.raw[6..8], self.data.flags_fragment_offset);
+    }
+
+    /// Reset the "more fragments" flag and the fragment offset, to make a reassembled datagram.
+    pub fn clear_fragmentation(&mut self) {
+        self.data.flags_fragment_offset &= !(FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK);
+        BigEndian::write_u16(&mut self.raw[6..8], self.data.flags_fragment_offset);
+    }
+
     pub fn set_source(&mut self, source: u32) {
         self.data.source = source;
         BigEndian::write_u32(&mut self.raw[12..16], source);
@@ -215,8 +351,7 @@ mod tests {
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_header() -> Vec<u8> {
-        let mut raw: Vec<u8> = Vec::new();
-        raw.reserve(20);
+        let mut raw: Vec<u8> = Vec::with_capacity(20);
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
         raw.write_u16::<BigEndian>(28).unwrap(); // total length
@@ -236,11 +371,57 @@ mod tests {
         assert_eq!(4, data.version);
         assert_eq!(20, data.header_length);
         assert_eq!(28, data.total_length);
+        assert_eq!(0, data.ttl);
         assert_eq!(Protocol::Udp, data.protocol);
         assert_eq!(0x12345678, data.source);
         assert_eq!(0x42424242, data.destination);
     }
 
+    #[test]
+    fn parse_tos() {
+        let mut raw = create_header();
+        raw[1] = 0xB8; // Expedited Forwarding, not ECN-capable
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(0xB8, data.tos());
+        assert_eq!(46, data.dscp());
+    }
+
+    fn create_packet_with_total_length(total_length: u16) -> Vec<u8> {
+        let mut raw = create_header();
+        raw.resize(28, 0); // 8 bytes of payload
+        BigEndian::write_u16(&mut raw[2..4], total_length);
+        raw
+    }
+
+    #[test]
+    fn try_parse_consistent_lengths() {
+        let raw = create_packet_with_total_length(28);
+        let data = Ipv4HeaderData::try_parse(&raw).unwrap();
+        assert_eq!(28, data.total_length);
+    }
+
+    #[test]
+    fn reject_total_length_smaller_than_header() {
+        let raw = create_packet_with_total_length(12);
+        let err = Ipv4HeaderData::try_parse(&raw).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        assert!(Ipv4HeaderData::try_parse(&[]).is_err());
+        assert!(Ipv4HeaderData::try_parse(&[0x45]).is_err());
+        let raw = create_packet_with_total_length(28);
+        assert!(Ipv4HeaderData::try_parse(&raw[..19]).is_err());
+    }
+
+    #[test]
+    fn reject_total_length_exceeding_packet() {
+        let raw = create_packet_with_total_length(29);
+        let err = Ipv4HeaderData::try_parse(&raw).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
     #[test]
     fn edit_header() {
         let raw = &mut create_header()[..];
@@ -285,7 +466,7 @@ mod tests {
         header.update_checksum();
 
         let mut sum: u32 =
-            0x4500 + 0x001C + 0x0000 + 0x0000 + 0x0011 + 0x0000 + 0x1234 + 0x5678 + 0x4242 + 0x4242;
+            ((0x4500 + 0x001C) + 0x0011) + 0x1234 + 0x5678 + 0x4242 + 0x4242;
         while (sum & !0xffff) != 0 {
             sum = (sum & 0xffff) + (sum >> 16);
         }
@@ -293,6 +474,73 @@ mod tests {
         assert_eq!(sum, header.checksum());
     }
 
+    #[test]
+    fn verify_checksum() {
+        let raw = &mut create_header()[..];
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        header_data.bind_mut(raw).update_checksum();
+        assert!(header_data.bind(raw).verify_checksum());
+
+        raw[11] ^= 0x01;
+        assert!(!header_data.bind(raw).verify_checksum());
+    }
+
+    #[test]
+    fn no_options() {
+        let raw = &create_header()[..];
+        let header_data = Ipv4HeaderData::parse(raw);
+        assert!(header_data.bind(raw).options().is_empty());
+    }
+
+    #[test]
+    fn parse_fragmentation() {
+        let raw = &mut create_header()[..];
+        raw[6] = 0x20 | 0x01; // more fragments, offset high bits
+        raw[7] = 0x02; // offset low bits
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        assert!(header_data.more_fragments());
+        assert_eq!(0x102 * 8, header_data.fragment_offset());
+        assert!(header_data.is_fragment());
+
+        header_data.bind_mut(raw).clear_fragmentation();
+        assert!(!header_data.is_fragment());
+        assert_eq!(&[0, 0], &raw[6..8]);
+    }
+
+    #[test]
+    fn set_dont_fragment() {
+        let raw = &mut create_header()[..];
+        raw[6] = 0x01; // offset high bits
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        assert!(!header_data.dont_fragment());
+
+        header_data.bind_mut(raw).set_dont_fragment(true);
+        assert!(header_data.dont_fragment());
+        assert_eq!(0x40 | 0x01, raw[6]);
+
+        header_data.bind_mut(raw).set_dont_fragment(false);
+        assert!(!header_data.dont_fragment());
+        assert_eq!(0x01, raw[6]);
+        assert_eq!(0x100 * 8, header_data.fragment_offset());
+    }
+
+    #[test]
+    fn update_checksum_on_set_ttl() {
+        let raw = &mut create_header()[..];
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header = header_data.bind_mut(raw);
+        header.set_ttl(64);
+        header.update_checksum();
+
+        header.set_ttl(63);
+        assert_eq!(63, header.ttl());
+        assert_eq!(63, header.raw[8]);
+
+        let checksum = header.checksum();
+        header.update_checksum();
+        assert_eq!(header.checksum(), checksum);
+    }
+
     #[test]
     fn peek_version_length_unavailable() {
         let raw: [u8; 0] = [];
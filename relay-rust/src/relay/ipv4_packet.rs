@@ -14,11 +14,17 @@
  * limitations under the License.
  */
 
-use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
+use std::io;
+use std::mem;
+
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut, MIN_HEADER_LENGTH};
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
 pub const MAX_PACKET_LENGTH: usize = 1 << 16;
 
+// same value as GnirehtetService.MTU in the client
+pub const MTU: u16 = 0x4000;
+
 pub struct Ipv4Packet<'a> {
     raw: &'a mut [u8],
     ipv4_header_data: Ipv4HeaderData,
@@ -28,8 +34,23 @@ pub struct Ipv4Packet<'a> {
 impl<'a> Ipv4Packet<'a> {
     pub fn parse(raw: &'a mut [u8]) -> Self {
         let ipv4_header_data = Ipv4HeaderData::parse(raw);
-        let transport_header_data = {
-            let payload = &raw[ipv4_header_data.header_length() as usize..];
+        Self::from_header_data(raw, ipv4_header_data)
+    }
+
+    /// Parse a packet received from the outside, rejecting it if its IPv4 lengths are
+    /// inconsistent.
+    pub fn try_parse(raw: &'a mut [u8]) -> io::Result<Self> {
+        let ipv4_header_data = Ipv4HeaderData::try_parse(raw)?;
+        Ok(Self::from_header_data(raw, ipv4_header_data))
+    }
+
+    fn from_header_data(raw: &'a mut [u8], ipv4_header_data: Ipv4HeaderData) -> Self {
+        let transport_header_data = if ipv4_header_data.is_fragment() {
+            // the transport header is meaningful only once the datagram is reassembled
+            None
+        } else {
+            let payload = &raw[ipv4_header_data.header_length() as usize
+                ..ipv4_header_data.total_length() as usize];
             TransportHeaderData::parse(ipv4_header_data.protocol(), payload)
         };
         Self {
@@ -61,7 +82,7 @@ impl<'a> Ipv4Packet<'a> {
         (&self.ipv4_header_data, self.transport_header_data.as_ref())
     }
 
-    pub fn headers(&self) -> (Ipv4Header, Option<TransportHeader>) {
+    pub fn headers(&self) -> (Ipv4Header<'_>, Option<TransportHeader<'_>>) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
             let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
@@ -86,14 +107,14 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header(&self) -> Ipv4Header {
+    pub fn ipv4_header(&self) -> Ipv4Header<'_> {
         let slice = &self.raw[..self.ipv4_header_data.header_length() as usize];
         self.ipv4_header_data.bind(slice)
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut<'_> {
         let slice = &mut self.raw[..self.ipv4_header_data.header_length() as usize];
         self.ipv4_header_data.bind_mut(slice)
     }
@@ -105,7 +126,7 @@ impl<'a> Ipv4Packet<'a> {
     }
 
     #[inline]
-    pub fn transport_header(&self) -> Option<TransportHeader> {
+    pub fn transport_header(&self) -> Option<TransportHeader<'_>> {
         if let Some(ref transport_header_data) = self.transport_header_data {
             let start = self.ipv4_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
@@ -124,7 +145,7 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     #[allow(dead_code)]
-    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut> {
+    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut<'_>> {
         if let Some(ref mut transport_header_data) = self.transport_header_data {
             let start = self.ipv4_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
@@ -146,7 +167,7 @@ impl<'a> Ipv4Packet<'a> {
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
     #[allow(dead_code)]
-    pub fn split(&self) -> (Ipv4Header, Option<(TransportHeader, &[u8])>) {
+    pub fn split(&self) -> (Ipv4Header<'_>, Option<(TransportHeader<'_>, &[u8])>) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
@@ -167,7 +188,7 @@ impl<'a> Ipv4Packet<'a> {
     ///  - the IP v4 header
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
-    pub fn split_mut(&mut self) -> (Ipv4HeaderMut, Option<(TransportHeaderMut, &mut [u8])>) {
+    pub fn split_mut(&mut self) -> (Ipv4HeaderMut<'_>, Option<(TransportHeaderMut<'_>, &mut [u8])>) {
         let transport_index = self.ipv4_header_data.header_length() as usize;
         if let Some(ref mut transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
@@ -213,6 +234,29 @@ impl<'a> Ipv4Packet<'a> {
         }
     }
 
+    /// Remove the IP options, moving the transport header and the payload right after the fixed
+    /// header.
+    ///
+    /// The packet is shortened accordingly, its lengths and header checksum are updated (the
+    /// transport checksum does not cover the options).
+    pub fn strip_options(&mut self) {
+        let header_length = self.ipv4_header_data.header_length() as usize;
+        if header_length <= MIN_HEADER_LENGTH {
+            return;
+        }
+        let raw = mem::take(&mut self.raw);
+        raw.copy_within(header_length.., MIN_HEADER_LENGTH);
+        let length = raw.len() - (header_length - MIN_HEADER_LENGTH);
+        self.raw = &mut raw[..length];
+
+        let mut ipv4_header = self
+            .ipv4_header_data
+            .bind_mut(&mut self.raw[..MIN_HEADER_LENGTH]);
+        ipv4_header.set_header_length(MIN_HEADER_LENGTH as u8);
+        ipv4_header.set_total_length(length as u16);
+        ipv4_header.update_checksum();
+    }
+
     /*#[inline]
     pub fn swap_source_and_destination(&mut self) {
         self.ipv4_header_mut().swap_source_and_destination();
@@ -225,12 +269,17 @@ impl<'a> Ipv4Packet<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::relay::icmp_header::IcmpHeaderData;
     use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ipv4_packet_buffer::Ipv4PacketBuffer;
+    use crate::relay::ipv6_packet::Ipv6Packet;
+    use crate::relay::transport_header::TransportHeader;
     use byteorder::{BigEndian, WriteBytesExt};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(32);
+        let mut raw = Vec::with_capacity(32);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -265,7 +314,7 @@ mod tests {
             assert_eq!(0x12345678, ipv4_header.source());
             assert_eq!(0x42424242, ipv4_header.destination());
 
-            if let Some(&TransportHeaderData::Udp(ref udp_header)) =
+            if let Some(TransportHeaderData::Udp(udp_header)) =
                 ipv4_packet.transport_header_data()
             {
                 assert_eq!(1234, udp_header.source_port());
@@ -282,4 +331,161 @@ mod tests {
         let ipv4_packet = Ipv4Packet::parse(raw);
         assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
     }
+
+    fn create_packet_with_record_route() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(40);
+
+        raw.write_u8(4u8 << 4 | 7).unwrap(); // version_and_ihl (28 bytes)
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(40).unwrap(); // total length 28 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u8(7).unwrap(); // option type (Record Route)
+        raw.write_u8(7).unwrap(); // option length
+        raw.write_u8(4).unwrap(); // pointer
+        raw.write_u32::<BigEndian>(0).unwrap(); // route data (1 slot)
+        raw.write_u8(0).unwrap(); // End of Option List
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
+    #[test]
+    fn payload_after_options() {
+        let raw = &mut create_packet_with_record_route()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let ipv4_header = ipv4_packet.ipv4_header();
+        assert_eq!(28, ipv4_header.header_length());
+        assert_eq!([7, 7, 4, 0, 0, 0, 0, 0], ipv4_header.options());
+
+        if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data() {
+            assert_eq!(1234, udp_header.source_port());
+            assert_eq!(5678, udp_header.destination_port());
+        } else {
+            panic!("No UDP transport header");
+        }
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn reject_truncated_transport_headers() {
+        // UDP header truncated to 4 bytes by the total length
+        let mut raw = create_packet();
+        raw[2..4].copy_from_slice(&24u16.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::try_parse(&mut raw).unwrap();
+        assert!(!ipv4_packet.is_valid());
+
+        for protocol in &[Protocol::Tcp, Protocol::Udp, Protocol::Icmp] {
+            assert!(TransportHeaderData::parse(*protocol, &[]).is_none());
+            assert!(TransportHeaderData::parse(*protocol, &[0]).is_none());
+        }
+    }
+
+    #[test]
+    fn strip_record_route_option() {
+        let mut raw = create_packet();
+        // Record Route with a single slot, padded by End of Option List
+        raw.splice(20..20, [7, 7, 4, 0, 0, 0, 0, 0].iter().cloned());
+        raw[0] = 4 << 4 | 7;
+        raw[2..4].copy_from_slice(&40u16.to_be_bytes());
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        ipv4_packet.compute_checksums();
+        assert_eq!(8, ipv4_packet.ipv4_header().options().len());
+
+        ipv4_packet.strip_options();
+        assert_eq!(32, ipv4_packet.raw().len());
+        assert_eq!(32, ipv4_packet.length());
+        let ipv4_header = ipv4_packet.ipv4_header();
+        assert_eq!(20, ipv4_header.header_length());
+        assert!(ipv4_header.options().is_empty());
+        assert!(ipv4_header.verify_checksum());
+        assert_eq!(&[0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+
+        // the stripped packet parses identically
+        let mut stripped = ipv4_packet.raw().to_vec();
+        let ipv4_packet = Ipv4Packet::parse(&mut stripped);
+        assert_eq!(4 << 4 | 5, ipv4_packet.raw()[0]);
+        assert_eq!(32, ipv4_packet.ipv4_header().total_length());
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Udp(udp_header)) => {
+                assert_eq!(1234, udp_header.source_port());
+                assert_eq!(5678, udp_header.destination_port());
+            }
+            _ => panic!("Expected UDP header"),
+        }
+    }
+
+    // feed arbitrary bytes to the parsers of the packets received from the client
+    #[test]
+    fn parse_arbitrary_bytes_without_panicking() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..20_000 {
+            let length = rng.gen_range(0, 96);
+            let mut raw: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            match rng.gen_range(0, 3) {
+                0 if length >= 20 => {
+                    // make the header plausible, so that the transport header is parsed
+                    raw[0] = 0x45;
+                    raw[2..4].copy_from_slice(&(rng.gen_range(0, length as u16 + 1)).to_be_bytes());
+                    raw[6] &= !0x3F; // not a fragment
+                    raw[7] = 0;
+                    raw[9] = [1, 6, 17][rng.gen_range(0, 3)];
+                }
+                1 if length >= 6 => {
+                    // an IPv6 header, whose payload length may exceed the packet
+                    raw[0] = 0x60;
+                    let payload_length = if rng.gen() {
+                        rng.gen()
+                    } else {
+                        rng.gen_range(0, length as u16)
+                    };
+                    raw[4..6].copy_from_slice(&payload_length.to_be_bytes());
+                    if length > 6 {
+                        raw[6] = [6, 17, 58][rng.gen_range(0, 3)];
+                    }
+                }
+                _ => (),
+            }
+            let _ = IcmpHeaderData::try_parse(&raw);
+            for protocol in &[Protocol::Tcp, Protocol::Udp, Protocol::Icmp] {
+                let _ = TransportHeaderData::parse(*protocol, &raw);
+            }
+            if let Ok(ipv4_packet) = Ipv4Packet::try_parse(&mut raw) {
+                let _ = ipv4_packet.payload();
+                if let Some(TransportHeader::Tcp(tcp_header)) = ipv4_packet.transport_header() {
+                    let _ = tcp_header.mss();
+                    let _ = tcp_header.window_scale();
+                }
+            }
+            if let Ok(ipv6_packet) = Ipv6Packet::try_parse(&mut raw) {
+                let _ = ipv6_packet.payload();
+            }
+            // as received from the client stream (which must start with an IP version)
+            if !matches!(raw.first().map(|&b| b >> 4), Some(4) | Some(6)) {
+                continue;
+            }
+            let mut packet_buffer = Ipv4PacketBuffer::new();
+            packet_buffer.read_from(&mut &raw[..]).unwrap();
+            if packet_buffer.packet_length().is_some() {
+                if let Some(Ok(ipv6_packet)) = packet_buffer.as_ipv6_packet() {
+                    let _ = ipv6_packet.payload();
+                }
+                if let Some(Ok(ipv4_packet)) = packet_buffer.as_ipv4_packet() {
+                    let _ = ipv4_packet.payload();
+                }
+                packet_buffer.next();
+            }
+        }
+    }
 }
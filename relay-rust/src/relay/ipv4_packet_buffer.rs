@@ -16,10 +16,13 @@
 
 use super::binary;
 use super::byte_buffer::ByteBuffer;
-use super::ipv4_header;
+use super::ipv4_header::{self, MIN_HEADER_LENGTH};
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::ipv6_header;
+use super::ipv6_packet::Ipv6Packet;
 
 use log::*;
+use std::cmp;
 use std::io;
 
 pub struct Ipv4PacketBuffer {
@@ -40,8 +43,22 @@ impl Ipv4PacketBuffer {
     fn available_packet_length(&self) -> Option<u16> {
         let data = self.buf.peek();
         trace!("Parse packet: {}", binary::build_packet_string(data));
-        if let Some((version, length)) = ipv4_header::peek_version_length(data) {
-            assert!(version == 4, "Not an Ipv4 packet, version={}", version);
+        let version_length = match self.version() {
+            // an overflowing length must still be consumed, the packet is rejected once parsed
+            Some(6) if data.len() >= 6 => {
+                Some((6, ipv6_header::peek_length(data).unwrap_or(u16::MAX)))
+            }
+            Some(6) => None,
+            // a malformed length must still span the header, or the packet could never be consumed
+            _ => ipv4_header::peek_version_length(data)
+                .map(|(version, length)| (version, cmp::max(length, MIN_HEADER_LENGTH as u16))),
+        };
+        if let Some((version, length)) = version_length {
+            assert!(
+                version == 4 || version == 6,
+                "Not an IP packet, version={}",
+                version
+            );
             if length as usize <= data.len() {
                 // full packet available
                 Some(length)
@@ -55,10 +72,35 @@ impl Ipv4PacketBuffer {
         }
     }
 
-    pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet> {
-        if self.available_packet_length().is_some() {
+    /// IP version of the packet in front of the buffer, if any.
+    pub fn version(&self) -> Option<u8> {
+        self.buf.peek().first().map(|&b| b >> 4)
+    }
+
+    /// Length of the packet in front of the buffer, if it is fully available.
+    pub fn packet_length(&self) -> Option<u16> {
+        self.available_packet_length()
+    }
+
+    /// The IPv4 packet in front of the buffer, if it is fully available.
+    ///
+    /// Return `Some(Err(_))` if it is malformed: it must be dropped (by calling `next()`).
+    pub fn as_ipv4_packet(&mut self) -> Option<io::Result<Ipv4Packet<'_>>> {
+        if self.version() == Some(4) && self.available_packet_length().is_some() {
             let data = self.buf.peek_mut();
-            Some(Ipv4Packet::parse(data))
+            Some(Ipv4Packet::try_parse(data))
+        } else {
+            None
+        }
+    }
+
+    /// The IPv6 packet in front of the buffer, if it is fully available.
+    ///
+    /// Return `Some(Err(_))` if it is malformed: it must be dropped (by calling `next()`).
+    pub fn as_ipv6_packet(&mut self) -> Option<io::Result<Ipv6Packet<'_>>> {
+        if self.version() == Some(6) && self.available_packet_length().is_some() {
+            let data = self.buf.peek_mut();
+            Some(Ipv6Packet::try_parse(data))
         } else {
             None
         }
@@ -133,7 +175,7 @@ mod tests {
         assert_eq!(0x12345678, ipv4_header.source());
         assert_eq!(0x42424242, ipv4_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
+        if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data()
         {
             assert_eq!(1234, udp_header.source_port());
             assert_eq!(5678, udp_header.destination_port());
@@ -150,7 +192,7 @@ mod tests {
         assert_eq!(0x11111111, ipv4_header.source());
         assert_eq!(0x22222222, ipv4_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
+        if let Some(TransportHeaderData::Udp(udp_header)) = ipv4_packet.transport_header_data()
         {
             assert_eq!(1111, udp_header.source_port());
             assert_eq!(2222, udp_header.destination_port());
@@ -167,7 +209,7 @@ mod tests {
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ipv4_packet().unwrap().unwrap();
         check_packet_headers(&packet);
     }
 
@@ -184,10 +226,30 @@ mod tests {
         let mut cursor = io::Cursor::new(&raw[14..]);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ipv4_packet().unwrap().unwrap();
         check_packet_headers(&packet);
     }
 
+    #[test]
+    fn drop_malformed_ipv4_packet() {
+        // a header alone, having a total length smaller than the header length
+        let mut raw = create_packet();
+        raw.truncate(20);
+        raw[2..4].copy_from_slice(&[0, 8]);
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        let err = packet_buffer.as_ipv4_packet().unwrap().err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // the malformed header is consumed, so that the next packet is available
+        assert_eq!(Some(20), packet_buffer.packet_length());
+        packet_buffer.next();
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+    }
+
     fn create_multi_packets() -> Vec<u8> {
         let mut raw = Vec::new();
         write_packet_to(&mut raw);
@@ -204,13 +266,76 @@ mod tests {
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
         packet_buffer.next();
-        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
         packet_buffer.next();
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
         packet_buffer.next();
 
         assert!(packet_buffer.as_ipv4_packet().is_none());
     }
+
+    fn write_ipv6_packet_to(raw: &mut Vec<u8>) {
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, class, flow label
+        raw.write_u16::<BigEndian>(12).unwrap(); // payload length 8 + 4
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        raw.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+    }
+
+    #[test]
+    fn parse_mixed_ip_versions() {
+        let mut raw = Vec::new();
+        write_ipv6_packet_to(&mut raw);
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        assert_eq!(Some(6), packet_buffer.version());
+        assert_eq!(Some(52), packet_buffer.packet_length());
+        assert!(packet_buffer.as_ipv4_packet().is_none());
+        {
+            let packet = packet_buffer.as_ipv6_packet().unwrap().unwrap();
+            assert_eq!(52, packet.length());
+            assert_eq!(Protocol::Udp, packet.ipv6_header_data().protocol());
+        }
+        packet_buffer.next();
+
+        assert!(packet_buffer.as_ipv6_packet().is_none());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+        packet_buffer.next();
+
+        assert!(packet_buffer.packet_length().is_none());
+    }
+
+    #[test]
+    fn drop_overflowing_ipv6_packet() {
+        let mut raw = Vec::new();
+        write_ipv6_packet_to(&mut raw);
+        raw[4..6].copy_from_slice(&[0xFF, 0xFF]);
+        // the packet is consumed as if it were as long as possible
+        raw.resize(usize::from(u16::MAX), 0);
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        assert_eq!(Some(u16::MAX), packet_buffer.packet_length());
+        assert!(packet_buffer.as_ipv6_packet().unwrap().is_err());
+        packet_buffer.next();
+        packet_buffer.read_from(&mut cursor).unwrap();
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap().unwrap());
+    }
 }
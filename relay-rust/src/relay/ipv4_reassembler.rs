@@ -0,0 +1,369 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cmp;
+use std::iter;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use super::ipv4_header::{Ipv4HeaderData, Protocol};
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+
+const TAG: &str = "Ipv4Reassembler";
+
+// same value as the Linux default (net.ipv4.ipfrag_time)
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// incomplete datagrams are evicted (oldest first) beyond this limit
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+/// Reassemble the IPv4 fragments received from the client into complete packets (rfc791).
+pub struct Ipv4Reassembler {
+    // there are typically very few fragmented datagrams at a time, HashMap would be less efficient
+    pending: Vec<PendingDatagram>,
+}
+
+#[derive(PartialEq, Eq)]
+struct FragmentKey {
+    source: u32,
+    destination: u32,
+    protocol: Protocol,
+    identification: u16,
+}
+
+struct PendingDatagram {
+    key: FragmentKey,
+    // IPv4 header of the first fragment, reused for the reassembled packet
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    // sorted and disjoint, so that repeated or overlapping fragments do not accumulate
+    received: Vec<Range<usize>>,
+    // known once the last fragment is received
+    payload_length: Option<usize>,
+    created: Instant,
+}
+
+impl FragmentKey {
+    fn from_header(header_data: &Ipv4HeaderData) -> Self {
+        Self {
+            source: header_data.source(),
+            destination: header_data.destination(),
+            protocol: header_data.protocol(),
+            identification: header_data.identification(),
+        }
+    }
+}
+
+impl PendingDatagram {
+    fn new(key: FragmentKey, now: Instant) -> Self {
+        Self {
+            key,
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            payload_length: None,
+            created: now,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let payload_length = match self.payload_length {
+            Some(payload_length) => payload_length,
+            None => return false,
+        };
+        if self.header.is_none() {
+            return false;
+        }
+        let end = match self.received[..] {
+            [] => 0,
+            [ref range] if range.start == 0 => range.end,
+            // hole
+            _ => return false,
+        };
+        end >= payload_length
+    }
+
+    /// Add `range` to the received ranges, merging it with the ranges it overlaps or touches.
+    fn add_received(&mut self, range: Range<usize>) {
+        if range.start == range.end {
+            return;
+        }
+        let first = self
+            .received
+            .partition_point(|received| received.end < range.start);
+        let last = self
+            .received
+            .partition_point(|received| received.start <= range.end);
+        let mut merged = range;
+        if first < last {
+            merged.start = cmp::min(merged.start, self.received[first].start);
+            merged.end = cmp::max(merged.end, self.received[last - 1].end);
+        }
+        self.received.splice(first..last, iter::once(merged));
+    }
+
+    fn build_packet(self) -> Vec<u8> {
+        let mut raw = self.header.expect("Reassembling a datagram without header");
+        let header_length = raw.len();
+        let payload_length = self.payload_length.unwrap();
+        raw.extend_from_slice(&self.payload[..payload_length]);
+
+        let mut header_data = Ipv4HeaderData::parse(&raw);
+        let mut header = header_data.bind_mut(&mut raw[..header_length]);
+        header.set_total_length((header_length + payload_length) as u16);
+        header.clear_fragmentation();
+        header.update_checksum();
+        raw
+    }
+}
+
+impl Ipv4Reassembler {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Store the fragment `raw` (a whole IPv4 packet).
+    ///
+    /// Return the reassembled packet once all its fragments are received.
+    pub fn push(&mut self, raw: &[u8], now: Instant) -> Option<Vec<u8>> {
+        self.remove_expired(now);
+
+        let header_data = Ipv4HeaderData::parse(raw);
+        let header_length = header_data.header_length() as usize;
+        let total_length = cmp::min(header_data.total_length() as usize, raw.len());
+        if total_length < header_length {
+            warn!(target: TAG, "Dropping truncated fragment");
+            return None;
+        }
+        let data = &raw[header_length..total_length];
+        let offset = header_data.fragment_offset() as usize;
+        let end = offset + data.len();
+        if header_length + end >= MAX_PACKET_LENGTH {
+            warn!(target: TAG, "Dropping fragment exceeding the maximum packet length");
+            return None;
+        }
+
+        let index = self.pending_index(FragmentKey::from_header(&header_data), now);
+        let pending = &mut self.pending[index];
+        if offset == 0 {
+            pending.header = Some(raw[..header_length].to_vec());
+        }
+        if !header_data.more_fragments() {
+            pending.payload_length = Some(end);
+        }
+        if pending.payload.len() < end {
+            pending.payload.resize(end, 0);
+        }
+        pending.payload[offset..end].copy_from_slice(data);
+        pending.add_received(offset..end);
+
+        if pending.is_complete() {
+            // keep the creation order, evictions rely on it
+            let pending = self.pending.remove(index);
+            Some(pending.build_packet())
+        } else {
+            None
+        }
+    }
+
+    fn pending_index(&mut self, key: FragmentKey, now: Instant) -> usize {
+        if let Some(index) = self.pending.iter().position(|pending| pending.key == key) {
+            return index;
+        }
+        if self.pending.len() >= MAX_PENDING_DATAGRAMS {
+            // the pending datagrams are ordered by creation, evict the oldest
+            warn!(target: TAG, "Too many incomplete datagrams, evicting the oldest");
+            self.pending.remove(0);
+        }
+        self.pending.push(PendingDatagram::new(key, now));
+        self.pending.len() - 1
+    }
+
+    /// Evict the datagrams not reassembled before the timeout.
+    pub fn remove_expired(&mut self, now: Instant) {
+        let before = self.pending.len();
+        self.pending
+            .retain(|pending| now.saturating_duration_since(pending.created) < REASSEMBLY_TIMEOUT);
+        let evicted = before - self.pending.len();
+        if evicted > 0 {
+            debug!(target: TAG, "Evicted {} incomplete datagram(s)", evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::transport_header::TransportHeaderData;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn create_fragment(flags_fragment_offset: u16, data: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(20 + data.len());
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(20 + data.len() as u16).unwrap(); // total length
+        raw.write_u16::<BigEndian>(0x4242).unwrap(); // identification
+        raw.write_u16::<BigEndian>(flags_fragment_offset).unwrap();
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    fn create_udp_datagram() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(24);
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(24).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        for i in 0..16 {
+            raw.write_u8(i).unwrap(); // payload
+        }
+        raw
+    }
+
+    // split the UDP datagram at 16 bytes (the offset must be a multiple of 8)
+    fn create_fragments() -> (Vec<u8>, Vec<u8>) {
+        let datagram = create_udp_datagram();
+        let first = create_fragment(0x2000, &datagram[..16]); // MF, offset 0
+        let second = create_fragment(2, &datagram[16..]); // offset 2 * 8
+        (first, second)
+    }
+
+    fn assert_reassembled(mut raw: Vec<u8>) {
+        let packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(44, packet.length());
+        assert!(!packet.ipv4_header_data().is_fragment());
+        match packet.transport_header_data() {
+            Some(TransportHeaderData::Udp(udp_header_data)) => {
+                assert_eq!(1234, udp_header_data.source_port());
+                assert_eq!(5678, udp_header_data.destination_port());
+            }
+            _ => panic!("Expected a UDP header"),
+        }
+        let expected: Vec<u8> = (0..16).collect();
+        assert_eq!(&expected[..], packet.payload().unwrap());
+    }
+
+    #[test]
+    fn reassemble_two_fragments() {
+        let now = Instant::now();
+        let (first, second) = create_fragments();
+        let mut reassembler = Ipv4Reassembler::new();
+        assert!(reassembler.push(&first, now).is_none());
+        let raw = reassembler.push(&second, now).expect("Expected a packet");
+        assert_reassembled(raw);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn reassemble_out_of_order_fragments() {
+        let now = Instant::now();
+        let (first, second) = create_fragments();
+        let mut reassembler = Ipv4Reassembler::new();
+        assert!(reassembler.push(&second, now).is_none());
+        let raw = reassembler.push(&first, now).expect("Expected a packet");
+        assert_reassembled(raw);
+    }
+
+    #[test]
+    fn merge_repeated_fragments() {
+        let now = Instant::now();
+        let (first, second) = create_fragments();
+        let mut reassembler = Ipv4Reassembler::new();
+        for _ in 0..100 {
+            assert!(reassembler.push(&first, now).is_none());
+        }
+        // overlapping the end of the first fragment
+        let datagram = create_udp_datagram();
+        let overlapping = create_fragment(0x2000 | 1, &datagram[8..16]); // MF, offset 1 * 8
+        assert!(reassembler.push(&overlapping, now).is_none());
+        assert_eq!(vec![0..16], reassembler.pending[0].received);
+
+        let raw = reassembler.push(&second, now).expect("Expected a packet");
+        assert_reassembled(raw);
+    }
+
+    #[test]
+    fn merge_received_ranges() {
+        let key = FragmentKey {
+            source: 0,
+            destination: 0,
+            protocol: Protocol::Udp,
+            identification: 0,
+        };
+        let mut pending = PendingDatagram::new(key, Instant::now());
+        pending.add_received(16..24);
+        pending.add_received(40..48);
+        pending.add_received(0..8);
+        assert_eq!(vec![0..8, 16..24, 40..48], pending.received);
+        // adjacent to the first, overlapping the second
+        pending.add_received(8..20);
+        assert_eq!(vec![0..24, 40..48], pending.received);
+        pending.add_received(24..40);
+        assert_eq!(vec![0..48], pending.received);
+    }
+
+    #[test]
+    fn evict_incomplete_datagram() {
+        let now = Instant::now();
+        let (first, second) = create_fragments();
+        let mut reassembler = Ipv4Reassembler::new();
+        assert!(reassembler.push(&first, now).is_none());
+        // the second fragment arrives too late
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert!(reassembler.push(&second, later).is_none());
+        assert_eq!(1, reassembler.pending.len());
+    }
+
+    #[test]
+    fn evict_oldest_after_reassembling_middle_datagram() {
+        let now = Instant::now();
+        let (first, second) = create_fragments();
+        let with_identification = |raw: &[u8], identification: u16| {
+            let mut raw = raw.to_vec();
+            raw[4..6].copy_from_slice(&identification.to_be_bytes());
+            raw
+        };
+        let mut reassembler = Ipv4Reassembler::new();
+        for identification in 0..MAX_PENDING_DATAGRAMS as u16 {
+            let fragment = with_identification(&first, identification);
+            assert!(reassembler.push(&fragment, now).is_none());
+        }
+        let fragment = with_identification(&second, 1);
+        assert!(reassembler.push(&fragment, now).is_some());
+
+        // overflow twice, the datagrams 0 then 2 are evicted
+        for identification in 0..3 {
+            let fragment =
+                with_identification(&first, MAX_PENDING_DATAGRAMS as u16 + identification);
+            assert!(reassembler.push(&fragment, now).is_none());
+        }
+        let identifications: Vec<u16> = reassembler
+            .pending
+            .iter()
+            .map(|pending| pending.key.identification)
+            .collect();
+        let expected: Vec<u16> = (3..MAX_PENDING_DATAGRAMS as u16 + 3).collect();
+        assert_eq!(expected, identifications);
+    }
+}
@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::Ipv6Addr;
+
+use super::ipv4_header::Protocol;
+
+pub const HEADER_LENGTH: usize = 40;
+
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// Fixed IPv6 header (rfc8200 section 3).
+///
+/// Extension headers are not supported: the next header is expected to be the transport one.
+#[derive(Clone, Debug)]
+pub struct Ipv6HeaderData {
+    payload_length: u16,
+    next_header: u8,
+    hop_limit: u8,
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+}
+
+/// Read the total length of the IPv6 packet starting at the beginning of `raw`, if enough data is
+/// available.
+///
+/// Return `None` if the total length does not fit in 16 bits, like an IPv4 length: such a packet
+/// could not be buffered.
+pub fn peek_length(raw: &[u8]) -> Option<u16> {
+    if raw.len() >= 6 {
+        // payload length is 16 bits starting at offset 4, excluding the fixed header
+        let payload_length = BigEndian::read_u16(&raw[4..6]);
+        (HEADER_LENGTH as u16).checked_add(payload_length)
+    } else {
+        None
+    }
+}
+
+#[allow(dead_code)]
+impl Ipv6HeaderData {
+    /// Create the header of a packet from `source` to `destination`, with an empty payload.
+    pub fn new(next_header: u8, source: Ipv6Addr, destination: Ipv6Addr) -> Self {
+        Self {
+            payload_length: 0,
+            next_header,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            source,
+            destination,
+        }
+    }
+
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut source = [0u8; 16];
+        source.copy_from_slice(&raw[8..24]);
+        let mut destination = [0u8; 16];
+        destination.copy_from_slice(&raw[24..40]);
+        Self {
+            payload_length: BigEndian::read_u16(&raw[4..6]),
+            next_header: raw[6],
+            hop_limit: raw[7],
+            source: source.into(),
+            destination: destination.into(),
+        }
+    }
+
+    /// Write the header into the first `HEADER_LENGTH` bytes of `raw`.
+    ///
+    /// The traffic class and the flow label are cleared.
+    pub fn write_to(&self, raw: &mut [u8]) {
+        raw[0] = 6 << 4;
+        raw[1..4].copy_from_slice(&[0, 0, 0]);
+        BigEndian::write_u16(&mut raw[4..6], self.payload_length);
+        raw[6] = self.next_header;
+        raw[7] = self.hop_limit;
+        raw[8..24].copy_from_slice(&self.source.octets());
+        raw[24..40].copy_from_slice(&self.destination.octets());
+    }
+
+    #[inline]
+    pub fn payload_length(&self) -> u16 {
+        self.payload_length
+    }
+
+    #[inline]
+    pub fn set_payload_length(&mut self, payload_length: u16) {
+        self.payload_length = payload_length;
+    }
+
+    #[inline]
+    pub fn total_length(&self) -> usize {
+        HEADER_LENGTH + self.payload_length as usize
+    }
+
+    #[inline]
+    pub fn next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    /// Transport protocol, if it is supported (ICMPv6 is not).
+    pub fn protocol(&self) -> Protocol {
+        match self.next_header {
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            next_header => Protocol::Other(next_header),
+        }
+    }
+
+    #[inline]
+    pub fn hop_limit(&self) -> u8 {
+        self.hop_limit
+    }
+
+    #[inline]
+    pub fn source(&self) -> Ipv6Addr {
+        self.source
+    }
+
+    #[inline]
+    pub fn destination(&self) -> Ipv6Addr {
+        self.destination
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn create_header() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(40);
+        raw.write_u32::<BigEndian>(6 << 28 | 0x12345).unwrap(); // version, class, flow label
+        raw.write_u16::<BigEndian>(12).unwrap(); // payload length
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(42).unwrap(); // hop limit
+        raw.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        raw.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        raw
+    }
+
+    #[test]
+    fn parse_header() {
+        let raw = create_header();
+        let header_data = Ipv6HeaderData::parse(&raw);
+        assert_eq!(12, header_data.payload_length());
+        assert_eq!(52, header_data.total_length());
+        assert_eq!(Protocol::Udp, header_data.protocol());
+        assert_eq!(42, header_data.hop_limit());
+        assert_eq!(
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap(),
+            header_data.source()
+        );
+        assert_eq!(
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap(),
+            header_data.destination()
+        );
+        assert_eq!(Some(52), peek_length(&raw));
+    }
+
+    #[test]
+    fn reject_overflowing_length() {
+        let mut raw = create_header();
+        raw[4..6].copy_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(None, peek_length(&raw));
+        // the largest length which fits
+        BigEndian::write_u16(&mut raw[4..6], u16::MAX - HEADER_LENGTH as u16);
+        assert_eq!(Some(u16::MAX), peek_length(&raw));
+    }
+
+    #[test]
+    fn write_header() {
+        let raw = create_header();
+        let header_data = Ipv6HeaderData::parse(&raw);
+        let mut written = [0u8; HEADER_LENGTH];
+        header_data.write_to(&mut written);
+        // only the flow label is lost
+        assert_eq!(6 << 4, written[0]);
+        assert_eq!(&raw[4..], &written[4..]);
+    }
+}
@@ -0,0 +1,166 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+
+use super::ipv4_header::Protocol;
+use super::ipv6_header::{self, Ipv6HeaderData};
+use super::transport_header::TransportHeaderData;
+
+pub struct Ipv6Packet<'a> {
+    raw: &'a mut [u8],
+    ipv6_header_data: Ipv6HeaderData,
+    transport_header_data: Option<TransportHeaderData>,
+}
+
+#[allow(dead_code)]
+impl<'a> Ipv6Packet<'a> {
+    /// Parse a packet received from the outside, rejecting it if its header is truncated or if
+    /// its payload length exceeds `raw`.
+    pub fn try_parse(raw: &'a mut [u8]) -> io::Result<Self> {
+        if raw.len() < ipv6_header::HEADER_LENGTH {
+            return Err(Self::invalid("Truncated IPv6 header"));
+        }
+        if raw[0] >> 4 != 6 {
+            return Err(Self::invalid("Not an IPv6 packet"));
+        }
+        if Ipv6HeaderData::parse(raw).total_length() > raw.len() {
+            return Err(Self::invalid("IPv6 payload length exceeds the packet"));
+        }
+        Ok(Self::parse(raw))
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    pub fn parse(raw: &'a mut [u8]) -> Self {
+        let ipv6_header_data = Ipv6HeaderData::parse(raw);
+        let total_length = ipv6_header_data.total_length();
+        let payload = &raw[ipv6_header::HEADER_LENGTH..total_length];
+        let transport_header_data = match ipv6_header_data.protocol() {
+            // only UDP is relayed over IPv6 for now
+            Protocol::Udp => TransportHeaderData::parse(Protocol::Udp, payload),
+            _ => None,
+        };
+        Self {
+            raw: &mut raw[..total_length],
+            ipv6_header_data,
+            transport_header_data,
+        }
+    }
+
+    #[inline]
+    pub fn raw(&self) -> &[u8] {
+        self.raw
+    }
+
+    #[inline]
+    pub fn ipv6_header_data(&self) -> &Ipv6HeaderData {
+        &self.ipv6_header_data
+    }
+
+    #[inline]
+    pub fn transport_header_data(&self) -> Option<&TransportHeaderData> {
+        self.transport_header_data.as_ref()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.transport_header_data.is_some()
+    }
+
+    #[inline]
+    pub fn length(&self) -> u16 {
+        self.raw.len() as u16
+    }
+
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.transport_header_data
+            .as_ref()
+            .map(|transport_header_data| {
+                let payload_index =
+                    ipv6_header::HEADER_LENGTH + transport_header_data.header_length() as usize;
+                &self.raw[payload_index..]
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::net::Ipv6Addr;
+
+    fn create_packet() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(52);
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, class, flow label
+        raw.write_u16::<BigEndian>(12).unwrap(); // payload length 8 + 4
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        raw.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
+    #[test]
+    fn parse_udp_packet() {
+        let mut raw = create_packet();
+        // trailing data of the next packet
+        raw.extend_from_slice(&[0x99; 4]);
+        let packet = Ipv6Packet::parse(&mut raw);
+        assert_eq!(52, packet.length());
+        match packet.transport_header_data() {
+            Some(TransportHeaderData::Udp(udp_header_data)) => {
+                assert_eq!(1234, udp_header_data.source_port());
+                assert_eq!(5678, udp_header_data.destination_port());
+            }
+            _ => panic!("Expected a UDP header"),
+        }
+        assert_eq!(&[0x11, 0x22, 0x33, 0x44], packet.payload().unwrap());
+    }
+
+    #[test]
+    fn reject_truncated_packets() {
+        let mut raw = create_packet();
+        assert!(Ipv6Packet::try_parse(&mut raw[..39]).is_err());
+        // the payload length exceeds the packet
+        assert!(Ipv6Packet::try_parse(&mut raw[..51]).is_err());
+        raw[4..6].copy_from_slice(&[0xFF, 0xFF]);
+        assert!(Ipv6Packet::try_parse(&mut raw).is_err());
+        let mut raw = create_packet();
+        raw[0] = 4 << 4;
+        assert!(Ipv6Packet::try_parse(&mut raw).is_err());
+        let mut raw = create_packet();
+        assert!(Ipv6Packet::try_parse(&mut raw).unwrap().is_valid());
+    }
+
+    #[test]
+    fn ignore_unsupported_protocol() {
+        let mut raw = create_packet();
+        raw[6] = 58; // ICMPv6
+        let packet = Ipv6Packet::parse(&mut raw);
+        assert!(!packet.is_valid());
+        assert!(packet.payload().is_none());
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::net::SocketAddrV6;
+
+use super::checksum;
+use super::datagram::DatagramReceiver;
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+use super::ipv6_header::{self, Ipv6HeaderData};
+use super::ipv6_packet::Ipv6Packet;
+use super::udp_header::UDP_HEADER_LENGTH;
+
+const UDP_PROTOCOL: u8 = 17;
+
+const PAYLOAD_INDEX: usize = ipv6_header::HEADER_LENGTH + UDP_HEADER_LENGTH as usize;
+
+/// Convert UDP datagrams received from the network to IPv6 packets for the client.
+pub struct Ipv6Packetizer {
+    buffer: Box<[u8; MAX_PACKET_LENGTH]>,
+    ipv6_header_data: Ipv6HeaderData,
+    source_port: u16,
+    destination_port: u16,
+}
+
+impl Ipv6Packetizer {
+    /// Create a packetizer for the replies to the datagrams sent from `client` to `remote`.
+    pub fn new(client: &SocketAddrV6, remote: &SocketAddrV6) -> Self {
+        Self {
+            buffer: Box::new([0; MAX_PACKET_LENGTH]),
+            ipv6_header_data: Ipv6HeaderData::new(UDP_PROTOCOL, *remote.ip(), *client.ip()),
+            source_port: remote.port(),
+            destination_port: client.port(),
+        }
+    }
+
+    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv6Packet<'_>> {
+        let r = source.recv(&mut self.buffer[PAYLOAD_INDEX..])?;
+        Ok(self.build(r as u16))
+    }
+
+    fn build(&mut self, payload_length: u16) -> Ipv6Packet<'_> {
+        let udp_length = u16::from(UDP_HEADER_LENGTH) + payload_length;
+        let total_length = ipv6_header::HEADER_LENGTH + udp_length as usize;
+
+        self.ipv6_header_data.set_payload_length(udp_length);
+        self.ipv6_header_data
+            .write_to(&mut self.buffer[..ipv6_header::HEADER_LENGTH]);
+
+        {
+            let udp_raw = &mut self.buffer[ipv6_header::HEADER_LENGTH..total_length];
+            BigEndian::write_u16(&mut udp_raw[0..2], self.source_port);
+            BigEndian::write_u16(&mut udp_raw[2..4], self.destination_port);
+            BigEndian::write_u16(&mut udp_raw[4..6], udp_length);
+            BigEndian::write_u16(&mut udp_raw[6..8], 0);
+
+            // unlike IPv4, the UDP checksum is mandatory over IPv6 (rfc8200 section 8.1)
+            let sum = checksum::ipv6_pseudo_header_sum(
+                &self.ipv6_header_data.source(),
+                &self.ipv6_header_data.destination(),
+                UDP_PROTOCOL,
+                u32::from(udp_length),
+            ) + checksum::sum(udp_raw);
+            let udp_checksum = match !checksum::fold(sum) {
+                // a computed checksum of zero is transmitted as all ones
+                0 => 0xFFFF,
+                c => c,
+            };
+            BigEndian::write_u16(&mut udp_raw[6..8], udp_checksum);
+        }
+
+        Ipv6Packet::parse(&mut self.buffer[..total_length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::datagram::tests::MockDatagramSocket;
+    use crate::relay::transport_header::TransportHeaderData;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn packetize_udp_reply() {
+        let client = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 1234, 0, 0);
+        let remote = SocketAddrV6::new("2001:db8::2".parse().unwrap(), 5678, 0, 0);
+        let mut packetizer = Ipv6Packetizer::new(&client, &remote);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44, 0x55];
+        let mut mock = MockDatagramSocket::from_data(&data);
+
+        let packet = packetizer.packetize(&mut mock).unwrap();
+        assert_eq!(53, packet.length());
+        let ipv6_header_data = packet.ipv6_header_data();
+        assert_eq!(13, ipv6_header_data.payload_length());
+        assert_eq!(*remote.ip(), ipv6_header_data.source());
+        assert_eq!(*client.ip(), ipv6_header_data.destination());
+        match packet.transport_header_data() {
+            Some(TransportHeaderData::Udp(udp_header_data)) => {
+                assert_eq!(5678, udp_header_data.source_port());
+                assert_eq!(1234, udp_header_data.destination_port());
+            }
+            _ => panic!("Expected a UDP header"),
+        }
+        assert_eq!(&data, packet.payload().unwrap());
+
+        // the checksum of the whole UDP packet, including the pseudo-header, must be valid
+        let sum = checksum::ipv6_pseudo_header_sum(
+            &ipv6_header_data.source(),
+            &ipv6_header_data.destination(),
+            UDP_PROTOCOL,
+            13,
+        ) + checksum::sum(&packet.raw()[ipv6_header::HEADER_LENGTH..]);
+        assert_eq!(0xFFFF, checksum::fold(sum));
+    }
+}
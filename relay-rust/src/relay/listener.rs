@@ -14,15 +14,16 @@
  * limitations under the License.
  */
 
-pub trait CloseListener<T> {
-    fn on_closed(&self, target: &T);
+/// Listener of the events concerning a `T` (a client closed, a connection opened or closed…).
+pub trait Listener<T> {
+    fn on_event(&self, target: &T);
 }
 
-impl<F, T> CloseListener<T> for F
+impl<F, T> Listener<T> for F
 where
     F: Fn(&T),
 {
-    fn on_closed(&self, target: &T) {
+    fn on_event(&self, target: &T) {
         self(target);
     }
 }
@@ -0,0 +1,116 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::{Level, LevelFilter};
+use std::collections::HashMap;
+
+/// Log levels per target (the `TAG` of each module, like `IcmpConnection`), with a default level
+/// for the other targets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter {
+    default_level: LevelFilter,
+    target_levels: HashMap<String, LevelFilter>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self::new(LevelFilter::Info)
+    }
+}
+
+impl LogFilter {
+    pub fn new(default_level: LevelFilter) -> Self {
+        Self {
+            default_level,
+            target_levels: HashMap::new(),
+        }
+    }
+
+    /// Parse a `RUST_LOG`-like specification: comma-separated `target=level` directives, plus an
+    /// optional bare `level` for the default.
+    ///
+    /// For example, `warn,IcmpConnection=trace` logs everything from `IcmpConnection`, but only
+    /// warnings and errors from the other targets.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.find('=') {
+                Some(index) => {
+                    let target = &directive[..index];
+                    let level = parse_level(&directive[index + 1..])?;
+                    filter.set_level(target, level);
+                }
+                None => filter.default_level = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn set_level(&mut self, target: &str, level: LevelFilter) {
+        self.target_levels.insert(target.to_string(), level);
+    }
+
+    /// Effective level for `target`.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.target_levels
+            .get(target)
+            .copied()
+            .unwrap_or(self.default_level)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level(target)
+    }
+
+    /// Most verbose level of any target, to be used as the global maximum level.
+    pub fn max_level(&self) -> LevelFilter {
+        self.target_levels
+            .values()
+            .copied()
+            .fold(self.default_level, Ord::max)
+    }
+}
+
+fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid log level: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_default_and_target_levels() {
+        let filter = LogFilter::parse("warn, IcmpConnection=trace,TcpConnection=off").unwrap();
+        assert_eq!(LevelFilter::Warn, filter.level("UdpConnection"));
+        assert_eq!(LevelFilter::Trace, filter.level("IcmpConnection"));
+        assert_eq!(LevelFilter::Off, filter.level("TcpConnection"));
+        assert_eq!(LevelFilter::Trace, filter.max_level());
+
+        assert!(filter.enabled("IcmpConnection", Level::Debug));
+        assert!(!filter.enabled("TcpConnection", Level::Error));
+        assert!(!filter.enabled("UdpConnection", Level::Info));
+    }
+
+    #[test]
+    fn reject_invalid_level() {
+        assert!(LogFilter::parse("IcmpConnection=verbose").is_err());
+        assert!(LogFilter::parse("loud").is_err());
+    }
+}
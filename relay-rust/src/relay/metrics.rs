@@ -0,0 +1,242 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Write;
+use std::ops::AddAssign;
+use std::time::Instant;
+
+use super::connection::ConnectionStats;
+use super::ipv4_header::Protocol;
+
+/// Snapshot of the relay activity, aggregated over the connections (or clients).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayMetrics {
+    pub active_tcp_connections: u64,
+    pub active_udp_connections: u64,
+    pub active_icmp_connections: u64,
+    pub icmp_sockets_opened: u64,
+    pub icmp_requests_limited: u64,
+    pub packets_rate_limited: u64,
+    pub selector_polls: u64,
+    pub selector_events: u64,
+    pub stats: ConnectionStats,
+}
+
+impl RelayMetrics {
+    pub fn record_active_connection(&mut self, protocol: Protocol) {
+        match protocol {
+            Protocol::Tcp => self.active_tcp_connections += 1,
+            Protocol::Udp => self.active_udp_connections += 1,
+            Protocol::Icmp => self.active_icmp_connections += 1,
+            Protocol::Other(_) => (),
+        }
+    }
+
+    /// Format the metrics in the Prometheus text exposition format.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn prometheus_text(&self) -> String {
+        let mut text = String::new();
+        write_header(
+            &mut text,
+            "gnirehtet_active_connections",
+            "Number of active connections.",
+            "gauge",
+        );
+        for &(protocol, value) in &[
+            ("tcp", self.active_tcp_connections),
+            ("udp", self.active_udp_connections),
+            ("icmp", self.active_icmp_connections),
+        ] {
+            writeln!(
+                text,
+                "gnirehtet_active_connections{{protocol=\"{}\"}} {}",
+                protocol, value
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut text,
+            "gnirehtet_bytes_total",
+            "Payload bytes sent to (out) or received from (in) the network.",
+            "counter",
+        );
+        writeln!(
+            text,
+            "gnirehtet_bytes_total{{direction=\"out\"}} {}",
+            self.stats.tx_bytes
+        )
+        .unwrap();
+        writeln!(
+            text,
+            "gnirehtet_bytes_total{{direction=\"in\"}} {}",
+            self.stats.rx_bytes
+        )
+        .unwrap();
+
+        write_header(
+            &mut text,
+            "gnirehtet_idle_seconds",
+            "Time elapsed since the last payload sent to (out) or received from (in) the network.",
+            "gauge",
+        );
+        let now = Instant::now();
+        for &(direction, idle) in &[
+            ("out", self.stats.tx_idle(now)),
+            ("in", self.stats.rx_idle(now)),
+        ] {
+            // no sample until some traffic is relayed
+            if let Some(idle) = idle {
+                writeln!(
+                    text,
+                    "gnirehtet_idle_seconds{{direction=\"{}\"}} {}",
+                    direction,
+                    idle.as_secs()
+                )
+                .unwrap();
+            }
+        }
+
+        write_counter(
+            &mut text,
+            "gnirehtet_dropped_packets_total",
+            "Packets from the clients which could not be sent to the network.",
+            self.stats.tx_dropped,
+        );
+        write_counter(
+            &mut text,
+            "gnirehtet_icmp_sockets_opened_total",
+            "ICMP sockets opened to relay echo requests.",
+            self.icmp_sockets_opened,
+        );
+        write_counter(
+            &mut text,
+            "gnirehtet_icmp_requests_limited_total",
+            "Echo requests dropped by the ICMP rate limit.",
+            self.icmp_requests_limited,
+        );
+        write_counter(
+            &mut text,
+            "gnirehtet_packets_rate_limited_total",
+            "Packets from the clients dropped by the packet rate limit.",
+            self.packets_rate_limited,
+        );
+        write_counter(
+            &mut text,
+            "gnirehtet_selector_polls_total",
+            "Wakeups of the event loop.",
+            self.selector_polls,
+        );
+        write_counter(
+            &mut text,
+            "gnirehtet_selector_events_total",
+            "Events handled by the event loop.",
+            self.selector_events,
+        );
+
+        write_header(
+            &mut text,
+            "gnirehtet_icmp_rtt_seconds",
+            "Round-trip time of the echo requests relayed.",
+            "summary",
+        );
+        writeln!(
+            text,
+            "gnirehtet_icmp_rtt_seconds_sum {}",
+            self.stats.rtt_total.as_secs_f64()
+        )
+        .unwrap();
+        writeln!(
+            text,
+            "gnirehtet_icmp_rtt_seconds_count {}",
+            self.stats.rtt_samples
+        )
+        .unwrap();
+        text
+    }
+}
+
+impl AddAssign for RelayMetrics {
+    fn add_assign(&mut self, other: Self) {
+        self.active_tcp_connections += other.active_tcp_connections;
+        self.active_udp_connections += other.active_udp_connections;
+        self.active_icmp_connections += other.active_icmp_connections;
+        self.icmp_sockets_opened += other.icmp_sockets_opened;
+        self.icmp_requests_limited += other.icmp_requests_limited;
+        self.packets_rate_limited += other.packets_rate_limited;
+        self.selector_polls += other.selector_polls;
+        self.selector_events += other.selector_events;
+        self.stats += other.stats;
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn write_header(text: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn write_counter(text: &mut String, name: &str, help: &str, value: u64) {
+    write_header(text, name, help, "counter");
+    writeln!(text, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn format_prometheus() {
+        let mut metrics = RelayMetrics::default();
+        metrics.record_active_connection(Protocol::Tcp);
+        metrics.record_active_connection(Protocol::Tcp);
+        metrics.record_active_connection(Protocol::Icmp);
+        metrics.icmp_sockets_opened = 5;
+        metrics.icmp_requests_limited = 3;
+        metrics.packets_rate_limited = 7;
+        metrics.selector_polls = 10;
+        metrics.selector_events = 25;
+        metrics.stats.record_tx(100);
+        metrics.stats.record_rx(42);
+        metrics.stats.record_tx_dropped();
+        metrics.stats.record_rtt(Duration::from_millis(250));
+        metrics.stats.record_rtt(Duration::from_millis(500));
+
+        let text = metrics.prometheus_text();
+        assert!(text.contains("# TYPE gnirehtet_active_connections gauge\n"));
+        assert!(text.contains("gnirehtet_active_connections{protocol=\"tcp\"} 2\n"));
+        assert!(text.contains("gnirehtet_active_connections{protocol=\"udp\"} 0\n"));
+        assert!(text.contains("gnirehtet_active_connections{protocol=\"icmp\"} 1\n"));
+        assert!(text.contains("gnirehtet_bytes_total{direction=\"out\"} 100\n"));
+        assert!(text.contains("gnirehtet_bytes_total{direction=\"in\"} 42\n"));
+        assert!(text.contains("gnirehtet_idle_seconds{direction=\"out\"} 0\n"));
+        assert!(text.contains("gnirehtet_idle_seconds{direction=\"in\"} 0\n"));
+        assert!(text.contains("gnirehtet_dropped_packets_total 1\n"));
+        assert!(text.contains("gnirehtet_icmp_sockets_opened_total 5\n"));
+        assert!(text.contains("gnirehtet_icmp_requests_limited_total 3\n"));
+        assert!(text.contains("gnirehtet_packets_rate_limited_total 7\n"));
+        assert!(text.contains("gnirehtet_selector_polls_total 10\n"));
+        assert!(text.contains("gnirehtet_selector_events_total 25\n"));
+        assert!(text.contains("# TYPE gnirehtet_icmp_rtt_seconds summary\n"));
+        assert!(text.contains("gnirehtet_icmp_rtt_seconds_sum 0.75\n"));
+        assert!(text.contains("gnirehtet_icmp_rtt_seconds_count 2\n"));
+
+        let text = RelayMetrics::default().prometheus_text();
+        assert!(!text.contains("gnirehtet_idle_seconds{"));
+    }
+}
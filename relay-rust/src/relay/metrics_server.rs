@@ -0,0 +1,552 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Event, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::connection::{ConnectionId, ConnectionInfo};
+use super::ipv4_header::Protocol;
+use super::metrics::RelayMetrics;
+use super::selector::Selector;
+
+const TAG: &str = "MetricsServer";
+
+// the requests are tiny, anything bigger is not a metrics scraper
+const MAX_REQUEST_LENGTH: usize = 4096;
+
+// the exposition format of Prometheus, also used for the other text responses
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Provide the metrics to serve on each request.
+pub type MetricsSource = Rc<dyn Fn() -> RelayMetrics>;
+
+/// Provide the live connections (along with their client id) to list on each request.
+pub type ConnectionsSource = Rc<dyn Fn(Instant) -> Vec<(u32, ConnectionInfo)>>;
+
+/// Reset the connection of a client (by client id), returning `false` if there is no such
+/// connection.
+pub type ConnectionResetter = Rc<dyn Fn(&mut Selector, u32, &ConnectionId) -> bool>;
+
+/// Minimal HTTP server exposing the relay metrics at `/metrics`.
+///
+/// The live connections are listed at `/connections`, as a netstat-like text table, or as JSON at
+/// `/connections.json`.
+///
+/// It also accepts control requests to reset a single TCP or UDP connection:
+///
+/// ```text
+/// POST /connections/reset?client=0&protocol=tcp&source=10.0.0.2:1234&destination=1.2.3.4:80
+/// ```
+///
+/// Each scraper connection serves a single request, then it is closed.
+pub struct MetricsServer {
+    tcp_listener: TcpListener,
+    source: MetricsSource,
+    connections: ConnectionsSource,
+    resetter: ConnectionResetter,
+}
+
+struct MetricsConnection {
+    stream: TcpStream,
+    token: Token,
+    source: MetricsSource,
+    connections: ConnectionsSource,
+    resetter: ConnectionResetter,
+    request: Vec<u8>,
+    // set once the whole request is received
+    response: Option<Vec<u8>>,
+    written: usize,
+}
+
+impl MetricsServer {
+    pub fn create(
+        selector: &mut Selector,
+        addr: &SocketAddr,
+        source: MetricsSource,
+        connections: ConnectionsSource,
+        resetter: ConnectionResetter,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let tcp_listener = TcpListener::from_std(net::TcpListener::bind(addr)?)?;
+        let rc = Rc::new(RefCell::new(Self {
+            tcp_listener,
+            source,
+            connections,
+            resetter,
+        }));
+
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        selector.register(
+            &rc.borrow().tcp_listener,
+            handler,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
+        Ok(rc)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, _: Event) {
+        match self.accept(selector) {
+            Ok(_) => debug!(target: TAG, "New scraper accepted"),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!(target: TAG, "Spurious event, ignoring");
+            }
+            Err(err) => error!(target: TAG, "Cannot accept scraper: {}", err),
+        }
+    }
+
+    fn accept(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let (stream, _) = self.tcp_listener.accept()?;
+        MetricsConnection::create(
+            selector,
+            stream,
+            self.source.clone(),
+            self.connections.clone(),
+            self.resetter.clone(),
+        )
+    }
+}
+
+impl MetricsConnection {
+    fn create(
+        selector: &mut Selector,
+        stream: TcpStream,
+        source: MetricsSource,
+        connections: ConnectionsSource,
+        resetter: ConnectionResetter,
+    ) -> io::Result<()> {
+        let rc = Rc::new(RefCell::new(Self {
+            stream,
+            token: Token(0), // default value, will be set afterwards
+            source,
+            connections,
+            resetter,
+            request: Vec::new(),
+            response: None,
+            written: 0,
+        }));
+
+        // the selector owns the connection until it is deregistered
+        let rc2 = rc.clone();
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        let mut self_ref = rc.borrow_mut();
+        let token = selector.register(
+            &self_ref.stream,
+            handler,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
+        self_ref.token = token;
+        Ok(())
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        let ready = event.readiness();
+        let result = if ready.is_writable() {
+            self.process_send()
+        } else if ready.is_readable() {
+            self.process_receive(selector)
+        } else {
+            Ok(true)
+        };
+        match result {
+            Ok(false) => (),
+            Ok(true) => self.close(selector),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!(target: TAG, "Spurious event, ignoring");
+            }
+            Err(err) => {
+                warn!(target: TAG, "Cannot serve metrics: {}", err);
+                self.close(selector);
+            }
+        }
+    }
+
+    // return Ok(true) once the connection must be closed
+    fn process_receive(&mut self, selector: &mut Selector) -> io::Result<bool> {
+        let mut buf = [0u8; 1024];
+        let r = self.stream.read(&mut buf)?;
+        if r == 0 {
+            // EOF before the end of the request
+            return Ok(true);
+        }
+        self.request.extend_from_slice(&buf[..r]);
+        if find_end_of_headers(&self.request).is_none() {
+            // request not complete yet
+            return Ok(self.request.len() > MAX_REQUEST_LENGTH);
+        }
+        self.response = Some(self.build_response(selector));
+        selector.reregister(
+            &self.stream,
+            self.token,
+            Ready::writable(),
+            PollOpt::level(),
+        )?;
+        self.process_send()
+    }
+
+    // return Ok(true) once the connection must be closed
+    fn process_send(&mut self) -> io::Result<bool> {
+        let response = self.response.as_ref().expect("No response to send");
+        while self.written < response.len() {
+            let w = self.stream.write(&response[self.written..])?;
+            if w == 0 {
+                return Ok(true);
+            }
+            self.written += w;
+        }
+        Ok(true)
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        if let Err(err) = selector.deregister(&self.stream, self.token) {
+            warn!(target: TAG, "Fail to deregister scraper stream: {:?}", err);
+        }
+        // the socket will be closed by RAII, shutdown now to flush the response
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn build_response(&self, selector: &mut Selector) -> Vec<u8> {
+        let request_line = self
+            .request
+            .split(|&b| b == b'\r')
+            .next()
+            .unwrap_or_default();
+        let mut parts = request_line.split(|&b| b == b' ');
+        let method = parts.next();
+        let target = parts.next().unwrap_or_default();
+        let mut target_parts = target.splitn(2, |&b| b == b'?');
+        let path = target_parts.next();
+        let query = target_parts.next().unwrap_or_default();
+        let mut content_type = TEXT_CONTENT_TYPE;
+        let (status, body) = match (method, path) {
+            (Some(b"GET"), Some(b"/metrics")) => ("200 OK", (self.source)().prometheus_text()),
+            (Some(b"GET"), Some(b"/connections")) => (
+                "200 OK",
+                format_connections_table(&(self.connections)(Instant::now())),
+            ),
+            (Some(b"GET"), Some(b"/connections.json")) => {
+                content_type = JSON_CONTENT_TYPE;
+                (
+                    "200 OK",
+                    format_connections_json(&(self.connections)(Instant::now())),
+                )
+            }
+            (Some(b"POST"), Some(b"/connections/reset")) => self.reset_connection(selector, query),
+            (Some(b"GET"), _) | (Some(b"POST"), _) => {
+                ("404 Not Found", String::from("Not found\n"))
+            }
+            _ => (
+                "405 Method Not Allowed",
+                String::from("Method not allowed\n"),
+            ),
+        };
+        format_response(status, content_type, &body)
+    }
+
+    fn reset_connection(&self, selector: &mut Selector, query: &[u8]) -> (&'static str, String) {
+        match parse_reset_query(query) {
+            Some((client_id, id)) => {
+                if (self.resetter)(selector, client_id, &id) {
+                    info!(target: TAG, "Connection reset on request: {}", id);
+                    ("200 OK", String::from("Connection reset\n"))
+                } else {
+                    ("404 Not Found", String::from("No such connection\n"))
+                }
+            }
+            None => ("400 Bad Request", String::from("Bad request\n")),
+        }
+    }
+}
+
+fn find_end_of_headers(request: &[u8]) -> Option<usize> {
+    request.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+// client=<client id>&protocol=<tcp|udp>&source=<ip:port>&destination=<ip:port>
+fn parse_reset_query(query: &[u8]) -> Option<(u32, ConnectionId)> {
+    let query = std::str::from_utf8(query).ok()?;
+    let mut client_id = None;
+    let mut protocol = None;
+    let mut source = None;
+    let mut destination = None;
+    for param in query.split('&') {
+        let (key, value) = param.split_once('=')?;
+        match key {
+            "client" => client_id = Some(value.parse::<u32>().ok()?),
+            "protocol" => {
+                protocol = Some(match value {
+                    "tcp" => Protocol::Tcp,
+                    "udp" => Protocol::Udp,
+                    _ => return None,
+                })
+            }
+            "source" => source = Some(value.parse::<SocketAddrV4>().ok()?),
+            "destination" => destination = Some(value.parse::<SocketAddrV4>().ok()?),
+            _ => return None,
+        }
+    }
+    let id = ConnectionId::from_endpoints(protocol?, source?, destination?);
+    Some((client_id?, id))
+}
+
+fn protocol_name(protocol: Protocol) -> String {
+    match protocol {
+        Protocol::Tcp => String::from("tcp"),
+        Protocol::Udp => String::from("udp"),
+        Protocol::Icmp => String::from("icmp"),
+        Protocol::Other(number) => number.to_string(),
+    }
+}
+
+fn format_connections_table(connections: &[(u32, ConnectionInfo)]) -> String {
+    let mut table = format!(
+        "{:<6} {:<5} {:<21} {:<21} {:<11} {:>8} {:>8} {:>8} {:>8} {:>12} {:>12} {:>8}\n",
+        "CLIENT",
+        "PROTO",
+        "SOURCE",
+        "DESTINATION",
+        "STATE",
+        "AGE",
+        "IDLE",
+        "TX_IDLE",
+        "RX_IDLE",
+        "TX_BYTES",
+        "RX_BYTES",
+        "RTT"
+    );
+    for (client_id, info) in connections {
+        let rtt = info
+            .stats
+            .last_rtt
+            .map_or_else(|| "-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+        table.push_str(&format!(
+            "{:<6} {:<5} {:<21} {:<21} {:<11} {:>7}s {:>7}s {:>8} {:>8} {:>12} {:>12} {:>8}\n",
+            client_id,
+            protocol_name(info.protocol),
+            info.source,
+            info.destination,
+            info.state,
+            info.age.as_secs(),
+            info.idle.as_secs(),
+            format_idle(info.tx_idle),
+            format_idle(info.rx_idle),
+            info.stats.tx_bytes,
+            info.stats.rx_bytes,
+            rtt
+        ));
+    }
+    table
+}
+
+// the time since the last traffic in seconds, or "-" if there was no traffic
+fn format_idle(idle: Option<Duration>) -> String {
+    idle.map_or_else(|| "-".to_string(), |idle| format!("{}s", idle.as_secs()))
+}
+
+// all the values are numbers or strings which need no escaping
+fn format_connections_json(connections: &[(u32, ConnectionInfo)]) -> String {
+    let entries: Vec<String> = connections
+        .iter()
+        .map(|(client_id, info)| {
+            format!(
+                "{{\"client\":{},\"protocol\":\"{}\",\"source\":\"{}\",\"destination\":\"{}\",\
+                 \"state\":\"{}\",\"age_secs\":{},\"idle_secs\":{},\"tx_idle_secs\":{},\
+                 \"rx_idle_secs\":{},\"tx_packets\":{},\"tx_bytes\":{},\"tx_dropped\":{},\
+                 \"rx_packets\":{},\"rx_bytes\":{},\"rtt_ms\":{}}}",
+                client_id,
+                protocol_name(info.protocol),
+                info.source,
+                info.destination,
+                info.state,
+                info.age.as_secs(),
+                info.idle.as_secs(),
+                info.tx_idle
+                    .map_or_else(|| "null".to_string(), |idle| idle.as_secs().to_string()),
+                info.rx_idle
+                    .map_or_else(|| "null".to_string(), |idle| idle.as_secs().to_string()),
+                info.stats.tx_packets,
+                info.stats.tx_bytes,
+                info.stats.tx_dropped,
+                info.stats.rx_packets,
+                info.stats.rx_bytes,
+                info.stats
+                    .last_rtt
+                    .map_or_else(|| "null".to_string(), |rtt| rtt.as_millis().to_string())
+            )
+        })
+        .collect();
+    format!("[{}]\n", entries.join(","))
+}
+
+fn format_response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::connection::ConnectionStats;
+    use mio::Events;
+    use std::net::{Ipv4Addr, TcpStream as StdTcpStream};
+    use std::time::Duration;
+
+    fn scrape(path: &str) -> String {
+        send_request("GET", path)
+    }
+
+    fn send_request(method: &str, path: &str) -> String {
+        let mut selector = Selector::create().unwrap();
+        let source: MetricsSource = Rc::new(|| {
+            let mut metrics = RelayMetrics {
+                active_udp_connections: 3,
+                ..RelayMetrics::default()
+            };
+            metrics.stats.record_tx(1234);
+            metrics
+        });
+        // only the connection 10.0.0.2:1234 -> 1.2.3.4:80 of client #0 exists
+        let resetter: ConnectionResetter = Rc::new(|_: &mut Selector, client_id, id| {
+            let existing = ConnectionId::from_endpoints(
+                Protocol::Tcp,
+                "10.0.0.2:1234".parse().unwrap(),
+                "1.2.3.4:80".parse().unwrap(),
+            );
+            client_id == 0 && *id == existing
+        });
+        let connections: ConnectionsSource = Rc::new(|_| {
+            let info = ConnectionInfo {
+                protocol: Protocol::Tcp,
+                source: "10.0.0.2:1234".parse().unwrap(),
+                destination: "1.2.3.4:80".parse().unwrap(),
+                state: "ESTABLISHED",
+                age: Duration::from_secs(12),
+                idle: Duration::from_secs(3),
+                tx_idle: Some(Duration::from_secs(5)),
+                rx_idle: None,
+                stats: ConnectionStats {
+                    last_rtt: Some(Duration::from_millis(42)),
+                    ..ConnectionStats::default()
+                },
+            };
+            vec![(0, info)]
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let server =
+            MetricsServer::create(&mut selector, &addr, source, connections, resetter).unwrap();
+        let addr = server.borrow().local_addr().unwrap();
+
+        let mut scraper = StdTcpStream::connect(addr).unwrap();
+        write!(
+            scraper,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+        scraper.shutdown(Shutdown::Write).unwrap();
+
+        // run the relay side until the response is sent (accept, read, write)
+        let mut events = Events::with_capacity(16);
+        for _ in 0..10 {
+            selector
+                .poll(&mut events, Some(Duration::from_millis(20)))
+                .unwrap();
+            selector.run_handlers(&events);
+        }
+
+        let mut response = String::new();
+        scraper.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("gnirehtet_active_connections{protocol=\"udp\"} 3\n"));
+        assert!(response.contains("gnirehtet_bytes_total{direction=\"out\"} 1234\n"));
+        assert!(response.contains("gnirehtet_dropped_packets_total 0\n"));
+        assert!(response.contains("gnirehtet_icmp_sockets_opened_total 0\n"));
+    }
+
+    #[test]
+    fn list_connections() {
+        let response = scrape("/connections");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let row = response.lines().last().unwrap();
+        let columns: Vec<&str> = row.split_whitespace().collect();
+        assert_eq!(
+            "0 tcp 10.0.0.2:1234 1.2.3.4:80 ESTABLISHED 12s 3s 5s - 0 0 42ms",
+            columns.join(" ")
+        );
+
+        let response = scrape("/connections.json");
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with(
+            "[{\"client\":0,\"protocol\":\"tcp\",\"source\":\"10.0.0.2:1234\",\
+             \"destination\":\"1.2.3.4:80\",\"state\":\"ESTABLISHED\",\"age_secs\":12,\
+             \"idle_secs\":3,\"tx_idle_secs\":5,\"rx_idle_secs\":null,\"tx_packets\":0,\
+             \"tx_bytes\":0,\"tx_dropped\":0,\"rx_packets\":0,\"rx_bytes\":0,\"rtt_ms\":42}]\n"
+        ));
+    }
+
+    #[test]
+    fn reject_unknown_path() {
+        let response = scrape("/other");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn reset_connection_on_request() {
+        let path = "/connections/reset?client=0&protocol=tcp&source=10.0.0.2:1234\
+                    &destination=1.2.3.4:80";
+        let response = send_request("POST", path);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let path = "/connections/reset?client=1&protocol=tcp&source=10.0.0.2:1234\
+                    &destination=1.2.3.4:80";
+        let response = send_request("POST", path);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = send_request("POST", "/connections/reset?client=0&protocol=tcp");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -14,35 +14,80 @@
  * limitations under the License.
  */
 
-pub use self::relay::Relay;
+pub use self::async_relay::{ConnectionEvents, NextEvent, RelayTask};
+pub use self::cidr::{Cidr, CidrSet};
+pub use self::connection::{
+    ClosedConnection, ConnectionId, ConnectionStats, OpenStage, OpenedConnection,
+};
+pub use self::datagram_buffer::QueuePolicy;
+pub use self::ipv4_header::Protocol;
+pub use self::listener::Listener;
+pub use self::log_filter::LogFilter;
+pub use self::relay::{Relay, ShutdownHandle};
+pub use self::relay_builder::RelayBuilder;
+pub use self::relay_config::{RelayConfig, UnknownProtocolPolicy};
+pub use self::replay::{replay_pcap, ReplayReport};
 pub mod byte_buffer;
 
+mod async_relay;
 mod binary;
+mod checksum;
+mod cidr;
 mod client;
-mod close_listener;
+mod config_file;
 #[macro_use]
 mod connection;
+mod connection_log;
+mod connection_table;
 mod datagram;
 mod datagram_buffer;
+mod dns_blocklist;
+mod dns_cache;
 #[macro_use]
 mod interrupt;
 mod ipv4_header;
 mod ipv4_packet;
 mod ipv4_packet_buffer;
+mod ipv4_reassembler;
+mod ipv6_header;
+mod ipv6_packet;
+mod ipv6_packetizer;
+mod listener;
+mod log_filter;
+mod metrics;
+#[cfg(feature = "metrics")]
+mod metrics_server;
 mod net;
+mod packet_sink;
 mod packet_source;
 mod packetizer;
+mod pcap;
+mod rate_limiter;
+#[cfg(unix)]
+mod raw_connection;
 #[allow(clippy::module_inception)] // relay.rs is in relay/
 mod relay;
+mod relay_builder;
+mod relay_config;
+mod replay;
 mod router;
 mod selector;
+mod sniffer;
+mod socket_pool;
+mod socks5;
 mod stream_buffer;
 mod tcp_connection;
 mod tcp_header;
+mod timer_wheel;
 mod transport_header;
+#[cfg(target_os = "linux")]
+mod tun_device;
 mod tunnel_server;
+mod udp6_connection;
 mod udp_connection;
 mod udp_header;
 mod icmp_socket;
 mod icmp_connection;
+mod icmp_dispatcher;
+mod icmp_error;
 mod icmp_header;
@@ -14,8 +14,21 @@
  * limitations under the License.
  */
 
+use log::*;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
 use super::binary;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use super::ipv4_header::Ipv4HeaderData;
+use super::relay_config::RelayConfig;
+
+const TAG: &str = "Net";
 
 pub fn to_addr(ipv4: u32) -> Ipv4Addr {
     let raw = binary::to_byte_array(ipv4);
@@ -26,3 +39,324 @@ pub fn to_socket_addr(ipv4: u32, port: u16) -> SocketAddrV4 {
     let addr = to_addr(ipv4);
     SocketAddrV4::new(addr, port)
 }
+
+/// Open an IPv4 socket for an outbound connection, bound to the source address and the network
+/// interface configured for the relay (if any).
+pub fn create_outbound_socket(
+    config: &RelayConfig,
+    socket_type: Type,
+    protocol: Option<Protocol>,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, socket_type, protocol)?;
+    set_buffer_sizes(&socket, config)?;
+    bind_outbound(
+        &socket,
+        IpAddr::V4(config.bind_address()),
+        config.bind_device(),
+    )?;
+    Ok(socket)
+}
+
+/// Open an IPv6 socket for an outbound connection, bound to the network interface configured for
+/// the relay (if any).
+///
+/// The configured source address is IPv4, so it does not apply.
+pub fn create_outbound_socket_v6(
+    config: &RelayConfig,
+    socket_type: Type,
+    protocol: Option<Protocol>,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, socket_type, protocol)?;
+    set_buffer_sizes(&socket, config)?;
+    bind_outbound(
+        &socket,
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        config.bind_device(),
+    )?;
+    Ok(socket)
+}
+
+/// Apply the socket buffer sizes configured for the relay (if any) to `socket`.
+pub fn set_buffer_sizes(socket: &Socket, config: &RelayConfig) -> io::Result<()> {
+    let receive_buffer_size = config.socket_receive_buffer_size();
+    let send_buffer_size = config.socket_send_buffer_size();
+    if let Some(size) = receive_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if (receive_buffer_size.is_some() || send_buffer_size.is_some())
+        && log_enabled!(target: TAG, Level::Debug)
+    {
+        // the kernel may adjust the requested values
+        debug!(
+            target: TAG,
+            "Socket buffer sizes: SO_RCVBUF={}, SO_SNDBUF={}",
+            socket.recv_buffer_size()?,
+            socket.send_buffer_size()?
+        );
+    }
+    Ok(())
+}
+
+/// Set the type of service of the packets sent on `socket`: the one configured for the relay, or
+/// the one of the packet from the client if it is preserved.
+pub fn set_outbound_tos(
+    socket: &Socket,
+    config: &RelayConfig,
+    ipv4_header_data: &Ipv4HeaderData,
+) -> io::Result<()> {
+    let tos = config
+        .remark_tos()
+        .or_else(|| Some(ipv4_header_data.tos()).filter(|_| config.preserve_tos()));
+    match tos {
+        // 0 is the default
+        Some(tos) if tos != 0 => set_tos(socket, tos),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_tos(socket: &Socket, tos: u8) -> io::Result<()> {
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_tos(_: &Socket, _: u8) -> io::Result<()> {
+    Ok(())
+}
+
+/// Enable the keepalive probes on the outbound TCP `socket`, if configured.
+pub fn set_keepalive(socket: &Socket, config: &RelayConfig) -> io::Result<()> {
+    match config.tcp_keepalive_idle() {
+        Some(idle) => {
+            let keepalive = with_probes(TcpKeepalive::new().with_time(idle), config);
+            socket.set_tcp_keepalive(&keepalive)
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_vendor = "apple"
+))]
+fn with_probes(keepalive: TcpKeepalive, config: &RelayConfig) -> TcpKeepalive {
+    keepalive
+        .with_interval(config.tcp_keepalive_interval())
+        .with_retries(config.tcp_keepalive_count())
+}
+
+// the system defaults apply
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_vendor = "apple"
+)))]
+fn with_probes(keepalive: TcpKeepalive, _: &RelayConfig) -> TcpKeepalive {
+    keepalive
+}
+
+/// Bind `socket` to `device` (if any) and to `address` (unless unspecified), before connecting.
+pub fn bind_outbound(socket: &Socket, address: IpAddr, device: Option<&str>) -> io::Result<()> {
+    if let Some(device) = device {
+        bind_device(socket, device)?;
+    }
+    if !address.is_unspecified() {
+        socket.bind(&SocketAddr::new(address, 0).into())?;
+    }
+    Ok(())
+}
+
+/// Start connecting `socket` to `address` without blocking.
+///
+/// The connection completes asynchronously, the socket becomes writable once it is established.
+pub fn connect_nonblocking(socket: &Socket, address: SocketAddr) -> io::Result<()> {
+    socket.set_nonblocking(true)?;
+    match socket.connect(&address.into()) {
+        Err(ref err) if is_in_progress(err) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_in_progress(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EINPROGRESS)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn is_in_progress(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Indicate whether `err` reports a datagram too large to be sent without fragmentation.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_message_too_long(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_message_too_long(_: &io::Error) -> bool {
+    false
+}
+
+/// Indicate whether `err` reports that the kernel lacks buffers (e.g. its interface queue is
+/// full), in which case the send may be retried later.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_no_buffer_space(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_no_buffer_space(_: &io::Error) -> bool {
+    false
+}
+
+/// Delay before polling again for writability a socket whose send failed for lack of buffers.
+///
+/// The sockets are level-triggered, so polling meanwhile would spin.
+pub const NO_BUFFER_SPACE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Set an integer socket option not exposed by socket2.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_socket_option(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let value_ptr = &value as *const libc::c_int as *const libc::c_void;
+    let length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // the value outlives the call, and its length is correct
+    let r = unsafe { libc::setsockopt(socket.as_raw_fd(), level, name, value_ptr, length) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read an integer socket option not exposed by socket2.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn socket_option(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let value_ptr = &mut value as *mut libc::c_int as *mut libc::c_void;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // the value outlives the call, and its length is correct
+    let r = unsafe { libc::getsockopt(socket.as_raw_fd(), level, name, value_ptr, &mut length) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_: &Socket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot bind to device {}: unsupported platform", device),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bind_outbound_socket_to_address() {
+        let mut config = RelayConfig::new(0);
+        config.set_bind_address(Ipv4Addr::LOCALHOST);
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        let local_addr = socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), local_addr.ip());
+    }
+
+    #[test]
+    fn set_outbound_socket_buffer_sizes() {
+        let mut config = RelayConfig::new(0);
+        config.set_socket_receive_buffer_size(Some(1 << 17));
+        config.set_socket_send_buffer_size(Some(1 << 17));
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        // Linux doubles the requested values, to account for its bookkeeping overhead
+        assert!(socket.recv_buffer_size().unwrap() >= 1 << 17);
+        assert!(socket.send_buffer_size().unwrap() >= 1 << 17);
+    }
+
+    fn create_header_data(tos: u8) -> Ipv4HeaderData {
+        let mut raw = [0u8; 20];
+        raw[0] = 4 << 4 | 5;
+        raw[1] = tos;
+        Ipv4HeaderData::parse(&raw)
+    }
+
+    fn outbound_tos(socket: &Socket) -> libc::c_int {
+        socket_option(socket, libc::IPPROTO_IP, libc::IP_TOS).unwrap()
+    }
+
+    #[test]
+    fn preserve_outbound_socket_tos() {
+        // DSCP AF41, ECN not set
+        let ipv4_header_data = create_header_data(34 << 2);
+        let mut config = RelayConfig::new(0);
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        set_outbound_tos(&socket, &config, &ipv4_header_data).unwrap();
+        assert_eq!(0, outbound_tos(&socket));
+
+        config.set_preserve_tos(true);
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        set_outbound_tos(&socket, &config, &ipv4_header_data).unwrap();
+        assert_eq!(i32::from(ipv4_header_data.tos()), outbound_tos(&socket));
+        assert_eq!(34, outbound_tos(&socket) >> 2);
+
+        // remarking takes precedence
+        config.set_remark_tos(Some(8 << 2));
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        set_outbound_tos(&socket, &config, &ipv4_header_data).unwrap();
+        assert_eq!(8 << 2, outbound_tos(&socket));
+    }
+
+    #[test]
+    fn enable_outbound_socket_keepalive() {
+        let mut config = RelayConfig::new(0);
+        let socket = create_outbound_socket(&config, Type::STREAM, None).unwrap();
+        set_keepalive(&socket, &config).unwrap();
+        assert!(!socket.keepalive().unwrap());
+
+        config.set_tcp_keepalive_idle(Some(Duration::from_secs(60)));
+        config.set_tcp_keepalive_interval(Duration::from_secs(10));
+        config.set_tcp_keepalive_count(3);
+        let socket = create_outbound_socket(&config, Type::STREAM, None).unwrap();
+        set_keepalive(&socket, &config).unwrap();
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(Duration::from_secs(60), socket.keepalive_time().unwrap());
+        assert_eq!(
+            Duration::from_secs(10),
+            socket.keepalive_interval().unwrap()
+        );
+        assert_eq!(3, socket.keepalive_retries().unwrap());
+    }
+
+    #[test]
+    fn do_not_bind_outbound_socket_by_default() {
+        let config = RelayConfig::new(0);
+        let socket = create_outbound_socket(&config, Type::DGRAM, None).unwrap();
+        let local_addr = socket.local_addr().unwrap().as_socket().unwrap();
+        // not bound yet, the kernel will choose on connect
+        assert_eq!(0, local_addr.port());
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+
+use super::ipv4_packet::Ipv4Packet;
+use super::selector::Selector;
+
+/// Destination of the packets relayed from the network to the client.
+///
+/// Connections reading from the network deliver their packets (and the ICMP errors they receive)
+/// to a packet sink rather than to the `Client` directly, so that their behavior can be tested
+/// without a real client.
+///
+/// It is implemented by `Client`.
+pub trait PacketSink {
+    fn send_to_client(
+        &mut self,
+        selector: &mut Selector,
+        ipv4_packet: &Ipv4Packet,
+    ) -> io::Result<()>;
+
+    /// Close the UDP flow rejected by the ICMP Destination Unreachable `message`, if any.
+    fn close_unreachable_flow(&mut self, selector: &mut Selector, message: &[u8]);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Packet sink capturing what would have been sent to the client, to be used in other tests
+    #[derive(Default)]
+    pub struct PacketCapture {
+        pub packets: Vec<Vec<u8>>,
+        pub unreachable_messages: Vec<Vec<u8>>,
+    }
+
+    impl PacketSink for PacketCapture {
+        fn send_to_client(&mut self, _: &mut Selector, ipv4_packet: &Ipv4Packet) -> io::Result<()> {
+            self.packets.push(ipv4_packet.raw().to_vec());
+            Ok(())
+        }
+
+        fn close_unreachable_flow(&mut self, _: &mut Selector, message: &[u8]) {
+            self.unreachable_messages.push(message.to_vec());
+        }
+    }
+}
@@ -25,8 +25,9 @@ use super::selector::Selector;
 ///
 /// This trait provides the abstraction of a packet source from which it can pull packets.
 ///
-/// It is implemented by `TcpConnection`.
+/// It is implemented by `TcpConnection` and `IcmpConnection`. A source may provide several packets
+/// in a row: it stays pending until it has no more packets.
 pub trait PacketSource {
-    fn get(&mut self) -> Option<Ipv4Packet>;
+    fn get(&mut self) -> Option<Ipv4Packet<'_>>;
     fn next(&mut self, selector: &mut Selector);
 }
@@ -15,27 +15,93 @@
  */
 
 use log::*;
+use rand::random;
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::io;
 
 use super::binary;
 use super::datagram::{DatagramReceiver, ReadAdapter};
+use super::icmp_header::{IcmpHeaderData, ICMP_HEADER_LENGTH};
 use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
+/// Error while packetizing a datagram received from the network.
+///
+/// Only `Io` concerns the socket itself: on the other errors, the datagram must just be dropped.
+#[derive(Debug)]
+pub enum PacketizeError {
+    /// The datagram could not be received.
+    Io(io::Error),
+    /// The datagram is too short to contain the header of its protocol.
+    Truncated,
+    /// The network (IP) header preceding the datagram is malformed.
+    BadHeader,
+    /// The checksum of the datagram is invalid.
+    BadChecksum,
+    /// The packet built from the datagram would exceed the MTU.
+    Oversized,
+    /// The TTL of the packet received is exhausted, it may not be forwarded to the client.
+    TtlExpired,
+}
+
+impl From<io::Error> for PacketizeError {
+    fn from(err: io::Error) -> Self {
+        // the sources report the malformed headers they parse as invalid data
+        if err.kind() == io::ErrorKind::InvalidData {
+            PacketizeError::BadHeader
+        } else {
+            PacketizeError::Io(err)
+        }
+    }
+}
+
+impl fmt::Display for PacketizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketizeError::Io(ref err) => write!(f, "I/O error: {}", err),
+            PacketizeError::Truncated => write!(f, "Truncated datagram"),
+            PacketizeError::BadHeader => write!(f, "Malformed header"),
+            PacketizeError::BadChecksum => write!(f, "Invalid checksum"),
+            PacketizeError::Oversized => write!(f, "Packet too large for the MTU"),
+            PacketizeError::TtlExpired => write!(f, "TTL expired"),
+        }
+    }
+}
+
+impl error::Error for PacketizeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PacketizeError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// Convert from level 5 to level 3 by appending correct IP and transport headers.
+///
+/// The packets built never exceed the MTU (the maximum length of the packets the client accepts).
+///
+/// Each packet built carries its own IPv4 identification, incremented from a random initial
+/// value, rather than the identification of the reference header.
 pub struct Packetizer {
     buffer: Box<[u8; MAX_PACKET_LENGTH]>,
     transport_index: usize,
     payload_index: usize,
     ipv4_header_data: Ipv4HeaderData,
     transport_header_data: TransportHeaderData,
+    mtu: u16,
+    preserve_ttl: bool,
+    next_identification: u16,
 }
 
 impl Packetizer {
     pub fn new(
         reference_ipv4_header: &Ipv4Header,
         reference_transport_header: &TransportHeader,
+        mtu: u16,
     ) -> Self {
         let mut buffer = Box::new([0; MAX_PACKET_LENGTH]);
 
@@ -65,30 +131,83 @@ impl Packetizer {
             payload_index,
             ipv4_header_data,
             transport_header_data,
+            mtu,
+            preserve_ttl: false,
+            next_identification: random(),
         }
     }
 
-    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet {
+    /// Derive the TTL of the packets from the TTL of the datagrams received, when their source
+    /// reports it, instead of keeping the TTL of the reference header.
+    pub fn set_preserve_ttl(&mut self, preserve_ttl: bool) {
+        self.preserve_ttl = preserve_ttl;
+    }
+
+    pub fn preserves_ttl(&self) -> bool {
+        self.preserve_ttl
+    }
+
+    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet<'_> {
         self.build(0)
     }
 
-    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet> {
+    /// Receive a datagram from `source` and packetize it.
+    ///
+    /// For ICMP, the payload is the whole ICMP message, so its header and checksum are verified.
+    ///
+    /// A datagram too large for the MTU is rejected.
+    ///
+    /// If the TTL is preserved, the relay counts as one more hop: a datagram received with a TTL
+    /// of 1 is rejected.
+    pub fn packetize<R: DatagramReceiver>(
+        &mut self,
+        source: &mut R,
+    ) -> Result<Ipv4Packet<'_>, PacketizeError> {
         let r = source.recv(&mut self.buffer[self.payload_index..])?;
         debug!(target: "PACK", "payload index {}, length {}, raw: {}", self.payload_index, r, binary::build_packet_string(&self.buffer[self.payload_index..]));
+        if r > self.max_payload_length() {
+            return Err(PacketizeError::Oversized);
+        }
+        if let Some(ttl) = source.received_ttl().filter(|_| self.preserve_ttl) {
+            if ttl <= 1 {
+                return Err(PacketizeError::TtlExpired);
+            }
+            self.ipv4_header_mut().set_ttl(ttl - 1);
+        }
+        if let TransportHeaderData::Icmp(_) = self.transport_header_data {
+            Self::verify_icmp_message(&self.buffer[self.payload_index..self.payload_index + r])?;
+        }
         let ipv4_packet = self.build(r as u16);
         Ok(ipv4_packet)
     }
 
+    fn verify_icmp_message(message: &[u8]) -> Result<(), PacketizeError> {
+        if message.len() < ICMP_HEADER_LENGTH {
+            return Err(PacketizeError::Truncated);
+        }
+        let icmp_header_data = IcmpHeaderData::parse(message);
+        if !icmp_header_data.bind(message).verify_checksum() {
+            return Err(PacketizeError::BadChecksum);
+        }
+        Ok(())
+    }
+
     /// Packetize from stream (`Read`) source.
     ///
     /// `Ok(Some(_))` when packet is available
     /// `Ok(None)` on EOF (read 0 byte)
     /// `Err(_)` on error
+    ///
+    /// The chunk read is limited so that the packet fits the MTU.
     pub fn packetize_read<R: io::Read>(
         &mut self,
         source: &mut R,
         max_chunk_size: Option<usize>,
-    ) -> io::Result<Option<Ipv4Packet>> {
+    ) -> io::Result<Option<Ipv4Packet<'_>>> {
+        let max_payload_length = self.max_payload_length();
+        let max_chunk_size = Some(max_chunk_size.map_or(max_payload_length, |size| {
+            cmp::min(size, max_payload_length)
+        }));
         let mut adapter = ReadAdapter::new(source, max_chunk_size);
         let r = adapter.recv(&mut self.buffer[self.payload_index..])?;
         debug!(target: "PACK", "payload index {}, length {}, raw: {}", self.payload_index, r, binary::build_packet_string(&self.buffer[self.payload_index..]));
@@ -101,20 +220,34 @@ impl Packetizer {
         Ok(option)
     }
 
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+    /// Maximum length of the packets the client accepts.
+    pub fn mtu(&self) -> usize {
+        self.mtu as usize
+    }
+
+    /// Maximum payload length of a packet built by this packetizer, so that it fits the MTU.
+    pub fn max_payload_length(&self) -> usize {
+        self.mtu().saturating_sub(self.payload_index)
+    }
+
+    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut<'_> {
         let raw = &mut self.buffer[..self.transport_index];
         self.ipv4_header_data.bind_mut(raw)
     }
 
-    pub fn transport_header_mut(&mut self) -> TransportHeaderMut {
+    pub fn transport_header_mut(&mut self) -> TransportHeaderMut<'_> {
         let raw = &mut self.buffer[self.transport_index..self.payload_index];
         self.transport_header_data.bind_mut(raw)
     }
 
-    fn build(&mut self, payload_length: u16) -> Ipv4Packet {
+    fn build(&mut self, payload_length: u16) -> Ipv4Packet<'_> {
         let total_length = self.payload_index as u16 + payload_length;
+        let identification = self.next_identification;
+        self.next_identification = identification.wrapping_add(1);
 
-        self.ipv4_header_mut().set_total_length(total_length);
+        let mut ipv4_header = self.ipv4_header_mut();
+        ipv4_header.set_total_length(total_length);
+        ipv4_header.set_identification(identification);
         self.transport_header_mut()
             .set_payload_length(payload_length);
 
@@ -127,7 +260,7 @@ impl Packetizer {
         ipv4_packet
     }
 
-    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet {
+    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet<'_> {
         Ipv4Packet::new(
             &mut self.buffer[..packet_length as usize],
             self.ipv4_header_data.clone(),
@@ -139,7 +272,9 @@ impl Packetizer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::relay::datagram::tests::MockDatagramSocket;
+    use crate::relay::datagram::tests::{LoopbackDatagramSocket, MockDatagramSocket};
+    use crate::relay::ipv4_packet::MTU;
+    use crate::relay::relay_config::MIN_MTU;
     use byteorder::{BigEndian, WriteBytesExt};
     use std::io;
 
@@ -174,7 +309,7 @@ mod tests {
 
         let ipv4_header = reference_packet.ipv4_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, MTU);
 
         let packet = packetizer.packetize(&mut mock).unwrap();
         assert_eq!(36, packet.ipv4_header_data().total_length());
@@ -191,7 +326,7 @@ mod tests {
 
         let ipv4_header = reference_packet.ipv4_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, MTU);
 
         let packet_length = packetizer.packetize(&mut mock).unwrap().length();
         let packet = packetizer.inflate(packet_length);
@@ -209,7 +344,7 @@ mod tests {
 
         let ipv4_header = reference_packet.ipv4_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, MTU);
 
         {
             let packet = packetizer
@@ -238,4 +373,179 @@ mod tests {
             assert_eq!([0x66, 0x77, 0x88], packet.payload().unwrap());
         }
     }
+
+    #[test]
+    fn distinct_identifications() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44];
+        let mut cursor = io::Cursor::new(&data);
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, MTU);
+
+        let mut identifications = Vec::new();
+        while let Some(packet) = packetizer.packetize_read(&mut cursor, Some(1)).unwrap() {
+            assert!(packet.ipv4_header().verify_checksum());
+            identifications.push(packet.ipv4_header_data().identification());
+        }
+        assert_eq!(4, identifications.len());
+        for pair in identifications.windows(2) {
+            assert_eq!(pair[0].wrapping_add(1), pair[1]);
+        }
+
+        let packet = packetizer.packetize_empty_payload();
+        assert_ne!(
+            identifications[3],
+            packet.ipv4_header_data().identification()
+        );
+    }
+
+    #[test]
+    fn refuse_oversized_packet() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = Ipv4Packet::parse(raw);
+
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, MIN_MTU);
+        // 20 bytes of IPv4 header + 8 bytes of UDP header
+        assert_eq!(40, packetizer.max_payload_length());
+
+        let mut mock = MockDatagramSocket::from_data(&[0x42; 41]);
+        assert!(matches!(
+            packetizer.packetize(&mut mock),
+            Err(PacketizeError::Oversized)
+        ));
+
+        let mut mock = MockDatagramSocket::from_data(&[0x42; 40]);
+        let packet = packetizer.packetize(&mut mock).unwrap();
+        assert_eq!(MIN_MTU, packet.ipv4_header_data().total_length());
+
+        // a stream is read in chunks fitting the MTU
+        let data = [0x42; 50];
+        let mut cursor = io::Cursor::new(&data[..]);
+        let packet = packetizer
+            .packetize_read(&mut cursor, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(MIN_MTU, packet.ipv4_header_data().total_length());
+    }
+
+    fn create_icmp_packetizer() -> Packetizer {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u8(8).unwrap(); // type (Echo Request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12340001).unwrap(); // identifier and sequence number
+
+        let reference_packet = Ipv4Packet::parse(&mut raw);
+        let ipv4_header = reference_packet.ipv4_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        Packetizer::new(&ipv4_header, &transport_header, MTU)
+    }
+
+    fn create_echo_reply() -> Vec<u8> {
+        let mut raw = vec![0, 0, 0, 0, 0x12, 0x34, 0x00, 0x01, 0x11, 0x22];
+        let mut icmp_header_data = IcmpHeaderData::parse(&raw);
+        icmp_header_data.bind_mut(&mut raw).update_checksum();
+        raw
+    }
+
+    #[test]
+    fn packetize_valid_icmp_message() {
+        let mut packetizer = create_icmp_packetizer();
+        let message = create_echo_reply();
+        let mut mock = MockDatagramSocket::from_data(&message);
+        let packet = packetizer.packetize(&mut mock).unwrap();
+        assert_eq!(&message[..], packet.payload().unwrap());
+    }
+
+    #[test]
+    fn derive_ttl_from_received_packet() {
+        let mut packetizer = create_icmp_packetizer();
+        let message = create_echo_reply();
+
+        // by default, the TTL of the reference header is kept
+        let mut source = &message[..];
+        let mut adapter = ReadAdapter::new(&mut source, None).with_ttl(Some(50));
+        let packet = packetizer.packetize(&mut adapter).unwrap();
+        assert_eq!(64, packet.ipv4_header().ttl());
+
+        packetizer.set_preserve_ttl(true);
+        let mut source = &message[..];
+        let mut adapter = ReadAdapter::new(&mut source, None).with_ttl(Some(50));
+        let packet = packetizer.packetize(&mut adapter).unwrap();
+        assert_eq!(49, packet.ipv4_header().ttl());
+        assert!(packet.ipv4_header().verify_checksum());
+
+        let mut source = &message[..];
+        let mut adapter = ReadAdapter::new(&mut source, None).with_ttl(Some(1));
+        assert!(matches!(
+            packetizer.packetize(&mut adapter),
+            Err(PacketizeError::TtlExpired)
+        ));
+    }
+
+    #[test]
+    fn report_io_error() {
+        let mut packetizer = create_icmp_packetizer();
+        // nothing to receive
+        let mut loopback = LoopbackDatagramSocket::default();
+        match packetizer.packetize(&mut loopback) {
+            Err(PacketizeError::Io(err)) => assert_eq!(io::ErrorKind::WouldBlock, err.kind()),
+            _ => panic!("Expected an I/O error"),
+        }
+    }
+
+    #[test]
+    fn report_truncated_icmp_message() {
+        let mut packetizer = create_icmp_packetizer();
+        let mut mock = MockDatagramSocket::from_data(&[0, 0, 0xff, 0xff]);
+        assert!(matches!(
+            packetizer.packetize(&mut mock),
+            Err(PacketizeError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn report_bad_header() {
+        struct MalformedSource;
+
+        impl DatagramReceiver for MalformedSource {
+            fn recv(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed"))
+            }
+        }
+
+        let mut packetizer = create_icmp_packetizer();
+        assert!(matches!(
+            packetizer.packetize(&mut MalformedSource),
+            Err(PacketizeError::BadHeader)
+        ));
+    }
+
+    #[test]
+    fn report_bad_icmp_checksum() {
+        let mut packetizer = create_icmp_packetizer();
+        let mut message = create_echo_reply();
+        message[9] ^= 0x01;
+        let mut mock = MockDatagramSocket::from_data(&message);
+        assert!(matches!(
+            packetizer.packetize(&mut mock),
+            Err(PacketizeError::BadChecksum)
+        ));
+    }
 }
@@ -0,0 +1,318 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use log::*;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TAG: &str = "Pcap";
+
+const MAGIC_NUMBER: u32 = 0xA1B2_C3D4;
+// same format, with nanosecond timestamps
+const MAGIC_NUMBER_NANOS: u32 = 0xA1B2_3C4D;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 0xFFFF;
+// packets start directly with the IPv4 header
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+// the largest snapshot length used by libpcap
+const MAX_CAPTURED_LENGTH: usize = 0x4_0000;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SharedPcapWriter = Rc<RefCell<PcapWriter<BufWriter<File>>>>;
+
+/// Record packets into the libpcap format, readable by Wireshark or tcpdump.
+pub struct PcapWriter<W: Write> {
+    output: W,
+    last_flush: Instant,
+    // records were written since the last flush
+    unflushed: bool,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create_shared(path: &Path) -> io::Result<SharedPcapWriter> {
+        let file = File::create(path)?;
+        let writer = PcapWriter::new(BufWriter::new(file))?;
+        Ok(Rc::new(RefCell::new(writer)))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut output: W) -> io::Result<Self> {
+        output.write_u32::<LittleEndian>(MAGIC_NUMBER)?;
+        output.write_u16::<LittleEndian>(VERSION_MAJOR)?;
+        output.write_u16::<LittleEndian>(VERSION_MINOR)?;
+        output.write_i32::<LittleEndian>(0)?; // thiszone (GMT)
+        output.write_u32::<LittleEndian>(0)?; // sigfigs
+        output.write_u32::<LittleEndian>(SNAPLEN)?;
+        output.write_u32::<LittleEndian>(LINKTYPE_RAW)?;
+        Ok(Self {
+            output,
+            last_flush: Instant::now(),
+            unflushed: false,
+        })
+    }
+
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.write_packet_at(timestamp, packet)
+    }
+
+    fn write_packet_at(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let captured_length = packet.len().min(SNAPLEN as usize);
+        self.output
+            .write_u32::<LittleEndian>(timestamp.as_secs() as u32)?;
+        self.output
+            .write_u32::<LittleEndian>(timestamp.subsec_micros())?;
+        self.output
+            .write_u32::<LittleEndian>(captured_length as u32)?;
+        self.output.write_u32::<LittleEndian>(packet.len() as u32)?;
+        self.output.write_all(&packet[..captured_length])?;
+        self.unflushed = true;
+        self.flush_if_due(Instant::now())?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.unflushed = false;
+        self.output.flush()
+    }
+
+    /// Instant when the records written since the last flush must be flushed, if any.
+    ///
+    /// The relay flushes them on this deadline, so that they do not wait for the next packet.
+    pub fn next_flush_deadline(&self) -> Option<Instant> {
+        if self.unflushed {
+            Some(self.last_flush + FLUSH_INTERVAL)
+        } else {
+            None
+        }
+    }
+
+    /// Flush the written records if their flush deadline is reached.
+    ///
+    /// Return `true` if they were flushed.
+    pub fn flush_if_due(&mut self, now: Instant) -> io::Result<bool> {
+        match self.next_flush_deadline() {
+            Some(deadline) if deadline <= now => {
+                self.flush()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    #[cfg(test)]
+    fn output(&self) -> &W {
+        &self.output
+    }
+}
+
+/// Read the packets of a libpcap capture of raw IP packets, like the ones written by `PcapWriter`.
+pub struct PcapReader<R: Read> {
+    input: R,
+    // captured on a host of the other byte order
+    swapped: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+        let magic_number = LittleEndian::read_u32(&header[0..4]);
+        let swapped = match magic_number {
+            MAGIC_NUMBER | MAGIC_NUMBER_NANOS => false,
+            _ if [MAGIC_NUMBER, MAGIC_NUMBER_NANOS].contains(&magic_number.swap_bytes()) => true,
+            _ => return Err(Self::invalid("Not a pcap file".into())),
+        };
+        let reader = Self { input, swapped };
+        let link_type = reader.read_u32(&header[20..24]);
+        if !matches!(link_type, LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6) {
+            let message = format!("Unsupported link type: {} (raw IP expected)", link_type);
+            return Err(Self::invalid(message));
+        }
+        Ok(reader)
+    }
+
+    /// Read the next captured packet, or `None` at the end of the capture.
+    ///
+    /// The packet is truncated if it exceeded the snapshot length of the capture.
+    pub fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0u8; 16];
+        // the capture may only end between two records
+        if self.input.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        self.input.read_exact(&mut header[1..])?;
+        let captured_length = self.read_u32(&header[8..12]) as usize;
+        if captured_length > MAX_CAPTURED_LENGTH {
+            let message = format!("Invalid captured length: {}", captured_length);
+            return Err(Self::invalid(message));
+        }
+        let mut packet = vec![0; captured_length];
+        self.input.read_exact(&mut packet)?;
+        Ok(Some(packet))
+    }
+
+    fn read_u32(&self, raw: &[u8]) -> u32 {
+        if self.swapped {
+            BigEndian::read_u32(raw)
+        } else {
+            LittleEndian::read_u32(raw)
+        }
+    }
+
+    fn invalid(message: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+/// Record `packet` into the optional capture, without failing the relay on error.
+pub fn capture(pcap_writer: Option<&SharedPcapWriter>, packet: &[u8]) {
+    if let Some(pcap_writer) = pcap_writer {
+        if let Err(err) = pcap_writer.borrow_mut().write_packet(packet) {
+            warn!(target: TAG, "Cannot write packet to capture: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn write_global_header() {
+        let pcap_writer = PcapWriter::new(Vec::new()).unwrap();
+        let raw = pcap_writer.output();
+        assert_eq!(24, raw.len());
+        assert_eq!(&[0xD4, 0xC3, 0xB2, 0xA1], &raw[0..4]);
+        assert_eq!(2, LittleEndian::read_u16(&raw[4..6]));
+        assert_eq!(4, LittleEndian::read_u16(&raw[6..8]));
+        assert_eq!(0xFFFF, LittleEndian::read_u32(&raw[16..20]));
+        assert_eq!(LINKTYPE_RAW, LittleEndian::read_u32(&raw[20..24]));
+    }
+
+    #[test]
+    fn write_packet_records() {
+        let mut pcap_writer = PcapWriter::new(Vec::new()).unwrap();
+        let packet1 = [0x45, 0, 0, 20];
+        let packet2 = [0x45, 0, 0, 28, 0xFF, 0xEE];
+        pcap_writer
+            .write_packet_at(Duration::new(1_500_000_000, 42_000), &packet1)
+            .unwrap();
+        pcap_writer
+            .write_packet_at(Duration::new(1_500_000_001, 0), &packet2)
+            .unwrap();
+
+        let raw = &pcap_writer.output()[24..];
+        assert_eq!(16 + 4 + 16 + 6, raw.len());
+
+        assert_eq!(1_500_000_000, LittleEndian::read_u32(&raw[0..4]));
+        assert_eq!(42, LittleEndian::read_u32(&raw[4..8]));
+        assert_eq!(4, LittleEndian::read_u32(&raw[8..12])); // captured length
+        assert_eq!(4, LittleEndian::read_u32(&raw[12..16])); // original length
+        assert_eq!(&packet1, &raw[16..20]);
+
+        let raw = &raw[20..];
+        assert_eq!(1_500_000_001, LittleEndian::read_u32(&raw[0..4]));
+        assert_eq!(0, LittleEndian::read_u32(&raw[4..8]));
+        assert_eq!(6, LittleEndian::read_u32(&raw[8..12]));
+        assert_eq!(6, LittleEndian::read_u32(&raw[12..16]));
+        assert_eq!(&packet2, &raw[16..]);
+    }
+
+    #[test]
+    fn flush_on_deadline() {
+        let path = std::env::temp_dir().join(format!("gnirehtet-{}.pcap", std::process::id()));
+        let pcap_writer = PcapWriter::create_shared(&path).unwrap();
+        let mut pcap_writer = pcap_writer.borrow_mut();
+        assert!(pcap_writer.next_flush_deadline().is_none());
+        pcap_writer.write_packet(&[0x45, 0, 0, 20]).unwrap();
+        let file_length = || fs::metadata(&path).unwrap().len();
+        // buffered until the deadline
+        assert_eq!(0, file_length());
+
+        let deadline = pcap_writer
+            .next_flush_deadline()
+            .expect("Expected a deadline");
+        assert!(!pcap_writer.flush_if_due(Instant::now()).unwrap());
+        assert!(pcap_writer.flush_if_due(deadline).unwrap());
+        let length = file_length();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(24 + 16 + 4, length);
+        assert!(pcap_writer.next_flush_deadline().is_none());
+    }
+
+    #[test]
+    fn read_written_packets() {
+        let mut pcap_writer = PcapWriter::new(Vec::new()).unwrap();
+        pcap_writer.write_packet(&[0x45, 0, 0, 20]).unwrap();
+        pcap_writer.write_packet(&[0x60, 1, 2]).unwrap();
+
+        let mut pcap_reader = PcapReader::new(&pcap_writer.output()[..]).unwrap();
+        assert_eq!(
+            vec![0x45, 0, 0, 20],
+            pcap_reader.read_packet().unwrap().unwrap()
+        );
+        assert_eq!(
+            vec![0x60, 1, 2],
+            pcap_reader.read_packet().unwrap().unwrap()
+        );
+        assert!(pcap_reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_swapped_capture() {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(MAGIC_NUMBER).unwrap();
+        raw.write_u16::<BigEndian>(VERSION_MAJOR).unwrap();
+        raw.write_u16::<BigEndian>(VERSION_MINOR).unwrap();
+        raw.write_u64::<BigEndian>(0).unwrap(); // thiszone and sigfigs
+        raw.write_u32::<BigEndian>(SNAPLEN).unwrap();
+        raw.write_u32::<BigEndian>(LINKTYPE_IPV4).unwrap();
+        raw.write_u64::<BigEndian>(0).unwrap(); // timestamp
+        raw.write_u32::<BigEndian>(2).unwrap();
+        raw.write_u32::<BigEndian>(2).unwrap();
+        raw.extend_from_slice(&[0x45, 0]);
+        // a record truncated by the end of the file
+        raw.extend_from_slice(&[0, 0, 0]);
+
+        let mut pcap_reader = PcapReader::new(&raw[..]).unwrap();
+        assert_eq!(vec![0x45, 0], pcap_reader.read_packet().unwrap().unwrap());
+        assert!(pcap_reader.read_packet().is_err());
+    }
+
+    #[test]
+    fn reject_unsupported_captures() {
+        assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+
+        let mut raw = PcapWriter::new(Vec::new()).unwrap().output().clone();
+        LittleEndian::write_u32(&mut raw[20..24], 1); // Ethernet
+        assert!(PcapReader::new(&raw[..]).is_err());
+    }
+}
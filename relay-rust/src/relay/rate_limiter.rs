@@ -0,0 +1,147 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting the number of bytes sent per second.
+///
+/// It may limit a number of packets as well, each consuming a single token.
+///
+/// The bucket is refilled at `rate` bytes per second, up to `burst` bytes. A packet is accepted
+/// once enough tokens are available; a packet larger than the burst is accepted when the bucket
+/// is full, so that it may never be blocked forever.
+pub struct RateLimiter {
+    rate: u64,
+    burst: u64,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        assert!(rate > 0, "Rate must not be zero");
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Consume `length` tokens if they are available at `now`.
+    pub fn try_consume(&mut self, length: usize, now: Instant) -> bool {
+        self.refill(now);
+        let required = self.required(length);
+        if self.tokens < required {
+            return false;
+        }
+        self.tokens -= required;
+        true
+    }
+
+    /// Delay after which `length` tokens will be available, computed at `now`.
+    pub fn delay(&mut self, length: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = self.required(length).saturating_sub(self.tokens);
+        // round up, so that the tokens are really available once the delay elapsed
+        let nanos = (missing * 1_000_000_000).div_ceil(self.rate);
+        Duration::from_nanos(nanos)
+    }
+
+    /// Number of tokens available at `now`.
+    pub fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Indicate whether the bucket is full at `now`, so that forgetting it changes nothing.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens == self.burst
+    }
+
+    fn required(&self, length: usize) -> u64 {
+        cmp::min(length as u64, self.burst)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let new_tokens = elapsed.as_nanos() as u64 * self.rate / 1_000_000_000;
+        if new_tokens == 0 {
+            // keep accumulating the elapsed time
+            return;
+        }
+        self.tokens = cmp::min(self.burst, self.tokens + new_tokens);
+        if self.tokens == self.burst {
+            self.last_refill = now;
+        } else {
+            // only account for the time actually converted to tokens
+            self.last_refill += Duration::from_nanos(new_tokens * 1_000_000_000 / self.rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_burst_then_wait() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(1000, 1500);
+        limiter.last_refill = start;
+
+        assert!(limiter.try_consume(1000, start));
+        assert!(!limiter.try_consume(1000, start));
+        assert_eq!(Duration::from_millis(500), limiter.delay(1000, start));
+
+        assert!(!limiter.try_consume(1000, start + Duration::from_millis(499)));
+        assert!(limiter.try_consume(1000, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn accept_packet_larger_than_burst_when_full() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(1000, 500);
+        limiter.last_refill = start;
+
+        assert!(limiter.try_consume(4000, start));
+        assert!(!limiter.try_consume(4000, start));
+        assert!(limiter.try_consume(4000, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn clamp_throughput() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100_000, 10_000);
+        limiter.last_refill = start;
+
+        // try to send a 1000-byte packet every millisecond (1MB/s) for 2 seconds
+        let mut sent = 0;
+        for i in 0..2000 {
+            if limiter.try_consume(1000, start + Duration::from_millis(i)) {
+                sent += 1000;
+            }
+        }
+        // 100KB/s for 2 seconds, plus the initial burst
+        assert!(sent <= 2 * 100_000 + 10_000, "sent {} bytes", sent);
+        assert!(sent >= 2 * 100_000 - 1000, "sent {} bytes", sent);
+    }
+}
@@ -0,0 +1,494 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use log::*;
+use mio::unix::EventedFd;
+use mio::{Event, Evented, Poll, PollOpt, Ready, Token};
+use socket2::{Socket, Type};
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use super::binary;
+use super::client::{Client, ClientChannel};
+use super::connection::{self, Connection, ConnectionId, ConnectionStats};
+use super::datagram::DatagramSender;
+use super::datagram_buffer::DatagramBuffer;
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Protocol};
+use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::net;
+use super::relay_config::RelayConfig;
+use super::selector::Selector;
+
+const TAG: &str = "RawConnection";
+
+const IPV4_HEADER_LENGTH: usize = 20;
+const TTL: u8 = 64;
+
+/// Raw IPv4 socket of a single protocol, connected to a single destination.
+struct RawSocket(Socket);
+
+impl DatagramSender for RawSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        retry_on_intr!(self.0.send(buf))
+    }
+}
+
+impl Evented for RawSocket {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// Flow of an IP protocol the relay does not parse (GRE, ESP…), relayed verbatim through a raw
+/// socket of that protocol.
+///
+/// Like for `IcmpConnection`, raw sockets require CAP_NET_RAW.
+pub struct RawConnection {
+    id: ConnectionId,
+    self_weak: Weak<RefCell<RawConnection>>,
+    client: Weak<RefCell<Client>>,
+    socket: RawSocket,
+    interests: Ready,
+    token: Token,
+    client_to_network: DatagramBuffer,
+    // IPv4 header of the packets sent to the client, only the total length and the checksum
+    // change
+    reply_header: [u8; IPV4_HEADER_LENGTH],
+    max_payload_length: usize,
+    buffer: Box<[u8; MAX_PACKET_LENGTH]>,
+    // the last send failed with ENOBUFS, do not poll for writability until the retry timer
+    no_buffer_space: bool,
+    closed: bool,
+    idle_since: Instant,
+    created_at: Instant,
+    idle_timeout: Duration,
+    stats: ConnectionStats,
+}
+
+impl RawConnection {
+    #[allow(clippy::needless_pass_by_value)] // semantically, the header is consumed
+    pub fn create(
+        selector: &mut Selector,
+        id: ConnectionId,
+        client: Weak<RefCell<Client>>,
+        ipv4_header: Ipv4Header,
+        config: &RelayConfig,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        cx_info!(target: TAG, id, "Open");
+        let socket = Self::create_socket(&id, config, ipv4_header.data())?;
+        let mut client_to_network =
+            DatagramBuffer::with_max_datagrams(config.udp_buffer_datagrams());
+        client_to_network.set_policy(config.datagram_queue_policy());
+        let interests = Ready::readable();
+        let rc = Rc::new(RefCell::new(Self {
+            id,
+            self_weak: Weak::new(),
+            client,
+            socket,
+            interests,
+            token: Token(0), // default value, will be set afterwards
+            client_to_network,
+            reply_header: Self::build_reply_header(&ipv4_header),
+            max_payload_length: config.mtu() as usize - IPV4_HEADER_LENGTH,
+            buffer: Box::new([0; MAX_PACKET_LENGTH]),
+            no_buffer_space: false,
+            closed: false,
+            idle_since: Instant::now(),
+            created_at: Instant::now(),
+            // there is no way to know when the flow ends, so expire it like a UDP flow
+            idle_timeout: config.udp_idle_timeout(),
+            stats: ConnectionStats::default(),
+        }));
+
+        {
+            let mut self_ref = rc.borrow_mut();
+            self_ref.self_weak = Rc::downgrade(&rc);
+
+            let rc2 = rc.clone();
+            // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
+            let handler =
+                move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+            let token =
+                selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
+            self_ref.token = token;
+        }
+        Ok(rc)
+    }
+
+    fn create_socket(
+        id: &ConnectionId,
+        config: &RelayConfig,
+        ipv4_header_data: &Ipv4HeaderData,
+    ) -> io::Result<RawSocket> {
+        let number = match id.protocol() {
+            Protocol::Other(number) => number,
+            p => panic!("Raw connection for a supported protocol: {:?}", p),
+        };
+        let socket = net::create_outbound_socket(config, Type::RAW, Some((number as i32).into()))?;
+        net::set_outbound_tos(&socket, config, ipv4_header_data)?;
+        // only receive the packets from the destination
+        socket.connect(&id.rewritten_destination().into())?;
+        socket.set_nonblocking(true)?;
+        Ok(RawSocket(socket))
+    }
+
+    // the packets are received from the destination of the client packets
+    fn build_reply_header(ipv4_header: &Ipv4Header) -> [u8; IPV4_HEADER_LENGTH] {
+        let original = ipv4_header.raw();
+        let mut header = [0u8; IPV4_HEADER_LENGTH];
+        header[0] = 4 << 4 | (IPV4_HEADER_LENGTH / 4) as u8; // version_and_ihl
+        header[8] = TTL;
+        header[9] = original[9]; // protocol
+        header[12..16].copy_from_slice(&original[16..20]);
+        header[16..20].copy_from_slice(&original[12..16]);
+        header
+    }
+
+    fn remove_from_router(&self) {
+        // route is embedded in router which is embedded in client: the client necessarily exists
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        client.router().remove(self);
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        #[allow(clippy::match_wild_err_arm)]
+        match self.process(selector, event) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
+            }
+            Err(_) => panic!("Unexpected unhandled error"),
+        }
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
+        if !self.closed {
+            self.touch();
+            let ready = event.readiness();
+            if ready.is_readable() || ready.is_writable() {
+                if ready.is_writable() {
+                    self.process_send(selector)?;
+                    if self.no_buffer_space {
+                        self.schedule_send_retry();
+                    }
+                }
+                if !self.closed && ready.is_readable() {
+                    self.process_receive(selector)?;
+                }
+                if !self.closed {
+                    self.update_interests(selector);
+                }
+            } else {
+                // error or hup
+                self.close(selector);
+            }
+            if self.closed {
+                // on_ready is not called from the router, so the connection must remove itself
+                self.remove_from_router();
+            }
+        }
+        Ok(())
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_send(&mut self, selector: &mut Selector) -> io::Result<()> {
+        match self.write() {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
+            }
+            Err(ref err) if net::is_no_buffer_space(err) => {
+                // the datagrams are kept, and retried once the retry timer elapses
+                cx_debug!(target: TAG, self.id, "No buffer space, retrying later");
+                self.no_buffer_space = true;
+            }
+            Err(err) => {
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot write: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
+        match self.read(selector) {
+            Ok(_) => (),
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    // rethrow
+                    return Err(err);
+                }
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot read: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
+        // a raw IPv4 socket receives the whole packet, including its IPv4 header
+        let size = retry_on_intr!((&self.socket.0).read(&mut self.buffer[IPV4_HEADER_LENGTH..]))?;
+        let payload_length = match Self::strip_ipv4_header(
+            &mut self.buffer[IPV4_HEADER_LENGTH..IPV4_HEADER_LENGTH + size],
+        ) {
+            Some(payload_length) => payload_length,
+            None => {
+                cx_warn!(target: TAG, self.id, "Dropping malformed packet");
+                return Ok(());
+            }
+        };
+        if payload_length > self.max_payload_length {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Dropping packet too large for the MTU ({} > {})",
+                payload_length,
+                self.max_payload_length
+            );
+            return Ok(());
+        }
+        let total_length = IPV4_HEADER_LENGTH + payload_length;
+        self.buffer[..IPV4_HEADER_LENGTH].copy_from_slice(&self.reply_header);
+        BigEndian::write_u16(&mut self.buffer[2..4], total_length as u16);
+        let mut ipv4_packet = Ipv4Packet::parse(&mut self.buffer[..total_length]);
+        ipv4_packet.ipv4_header_mut().update_checksum();
+
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        match client_rc
+            .borrow_mut()
+            .send_to_client(selector, &ipv4_packet)
+        {
+            Ok(_) => {
+                self.stats.record_rx(payload_length);
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Packet ({} bytes) sent to client",
+                    ipv4_packet.length()
+                );
+                if log_enabled!(target: TAG, Level::Trace) {
+                    cx_trace!(
+                        target: TAG,
+                        self.id,
+                        "{}",
+                        binary::build_trace_packet_string(ipv4_packet.raw())
+                    );
+                }
+            }
+            Err(_) => cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet"),
+        }
+        Ok(())
+    }
+
+    /// Move the payload of the IPv4 packet in `raw` (exactly) to its start, and return its
+    /// length.
+    ///
+    /// Return `None` if the packet is malformed.
+    fn strip_ipv4_header(raw: &mut [u8]) -> Option<usize> {
+        if raw.len() < IPV4_HEADER_LENGTH || raw[0] >> 4 != 4 {
+            return None;
+        }
+        let header_length = ((raw[0] & 0xf) << 2) as usize;
+        if header_length < IPV4_HEADER_LENGTH || header_length > raw.len() {
+            return None;
+        }
+        // the received size is authoritative, some systems rewrite the total length field
+        raw.copy_within(header_length.., 0);
+        Some(raw.len() - header_length)
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        let (datagrams, bytes) = self.client_to_network.write_batch_to(&mut self.socket)?;
+        self.stats.record_tx_datagrams(datagrams, bytes);
+        Ok(())
+    }
+
+    /// Poll for writability again once `NO_BUFFER_SPACE_RETRY_DELAY` elapsed.
+    ///
+    /// To be used if called by on_ready() (so the client is not borrowed yet).
+    fn schedule_send_retry(&self) {
+        let deadline = Instant::now() + net::NO_BUFFER_SPACE_RETRY_DELAY;
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let connection: Weak<RefCell<dyn Connection>> = self.self_weak.clone();
+        client_rc
+            .borrow_mut()
+            .router()
+            .schedule_timeout(deadline, connection);
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let ready = if self.client_to_network.is_empty() || self.no_buffer_space {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
+        if self.interests != ready {
+            // interests must be changed
+            self.interests = ready;
+            selector
+                .reregister(&self.socket, self.token, ready, PollOpt::level())
+                .expect("Cannot register on poll");
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+    }
+
+    fn payload<'a>(ipv4_packet: &'a Ipv4Packet) -> &'a [u8] {
+        let header_length = ipv4_packet.ipv4_header_data().header_length() as usize;
+        &ipv4_packet.raw()[header_length..]
+    }
+}
+
+impl Connection for RawConnection {
+    fn id(&self) -> &ConnectionId {
+        &self.id
+    }
+
+    fn send_to_network(
+        &mut self,
+        selector: &mut Selector,
+        _: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        match self.client_to_network.read_from(Self::payload(ipv4_packet)) {
+            Ok(evicted) => {
+                self.stats.record_tx_evicted(evicted);
+                self.update_interests(selector);
+            }
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.stats.record_tx_dropped();
+            }
+        }
+    }
+
+    fn can_accept(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        self.client_to_network
+            .accepts(Self::payload(ipv4_packet).len())
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        cx_info!(target: TAG, self.id, "Close");
+        self.closed = true;
+        if let Err(err) = selector.deregister(&self.socket, self.token) {
+            // do not panic, this can happen in mio
+            // see <https://github.com/Genymobile/gnirehtet/issues/136>
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Fail to deregister raw socket: {:?}",
+                err
+            );
+        }
+        // socket will be closed by RAII
+    }
+
+    fn is_expired(&self) -> bool {
+        connection::is_idle_expired(self.idle_since, self.idle_timeout)
+    }
+
+    fn expiry(&self) -> Option<Instant> {
+        Some(self.idle_since + self.idle_timeout)
+    }
+
+    fn handle_timeout(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        if self.no_buffer_space {
+            self.no_buffer_space = false;
+            self.update_interests(selector);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn idle_since(&self) -> Instant {
+        self.idle_since
+    }
+
+    fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_header_with_options() {
+        // 24-byte header (with a 4-byte option), then a 4-byte payload
+        let mut raw = [0u8; 28];
+        raw[0] = 4 << 4 | 6;
+        raw[24..].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(Some(4), RawConnection::strip_ipv4_header(&mut raw));
+        assert_eq!([1, 2, 3, 4], raw[..4]);
+
+        let mut truncated = [4 << 4 | 5, 0, 0, 20];
+        assert_eq!(None, RawConnection::strip_ipv4_header(&mut truncated));
+    }
+}
@@ -16,56 +16,342 @@
 
 use chrono::Local;
 use log::*;
-use mio::Events;
+use mio::{Events, PollOpt, Ready, Registration, SetReadiness};
 use std::cell::RefCell;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::io;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "metrics")]
+use super::connection::ConnectionId;
+use super::connection::{
+    ClosedConnection, OpenedConnection, SharedConnectionCloseListener, SharedConnectionOpenListener,
+};
+use super::connection_log::ConnectionLog;
+use super::dns_blocklist::DnsBlocklist;
+use super::icmp_socket::{IcmpSocket, IcmpSocketKind};
+use super::listener::Listener;
+#[cfg(feature = "metrics")]
+use super::metrics::RelayMetrics;
+#[cfg(feature = "metrics")]
+use super::metrics_server::MetricsServer;
+use super::pcap::{PcapWriter, SharedPcapWriter};
+use super::relay_config::RelayConfig;
 use super::selector::Selector;
+#[cfg(target_os = "linux")]
+use super::tun_device::TunDevice;
 use super::tunnel_server::TunnelServer;
-use super::udp_connection::IDLE_TIMEOUT_SECONDS;
 
 const TAG: &str = "Relay";
 const CLEANING_INTERVAL_SECONDS: i64 = 60;
 
 pub struct Relay {
-    port: u16,
+    config: Rc<RelayConfig>,
+    connection_open_listeners: Vec<SharedConnectionOpenListener>,
+    connection_close_listeners: Vec<SharedConnectionCloseListener>,
+    // wake up the poll loop on shutdown request
+    shutdown_registration: Registration,
+    shutdown_handle: ShutdownHandle,
+}
+
+/// Request the relay to stop, from any thread (typically a signal handler).
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    set_readiness: SetReadiness,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Err(err) = self.set_readiness.set_readiness(Ready::readable()) {
+            error!(target: TAG, "Cannot wake up the relay: {}", err);
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
 }
 
 impl Relay {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(config: RelayConfig) -> Self {
+        let (shutdown_registration, set_readiness) = Registration::new2();
+        Self {
+            config: Rc::new(config),
+            connection_open_listeners: Vec::new(),
+            connection_close_listeners: Vec::new(),
+            shutdown_registration,
+            shutdown_handle: ShutdownHandle {
+                requested: Arc::new(AtomicBool::new(false)),
+                set_readiness,
+            },
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// Register a listener to notify of every connection opened by the relay (except UDP over
+    /// IPv6): once created, then for TCP once its handshake with the client is complete.
+    ///
+    /// It is called from the relay thread, and must not block.
+    pub fn add_connection_open_listener<L>(&mut self, listener: L)
+    where
+        L: Listener<OpenedConnection> + 'static,
+    {
+        self.connection_open_listeners.push(Rc::new(listener));
+    }
+
+    /// Register a listener to notify of every connection closed by the relay (except UDP over
+    /// IPv6), with its final traffic.
+    ///
+    /// It is called from the relay thread, and must not block.
+    pub fn add_connection_close_listener<L>(&mut self, listener: L)
+    where
+        L: Listener<ClosedConnection> + 'static,
+    {
+        self.connection_close_listeners.push(Rc::new(listener));
     }
 
     pub fn run(&self) -> io::Result<()> {
         let mut selector = Selector::create().unwrap();
-        let tunnel_server = TunnelServer::create(self.port, &mut selector)?;
-        info!(target: TAG, "Relay server started");
-        self.poll_loop(&mut selector, &tunnel_server)
+        // nothing to handle, the poll loop checks for shutdown requests on every wakeup
+        selector.register(
+            &self.shutdown_registration,
+            |_: &mut Selector, _| (),
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let pcap_writer = match self.config.pcap_path() {
+            Some(path) => {
+                info!(target: TAG, "Capturing packets to {}", path.display());
+                Some(PcapWriter::create_shared(path)?)
+            }
+            None => None,
+        };
+        let connection_log = match self.config.connection_log_path() {
+            Some(path) => {
+                info!(target: TAG, "Logging the connections to {}", path.display());
+                Some(ConnectionLog::open_shared(path)?)
+            }
+            None => None,
+        };
+        let dns_blocklist = match self.config.dns_blocklist_path() {
+            Some(path) => {
+                let dns_blocklist = DnsBlocklist::load(path)?;
+                info!(
+                    target: TAG,
+                    "Blocking {} domains from {}",
+                    dns_blocklist.len(),
+                    path.display()
+                );
+                Some(Rc::new(dns_blocklist))
+            }
+            None => None,
+        };
+        let config = self.check_icmp_capability();
+        let tunnel_server = TunnelServer::create(
+            config,
+            pcap_writer.clone(),
+            self.connection_open_listeners.clone(),
+            self.connection_close_listeners.clone(),
+            dns_blocklist,
+            connection_log,
+            &mut selector,
+        )?;
+        if let Some(name) = self.config.tun_interface() {
+            Self::attach_tun_device(&mut selector, &tunnel_server, name)?;
+        }
+        self.start_metrics_server(&mut selector, &tunnel_server)?;
+        let local_addr = tunnel_server.borrow().local_addr()?;
+        info!(target: TAG, "Relay server started on {}", local_addr);
+        tunnel_server.borrow_mut().refill_socket_pool();
+        self.poll_loop(&mut selector, &tunnel_server, pcap_writer.as_ref())?;
+        Self::drain(&mut selector, &tunnel_server);
+        if let Some(pcap_writer) = pcap_writer {
+            // the file is closed once the last reference is dropped
+            if let Err(err) = pcap_writer.borrow_mut().flush() {
+                error!(target: TAG, "Cannot flush packet capture: {}", err);
+            }
+        }
+        info!(target: TAG, "Relay server stopped");
+        Ok(())
+    }
+
+    // rather than failing for every ICMP connection, disable ICMP relaying if no ICMP socket may
+    // be opened (the denied raw sockets are already replaced by datagram sockets)
+    fn check_icmp_capability(&self) -> Rc<RelayConfig> {
+        Self::apply_icmp_capability(&self.config, IcmpSocket::check_capability)
+    }
+
+    // the capability is only checked if ICMP is relayed
+    fn apply_icmp_capability<F>(config: &Rc<RelayConfig>, check_capability: F) -> Rc<RelayConfig>
+    where
+        F: FnOnce() -> Option<IcmpSocketKind>,
+    {
+        if !config.relay_icmp() || check_capability().is_some() {
+            return config.clone();
+        }
+        let mut config = (**config).clone();
+        config.set_relay_icmp(false);
+        Rc::new(config)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn attach_tun_device(
+        selector: &mut Selector,
+        tunnel_server: &Rc<RefCell<TunnelServer>>,
+        name: &str,
+    ) -> io::Result<()> {
+        let device = TunDevice::open(name)?;
+        tunnel_server
+            .borrow_mut()
+            .attach_tun_device(selector, device)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn attach_tun_device(
+        _: &mut Selector,
+        _: &Rc<RefCell<TunnelServer>>,
+        name: &str,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot open TUN interface {}: unsupported platform", name),
+        ))
+    }
+
+    #[cfg(feature = "metrics")]
+    fn start_metrics_server(
+        &self,
+        selector: &mut Selector,
+        tunnel_server: &Rc<RefCell<TunnelServer>>,
+    ) -> io::Result<()> {
+        if let Some(addr) = self.config.metrics_address() {
+            let weak = Rc::downgrade(tunnel_server);
+            let load = selector.shared_load();
+            let source = Rc::new(move || {
+                let mut metrics: RelayMetrics = weak
+                    .upgrade()
+                    .map(|tunnel_server| tunnel_server.borrow().metrics())
+                    .unwrap_or_default();
+                metrics.selector_polls = load.get().polls;
+                metrics.selector_events = load.get().events;
+                metrics
+            });
+            let weak = Rc::downgrade(tunnel_server);
+            let connections = Rc::new(move |now| {
+                weak.upgrade()
+                    .map(|tunnel_server| tunnel_server.borrow().connections_info(now))
+                    .unwrap_or_default()
+            });
+            let weak = Rc::downgrade(tunnel_server);
+            let resetter = Rc::new(
+                move |selector: &mut Selector, client_id, id: &ConnectionId| {
+                    weak.upgrade().is_some_and(|tunnel_server| {
+                        tunnel_server
+                            .borrow_mut()
+                            .reset_connection(selector, client_id, id)
+                    })
+                },
+            );
+            // the selector keeps the server alive
+            let metrics_server =
+                MetricsServer::create(selector, &addr, source, connections, resetter)?;
+            let local_addr = metrics_server.borrow().local_addr()?;
+            info!(target: TAG, "Serving metrics on http://{}/metrics", local_addr);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn start_metrics_server(
+        &self,
+        _: &mut Selector,
+        _: &Rc<RefCell<TunnelServer>>,
+    ) -> io::Result<()> {
+        if self.config.metrics_address().is_some() {
+            warn!(
+                target: TAG,
+                "Metrics requested, but the relay is built without the \"metrics\" feature"
+            );
+        }
+        Ok(())
+    }
+
+    /// Close all the clients, and thus all their connections.
+    fn drain(selector: &mut Selector, tunnel_server: &Rc<RefCell<TunnelServer>>) {
+        // the clients remove themselves from the tunnel server on close, so do not borrow it
+        let clients = tunnel_server.borrow().clients().to_vec();
+        info!(target: TAG, "Closing {} client(s)", clients.len());
+        for client in clients {
+            client.borrow_mut().close(selector);
+        }
     }
 
     fn poll_loop(
         &self,
         selector: &mut Selector,
         tunnel_server: &Rc<RefCell<TunnelServer>>,
+        pcap_writer: Option<&SharedPcapWriter>,
     ) -> io::Result<()> {
         let mut events = Events::with_capacity(1024);
-        // no connection may expire before the UDP idle timeout delay
-        let mut next_cleaning_deadline = Local::now().timestamp() + IDLE_TIMEOUT_SECONDS as i64;
+        // no UDP connection may expire before the UDP idle timeout delay
+        let first_cleaning_delay = self.config.udp_idle_timeout().as_secs() as i64;
+        let mut next_cleaning_deadline = Local::now().timestamp() + first_cleaning_delay;
         loop {
             retry_on_intr!({
                 let timeout_seconds = max(0, next_cleaning_deadline - Local::now().timestamp());
-                let timeout = Some(Duration::new(timeout_seconds as u64, 0));
-                selector.poll(&mut events, timeout)
+                let mut timeout = Duration::new(timeout_seconds as u64, 0);
+                // wake up as soon as a throttled client may send again
+                if let Some(deadline) = tunnel_server.borrow().next_throttle_deadline() {
+                    timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+                }
+                // and as soon as a connection may expire
+                if let Some(deadline) = tunnel_server.borrow().next_expiry_deadline() {
+                    timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+                }
+                // and as soon as the captured packets must be flushed
+                if let Some(deadline) =
+                    pcap_writer.and_then(|pcap_writer| pcap_writer.borrow().next_flush_deadline())
+                {
+                    timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+                }
+                selector.poll(&mut events, Some(timeout))
             })?;
 
+            if self.shutdown_handle.is_requested() {
+                info!(target: TAG, "Shutdown requested");
+                return Ok(());
+            }
+
+            let resumed = tunnel_server.borrow_mut().resume_throttled(selector);
+            let flushed = pcap_writer.is_some_and(|pcap_writer| {
+                pcap_writer
+                    .borrow_mut()
+                    .flush_if_due(Instant::now())
+                    .unwrap_or_else(|err| {
+                        error!(target: TAG, "Cannot flush packet capture: {}", err);
+                        true
+                    })
+            });
+
             let now = Local::now().timestamp();
+            let expiry_reached = tunnel_server
+                .borrow()
+                .next_expiry_deadline()
+                .is_some_and(|deadline| deadline <= Instant::now());
             if now >= next_cleaning_deadline {
                 tunnel_server.borrow_mut().clean_up(selector);
                 next_cleaning_deadline = now + CLEANING_INTERVAL_SECONDS;
-            } else if events.is_empty() {
+            } else if expiry_reached {
+                tunnel_server.borrow_mut().clean_up(selector);
+            } else if events.is_empty() && !resumed && !flushed {
                 debug!(
                     target: TAG,
                     "Spurious wakeup: poll() returned without any event"
@@ -74,6 +360,52 @@ impl Relay {
             }
 
             selector.run_handlers(&events);
+
+            // the connections may have drained their buffers while handling the events
+            tunnel_server.borrow_mut().resume_blocked(selector);
+            tunnel_server.borrow_mut().refill_socket_pool();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::Type;
+    use std::sync::Once;
+
+    // the probe of the ICMP sockets fails with EPERM, for raw sockets or for any socket
+    fn check_denied_capability(dgram_denied: bool, reported: &Once) -> Option<IcmpSocketKind> {
+        IcmpSocket::check_capability_with(
+            |socket_type| {
+                if socket_type == Type::RAW || dgram_denied {
+                    Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                } else {
+                    Ok(())
+                }
+            },
+            reported,
+            |_, _| (),
+        )
+    }
+
+    #[test]
+    fn disable_icmp_if_denied() {
+        let reported = Once::new();
+        let config = Rc::new(RelayConfig::new(0));
+        let checked =
+            Relay::apply_icmp_capability(&config, || check_denied_capability(true, &reported));
+        assert!(!checked.relay_icmp());
+
+        // the datagram sockets are used instead of the raw sockets
+        let checked =
+            Relay::apply_icmp_capability(&config, || check_denied_capability(false, &reported));
+        assert!(checked.relay_icmp());
+
+        // not probed if ICMP is not relayed anyway
+        let mut config = RelayConfig::new(0);
+        config.set_relay_icmp(false);
+        let checked = Relay::apply_icmp_capability(&Rc::new(config), || panic!("Probed"));
+        assert!(!checked.relay_icmp());
+    }
+}
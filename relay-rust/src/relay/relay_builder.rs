@@ -0,0 +1,480 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use super::cidr::CidrSet;
+use super::datagram_buffer::QueuePolicy;
+use super::relay_config::{RelayConfig, UnknownProtocolPolicy, MIN_MTU};
+
+/// Fluent construction of a validated `RelayConfig`, to embed the relay in another program.
+///
+/// For example:
+///
+/// ```no_run
+/// use relaylib::{Relay, RelayBuilder};
+/// use std::time::Duration;
+///
+/// let config = RelayBuilder::new(31416)
+///     .udp_idle_timeout(Duration::from_secs(30))
+///     .rate_limit(Some(1 << 20))
+///     .build()
+///     .expect("Invalid configuration");
+/// Relay::new(config).run().unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct RelayBuilder {
+    config: RelayConfig,
+}
+
+impl RelayBuilder {
+    pub fn new(port: u16) -> Self {
+        Self {
+            config: RelayConfig::new(port),
+        }
+    }
+
+    pub fn listen_address(mut self, listen_address: IpAddr) -> Self {
+        self.config.set_listen_address(listen_address);
+        self
+    }
+
+    pub fn tcp_idle_timeout(mut self, tcp_idle_timeout: Option<Duration>) -> Self {
+        self.config.set_tcp_idle_timeout(tcp_idle_timeout);
+        self
+    }
+
+    pub fn tcp_initial_rto(mut self, tcp_initial_rto: Duration) -> Self {
+        self.config.set_tcp_initial_rto(tcp_initial_rto);
+        self
+    }
+
+    pub fn tcp_max_retransmissions(mut self, tcp_max_retransmissions: u32) -> Self {
+        self.config
+            .set_tcp_max_retransmissions(tcp_max_retransmissions);
+        self
+    }
+
+    pub fn tcp_keepalive_idle(mut self, tcp_keepalive_idle: Option<Duration>) -> Self {
+        self.config.set_tcp_keepalive_idle(tcp_keepalive_idle);
+        self
+    }
+
+    pub fn tcp_keepalive_interval(mut self, tcp_keepalive_interval: Duration) -> Self {
+        self.config
+            .set_tcp_keepalive_interval(tcp_keepalive_interval);
+        self
+    }
+
+    pub fn tcp_keepalive_count(mut self, tcp_keepalive_count: u32) -> Self {
+        self.config.set_tcp_keepalive_count(tcp_keepalive_count);
+        self
+    }
+
+    pub fn tcp_socket_pool_size(mut self, tcp_socket_pool_size: usize) -> Self {
+        self.config.set_tcp_socket_pool_size(tcp_socket_pool_size);
+        self
+    }
+
+    pub fn udp_idle_timeout(mut self, udp_idle_timeout: Duration) -> Self {
+        self.config.set_udp_idle_timeout(udp_idle_timeout);
+        self
+    }
+
+    pub fn icmp_idle_timeout(mut self, icmp_idle_timeout: Duration) -> Self {
+        self.config.set_icmp_idle_timeout(icmp_idle_timeout);
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.config.set_mtu(mtu);
+        self
+    }
+
+    pub fn outbound_mtu(mut self, outbound_mtu: Option<u16>) -> Self {
+        self.config.set_outbound_mtu(outbound_mtu);
+        self
+    }
+
+    pub fn verify_ipv4_checksums(mut self, verify_ipv4_checksums: bool) -> Self {
+        self.config.set_verify_ipv4_checksums(verify_ipv4_checksums);
+        self
+    }
+
+    pub fn verify_echo_payloads(mut self, verify_echo_payloads: bool) -> Self {
+        self.config.set_verify_echo_payloads(verify_echo_payloads);
+        self
+    }
+
+    pub fn relay_icmp(mut self, relay_icmp: bool) -> Self {
+        self.config.set_relay_icmp(relay_icmp);
+        self
+    }
+
+    pub fn reply_icmp_prohibited(mut self, reply_icmp_prohibited: bool) -> Self {
+        self.config.set_reply_icmp_prohibited(reply_icmp_prohibited);
+        self
+    }
+
+    pub fn relay_raw(mut self, relay_raw: bool) -> Self {
+        self.config.set_relay_raw(relay_raw);
+        self
+    }
+
+    pub fn unknown_protocol_policy(
+        mut self,
+        unknown_protocol_policy: UnknownProtocolPolicy,
+    ) -> Self {
+        self.config
+            .set_unknown_protocol_policy(unknown_protocol_policy);
+        self
+    }
+
+    pub fn strip_ipv4_options(mut self, strip_ipv4_options: bool) -> Self {
+        self.config.set_strip_ipv4_options(strip_ipv4_options);
+        self
+    }
+
+    pub fn preserve_ttl(mut self, preserve_ttl: bool) -> Self {
+        self.config.set_preserve_ttl(preserve_ttl);
+        self
+    }
+
+    pub fn preserve_tos(mut self, preserve_tos: bool) -> Self {
+        self.config.set_preserve_tos(preserve_tos);
+        self
+    }
+
+    pub fn remark_tos(mut self, remark_tos: Option<u8>) -> Self {
+        self.config.set_remark_tos(remark_tos);
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.set_dry_run(dry_run);
+        self
+    }
+
+    pub fn log_target_names(mut self, log_target_names: bool) -> Self {
+        self.config.set_log_target_names(log_target_names);
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.config.set_rate_limit(rate_limit);
+        self
+    }
+
+    pub fn rate_limit_burst(mut self, rate_limit_burst: u64) -> Self {
+        self.config.set_rate_limit_burst(rate_limit_burst);
+        self
+    }
+
+    pub fn packet_rate_limit(mut self, packet_rate_limit: Option<u64>) -> Self {
+        self.config.set_packet_rate_limit(packet_rate_limit);
+        self
+    }
+
+    pub fn packet_rate_limit_burst(mut self, packet_rate_limit_burst: u64) -> Self {
+        self.config
+            .set_packet_rate_limit_burst(packet_rate_limit_burst);
+        self
+    }
+
+    pub fn icmp_rate_limit(mut self, icmp_rate_limit: Option<u64>) -> Self {
+        self.config.set_icmp_rate_limit(icmp_rate_limit);
+        self
+    }
+
+    pub fn icmp_rate_limit_burst(mut self, icmp_rate_limit_burst: u64) -> Self {
+        self.config.set_icmp_rate_limit_burst(icmp_rate_limit_burst);
+        self
+    }
+
+    pub fn local_reply_ttl(mut self, local_reply_ttl: u8) -> Self {
+        self.config.set_local_reply_ttl(local_reply_ttl);
+        self
+    }
+
+    pub fn tun_interface(mut self, tun_interface: Option<String>) -> Self {
+        self.config.set_tun_interface(tun_interface);
+        self
+    }
+
+    pub fn tcp_buffer_high_watermark(mut self, tcp_buffer_high_watermark: usize) -> Self {
+        self.config
+            .set_tcp_buffer_high_watermark(tcp_buffer_high_watermark);
+        self
+    }
+
+    pub fn tcp_buffer_low_watermark(mut self, tcp_buffer_low_watermark: Option<usize>) -> Self {
+        self.config
+            .set_tcp_buffer_low_watermark(tcp_buffer_low_watermark);
+        self
+    }
+
+    pub fn udp_buffer_datagrams(mut self, udp_buffer_datagrams: usize) -> Self {
+        self.config.set_udp_buffer_datagrams(udp_buffer_datagrams);
+        self
+    }
+
+    pub fn icmp_buffer_datagrams(mut self, icmp_buffer_datagrams: usize) -> Self {
+        self.config.set_icmp_buffer_datagrams(icmp_buffer_datagrams);
+        self
+    }
+
+    pub fn icmp_max_payload(mut self, icmp_max_payload: usize) -> Self {
+        self.config.set_icmp_max_payload(icmp_max_payload);
+        self
+    }
+
+    pub fn datagram_queue_policy(mut self, datagram_queue_policy: QueuePolicy) -> Self {
+        self.config.set_datagram_queue_policy(datagram_queue_policy);
+        self
+    }
+
+    pub fn socket_receive_buffer_size(mut self, socket_receive_buffer_size: Option<usize>) -> Self {
+        self.config
+            .set_socket_receive_buffer_size(socket_receive_buffer_size);
+        self
+    }
+
+    pub fn socket_send_buffer_size(mut self, socket_send_buffer_size: Option<usize>) -> Self {
+        self.config
+            .set_socket_send_buffer_size(socket_send_buffer_size);
+        self
+    }
+
+    pub fn allowed_destinations(mut self, allowed_destinations: CidrSet) -> Self {
+        self.config.set_allowed_destinations(allowed_destinations);
+        self
+    }
+
+    pub fn denied_destinations(mut self, denied_destinations: CidrSet) -> Self {
+        self.config.set_denied_destinations(denied_destinations);
+        self
+    }
+
+    pub fn reply_denied_prohibited(mut self, reply_denied_prohibited: bool) -> Self {
+        self.config
+            .set_reply_denied_prohibited(reply_denied_prohibited);
+        self
+    }
+
+    /// Validate the settings, and return the resulting configuration.
+    pub fn build(self) -> Result<RelayConfig, String> {
+        let config = self.config;
+        if config.listen_address().is_multicast() {
+            return Err(format!(
+                "Cannot listen on a multicast address: {}",
+                config.listen_address()
+            ));
+        }
+        if config.mtu() < MIN_MTU {
+            return Err(format!(
+                "MTU too small: {} (minimum {})",
+                config.mtu(),
+                MIN_MTU
+            ));
+        }
+        if let Some(outbound_mtu) = config.outbound_mtu().filter(|&mtu| mtu < MIN_MTU) {
+            return Err(format!(
+                "Outbound MTU too small: {} (minimum {})",
+                outbound_mtu, MIN_MTU
+            ));
+        }
+        if config.tcp_idle_timeout() == Some(Duration::from_secs(0)) {
+            return Err("The TCP idle timeout may not be zero".to_string());
+        }
+        if config.tcp_initial_rto() == Duration::from_secs(0) {
+            return Err("The TCP retransmission timeout may not be zero".to_string());
+        }
+        // the keepalive options are set in seconds
+        let one_second = Duration::from_secs(1);
+        if config
+            .tcp_keepalive_idle()
+            .is_some_and(|idle| idle < one_second)
+            || config.tcp_keepalive_interval() < one_second
+        {
+            return Err("The TCP keepalive delays must be at least 1 second".to_string());
+        }
+        if config.tcp_keepalive_count() == 0 {
+            return Err("The TCP keepalive must send at least one probe".to_string());
+        }
+        if config.udp_idle_timeout() == Duration::from_secs(0) {
+            return Err("The UDP idle timeout may not be zero".to_string());
+        }
+        if config.icmp_idle_timeout() == Duration::from_secs(0) {
+            return Err("The ICMP idle timeout may not be zero".to_string());
+        }
+        if config.rate_limit() == Some(0) {
+            return Err("The rate limit may not be zero".to_string());
+        }
+        if config.rate_limit().is_some() && config.rate_limit_burst() == 0 {
+            return Err("The rate limit burst may not be zero".to_string());
+        }
+        if config.packet_rate_limit() == Some(0) {
+            return Err("The packet rate limit may not be zero".to_string());
+        }
+        if config.packet_rate_limit().is_some() && config.packet_rate_limit_burst() == 0 {
+            return Err("The packet rate limit burst may not be zero".to_string());
+        }
+        if config.icmp_rate_limit() == Some(0) {
+            return Err("The ICMP rate limit may not be zero".to_string());
+        }
+        if config.icmp_rate_limit().is_some() && config.icmp_rate_limit_burst() == 0 {
+            return Err("The ICMP rate limit burst may not be zero".to_string());
+        }
+        if config.local_reply_ttl() == 0 {
+            return Err("The local reply TTL may not be zero".to_string());
+        }
+        // a full segment must always fit
+        if config.tcp_buffer_high_watermark() < usize::from(config.mtu()) {
+            return Err(format!(
+                "TCP buffer high watermark too small: {} (minimum {})",
+                config.tcp_buffer_high_watermark(),
+                config.mtu()
+            ));
+        }
+        if config.tcp_buffer_low_watermark() > config.tcp_buffer_high_watermark() {
+            return Err("The TCP low watermark may not exceed the high watermark".to_string());
+        }
+        // no datagram could ever be relayed
+        if config.udp_buffer_datagrams() == 0 {
+            return Err("The UDP buffer must hold at least one datagram".to_string());
+        }
+        if config.icmp_buffer_datagrams() == 0 {
+            return Err("The ICMP buffer must hold at least one datagram".to_string());
+        }
+        if config.socket_receive_buffer_size() == Some(0)
+            || config.socket_send_buffer_size() == Some(0)
+        {
+            return Err("The socket buffer sizes may not be zero".to_string());
+        }
+        Ok(config)
+    }
+}
+
+impl From<RelayConfig> for RelayBuilder {
+    /// Start from an existing configuration, for instance loaded from a file.
+    fn from(config: RelayConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_valid_config() {
+        let config = RelayBuilder::new(1234)
+            .listen_address("10.1.2.3".parse().unwrap())
+            .udp_idle_timeout(Duration::from_secs(30))
+            .mtu(1500)
+            .rate_limit(Some(1 << 20))
+            .icmp_buffer_datagrams(8)
+            .icmp_max_payload(512)
+            .socket_send_buffer_size(Some(1 << 17))
+            .tcp_buffer_high_watermark(1 << 16)
+            .build()
+            .unwrap();
+        assert_eq!(1234, config.port());
+        assert_eq!(
+            "10.1.2.3".parse::<IpAddr>().unwrap(),
+            config.listen_address()
+        );
+        assert_eq!(Duration::from_secs(30), config.udp_idle_timeout());
+        assert_eq!(1500, config.mtu());
+        assert_eq!(Some(1 << 20), config.rate_limit());
+        assert_eq!(8, config.icmp_buffer_datagrams());
+        assert_eq!(512, config.icmp_max_payload());
+        assert_eq!(Some(1 << 17), config.socket_send_buffer_size());
+        // the low watermark follows the high watermark
+        assert_eq!(1 << 16, config.tcp_buffer_low_watermark());
+    }
+
+    #[test]
+    fn reject_invalid_configs() {
+        assert!(RelayBuilder::new(0)
+            .listen_address("224.0.0.1".parse().unwrap())
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0).mtu(MIN_MTU - 1).build().is_err());
+        assert!(RelayBuilder::new(0).mtu(20).build().is_err());
+        assert!(RelayBuilder::new(0)
+            .outbound_mtu(Some(MIN_MTU - 1))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .udp_idle_timeout(Duration::from_secs(0))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .tcp_idle_timeout(Some(Duration::from_secs(0)))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .tcp_initial_rto(Duration::from_secs(0))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .tcp_keepalive_idle(Some(Duration::from_millis(500)))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0).tcp_keepalive_count(0).build().is_err());
+        assert!(RelayBuilder::new(0).rate_limit(Some(0)).build().is_err());
+        assert!(RelayBuilder::new(0)
+            .packet_rate_limit(Some(0))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .packet_rate_limit(Some(100))
+            .packet_rate_limit_burst(0)
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .icmp_rate_limit(Some(0))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .rate_limit(Some(1000))
+            .rate_limit_burst(0)
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0).local_reply_ttl(0).build().is_err());
+        // below the MTU
+        assert!(RelayBuilder::new(0)
+            .tcp_buffer_high_watermark(1000)
+            .tcp_buffer_low_watermark(Some(500))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .tcp_buffer_high_watermark(1 << 16)
+            .tcp_buffer_low_watermark(Some(1 << 17))
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .udp_buffer_datagrams(0)
+            .build()
+            .is_err());
+        assert!(RelayBuilder::new(0)
+            .socket_receive_buffer_size(Some(0))
+            .build()
+            .is_err());
+    }
+}
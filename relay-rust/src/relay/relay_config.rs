@@ -0,0 +1,861 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::cidr::CidrSet;
+use super::config_file;
+use super::datagram_buffer::QueuePolicy;
+use super::ipv4_packet::{MAX_PACKET_LENGTH, MTU};
+
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+pub const DEFAULT_ICMP_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+// rfc6298: the RTO should be set to 1 second before any RTT measurement
+pub const DEFAULT_TCP_INITIAL_RTO: Duration = Duration::from_secs(1);
+pub const DEFAULT_TCP_MAX_RETRANSMISSIONS: u32 = 5;
+// the defaults of Linux
+pub const DEFAULT_TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+pub const DEFAULT_TCP_KEEPALIVE_COUNT: u32 = 9;
+pub const DEFAULT_TCP_BUFFER_HIGH_WATERMARK: usize = 4 * MAX_PACKET_LENGTH;
+pub const DEFAULT_UDP_BUFFER_DATAGRAMS: usize = 4;
+pub const DEFAULT_ICMP_BUFFER_DATAGRAMS: usize = 4;
+// the largest payload of an echo request fitting an Ethernet frame without fragmentation
+pub const DEFAULT_ICMP_MAX_PAYLOAD: usize = 1472;
+pub const DEFAULT_RATE_LIMIT_BURST: u64 = 1 << 16;
+pub const DEFAULT_ICMP_RATE_LIMIT_BURST: u64 = 10;
+pub const DEFAULT_PACKET_RATE_LIMIT_BURST: u64 = 256;
+// the initial TTL of most Linux and BSD hosts
+pub const DEFAULT_LOCAL_REPLY_TTL: u8 = 64;
+// rfc791: every host must accept datagrams of 68 bytes
+pub const MIN_MTU: u16 = 68;
+
+/// Disposition of the packets of an IP protocol the relay cannot route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownProtocolPolicy {
+    /// Drop the packets silently.
+    Drop,
+    /// Drop the packets, logging each of them.
+    Log,
+    /// Drop the packets, replying with an ICMP Destination Unreachable (Protocol Unreachable).
+    Reply,
+}
+
+impl FromStr for UnknownProtocolPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(UnknownProtocolPolicy::Drop),
+            "log" => Ok(UnknownProtocolPolicy::Log),
+            "reply" => Ok(UnknownProtocolPolicy::Reply),
+            _ => Err(format!("Invalid unknown protocol policy: \"{}\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for UnknownProtocolPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            UnknownProtocolPolicy::Drop => "drop",
+            UnknownProtocolPolicy::Log => "log",
+            UnknownProtocolPolicy::Reply => "reply",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings of the relay server, shared by the tunnel server, the clients and their connections.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    port: u16,
+    listen_address: IpAddr,
+    tcp_idle_timeout: Option<Duration>,
+    tcp_initial_rto: Duration,
+    tcp_max_retransmissions: u32,
+    tcp_keepalive_idle: Option<Duration>,
+    tcp_keepalive_interval: Duration,
+    tcp_keepalive_count: u32,
+    tcp_socket_pool_size: usize,
+    udp_idle_timeout: Duration,
+    icmp_idle_timeout: Duration,
+    mtu: u16,
+    outbound_mtu: Option<u16>,
+    verify_ipv4_checksums: bool,
+    verify_echo_payloads: bool,
+    relay_icmp: bool,
+    reply_icmp_prohibited: bool,
+    relay_raw: bool,
+    unknown_protocol_policy: UnknownProtocolPolicy,
+    strip_ipv4_options: bool,
+    preserve_ttl: bool,
+    preserve_tos: bool,
+    remark_tos: Option<u8>,
+    dry_run: bool,
+    log_target_names: bool,
+    tcp_buffer_high_watermark: usize,
+    tcp_buffer_low_watermark: Option<usize>,
+    udp_buffer_datagrams: usize,
+    icmp_buffer_datagrams: usize,
+    icmp_max_payload: usize,
+    datagram_queue_policy: QueuePolicy,
+    pcap_path: Option<PathBuf>,
+    connection_log_path: Option<PathBuf>,
+    rate_limit: Option<u64>,
+    rate_limit_burst: u64,
+    packet_rate_limit: Option<u64>,
+    packet_rate_limit_burst: u64,
+    icmp_rate_limit: Option<u64>,
+    icmp_rate_limit_burst: u64,
+    local_reply_ttl: u8,
+    bind_address: Ipv4Addr,
+    bind_device: Option<String>,
+    tun_interface: Option<String>,
+    socket_receive_buffer_size: Option<usize>,
+    socket_send_buffer_size: Option<usize>,
+    dns_cache_entries: Option<usize>,
+    dns_blocklist_path: Option<PathBuf>,
+    dns_upstream: Option<SocketAddrV4>,
+    socks5_proxy: Option<SocketAddrV4>,
+    allowed_destinations: CidrSet,
+    denied_destinations: CidrSet,
+    reply_denied_prohibited: bool,
+    max_connections: Option<usize>,
+    max_tcp_connections: Option<usize>,
+    max_udp_connections: Option<usize>,
+    max_icmp_connections: Option<usize>,
+    metrics_address: Option<SocketAddr>,
+}
+
+impl RelayConfig {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            tcp_idle_timeout: None,
+            tcp_initial_rto: DEFAULT_TCP_INITIAL_RTO,
+            tcp_max_retransmissions: DEFAULT_TCP_MAX_RETRANSMISSIONS,
+            tcp_keepalive_idle: None,
+            tcp_keepalive_interval: DEFAULT_TCP_KEEPALIVE_INTERVAL,
+            tcp_keepalive_count: DEFAULT_TCP_KEEPALIVE_COUNT,
+            tcp_socket_pool_size: 0,
+            udp_idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            icmp_idle_timeout: DEFAULT_ICMP_IDLE_TIMEOUT,
+            mtu: MTU,
+            outbound_mtu: None,
+            verify_ipv4_checksums: true,
+            verify_echo_payloads: false,
+            relay_icmp: true,
+            reply_icmp_prohibited: false,
+            relay_raw: true,
+            unknown_protocol_policy: UnknownProtocolPolicy::Drop,
+            strip_ipv4_options: false,
+            preserve_ttl: false,
+            preserve_tos: false,
+            remark_tos: None,
+            dry_run: false,
+            log_target_names: false,
+            tcp_buffer_high_watermark: DEFAULT_TCP_BUFFER_HIGH_WATERMARK,
+            tcp_buffer_low_watermark: None,
+            udp_buffer_datagrams: DEFAULT_UDP_BUFFER_DATAGRAMS,
+            icmp_buffer_datagrams: DEFAULT_ICMP_BUFFER_DATAGRAMS,
+            icmp_max_payload: DEFAULT_ICMP_MAX_PAYLOAD,
+            datagram_queue_policy: QueuePolicy::TailDrop,
+            pcap_path: None,
+            connection_log_path: None,
+            rate_limit: None,
+            rate_limit_burst: DEFAULT_RATE_LIMIT_BURST,
+            packet_rate_limit: None,
+            packet_rate_limit_burst: DEFAULT_PACKET_RATE_LIMIT_BURST,
+            icmp_rate_limit: None,
+            icmp_rate_limit_burst: DEFAULT_ICMP_RATE_LIMIT_BURST,
+            local_reply_ttl: DEFAULT_LOCAL_REPLY_TTL,
+            bind_address: Ipv4Addr::UNSPECIFIED,
+            bind_device: None,
+            tun_interface: None,
+            socket_receive_buffer_size: None,
+            socket_send_buffer_size: None,
+            dns_cache_entries: None,
+            dns_blocklist_path: None,
+            dns_upstream: None,
+            socks5_proxy: None,
+            allowed_destinations: CidrSet::default(),
+            denied_destinations: CidrSet::default(),
+            reply_denied_prohibited: false,
+            max_connections: None,
+            max_tcp_connections: None,
+            max_udp_connections: None,
+            max_icmp_connections: None,
+            metrics_address: None,
+        }
+    }
+
+    /// Load and validate the settings from a TOML file, on top of the defaults.
+    ///
+    /// The keys are named after the getters (e.g. `udp_idle_timeout = 30`), the durations are
+    /// expressed in seconds. The port is not read from the file: the `adb reverse` tunnel must use
+    /// the same one.
+    pub fn from_toml_file<P: AsRef<Path>>(port: u16, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        config_file::parse(port, &content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path.display(), err),
+            )
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Local address the tunnel server accepts the clients on.
+    ///
+    /// Localhost by default, the clients connect through `adb reverse`.
+    pub fn listen_address(&self) -> IpAddr {
+        self.listen_address
+    }
+
+    pub fn set_listen_address(&mut self, listen_address: IpAddr) {
+        self.listen_address = listen_address;
+    }
+
+    /// Delay after which an established TCP connection without any traffic is closed, if any.
+    ///
+    /// TCP connections never expire by default, they are closed by either end.
+    pub fn tcp_idle_timeout(&self) -> Option<Duration> {
+        self.tcp_idle_timeout
+    }
+
+    pub fn set_tcp_idle_timeout(&mut self, tcp_idle_timeout: Option<Duration>) {
+        self.tcp_idle_timeout = tcp_idle_timeout;
+    }
+
+    /// Delay before the first retransmission of the data or the FIN not acknowledged by the
+    /// client.
+    ///
+    /// It is doubled after every retransmission, and reset once the client acknowledges more.
+    pub fn tcp_initial_rto(&self) -> Duration {
+        self.tcp_initial_rto
+    }
+
+    pub fn set_tcp_initial_rto(&mut self, tcp_initial_rto: Duration) {
+        self.tcp_initial_rto = tcp_initial_rto;
+    }
+
+    /// Number of retransmissions of an unacknowledged segment (data or FIN) before the TCP
+    /// connection is reset.
+    pub fn tcp_max_retransmissions(&self) -> u32 {
+        self.tcp_max_retransmissions
+    }
+
+    pub fn set_tcp_max_retransmissions(&mut self, tcp_max_retransmissions: u32) {
+        self.tcp_max_retransmissions = tcp_max_retransmissions;
+    }
+
+    /// Delay without traffic after which the outbound TCP sockets send keepalive probes, if
+    /// enabled.
+    ///
+    /// The probes keep the idle connections alive in the NAT of the network. Disabled by default.
+    pub fn tcp_keepalive_idle(&self) -> Option<Duration> {
+        self.tcp_keepalive_idle
+    }
+
+    pub fn set_tcp_keepalive_idle(&mut self, tcp_keepalive_idle: Option<Duration>) {
+        self.tcp_keepalive_idle = tcp_keepalive_idle;
+    }
+
+    /// Delay between two TCP keepalive probes not acknowledged.
+    pub fn tcp_keepalive_interval(&self) -> Duration {
+        self.tcp_keepalive_interval
+    }
+
+    pub fn set_tcp_keepalive_interval(&mut self, tcp_keepalive_interval: Duration) {
+        self.tcp_keepalive_interval = tcp_keepalive_interval;
+    }
+
+    /// Number of TCP keepalive probes not acknowledged before the connection is dropped.
+    pub fn tcp_keepalive_count(&self) -> u32 {
+        self.tcp_keepalive_count
+    }
+
+    pub fn set_tcp_keepalive_count(&mut self, tcp_keepalive_count: u32) {
+        self.tcp_keepalive_count = tcp_keepalive_count;
+    }
+
+    /// Number of sockets created in advance for the outbound TCP connections, to shorten their
+    /// setup.
+    ///
+    /// The pool is shared by all the clients. Disabled (0) by default.
+    pub fn tcp_socket_pool_size(&self) -> usize {
+        self.tcp_socket_pool_size
+    }
+
+    pub fn set_tcp_socket_pool_size(&mut self, tcp_socket_pool_size: usize) {
+        self.tcp_socket_pool_size = tcp_socket_pool_size;
+    }
+
+    /// Delay after which a UDP connection without any traffic is closed.
+    pub fn udp_idle_timeout(&self) -> Duration {
+        self.udp_idle_timeout
+    }
+
+    pub fn set_udp_idle_timeout(&mut self, udp_idle_timeout: Duration) {
+        self.udp_idle_timeout = udp_idle_timeout;
+    }
+
+    pub fn icmp_idle_timeout(&self) -> Duration {
+        self.icmp_idle_timeout
+    }
+
+    pub fn set_icmp_idle_timeout(&mut self, icmp_idle_timeout: Duration) {
+        self.icmp_idle_timeout = icmp_idle_timeout;
+    }
+
+    /// Maximum length of the packets sent to the client, which must match the MTU of its tunnel
+    /// interface.
+    ///
+    /// TCP segments are sized accordingly, and the MSS announced by the client is clamped to fit.
+    /// It may not be lower than `MIN_MTU`.
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+    }
+
+    /// Path MTU towards the network, if known.
+    ///
+    /// The datagrams from the clients having the DF flag and exceeding it are dropped, and
+    /// reported by an ICMP Fragmentation Needed error. It may not be lower than `MIN_MTU`.
+    pub fn outbound_mtu(&self) -> Option<u16> {
+        self.outbound_mtu
+    }
+
+    pub fn set_outbound_mtu(&mut self, outbound_mtu: Option<u16>) {
+        self.outbound_mtu = outbound_mtu;
+    }
+
+    /// Indicate whether the IPv4 header checksum of the packets from the clients is verified.
+    ///
+    /// Packets having an invalid checksum are dropped. Enabled by default.
+    pub fn verify_ipv4_checksums(&self) -> bool {
+        self.verify_ipv4_checksums
+    }
+
+    pub fn set_verify_ipv4_checksums(&mut self, verify_ipv4_checksums: bool) {
+        self.verify_ipv4_checksums = verify_ipv4_checksums;
+    }
+
+    /// Indicate whether the echo replies are checked to carry the payload of their request, for
+    /// diagnostics.
+    ///
+    /// A mismatch (revealing a misbehaving middlebox) is logged, the reply is relayed anyway.
+    /// Disabled by default.
+    pub fn verify_echo_payloads(&self) -> bool {
+        self.verify_echo_payloads
+    }
+
+    pub fn set_verify_echo_payloads(&mut self, verify_echo_payloads: bool) {
+        self.verify_echo_payloads = verify_echo_payloads;
+    }
+
+    /// Indicate whether the ICMP messages from the clients are relayed to the network.
+    ///
+    /// Otherwise, they are dropped without opening any ICMP socket, so that the relay needs no
+    /// privilege for ICMP. The echo requests to the relay itself are still answered. Enabled by
+    /// default.
+    pub fn relay_icmp(&self) -> bool {
+        self.relay_icmp
+    }
+
+    pub fn set_relay_icmp(&mut self, relay_icmp: bool) {
+        self.relay_icmp = relay_icmp;
+    }
+
+    /// Indicate whether the echo requests dropped because ICMP is not relayed are answered by an
+    /// ICMP Destination Unreachable (Administratively Prohibited), instead of timing out.
+    /// Disabled by default.
+    pub fn reply_icmp_prohibited(&self) -> bool {
+        self.reply_icmp_prohibited
+    }
+
+    pub fn set_reply_icmp_prohibited(&mut self, reply_icmp_prohibited: bool) {
+        self.reply_icmp_prohibited = reply_icmp_prohibited;
+    }
+
+    /// Indicate whether the packets of the IP protocols without transport support (e.g. GRE or
+    /// SCTP) are relayed verbatim over raw sockets, on unix.
+    ///
+    /// Otherwise, they are handled by the unknown protocol policy. Enabled by default.
+    pub fn relay_raw(&self) -> bool {
+        self.relay_raw
+    }
+
+    pub fn set_relay_raw(&mut self, relay_raw: bool) {
+        self.relay_raw = relay_raw;
+    }
+
+    /// The disposition of the packets of an IP protocol which is not relayed, either because raw
+    /// sockets are disabled or unsupported on the platform. Silently dropped by default.
+    pub fn unknown_protocol_policy(&self) -> UnknownProtocolPolicy {
+        self.unknown_protocol_policy
+    }
+
+    pub fn set_unknown_protocol_policy(&mut self, unknown_protocol_policy: UnknownProtocolPolicy) {
+        self.unknown_protocol_policy = unknown_protocol_policy;
+    }
+
+    /// Indicate whether the IPv4 options of the packets from the clients are removed before they
+    /// are routed, some networks dropping the packets having options.
+    ///
+    /// Otherwise, the replies built from the headers of the packets relayed carry their options.
+    /// Disabled by default.
+    pub fn strip_ipv4_options(&self) -> bool {
+        self.strip_ipv4_options
+    }
+
+    pub fn set_strip_ipv4_options(&mut self, strip_ipv4_options: bool) {
+        self.strip_ipv4_options = strip_ipv4_options;
+    }
+
+    /// Indicate whether the TTL of the packets sent to the clients derives from the TTL of the
+    /// packets received from the network (decremented, the relay being one more hop).
+    ///
+    /// Otherwise, the TTL of the packets from the client is reused. It is only known for the UDP
+    /// datagrams (on Linux) and for the ICMP messages received on raw sockets. Disabled by
+    /// default.
+    pub fn preserve_ttl(&self) -> bool {
+        self.preserve_ttl
+    }
+
+    pub fn set_preserve_ttl(&mut self, preserve_ttl: bool) {
+        self.preserve_ttl = preserve_ttl;
+    }
+
+    /// Indicate whether the outbound sockets send their packets with the type of service (the
+    /// DSCP and ECN fields) of the first packet of their connection, to keep its QoS marking.
+    ///
+    /// It applies to the TCP, UDP and raw connections (the ICMP sockets are shared), on Linux.
+    /// Disabled by default.
+    pub fn preserve_tos(&self) -> bool {
+        self.preserve_tos
+    }
+
+    pub fn set_preserve_tos(&mut self, preserve_tos: bool) {
+        self.preserve_tos = preserve_tos;
+    }
+
+    /// Type of service to set on all the outbound sockets instead, if any.
+    pub fn remark_tos(&self) -> Option<u8> {
+        self.remark_tos
+    }
+
+    pub fn set_remark_tos(&mut self, remark_tos: Option<u8>) {
+        self.remark_tos = remark_tos;
+    }
+
+    /// Indicate whether the packets from the clients are only parsed, validated and logged.
+    ///
+    /// No connection to the network is opened: the packets to relay are dropped. This allows to
+    /// test a configuration or the parsers against captured traffic. Disabled by default.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Indicate whether the names targeted by the packets from the clients (the DNS query name,
+    /// the TLS server name or the HTTP host) are logged at debug level, for diagnostics.
+    ///
+    /// The payloads are only parsed, the forwarding is unchanged. Disabled by default.
+    pub fn log_target_names(&self) -> bool {
+        self.log_target_names
+    }
+
+    pub fn set_log_target_names(&mut self, log_target_names: bool) {
+        self.log_target_names = log_target_names;
+    }
+
+    /// Capacity of the buffer of the data received from the client, per TCP connection.
+    ///
+    /// Once it is full, a zero window is advertised to the client.
+    ///
+    /// It also bounds the data sent to the client and not acknowledged yet: the network is not
+    /// read beyond.
+    pub fn tcp_buffer_high_watermark(&self) -> usize {
+        self.tcp_buffer_high_watermark
+    }
+
+    pub fn set_tcp_buffer_high_watermark(&mut self, tcp_buffer_high_watermark: usize) {
+        self.tcp_buffer_high_watermark = tcp_buffer_high_watermark;
+    }
+
+    /// Amount of buffered data below which the window is reopened, once the high watermark has
+    /// been reached.
+    ///
+    /// Equal to the high watermark unless set, so that the window is reopened as soon as some
+    /// data are written to the network.
+    pub fn tcp_buffer_low_watermark(&self) -> usize {
+        self.tcp_buffer_low_watermark
+            .unwrap_or(self.tcp_buffer_high_watermark)
+    }
+
+    pub fn set_tcp_buffer_low_watermark(&mut self, tcp_buffer_low_watermark: Option<usize>) {
+        self.tcp_buffer_low_watermark = tcp_buffer_low_watermark;
+    }
+
+    /// Number of datagrams queued from the client to the network, per UDP connection.
+    ///
+    /// Further datagrams are dropped. Each slot reserves 64K, so the memory used by every UDP
+    /// connection grows linearly with this value.
+    pub fn udp_buffer_datagrams(&self) -> usize {
+        self.udp_buffer_datagrams
+    }
+
+    pub fn set_udp_buffer_datagrams(&mut self, udp_buffer_datagrams: usize) {
+        self.udp_buffer_datagrams = udp_buffer_datagrams;
+    }
+
+    /// Number of echo requests queued from the client to the network, per ICMP connection.
+    ///
+    /// As for UDP, each slot reserves 64K.
+    pub fn icmp_buffer_datagrams(&self) -> usize {
+        self.icmp_buffer_datagrams
+    }
+
+    pub fn set_icmp_buffer_datagrams(&mut self, icmp_buffer_datagrams: usize) {
+        self.icmp_buffer_datagrams = icmp_buffer_datagrams;
+    }
+
+    /// Maximum payload of the echo requests relayed to the network, so that the relay may not be
+    /// used for ICMP amplification.
+    ///
+    /// The larger requests are dropped: truncated, their replies would not echo them. 1472 bytes
+    /// by default.
+    pub fn icmp_max_payload(&self) -> usize {
+        self.icmp_max_payload
+    }
+
+    pub fn set_icmp_max_payload(&mut self, icmp_max_payload: usize) {
+        self.icmp_max_payload = icmp_max_payload;
+    }
+
+    /// The datagram to drop when the queue of a UDP, ICMP or raw connection to the network is full.
+    ///
+    /// Dropping the oldest one (head drop) keeps the latency of real-time traffic low. The new one
+    /// is rejected (tail drop) by default.
+    pub fn datagram_queue_policy(&self) -> QueuePolicy {
+        self.datagram_queue_policy
+    }
+
+    pub fn set_datagram_queue_policy(&mut self, datagram_queue_policy: QueuePolicy) {
+        self.datagram_queue_policy = datagram_queue_policy;
+    }
+
+    /// File to record all the relayed packets into (in pcap format), if any.
+    pub fn pcap_path(&self) -> Option<&Path> {
+        self.pcap_path.as_deref()
+    }
+
+    pub fn set_pcap_path(&mut self, pcap_path: Option<PathBuf>) {
+        self.pcap_path = pcap_path;
+    }
+
+    /// File to append the connection events into (one JSON object per line), if any.
+    pub fn connection_log_path(&self) -> Option<&Path> {
+        self.connection_log_path.as_deref()
+    }
+
+    pub fn set_connection_log_path(&mut self, connection_log_path: Option<PathBuf>) {
+        self.connection_log_path = connection_log_path;
+    }
+
+    /// Maximum number of bytes per second each client may send to the network, if limited.
+    ///
+    /// Packets exceeding the rate are kept in the client buffer until enough bandwidth is
+    /// available, so that the client is slowed down instead of losing packets.
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate_limit
+    }
+
+    pub fn set_rate_limit(&mut self, rate_limit: Option<u64>) {
+        self.rate_limit = rate_limit;
+    }
+
+    /// Number of bytes a client may send at once, above the rate limit, after being idle.
+    pub fn rate_limit_burst(&self) -> u64 {
+        self.rate_limit_burst
+    }
+
+    pub fn set_rate_limit_burst(&mut self, rate_limit_burst: u64) {
+        self.rate_limit_burst = rate_limit_burst;
+    }
+
+    /// Maximum number of packets per second each client may send to the network, if limited.
+    ///
+    /// Unlike the bandwidth, the packets exceeding the rate are dropped: a flood of tiny packets
+    /// must not fill the buffers.
+    pub fn packet_rate_limit(&self) -> Option<u64> {
+        self.packet_rate_limit
+    }
+
+    pub fn set_packet_rate_limit(&mut self, packet_rate_limit: Option<u64>) {
+        self.packet_rate_limit = packet_rate_limit;
+    }
+
+    /// Number of packets a client may send at once, above the packet rate limit.
+    pub fn packet_rate_limit_burst(&self) -> u64 {
+        self.packet_rate_limit_burst
+    }
+
+    pub fn set_packet_rate_limit_burst(&mut self, packet_rate_limit_burst: u64) {
+        self.packet_rate_limit_burst = packet_rate_limit_burst;
+    }
+
+    /// Maximum number of echo requests per second each client may send to a single destination,
+    /// if limited.
+    ///
+    /// The requests exceeding the rate are dropped, so that a flood ping does not get the relay
+    /// host blocked upstream.
+    pub fn icmp_rate_limit(&self) -> Option<u64> {
+        self.icmp_rate_limit
+    }
+
+    pub fn set_icmp_rate_limit(&mut self, icmp_rate_limit: Option<u64>) {
+        self.icmp_rate_limit = icmp_rate_limit;
+    }
+
+    /// Number of echo requests a client may send at once to a destination, above the ICMP rate
+    /// limit.
+    pub fn icmp_rate_limit_burst(&self) -> u64 {
+        self.icmp_rate_limit_burst
+    }
+
+    pub fn set_icmp_rate_limit_burst(&mut self, icmp_rate_limit_burst: u64) {
+        self.icmp_rate_limit_burst = icmp_rate_limit_burst;
+    }
+
+    /// TTL of the ICMP replies generated by the relay to the requests sent to its own address.
+    ///
+    /// Some tools guess the operating system of a host from the TTL of its replies. Default is 64.
+    pub fn local_reply_ttl(&self) -> u8 {
+        self.local_reply_ttl
+    }
+
+    pub fn set_local_reply_ttl(&mut self, local_reply_ttl: u8) {
+        self.local_reply_ttl = local_reply_ttl;
+    }
+
+    /// Local address the outbound sockets are bound to.
+    ///
+    /// If unspecified (the default), the kernel chooses the source address of each connection.
+    pub fn bind_address(&self) -> Ipv4Addr {
+        self.bind_address
+    }
+
+    pub fn set_bind_address(&mut self, bind_address: Ipv4Addr) {
+        self.bind_address = bind_address;
+    }
+
+    /// Network interface the outbound sockets are bound to (`SO_BINDTODEVICE`), if any.
+    ///
+    /// Only supported on Linux and Android.
+    pub fn bind_device(&self) -> Option<&str> {
+        self.bind_device.as_deref()
+    }
+
+    pub fn set_bind_device(&mut self, bind_device: Option<String>) {
+        self.bind_device = bind_device;
+    }
+
+    /// Local TUN interface to relay the packets of, in addition to those of the devices, if any.
+    ///
+    /// The interface is created if it does not exist, which requires `CAP_NET_ADMIN`. Its address
+    /// and routes are left to the user; the outbound sockets should be bound to another interface
+    /// (see `bind_device()`), so that they are not routed back through it.
+    ///
+    /// Only supported on Linux.
+    pub fn tun_interface(&self) -> Option<&str> {
+        self.tun_interface.as_deref()
+    }
+
+    pub fn set_tun_interface(&mut self, tun_interface: Option<String>) {
+        self.tun_interface = tun_interface;
+    }
+
+    /// Receive buffer size (`SO_RCVBUF`) of the outbound sockets, if not the kernel default.
+    ///
+    /// Larger buffers help high-throughput flows over links with a high bandwidth-delay product.
+    /// The kernel may double the value, and caps it (`net.core.rmem_max` on Linux).
+    pub fn socket_receive_buffer_size(&self) -> Option<usize> {
+        self.socket_receive_buffer_size
+    }
+
+    pub fn set_socket_receive_buffer_size(&mut self, socket_receive_buffer_size: Option<usize>) {
+        self.socket_receive_buffer_size = socket_receive_buffer_size;
+    }
+
+    /// Send buffer size (`SO_SNDBUF`) of the outbound sockets, if not the kernel default.
+    pub fn socket_send_buffer_size(&self) -> Option<usize> {
+        self.socket_send_buffer_size
+    }
+
+    pub fn set_socket_send_buffer_size(&mut self, socket_send_buffer_size: Option<usize>) {
+        self.socket_send_buffer_size = socket_send_buffer_size;
+    }
+
+    /// Maximum number of DNS responses cached per client, if DNS caching is enabled.
+    ///
+    /// Repeated queries are then answered by the relay until the responses expire.
+    pub fn dns_cache_entries(&self) -> Option<usize> {
+        self.dns_cache_entries
+    }
+
+    pub fn set_dns_cache_entries(&mut self, dns_cache_entries: Option<usize>) {
+        self.dns_cache_entries = dns_cache_entries;
+    }
+
+    /// File listing the domains to block (one per line, `*.` prefixed to block the subdomains), if
+    /// any.
+    ///
+    /// The relay answers the DNS queries for these domains by itself, with a name error.
+    pub fn dns_blocklist_path(&self) -> Option<&Path> {
+        self.dns_blocklist_path.as_deref()
+    }
+
+    pub fn set_dns_blocklist_path(&mut self, dns_blocklist_path: Option<PathBuf>) {
+        self.dns_blocklist_path = dns_blocklist_path;
+    }
+
+    /// Resolver to send all the DNS queries (UDP, to port 53) to, whatever their destination, if
+    /// any.
+    ///
+    /// The client receives the responses from the destination of its queries, so the redirection
+    /// is transparent.
+    pub fn dns_upstream(&self) -> Option<SocketAddrV4> {
+        self.dns_upstream
+    }
+
+    pub fn set_dns_upstream(&mut self, dns_upstream: Option<SocketAddrV4>) {
+        self.dns_upstream = dns_upstream;
+    }
+
+    /// SOCKS5 proxy to open all the TCP connections through (without authentication), if any.
+    pub fn socks5_proxy(&self) -> Option<SocketAddrV4> {
+        self.socks5_proxy
+    }
+
+    pub fn set_socks5_proxy(&mut self, socks5_proxy: Option<SocketAddrV4>) {
+        self.socks5_proxy = socks5_proxy;
+    }
+
+    /// Destinations the clients may connect to, all of them if empty (the default).
+    pub fn allowed_destinations(&self) -> &CidrSet {
+        &self.allowed_destinations
+    }
+
+    pub fn set_allowed_destinations(&mut self, allowed_destinations: CidrSet) {
+        self.allowed_destinations = allowed_destinations;
+    }
+
+    /// Destinations the clients may not connect to, even if they are allowed.
+    ///
+    /// Their packets are dropped before any connection is opened. Only the IPv4 destinations are
+    /// filtered.
+    pub fn denied_destinations(&self) -> &CidrSet {
+        &self.denied_destinations
+    }
+
+    pub fn set_denied_destinations(&mut self, denied_destinations: CidrSet) {
+        self.denied_destinations = denied_destinations;
+    }
+
+    /// Indicate whether the packets to a destination not allowed are answered by an ICMP
+    /// Destination Unreachable (Administratively Prohibited), instead of timing out. Disabled by
+    /// default.
+    pub fn reply_denied_prohibited(&self) -> bool {
+        self.reply_denied_prohibited
+    }
+
+    pub fn set_reply_denied_prohibited(&mut self, reply_denied_prohibited: bool) {
+        self.reply_denied_prohibited = reply_denied_prohibited;
+    }
+
+    /// Maximum number of connections per client, if limited.
+    ///
+    /// Once reached, the least recently used connection is closed to accept a new one, so that a
+    /// client may not exhaust the file descriptors of the relay.
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) {
+        self.max_connections = max_connections;
+    }
+
+    /// Maximum number of TCP connections per client, if limited.
+    ///
+    /// Unlike `max_connections()`, no connection is evicted once reached: the new flows of the
+    /// protocol are rejected, while those of the other protocols are still accepted.
+    pub fn max_tcp_connections(&self) -> Option<usize> {
+        self.max_tcp_connections
+    }
+
+    pub fn set_max_tcp_connections(&mut self, max_tcp_connections: Option<usize>) {
+        self.max_tcp_connections = max_tcp_connections;
+    }
+
+    /// Maximum number of UDP connections (over IPv4 or IPv6) per client, if limited.
+    pub fn max_udp_connections(&self) -> Option<usize> {
+        self.max_udp_connections
+    }
+
+    pub fn set_max_udp_connections(&mut self, max_udp_connections: Option<usize>) {
+        self.max_udp_connections = max_udp_connections;
+    }
+
+    /// Maximum number of ICMP connections per client, if limited.
+    ///
+    /// Each may hold a raw socket, the scarcest resource of the relay.
+    pub fn max_icmp_connections(&self) -> Option<usize> {
+        self.max_icmp_connections
+    }
+
+    pub fn set_max_icmp_connections(&mut self, max_icmp_connections: Option<usize>) {
+        self.max_icmp_connections = max_icmp_connections;
+    }
+
+    /// Address to serve the metrics on (in Prometheus format, at `/metrics`), if any.
+    ///
+    /// Only available if the relay is built with the `metrics` feature.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    pub fn set_metrics_address(&mut self, metrics_address: Option<SocketAddr>) {
+        self.metrics_address = metrics_address;
+    }
+}
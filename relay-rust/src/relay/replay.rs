@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::{PollOpt, Ready, Registration};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::client::ClientChannel;
+use super::connection::ConnectionId;
+use super::ipv4_header;
+use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::ipv6_packet::Ipv6Packet;
+use super::pcap::PcapReader;
+use super::relay_config::RelayConfig;
+use super::router::Router;
+use super::selector::Selector;
+use super::stream_buffer::StreamBuffer;
+
+const TAG: &str = "Replay";
+
+/// Outcome of a capture fed back through the relay parsers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of packets read from the capture.
+    pub packets: usize,
+    /// Number of packets rejected by the parsers.
+    pub malformed: usize,
+    /// Number of packets the router answered by itself (ICMP errors, echo replies, DNS answers).
+    pub replies: usize,
+}
+
+/// Feed the packets of a pcap capture of raw IP packets through the router, in dry run.
+///
+/// The routing decision of every packet is logged, but no connection is opened to the network.
+pub fn replay_pcap(path: &Path, config: RelayConfig) -> io::Result<ReplayReport> {
+    let file = File::open(path)?;
+    replay(BufReader::new(file), config)
+}
+
+fn replay<R: Read>(input: R, mut config: RelayConfig) -> io::Result<ReplayReport> {
+    let mut pcap_reader = PcapReader::new(input)?;
+    config.set_dry_run(true);
+    let mut router = Router::new(Rc::new(config));
+    let mut selector = Selector::create()?;
+    // the local replies are not sent anywhere, but the client channel must be registered
+    let (registration, _set_readiness) = Registration::new2();
+    let token = selector.register(
+        &registration,
+        |_: &mut Selector, _| {},
+        Ready::readable(),
+        PollOpt::level(),
+    )?;
+    let mut interests = Ready::readable();
+    let mut network_to_client = StreamBuffer::new(4 * MAX_PACKET_LENGTH);
+
+    let mut report = ReplayReport::default();
+    while let Some(mut raw) = pcap_reader.read_packet()? {
+        report.packets += 1;
+        let index = report.packets;
+        let result = {
+            let mut client_channel = ClientChannel::new(
+                &mut network_to_client,
+                &registration,
+                token,
+                &mut interests,
+                None,
+                false,
+            );
+            route(&mut selector, &mut router, &mut client_channel, &mut raw)
+        };
+        let replies = drain_replies(&mut network_to_client)?;
+        report.replies += replies;
+        match result {
+            Ok(description) => info!(
+                target: TAG,
+                "Packet #{}: {} routed, {} local replies", index, description, replies
+            ),
+            Err(err) => {
+                report.malformed += 1;
+                warn!(target: TAG, "Packet #{}: malformed: {}", index, err);
+            }
+        }
+    }
+    router.clear(&mut selector);
+    info!(
+        target: TAG,
+        "Replayed {} packets: {} malformed, {} local replies",
+        report.packets,
+        report.malformed,
+        report.replies
+    );
+    Ok(report)
+}
+
+// return a description of the packet, or the parse error
+fn route(
+    selector: &mut Selector,
+    router: &mut Router,
+    client_channel: &mut ClientChannel,
+    raw: &mut [u8],
+) -> io::Result<String> {
+    match raw.first().map(|&b| b >> 4) {
+        Some(4) => {
+            let mut ipv4_packet = Ipv4Packet::try_parse(raw)?;
+            let description = match ipv4_packet.headers_data() {
+                (ipv4_header_data, Some(transport_header_data)) => {
+                    ConnectionId::from_headers(ipv4_header_data, transport_header_data).to_string()
+                }
+                (ipv4_header_data, None) => format!("{:?}", ipv4_header_data.protocol()),
+            };
+            router.send_to_network(selector, client_channel, &mut ipv4_packet)?;
+            Ok(description)
+        }
+        Some(6) => {
+            let ipv6_packet = Ipv6Packet::try_parse(raw)?;
+            router.send_ipv6_to_network(selector, &ipv6_packet)?;
+            Ok(format!(
+                "IPv6 (next header {})",
+                ipv6_packet.ipv6_header_data().next_header()
+            ))
+        }
+        Some(version) => Err(invalid(&format!("Not an IP packet, version={}", version))),
+        None => Err(invalid("Empty packet")),
+    }
+}
+
+// count and discard the packets the router sent back to the client
+fn drain_replies(network_to_client: &mut StreamBuffer) -> io::Result<usize> {
+    let mut raw = Vec::new();
+    network_to_client.write_to(&mut raw)?;
+    let mut replies = 0;
+    let mut data = &raw[..];
+    while let Some((_, length)) = ipv4_header::peek_version_length(data) {
+        replies += 1;
+        data = &data[(length as usize).clamp(1, data.len())..];
+    }
+    Ok(replies)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::pcap::PcapWriter;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn create_udp_packet(ttl: u8) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(0x4500_0020).unwrap(); // total length 32
+        raw.write_u32::<BigEndian>(0).unwrap(); // id, flags, fragment offset
+        raw.write_u8(ttl).unwrap();
+        raw.write_u8(17).unwrap(); // UDP
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a00_0002).unwrap(); // 10.0.0.2
+        raw.write_u32::<BigEndian>(0x0101_0101).unwrap(); // 1.1.1.1
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x1122_3344).unwrap(); // payload
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        ipv4_packet.compute_checksums();
+        raw
+    }
+
+    #[test]
+    fn replay_embedded_capture() {
+        let mut capture = Vec::new();
+        {
+            let mut pcap_writer = PcapWriter::new(&mut capture).unwrap();
+            pcap_writer.write_packet(&create_udp_packet(64)).unwrap();
+            // the TTL expires on the relay, which answers with a Time Exceeded
+            pcap_writer.write_packet(&create_udp_packet(1)).unwrap();
+            // truncated packets
+            pcap_writer
+                .write_packet(&create_udp_packet(64)[..16])
+                .unwrap();
+            pcap_writer.write_packet(&[0x60, 0, 0, 0, 0, 8]).unwrap();
+            pcap_writer.write_packet(&[0x12, 0x34]).unwrap();
+            pcap_writer.write_packet(&[]).unwrap();
+        }
+
+        let report = replay(&capture[..], RelayConfig::new(0)).unwrap();
+        assert_eq!(6, report.packets);
+        assert_eq!(4, report.malformed);
+        assert_eq!(1, report.replies);
+    }
+}
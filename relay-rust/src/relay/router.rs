@@ -16,53 +16,255 @@
 
 use log::*;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::connection::{
+    self, ClosedConnection, Connection, ConnectionId, ConnectionInfo, ConnectionStats, OpenStage,
+    OpenedConnection, SharedConnectionCloseListener, SharedConnectionOpenListener,
+};
+use super::connection_log::SharedConnectionLog;
+use super::connection_table::ConnectionTable;
+use super::dns_blocklist::SharedDnsBlocklist;
+use super::dns_cache::{self, DnsCache, SharedDnsCache, DNS_PORT};
 use super::icmp_connection::IcmpConnection;
-use super::ipv4_header::Protocol;
+use super::icmp_dispatcher::{IcmpDispatcher, SharedIcmpDispatcher};
+use super::icmp_error;
+use super::icmp_header::{ICMP_HEADER_LENGTH, TIMESTAMP_MESSAGE_LENGTH};
+use super::ipv4_header::{Ipv4Header, Protocol};
 use super::ipv4_packet::Ipv4Packet;
+use super::ipv4_reassembler::Ipv4Reassembler;
+use super::ipv6_packet::Ipv6Packet;
+use super::metrics::RelayMetrics;
+use super::rate_limiter::RateLimiter;
+#[cfg(unix)]
+use super::raw_connection::RawConnection;
+use super::relay_config::{RelayConfig, UnknownProtocolPolicy};
 use super::selector::Selector;
-use super::tcp_connection::TcpConnection;
+use super::sniffer;
+use super::socket_pool::SharedSocketPool;
+use super::tcp_connection::{self, TcpConnection};
+use super::timer_wheel::TimerWheel;
+use super::transport_header::{TransportHeaderData, TransportHeaderMut};
+use super::udp6_connection::{Udp6Connection, Udp6ConnectionId};
 use super::udp_connection::UdpConnection;
 
 const TAG: &str = "Router";
 
+// resolution of the idle timeouts, and number of slots covering a round of the expiry wheel
+const EXPIRY_TICK: Duration = Duration::from_millis(250);
+const EXPIRY_SLOTS: usize = 256;
+// a connection not expired at its deadline for another reason (e.g. a closing TCP connection) is
+// checked again after this delay
+const EXPIRY_RECHECK_DELAY: Duration = Duration::from_secs(1);
+
+// beyond, the buckets which are full (i.e. of the destinations not pinged recently) are forgotten
+const MAX_ICMP_RATE_LIMITERS: usize = 1024;
+
 pub struct Router {
     client: Weak<RefCell<Client>>,
-    // there are typically only few connections per client, HashMap would be less efficient
-    connections: Vec<Rc<RefCell<dyn Connection>>>,
+    connections: ConnectionTable,
+    // UDP flows over IPv6, which are not identified by a (IPv4) ConnectionId
+    udp6_connections: Vec<Rc<RefCell<Udp6Connection>>>,
+    config: Rc<RelayConfig>,
+    // traffic of the connections already removed
+    removed_stats: ConnectionStats,
+    icmp_dispatcher: SharedIcmpDispatcher,
+    reassembler: Ipv4Reassembler,
+    dns_cache: Option<SharedDnsCache>,
+    dns_blocklist: Option<SharedDnsBlocklist>,
+    tcp_socket_pool: Option<SharedSocketPool>,
+    open_listeners: Vec<SharedConnectionOpenListener>,
+    close_listeners: Vec<SharedConnectionCloseListener>,
+    // the log and the id of the client in the log
+    connection_log: Option<(SharedConnectionLog, u32)>,
+    // the connections are scheduled at their expiry when created, and only rescheduled (at their
+    // new expiry, if they have been touched since) when their deadline is reached
+    expiry_wheel: TimerWheel<Weak<RefCell<dyn Connection>>>,
+    udp6_expiry_wheel: TimerWheel<Weak<RefCell<Udp6Connection>>>,
+    // the echo requests rate, per destination address
+    icmp_rate_limiters: HashMap<u32, RateLimiter>,
+    icmp_requests_limited: u64,
 }
 
 impl Router {
-    pub fn new() -> Self {
+    pub fn new(config: Rc<RelayConfig>) -> Self {
+        let dns_cache = config.dns_cache_entries().map(DnsCache::new_shared);
+        let now = Instant::now();
         Self {
             client: Weak::new(),
-            connections: Vec::new(),
+            connections: ConnectionTable::default(),
+            udp6_connections: Vec::new(),
+            config,
+            removed_stats: ConnectionStats::default(),
+            icmp_dispatcher: IcmpDispatcher::new_shared(),
+            reassembler: Ipv4Reassembler::new(),
+            dns_cache,
+            dns_blocklist: None,
+            tcp_socket_pool: None,
+            open_listeners: Vec::new(),
+            close_listeners: Vec::new(),
+            connection_log: None,
+            expiry_wheel: TimerWheel::new(now, EXPIRY_TICK, EXPIRY_SLOTS),
+            udp6_expiry_wheel: TimerWheel::new(now, EXPIRY_TICK, EXPIRY_SLOTS),
+            icmp_rate_limiters: HashMap::new(),
+            icmp_requests_limited: 0,
         }
     }
 
+    pub fn set_dns_blocklist(&mut self, dns_blocklist: Option<SharedDnsBlocklist>) {
+        self.dns_blocklist = dns_blocklist;
+    }
+
+    /// Take the sockets of the new TCP connections from `tcp_socket_pool`, rather than creating
+    /// them.
+    pub fn set_tcp_socket_pool(&mut self, tcp_socket_pool: Option<SharedSocketPool>) {
+        self.tcp_socket_pool = tcp_socket_pool;
+    }
+
+    /// Log the lifecycle events of the (IPv4) connections of this router, for the client
+    /// `client_id`.
+    pub fn set_connection_log(&mut self, connection_log: SharedConnectionLog, client_id: u32) {
+        self.connection_log = Some((connection_log, client_id));
+    }
+
+    /// Register a listener to notify of every (IPv4) connection created by this router, then of
+    /// every TCP connection established.
+    ///
+    /// As for the close listeners, it must not call back into the relay.
+    pub fn add_open_listener(&mut self, listener: SharedConnectionOpenListener) {
+        self.open_listeners.push(listener);
+    }
+
+    /// Register a listener to notify of every (IPv4) connection removed from this router, with its
+    /// final traffic.
+    ///
+    /// It is called while the router is borrowed, so it must not call back into the relay.
+    pub fn add_close_listener(&mut self, listener: SharedConnectionCloseListener) {
+        self.close_listeners.push(listener);
+    }
+
     // expose client initialization after construction to break cyclic initialization dependencies
     pub fn set_client(&mut self, client: Weak<RefCell<Client>>) {
         self.client = client;
     }
 
+    /// Relay an IPv4 packet from the client.
+    ///
+    /// Return a `WouldBlock` error, without consuming the packet, if its connection cannot accept
+    /// it now: the client must retry later.
     pub fn send_to_network(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ipv4_packet: &mut Ipv4Packet,
+    ) -> io::Result<()> {
+        if self.config.verify_ipv4_checksums() && !ipv4_packet.ipv4_header().verify_checksum() {
+            debug!(target: TAG, "Invalid IPv4 header checksum, dropping packet");
+            return Ok(());
+        }
+        if ipv4_packet.ipv4_header_data().is_fragment() {
+            if let Some(mut raw) = self.reassembler.push(ipv4_packet.raw(), Instant::now()) {
+                // the fragments are consumed, so the reassembled datagram cannot be retried: it is
+                // dropped by its connection if it is full
+                let mut reassembled = Ipv4Packet::parse(&mut raw);
+                self.route(selector, client_channel, &mut reassembled);
+            }
+            return Ok(());
+        }
+        if !self.can_accept(ipv4_packet) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Connection buffer full",
+            ));
+        }
+        self.route(selector, client_channel, ipv4_packet);
+        Ok(())
+    }
+
+    // check before any change (like the TTL decrement), the packet may be retried
+    fn can_accept(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let transport_header_data = match ipv4_packet.transport_header_data() {
+            Some(transport_header_data) => transport_header_data,
+            None => return true,
+        };
+        let id = ConnectionId::from_headers(ipv4_packet.ipv4_header_data(), transport_header_data);
+        self.find_index(&id)
+            .is_none_or(|index| self.connections[index].borrow().can_accept(ipv4_packet))
+    }
+
+    fn route(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &mut Ipv4Packet,
     ) {
-        if ipv4_packet.is_valid() {
+        if self.config.strip_ipv4_options() && !ipv4_packet.ipv4_header().options().is_empty() {
+            // strip a copy: the client consumes the packet from its buffer by its original length
+            let mut raw = ipv4_packet.raw().to_vec();
+            let mut stripped = Ipv4Packet::parse(&mut raw);
+            stripped.strip_options();
+            self.route(selector, client_channel, &mut stripped);
+            return;
+        }
+        if ipv4_packet.is_valid() || Self::is_raw(ipv4_packet) {
+            let local_reply_ttl = self.config.local_reply_ttl();
+            if Self::answer_echo_request(selector, client_channel, ipv4_packet, local_reply_ttl) {
+                return;
+            }
+            if let Err(time_exceeded) = Self::decrement_ttl(ipv4_packet) {
+                debug!(target: TAG, "TTL exceeded, dropping packet");
+                if let Some(mut raw) = time_exceeded {
+                    let error_packet = Ipv4Packet::parse(&mut raw);
+                    if let Err(err) = client_channel.send_to_client(selector, &error_packet) {
+                        warn!(target: TAG, "Cannot send Time Exceeded to client: {}", err);
+                    }
+                }
+                return;
+            }
+            if self.reject_oversized_datagram(selector, client_channel, ipv4_packet) {
+                return;
+            }
+            let max_mss = tcp_connection::max_payload_length(self.config.mtu());
+            Self::clamp_mss(ipv4_packet, max_mss);
+            if self.config.log_target_names() {
+                Self::log_target_name(ipv4_packet);
+            }
+            if self.answer_dns_query(selector, client_channel, ipv4_packet) {
+                return;
+            }
+            if self.drop_icmp(selector, client_channel, ipv4_packet) {
+                return;
+            }
+            if self.drop_denied_destination(selector, client_channel, ipv4_packet) {
+                return;
+            }
+            if self.drop_unknown_protocol(selector, client_channel, ipv4_packet) {
+                return;
+            }
+            if self.limit_echo_request(ipv4_packet, Instant::now()) {
+                return;
+            }
+            if self.config.dry_run() {
+                Self::drop_dry_run(ipv4_packet);
+                return;
+            }
             match self.connection(selector, ipv4_packet) {
                 Ok(index) => {
                     let closed = {
                         let connection_ref = &self.connections[index];
                         let mut connection = connection_ref.borrow_mut();
+                        let was_established = connection.is_established();
                         connection.send_to_network(selector, client_channel, ipv4_packet);
+                        if !was_established && connection.is_established() {
+                            self.notify_opened(connection.id(), OpenStage::Established);
+                        }
                         if connection.is_closed() {
                             debug!(
                                 target: TAG,
@@ -76,10 +278,20 @@ impl Router {
                     };
                     if closed {
                         // the connection is closed, remove it
-                        self.connections.swap_remove(index);
+                        self.remove_at(index, false);
+                    }
+                }
+                Err(err) => {
+                    error!(target: TAG, "Cannot create route, dropping packet: {}", err);
+                    if let Some(code) = icmp_error::destination_unreachable_code(&err) {
+                        Self::send_destination_unreachable(
+                            selector,
+                            client_channel,
+                            ipv4_packet,
+                            code,
+                        );
                     }
                 }
-                Err(err) => error!(target: TAG, "Cannot create route, dropping packet: {}", err),
             }
         } else {
             warn!(target: TAG, "Dropping invalid packet");
@@ -93,34 +305,571 @@ impl Router {
         }
     }
 
+    /// Drop `ipv4_packet` if it is an ICMP message while ICMP is not relayed, replying to an echo
+    /// request with Administratively Prohibited if configured.
+    ///
+    /// Return `true` if the packet is dropped.
+    fn drop_icmp(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) -> bool {
+        if self.config.relay_icmp() || ipv4_packet.ipv4_header_data().protocol() != Protocol::Icmp {
+            return false;
+        }
+        debug!(target: TAG, "ICMP relaying disabled, dropping packet");
+        // only an echo request may be answered by an error
+        if self.config.reply_icmp_prohibited() && icmp_error::may_reply_with_error(ipv4_packet) {
+            Self::send_administratively_prohibited(selector, client_channel, ipv4_packet);
+        }
+        true
+    }
+
+    /// Drop `ipv4_packet` if its destination is not allowed, replying with Administratively
+    /// Prohibited if configured.
+    ///
+    /// Return `true` if the packet is dropped.
+    fn drop_denied_destination(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) -> bool {
+        let destination = ipv4_packet.ipv4_header_data().destination();
+        if self.is_allowed(destination) {
+            return false;
+        }
+        debug!(
+            target: TAG,
+            "Destination {} not allowed, dropping packet",
+            Ipv4Addr::from(destination)
+        );
+        if self.config.reply_denied_prohibited() && icmp_error::may_reply_with_error(ipv4_packet) {
+            Self::send_administratively_prohibited(selector, client_channel, ipv4_packet);
+        }
+        true
+    }
+
+    fn send_administratively_prohibited(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        let mut raw = icmp_error::build_destination_unreachable(
+            ipv4_packet,
+            icmp_error::CODE_ADMINISTRATIVELY_PROHIBITED,
+        );
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &error_packet) {
+            warn!(
+                target: TAG,
+                "Cannot send Administratively Prohibited to client: {}", err
+            );
+        }
+    }
+
+    // a denied destination is never allowed, and only the allowed ones are if any
+    fn is_allowed(&self, destination: u32) -> bool {
+        let allowed_destinations = self.config.allowed_destinations();
+        !self.config.denied_destinations().contains(destination)
+            && (allowed_destinations.is_empty() || allowed_destinations.contains(destination))
+    }
+
+    /// Drop `ipv4_packet` if it is an echo request exceeding the ICMP rate limit of its
+    /// destination.
+    ///
+    /// Return `true` if the packet is dropped.
+    fn limit_echo_request(&mut self, ipv4_packet: &Ipv4Packet, now: Instant) -> bool {
+        let rate = match self.config.icmp_rate_limit() {
+            Some(rate) => rate,
+            None => return false,
+        };
+        match ipv4_packet.transport_header_data() {
+            Some(TransportHeaderData::Icmp(icmp_header_data))
+                if icmp_header_data.is_echo_request() => {}
+            _ => return false,
+        }
+        let destination = ipv4_packet.ipv4_header_data().destination();
+        if self.icmp_rate_limiters.len() >= MAX_ICMP_RATE_LIMITERS
+            && !self.icmp_rate_limiters.contains_key(&destination)
+        {
+            self.icmp_rate_limiters
+                .retain(|_, limiter| !limiter.is_full(now));
+            if self.icmp_rate_limiters.len() >= MAX_ICMP_RATE_LIMITERS {
+                // all the destinations were pinged recently, forget the one the closest to full
+                let fullest = self
+                    .icmp_rate_limiters
+                    .iter_mut()
+                    .map(|(&destination, limiter)| (destination, limiter.available(now)))
+                    .max_by_key(|&(_, available)| available)
+                    .map(|(destination, _)| destination)
+                    .unwrap();
+                self.icmp_rate_limiters.remove(&fullest);
+            }
+        }
+        let burst = self.config.icmp_rate_limit_burst();
+        let limiter = self
+            .icmp_rate_limiters
+            .entry(destination)
+            .or_insert_with(|| RateLimiter::new(rate, burst));
+        if limiter.try_consume(1, now) {
+            return false;
+        }
+        self.icmp_requests_limited += 1;
+        debug!(
+            target: TAG,
+            "ICMP rate limit exceeded for {}, dropping echo request",
+            Ipv4Addr::from(destination)
+        );
+        true
+    }
+
+    fn log_target_name(ipv4_packet: &Ipv4Packet) {
+        if !log_enabled!(target: TAG, Level::Debug) {
+            return;
+        }
+        let (transport_header_data, payload) =
+            match (ipv4_packet.transport_header_data(), ipv4_packet.payload()) {
+                (Some(transport_header_data), Some(payload)) => (transport_header_data, payload),
+                _ => return,
+            };
+        let protocol = ipv4_packet.ipv4_header_data().protocol();
+        let destination_port = transport_header_data.destination_port();
+        if let Some(name) = sniffer::sniff(protocol, destination_port, payload) {
+            let id =
+                ConnectionId::from_headers(ipv4_packet.ipv4_header_data(), transport_header_data);
+            debug!(target: TAG, "{}: {}", id, name);
+        }
+    }
+
+    // the packet is valid, but must not open any connection to the network
+    fn drop_dry_run(ipv4_packet: &Ipv4Packet) {
+        match ipv4_packet.transport_header_data() {
+            Some(transport_header_data) => {
+                let id = ConnectionId::from_headers(
+                    ipv4_packet.ipv4_header_data(),
+                    transport_header_data,
+                );
+                info!(target: TAG, "Dry run, dropping packet of {}", id);
+            }
+            None => info!(
+                target: TAG,
+                "Dry run, dropping packet (protocol {:?})",
+                ipv4_packet.ipv4_header_data().protocol()
+            ),
+        }
+        if log_enabled!(target: TAG, Level::Trace) {
+            trace!(
+                target: TAG,
+                "{}",
+                binary::build_packet_string(ipv4_packet.raw())
+            );
+        }
+    }
+
+    // a packet of a protocol without transport header support is relayed verbatim
+    fn is_raw(ipv4_packet: &Ipv4Packet) -> bool {
+        let ipv4_header_data = ipv4_packet.ipv4_header_data();
+        matches!(ipv4_header_data.protocol(), Protocol::Other(_)) && !ipv4_header_data.is_fragment()
+    }
+
+    /// Relay an IPv6 packet from the client.
+    ///
+    /// Only UDP is supported over IPv6 for now, other packets are dropped. As for IPv4, return a
+    /// `WouldBlock` error if the connection cannot accept the packet now.
+    pub fn send_ipv6_to_network(
+        &mut self,
+        selector: &mut Selector,
+        ipv6_packet: &Ipv6Packet,
+    ) -> io::Result<()> {
+        let id = match Udp6ConnectionId::from_packet(ipv6_packet) {
+            Some(id) => id,
+            None => {
+                warn!(
+                    target: TAG,
+                    "Dropping unsupported IPv6 packet (next header {})",
+                    ipv6_packet.ipv6_header_data().next_header()
+                );
+                return Ok(());
+            }
+        };
+        if self.config.dry_run() {
+            info!(target: TAG, "Dry run, dropping IPv6 packet of {}", id);
+            return Ok(());
+        }
+        if let Some(connection) = self
+            .udp6_connections
+            .iter()
+            .find(|connection| *connection.borrow().id() == id)
+        {
+            if !connection.borrow().can_accept(ipv6_packet) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Connection buffer full",
+                ));
+            }
+        }
+        match self.udp6_connection(selector, id) {
+            Ok(index) => {
+                let closed = {
+                    let mut connection = self.udp6_connections[index].borrow_mut();
+                    connection.send_to_network(selector, ipv6_packet);
+                    connection.is_closed()
+                };
+                if closed {
+                    self.remove_udp6_at(index);
+                }
+            }
+            Err(err) => error!(target: TAG, "Cannot create IPv6 route, dropping packet: {}", err),
+        }
+        Ok(())
+    }
+
+    fn udp6_connection(
+        &mut self,
+        selector: &mut Selector,
+        id: Udp6ConnectionId,
+    ) -> io::Result<usize> {
+        if let Some(index) = self
+            .udp6_connections
+            .iter()
+            .position(|connection| *connection.borrow().id() == id)
+        {
+            return Ok(index);
+        }
+        self.check_protocol_limit(Protocol::Udp)?;
+        self.ensure_connection_capacity(selector);
+        let connection = Udp6Connection::create(selector, id, self.client.clone(), &self.config)?;
+        let expiry = connection.borrow().expiry();
+        self.udp6_expiry_wheel
+            .schedule(expiry, Rc::downgrade(&connection));
+        self.udp6_connections.push(connection);
+        Ok(self.udp6_connections.len() - 1)
+    }
+
+    /// Decrement the TTL of `ipv4_packet`, as any router does.
+    ///
+    /// Return `Err` if it expired: the packet must be dropped, and the ICMP Time Exceeded error (if
+    /// any) sent back to the client, so that traceroute terminates at the relay hop.
+    fn decrement_ttl(ipv4_packet: &mut Ipv4Packet) -> Result<(), Option<Vec<u8>>> {
+        let ttl = ipv4_packet.ipv4_header_data().ttl();
+        if ttl <= 1 {
+            let time_exceeded = if icmp_error::may_reply_with_error(ipv4_packet) {
+                Some(icmp_error::build_time_exceeded(ipv4_packet))
+            } else {
+                None
+            };
+            return Err(time_exceeded);
+        }
+        ipv4_packet.ipv4_header_mut().set_ttl(ttl - 1);
+        Ok(())
+    }
+
+    /// Lower the MSS advertised by the client in TCP SYN segments, so that the segments fit in the
+    /// tunnel MTU.
+    fn clamp_mss(ipv4_packet: &mut Ipv4Packet, max_mss: u16) {
+        if let (_, Some((TransportHeaderMut::Tcp(mut tcp_header), _))) = ipv4_packet.split_mut() {
+            if tcp_header.is_syn() && tcp_header.clamp_mss(max_mss) {
+                debug!(target: TAG, "MSS clamped to {}", max_mss);
+            }
+        }
+    }
+
+    /// Answer a DNS query for a blocked domain, or from the cache, without any network round trip,
+    /// if possible.
+    ///
+    /// Return `true` if the query was answered.
+    fn answer_dns_query(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) -> bool {
+        if self.dns_blocklist.is_none() && self.dns_cache.is_none() {
+            return false;
+        }
+        match ipv4_packet.transport_header_data() {
+            Some(TransportHeaderData::Udp(udp_header_data))
+                if udp_header_data.destination_port() == DNS_PORT => {}
+            _ => return false,
+        }
+        let query = ipv4_packet.payload().expect("UDP packet without payload");
+        let blocked = self
+            .dns_blocklist
+            .as_ref()
+            .and_then(|dns_blocklist| dns_blocklist.answer(query));
+        let response = match blocked {
+            Some(response) => {
+                debug!(target: TAG, "DNS query for a blocked domain, answering name error");
+                response
+            }
+            None => {
+                let cached = self
+                    .dns_cache
+                    .as_ref()
+                    .and_then(|dns_cache| dns_cache.borrow_mut().lookup(query, Instant::now()));
+                match cached {
+                    Some(response) => {
+                        debug!(target: TAG, "DNS query answered from cache");
+                        response
+                    }
+                    None => return false,
+                }
+            }
+        };
+        let mut raw = dns_cache::build_response_packet(ipv4_packet, &response);
+        let response_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &response_packet) {
+            warn!(target: TAG, "Cannot send DNS response to client: {}", err);
+        }
+        true
+    }
+
+    /// Reply locally to an ICMP echo or timestamp request addressed to the relay itself, without
+    /// opening any socket. The reply is sent with the given `ttl`.
+    ///
+    /// Return `true` if `ipv4_packet` has been answered.
+    fn answer_echo_request(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        ttl: u8,
+    ) -> bool {
+        if ipv4_packet.ipv4_header_data().destination() != connection::LOCALHOST_FORWARD {
+            return false;
+        }
+        let length = ipv4_packet.payload().map_or(0, <[u8]>::len);
+        let mut raw = match ipv4_packet.transport_header_data() {
+            Some(TransportHeaderData::Icmp(icmp_header_data))
+                if icmp_header_data.is_echo_request() && length >= ICMP_HEADER_LENGTH =>
+            {
+                debug!(target: TAG, "Echo request to the relay, answering locally");
+                icmp_error::build_echo_reply(ipv4_packet)
+            }
+            Some(TransportHeaderData::Icmp(icmp_header_data))
+                if icmp_header_data.is_timestamp_request()
+                    && length >= TIMESTAMP_MESSAGE_LENGTH =>
+            {
+                debug!(target: TAG, "Timestamp request to the relay, answering locally");
+                icmp_error::build_timestamp_reply(ipv4_packet, icmp_error::timestamp_now())
+            }
+            _ => return false,
+        };
+        let mut reply_packet = Ipv4Packet::parse(&mut raw);
+        reply_packet.ipv4_header_mut().set_ttl(ttl);
+        if let Err(err) = client_channel.send_to_client(selector, &reply_packet) {
+            warn!(target: TAG, "Cannot send ICMP reply to client: {}", err);
+        }
+        true
+    }
+
+    /// Drop `ipv4_packet` if it has the DF flag but exceeds the outbound MTU, and tell the client
+    /// (rfc1191 section 4): it must not be fragmented to fit the outbound path.
+    ///
+    /// Return `true` if the packet is dropped.
+    fn reject_oversized_datagram(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) -> bool {
+        let outbound_mtu = match self.config.outbound_mtu() {
+            Some(outbound_mtu) => outbound_mtu,
+            None => return false,
+        };
+        let ipv4_header_data = ipv4_packet.ipv4_header_data();
+        // TCP is terminated by the relay, which segments the stream for the network itself
+        if !ipv4_header_data.dont_fragment()
+            || ipv4_header_data.total_length() <= outbound_mtu
+            || ipv4_header_data.protocol() == Protocol::Tcp
+        {
+            return false;
+        }
+        debug!(
+            target: TAG,
+            "Datagram with DF flag too large ({} > {}), dropping packet",
+            ipv4_header_data.total_length(),
+            outbound_mtu
+        );
+        if icmp_error::may_reply_with_error(ipv4_packet) {
+            let mut raw = icmp_error::build_fragmentation_needed(ipv4_packet, outbound_mtu);
+            let error_packet = Ipv4Packet::parse(&mut raw);
+            if let Err(err) = client_channel.send_to_client(selector, &error_packet) {
+                warn!(
+                    target: TAG,
+                    "Cannot send Fragmentation Needed to client: {}", err
+                );
+            }
+        }
+        true
+    }
+
+    /// Notify the client that the destination of `ipv4_packet` is unreachable, so that it does not
+    /// wait for a timeout.
+    fn send_destination_unreachable(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        code: u8,
+    ) {
+        if ipv4_packet.ipv4_header_data().protocol() == Protocol::Icmp {
+            // avoid replying to an ICMP error by another one (rfc1122 section 3.2.2)
+            return;
+        }
+        let mut raw = icmp_error::build_destination_unreachable(ipv4_packet, code);
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &error_packet) {
+            warn!(
+                target: TAG,
+                "Cannot send Destination Unreachable to client: {}", err
+            );
+        }
+    }
+
+    /// Indicate whether a raw socket may relay the packets of `protocol`.
+    ///
+    /// For a raw socket, 0 means any protocol (`IPPROTO_IP`) and 255 includes the IP header in
+    /// the payload (`IPPROTO_RAW`), so neither may be relayed verbatim.
+    fn has_raw_socket(protocol: Protocol) -> bool {
+        !matches!(protocol, Protocol::Other(0) | Protocol::Other(255))
+    }
+
+    /// Drop `ipv4_packet` if its protocol has no transport support while raw packets are not
+    /// relayed (or cannot be, for some protocol numbers), according to the unknown protocol
+    /// policy.
+    ///
+    /// Return `true` if the packet is dropped.
+    fn drop_unknown_protocol(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) -> bool {
+        let ipv4_header_data = ipv4_packet.ipv4_header_data();
+        let protocol = ipv4_header_data.protocol();
+        if !matches!(protocol, Protocol::Other(_))
+            || (cfg!(unix) && self.config.relay_raw() && Self::has_raw_socket(protocol))
+        {
+            return false;
+        }
+        match self.config.unknown_protocol_policy() {
+            UnknownProtocolPolicy::Drop => {
+                debug!(target: TAG, "Dropping packet of protocol {:?}", protocol);
+            }
+            UnknownProtocolPolicy::Log => info!(
+                target: TAG,
+                "Dropping packet of unsupported protocol {:?} to {}",
+                protocol,
+                Ipv4Addr::from(ipv4_header_data.destination())
+            ),
+            UnknownProtocolPolicy::Reply => {
+                debug!(target: TAG, "Protocol {:?} unreachable", protocol);
+                Self::send_destination_unreachable(
+                    selector,
+                    client_channel,
+                    ipv4_packet,
+                    icmp_error::CODE_PROTOCOL_UNREACHABLE,
+                );
+            }
+        }
+        true
+    }
+
     fn connection(
         &mut self,
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<usize> {
-        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
-        let transport_header_data = transport_header_data.expect("No transport");
-        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
+        let id = match ipv4_packet.headers_data() {
+            (ipv4_header_data, Some(transport_header_data)) => {
+                ConnectionId::from_headers(ipv4_header_data, transport_header_data)
+            }
+            (ipv4_header_data, None) => ConnectionId::from_ipv4_header(ipv4_header_data),
+        };
         let index = match self.find_index(&id) {
             Some(index) => index,
             None => {
-                let connection =
-                    Self::create_connection(selector, id, self.client.clone(), ipv4_packet)?;
-                let index = self.connections.len();
-                self.connections.push(connection);
-                index
+                self.check_protocol_limit(id.protocol())?;
+                self.ensure_connection_capacity(selector);
+                let connection = Self::create_connection(
+                    selector,
+                    id,
+                    self.client.clone(),
+                    ipv4_packet,
+                    &self.config,
+                    self.dns_cache.as_ref(),
+                    self.tcp_socket_pool.as_ref(),
+                    &self.icmp_dispatcher,
+                )?;
+                self.add_connection(connection);
+                self.connections.len() - 1
             }
         };
         Ok(index)
     }
 
+    /// Call `handle_timeout()` on `connection` at `deadline`, for a timer started outside the
+    /// router (its earlier `expiry()` may not be scheduled).
+    pub fn schedule_timeout(
+        &mut self,
+        deadline: Instant,
+        connection: Weak<RefCell<dyn Connection>>,
+    ) {
+        self.expiry_wheel.schedule(deadline, connection);
+    }
+
+    /// Call `handle_timeout()` on the UDP-over-IPv6 `connection` at `deadline`.
+    pub fn schedule_udp6_timeout(
+        &mut self,
+        deadline: Instant,
+        connection: Weak<RefCell<Udp6Connection>>,
+    ) {
+        self.udp6_expiry_wheel.schedule(deadline, connection);
+    }
+
+    fn add_connection(&mut self, connection: Rc<RefCell<dyn Connection>>) {
+        if let Some(expiry) = connection.borrow().expiry() {
+            self.expiry_wheel
+                .schedule(expiry, Rc::downgrade(&connection));
+        }
+        if let Some((connection_log, client_id)) = &self.connection_log {
+            connection_log
+                .borrow_mut()
+                .on_opened(*client_id, connection.borrow().id());
+        }
+        self.notify_opened(connection.borrow().id(), OpenStage::Created);
+        self.connections.push(connection);
+    }
+
+    fn notify_opened(&self, id: &ConnectionId, stage: OpenStage) {
+        if !self.open_listeners.is_empty() {
+            let opened = OpenedConnection {
+                id: id.clone(),
+                stage,
+            };
+            for listener in &self.open_listeners {
+                listener.on_event(&opened);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn create_connection(
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
         ipv4_packet: &Ipv4Packet,
+        config: &RelayConfig,
+        dns_cache: Option<&SharedDnsCache>,
+        tcp_socket_pool: Option<&SharedSocketPool>,
+        icmp_dispatcher: &SharedIcmpDispatcher,
     ) -> io::Result<Rc<RefCell<dyn Connection>>> {
+        let id = id.with_new_flow_id();
         let (ipv4_header, transport_header) = ipv4_packet.headers();
+        if let Protocol::Other(_) = id.protocol() {
+            return Self::create_raw_connection(selector, id, client, ipv4_header, config);
+        }
         let transport_header = transport_header.expect("No transport");
         match id.protocol() {
             Protocol::Tcp => Ok(TcpConnection::create(
@@ -129,41 +878,164 @@ impl Router {
                 client,
                 ipv4_header,
                 transport_header,
+                config,
+                tcp_socket_pool,
             )?),
-            Protocol::Udp => Ok(UdpConnection::create(
-                selector,
-                id,
-                client,
-                ipv4_header,
-                transport_header,
-            )?),
+            Protocol::Udp => {
+                // only the responses from DNS servers are cached
+                let is_dns = id.rewritten_destination().port() == DNS_PORT;
+                let dns_cache = dns_cache.filter(|_| is_dns).cloned();
+                Ok(UdpConnection::create(
+                    selector,
+                    id,
+                    client,
+                    ipv4_header,
+                    transport_header,
+                    config,
+                    dns_cache,
+                )?)
+            }
             Protocol::Icmp => Ok(IcmpConnection::create(
                 selector,
                 id,
                 client,
                 ipv4_header,
                 transport_header,
+                config,
+                icmp_dispatcher,
             )?),
-            p => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported protocol: {:?}", p),
-            )),
+            Protocol::Other(_) => unreachable!(),
         }
     }
 
-    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
-        self.connections
+    #[cfg(unix)]
+    fn create_raw_connection(
+        selector: &mut Selector,
+        id: ConnectionId,
+        client: Weak<RefCell<Client>>,
+        ipv4_header: Ipv4Header,
+        config: &RelayConfig,
+    ) -> io::Result<Rc<RefCell<dyn Connection>>> {
+        Ok(RawConnection::create(
+            selector,
+            id,
+            client,
+            ipv4_header,
+            config,
+        )?)
+    }
+
+    #[cfg(not(unix))]
+    fn create_raw_connection(
+        _: &mut Selector,
+        id: ConnectionId,
+        _: Weak<RefCell<Client>>,
+        _: Ipv4Header,
+        _: &RelayConfig,
+    ) -> io::Result<Rc<RefCell<dyn Connection>>> {
+        Err(io::Error::other(format!(
+            "Unsupported protocol: {:?}",
+            id.protocol()
+        )))
+    }
+
+    // reject a new flow if the connections of its protocol already reached their limit
+    fn check_protocol_limit(&self, protocol: Protocol) -> io::Result<()> {
+        let max_connections = match protocol {
+            Protocol::Tcp => self.config.max_tcp_connections(),
+            Protocol::Udp => self.config.max_udp_connections(),
+            Protocol::Icmp => self.config.max_icmp_connections(),
+            Protocol::Other(_) => None,
+        };
+        let max_connections = match max_connections {
+            Some(max_connections) => max_connections,
+            None => return Ok(()),
+        };
+        let mut count = self
+            .connections
+            .iter()
+            .filter(|connection| connection.borrow().id().protocol() == protocol)
+            .count();
+        if protocol == Protocol::Udp {
+            count += self.udp6_connections.len();
+        }
+        if count >= max_connections {
+            return Err(io::Error::other(format!(
+                "Too many {:?} connections (maximum {})",
+                protocol, max_connections
+            )));
+        }
+        Ok(())
+    }
+
+    // evict a connection if the limit is reached, before creating a new one
+    fn ensure_connection_capacity(&mut self, selector: &mut Selector) {
+        if let Some(max_connections) = self.config.max_connections() {
+            if self.connections.len() + self.udp6_connections.len() >= max_connections {
+                self.evict_least_recently_used(selector);
+            }
+        }
+    }
+
+    fn evict_least_recently_used(&mut self, selector: &mut Selector) {
+        let oldest = self
+            .connections
             .iter()
-            .position(|connection| connection.borrow().id() == id)
+            .enumerate()
+            .min_by_key(|(_, connection)| connection.borrow().idle_since())
+            .map(|(index, connection)| (index, connection.borrow().idle_since()));
+        let oldest_udp6 = self
+            .udp6_connections
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, connection)| connection.borrow().idle_since())
+            .map(|(index, connection)| (index, connection.borrow().idle_since()));
+        match (oldest, oldest_udp6) {
+            (Some((_, idle_since)), Some((index, udp6_idle_since)))
+                if udp6_idle_since < idle_since =>
+            {
+                self.evict_udp6_at(selector, index)
+            }
+            (None, Some((index, _))) => self.evict_udp6_at(selector, index),
+            (Some((index, _)), _) => {
+                {
+                    let mut connection = self.connections[index].borrow_mut();
+                    info!(
+                        target: TAG,
+                        "Too many connections, evicting the least recently used: {}",
+                        connection.id()
+                    );
+                    connection.close(selector);
+                }
+                self.remove_at(index, false);
+            }
+            (None, None) => (),
+        }
+    }
+
+    fn evict_udp6_at(&mut self, selector: &mut Selector, index: usize) {
+        {
+            let mut connection = self.udp6_connections[index].borrow_mut();
+            info!(
+                target: TAG,
+                "Too many connections, evicting the least recently used: {}",
+                connection.id()
+            );
+            connection.close(selector);
+        }
+        self.remove_udp6_at(index);
+    }
+
+    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
+        self.connections.find_index(id)
     }
 
     pub fn remove(&mut self, connection: &dyn Connection) {
         let index = self
-            .connections
-            .iter()
-            .position(|item| {
-                // compare (thin) pointers to find the connection to remove
-                binary::ptr_data_eq(connection, item.as_ptr())
+            .find_index(connection.id())
+            .filter(|&index| {
+                // compare (thin) pointers to check that this is the connection to remove
+                binary::ptr_data_eq(connection, self.connections[index].as_ptr())
             })
             .expect("Removing an unknown connection");
         debug!(
@@ -171,21 +1043,255 @@ impl Router {
             "Self-removing connection from router: {}",
             connection.id()
         );
+        self.record_removed(connection, false);
         self.connections.swap_remove(index);
     }
 
-    pub fn clear(&mut self, selector: &mut Selector) {
-        for connection in &mut self.connections {
-            connection.borrow_mut().close(selector);
+    /// Reset the (IPv4) connection identified by `id` and remove it from the router.
+    ///
+    /// Return `false` if there is no such connection.
+    pub fn reset_connection(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+    ) -> bool {
+        let index = match self.find_index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        {
+            let mut connection = self.connections[index].borrow_mut();
+            info!(target: TAG, "Resetting connection: {}", connection.id());
+            connection.reset(selector, client_channel);
         }
-        self.connections.clear();
+        self.remove_at(index, false);
+        true
     }
 
-    pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        // remove the last items first, otherwise i might not be less than len() on swap_remove(i)
-        for i in (0..self.connections.len()).rev() {
-            let expired = {
-                let mut connection = self.connections[i].borrow_mut();
+    fn remove_at(&mut self, index: usize, expired: bool) {
+        let connection = self.connections.swap_remove(index);
+        self.record_removed(&*connection.borrow(), expired);
+    }
+
+    // every connection is removed exactly once, so each one is reported once
+    fn record_removed(&mut self, connection: &dyn Connection, expired: bool) {
+        let stats = connection.stats();
+        self.removed_stats += stats;
+        if !self.close_listeners.is_empty() || self.connection_log.is_some() {
+            let closed = ClosedConnection {
+                id: connection.id().clone(),
+                stats,
+                expired,
+            };
+            for listener in &self.close_listeners {
+                listener.on_event(&closed);
+            }
+            if let Some((connection_log, client_id)) = &self.connection_log {
+                connection_log.borrow_mut().on_closed(*client_id, &closed);
+            }
+        }
+    }
+
+    pub fn remove_udp6(&mut self, connection: &Udp6Connection) {
+        let index = self
+            .udp6_connections
+            .iter()
+            .position(|item| std::ptr::eq(connection, item.as_ptr()))
+            .expect("Removing an unknown connection");
+        debug!(
+            target: TAG,
+            "Self-removing connection from router: {}",
+            connection.id()
+        );
+        self.removed_stats += connection.stats();
+        self.udp6_connections.swap_remove(index);
+    }
+
+    fn remove_udp6_at(&mut self, index: usize) {
+        let connection = self.udp6_connections.swap_remove(index);
+        self.removed_stats += connection.borrow().stats();
+    }
+
+    pub fn clear(&mut self, selector: &mut Selector) {
+        for connection in &mem::take(&mut self.connections) {
+            let mut connection = connection.borrow_mut();
+            connection.close(selector);
+            self.record_removed(&*connection, false);
+        }
+        for connection in &mut self.udp6_connections {
+            let mut connection = connection.borrow_mut();
+            connection.close(selector);
+            self.removed_stats += connection.stats();
+        }
+        self.udp6_connections.clear();
+        self.icmp_dispatcher.borrow_mut().close(selector);
+    }
+
+    /// Aggregate the traffic of all the connections of this router, including the removed ones.
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = self.removed_stats;
+        for connection in &self.connections {
+            stats += connection.borrow().stats();
+        }
+        for connection in &self.udp6_connections {
+            stats += connection.borrow().stats();
+        }
+        stats
+    }
+
+    /// Close the UDP connection rejected by the ICMP Port Unreachable error `message`, received
+    /// from the network, if any.
+    ///
+    /// Return the error to forward to the client once the connection is closed.
+    pub fn close_unreachable_flow(
+        &mut self,
+        selector: &mut Selector,
+        message: &[u8],
+    ) -> Option<Vec<u8>> {
+        let (source, destination) = icmp_error::port_unreachable_udp_flow(message)?;
+        let index = self.connections.iter().position(|connection| {
+            // the ICMP connection which received the error is currently borrowed, skip it
+            connection.try_borrow().is_ok_and(|connection| {
+                connection.id().protocol() == Protocol::Udp
+                    && connection.network_destination() == destination
+                    && connection.local_port() == Some(source.port())
+            })
+        })?;
+        let error = {
+            let mut connection = self.connections[index].borrow_mut();
+            debug!(
+                target: TAG,
+                "Port unreachable, removing connection from router: {}",
+                connection.id()
+            );
+            connection.close(selector);
+            connection.build_port_unreachable()
+        };
+        self.remove_at(index, false);
+        error
+    }
+
+    /// Snapshot of the live connections of this router, to render a netstat-like table.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn connections_info(&self, now: Instant) -> Vec<ConnectionInfo> {
+        let mut infos: Vec<ConnectionInfo> = self
+            .connections
+            .iter()
+            .map(|connection| ConnectionInfo::of(&*connection.borrow(), now))
+            .collect();
+        for connection in &self.udp6_connections {
+            let connection = connection.borrow();
+            let stats = connection.stats();
+            infos.push(ConnectionInfo {
+                protocol: Protocol::Udp,
+                source: (*connection.id().source()).into(),
+                destination: (*connection.id().destination()).into(),
+                state: if connection.is_closed() {
+                    "CLOSED"
+                } else {
+                    "OPEN"
+                },
+                age: now.saturating_duration_since(connection.created_at()),
+                idle: now.saturating_duration_since(connection.idle_since()),
+                tx_idle: stats.tx_idle(now),
+                rx_idle: stats.rx_idle(now),
+                stats,
+            });
+        }
+        infos
+    }
+
+    /// Snapshot of the activity of this router, including the removed connections.
+    pub fn metrics(&self) -> RelayMetrics {
+        let mut metrics = RelayMetrics {
+            icmp_sockets_opened: self.icmp_dispatcher.borrow().sockets_opened(),
+            icmp_requests_limited: self.icmp_requests_limited,
+            stats: self.stats(),
+            ..RelayMetrics::default()
+        };
+        for connection in &self.connections {
+            metrics.record_active_connection(connection.borrow().id().protocol());
+        }
+        for _ in &self.udp6_connections {
+            metrics.record_active_connection(Protocol::Udp);
+        }
+        metrics
+    }
+
+    /// Close and remove the connections whose idle timeout elapsed.
+    ///
+    /// Only the connections whose deadline is reached are visited, the others stay in the
+    /// expiry wheel.
+    pub fn clean_expired_connections(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
+        let now = Instant::now();
+        self.reassembler.remove_expired(now);
+        for weak in self.expiry_wheel.expire(now) {
+            // the connections removed by other means are dropped lazily
+            let connection_rc = match weak.upgrade() {
+                Some(connection_rc) => connection_rc,
+                None => continue,
+            };
+            let index = match self
+                .find_index(connection_rc.borrow().id())
+                .filter(|&index| Rc::ptr_eq(&self.connections[index], &connection_rc))
+            {
+                Some(index) => index,
+                None => continue,
+            };
+            // Some(expired) if the connection is closed
+            let closed = {
+                let mut connection = connection_rc.borrow_mut();
+                if connection.is_expired() {
+                    debug!(
+                        target: TAG,
+                        "Removing expired connection from router: {}",
+                        connection.id()
+                    );
+                    connection.close(selector);
+                    Some(true)
+                } else {
+                    connection.handle_timeout(selector, client_channel);
+                    if connection.is_closed() {
+                        debug!(
+                            target: TAG,
+                            "Removing timed out connection from router: {}",
+                            connection.id()
+                        );
+                        Some(false)
+                    } else {
+                        if let Some(expiry) = connection.expiry() {
+                            // touched since it was scheduled, or another timer is pending
+                            let deadline = Self::reschedule_deadline(expiry, now);
+                            self.expiry_wheel.schedule(deadline, weak);
+                        }
+                        None
+                    }
+                }
+            };
+            if let Some(expired) = closed {
+                self.remove_at(index, expired);
+            }
+        }
+        for weak in self.udp6_expiry_wheel.expire(now) {
+            let connection_rc = match weak.upgrade() {
+                Some(connection_rc) => connection_rc,
+                None => continue,
+            };
+            let index = match self
+                .udp6_connections
+                .iter()
+                .position(|item| Rc::ptr_eq(item, &connection_rc))
+            {
+                Some(index) => index,
+                None => continue,
+            };
+            let expired = {
+                let mut connection = connection_rc.borrow_mut();
                 if connection.is_expired() {
                     debug!(
                         target: TAG,
@@ -195,12 +1301,852 @@ impl Router {
                     connection.close(selector);
                     true
                 } else {
+                    connection.handle_timeout(selector);
+                    let deadline = Self::reschedule_deadline(connection.expiry(), now);
+                    self.udp6_expiry_wheel.schedule(deadline, weak);
                     false
                 }
             };
             if expired {
-                self.connections.swap_remove(i);
+                self.remove_udp6_at(index);
+            }
+        }
+        self.icmp_dispatcher
+            .borrow_mut()
+            .release_if_unused(selector);
+    }
+
+    fn reschedule_deadline(expiry: Instant, now: Instant) -> Instant {
+        if expiry > now {
+            expiry
+        } else {
+            now + EXPIRY_RECHECK_DELAY
+        }
+    }
+
+    /// Earliest instant when a connection may expire, if any.
+    pub fn next_expiry_deadline(&self) -> Option<Instant> {
+        match (
+            self.expiry_wheel.next_deadline(),
+            self.udp6_expiry_wheel.next_deadline(),
+        ) {
+            (Some(deadline), Some(udp6_deadline)) => Some(deadline.min(udp6_deadline)),
+            (deadline, udp6_deadline) => deadline.or(udp6_deadline),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::relay::icmp_header::{
+        IcmpHeaderData, TYPE_DESTINATION_UNREACHABLE, TYPE_TIME_EXCEEDED,
+    };
+    use crate::relay::stream_buffer::StreamBuffer;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use mio::net::TcpStream;
+    use mio::{Events, PollOpt, Ready, Token};
+    use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+    use std::time::Duration;
+
+    /// Client stream (registered to the selector) to build the `ClientChannel` given to the
+    /// router.
+    pub struct FakeClient {
+        pub network_to_client: StreamBuffer,
+        stream: TcpStream,
+        token: Token,
+        interests: Ready,
+        // keep the peer alive
+        _listener: net::TcpListener,
+    }
+
+    impl FakeClient {
+        pub fn new(selector: &mut Selector) -> Self {
+            let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let stream = TcpStream::from_stream(stream).unwrap();
+            // the replies change the interests of the client stream
+            let handler = |_: &mut Selector, _| {};
+            let token = selector
+                .register(&stream, handler, Ready::readable(), PollOpt::level())
+                .unwrap();
+            Self {
+                network_to_client: StreamBuffer::new(1024),
+                stream,
+                token,
+                interests: Ready::readable(),
+                _listener: listener,
+            }
+        }
+
+        pub fn channel(&mut self) -> ClientChannel<'_> {
+            ClientChannel::new(
+                &mut self.network_to_client,
+                &self.stream,
+                self.token,
+                &mut self.interests,
+                None,
+                false,
+            )
+        }
+    }
+
+    struct FakeConnection {
+        id: ConnectionId,
+        stats: ConnectionStats,
+        closed: bool,
+        idle_since: Instant,
+        created_at: Instant,
+        idle_timeout: Option<Duration>,
+    }
+
+    impl Connection for FakeConnection {
+        fn id(&self) -> &ConnectionId {
+            &self.id
+        }
+
+        fn send_to_network(&mut self, _: &mut Selector, _: &mut ClientChannel, _: &Ipv4Packet) {}
+
+        fn close(&mut self, _: &mut Selector) {
+            self.closed = true;
+        }
+
+        fn is_expired(&self) -> bool {
+            self.idle_timeout.is_some_and(|idle_timeout| {
+                connection::is_idle_expired(self.idle_since, idle_timeout)
+            })
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed
+        }
+
+        fn idle_since(&self) -> Instant {
+            self.idle_since
+        }
+
+        fn created_at(&self) -> Instant {
+            self.created_at
+        }
+
+        fn expiry(&self) -> Option<Instant> {
+            self.idle_timeout
+                .map(|idle_timeout| self.idle_since + idle_timeout)
+        }
+
+        fn stats(&self) -> ConnectionStats {
+            self.stats
+        }
+    }
+
+    fn create_udp_packet(source_port: u16) -> Vec<u8> {
+        create_udp_packet_with_ttl(source_port, 0)
+    }
+
+    fn create_udp_packet_with_ttl(source_port: u16, ttl: u8) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(ttl).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(source_port).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(8).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw
+    }
+
+    fn create_fake_connection(source_port: u16, tx_bytes: &[usize]) -> Rc<RefCell<FakeConnection>> {
+        create_fake_connection_of(create_udp_packet(source_port), tx_bytes)
+    }
+
+    // fake connection of the flow of the packet `raw`
+    fn create_fake_connection_of(
+        mut raw: Vec<u8>,
+        tx_bytes: &[usize],
+    ) -> Rc<RefCell<FakeConnection>> {
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let mut stats = ConnectionStats::default();
+        for &bytes in tx_bytes {
+            stats.record_tx(bytes);
+        }
+        Rc::new(RefCell::new(FakeConnection {
+            id,
+            stats,
+            closed: false,
+            idle_since: Instant::now(),
+            created_at: Instant::now(),
+            idle_timeout: None,
+        }))
+    }
+
+    fn create_localhost_udp_packet(source_port: u16) -> Vec<u8> {
+        let mut raw = create_udp_packet(source_port);
+        raw[16..20].copy_from_slice(&[127, 0, 0, 1]); // destination address
+        raw
+    }
+
+    #[test]
+    fn aggregate_stats() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        router
+            .connections
+            .push(create_fake_connection(1000, &[10, 20]));
+        router.connections.push(create_fake_connection(2000, &[5]));
+
+        let stats = router.stats();
+        assert_eq!(3, stats.tx_packets);
+        assert_eq!(35, stats.tx_bytes);
+        assert_eq!(2, router.metrics().active_udp_connections);
+
+        // the traffic of removed connections is kept
+        router.clear(&mut selector);
+        assert!(router.connections.is_empty());
+        assert_eq!(stats, router.stats());
+        assert_eq!(0, router.metrics().active_udp_connections);
+    }
+
+    #[test]
+    fn close_all_connections_on_clear() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        let connections: Vec<_> = (0..3)
+            .map(|i| create_fake_connection(1000 + i, &[]))
+            .collect();
+        for connection in &connections {
+            router.connections.push(connection.clone());
+        }
+
+        router.clear(&mut selector);
+        assert!(router.connections.is_empty());
+        assert!(connections
+            .iter()
+            .all(|connection| connection.borrow().is_closed()));
+    }
+
+    #[test]
+    fn reset_connection_by_id() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        let connection1 = create_fake_connection(1000, &[]);
+        let connection2 = create_fake_connection(2000, &[]);
+        router.connections.push(connection1.clone());
+        router.connections.push(connection2.clone());
+
+        let mut client = FakeClient::new(&mut selector);
+        let mut client_channel = client.channel();
+
+        let id = ConnectionId::from_endpoints(
+            Protocol::Udp,
+            SocketAddrV4::new(Ipv4Addr::new(0x12, 0x34, 0x56, 0x78), 2000),
+            SocketAddrV4::new(Ipv4Addr::new(0x42, 0x42, 0x42, 0x42), 5678),
+        );
+        assert!(router.reset_connection(&mut selector, &mut client_channel, &id));
+        assert!(connection2.borrow().is_closed());
+        assert!(!connection1.borrow().is_closed());
+        assert_eq!(1, router.connections.len());
+
+        // already removed
+        assert!(!router.reset_connection(&mut selector, &mut client_channel, &id));
+    }
+
+    #[test]
+    fn validate_packets_without_opening_connections() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_dry_run(true);
+        let mut router = Router::new(Rc::new(config));
+
+        let mut client = FakeClient::new(&mut selector);
+        let mut client_channel = client.channel();
+
+        // the captured stream, including a packet having a corrupted checksum
+        let mut packets: Vec<_> = (1000..1004)
+            .map(|port| {
+                let mut raw = create_udp_packet_with_ttl(port, 64);
+                Ipv4Packet::parse(&mut raw).compute_checksums();
+                raw
+            })
+            .collect();
+        packets[2][10] ^= 0x01;
+
+        for raw in &mut packets {
+            let mut ipv4_packet = Ipv4Packet::parse(raw);
+            router
+                .send_to_network(&mut selector, &mut client_channel, &mut ipv4_packet)
+                .unwrap();
+        }
+        assert!(router.connections.is_empty());
+        assert!(router.udp6_connections.is_empty());
+    }
+
+    #[test]
+    fn drop_packets_to_denied_destinations() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_denied_destinations("10.0.0.0/8".parse().unwrap());
+        let mut router = Router::new(Rc::new(config));
+
+        let mut client = FakeClient::new(&mut selector);
+        let mut client_channel = client.channel();
+
+        for &destination in &[[10, 1, 2, 3], [1, 1, 1, 1]] {
+            let mut raw = create_udp_packet_with_ttl(1000, 64);
+            raw[16..20].copy_from_slice(&destination);
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.compute_checksums();
+            router
+                .send_to_network(&mut selector, &mut client_channel, &mut ipv4_packet)
+                .unwrap();
+        }
+        // only the allowed destination got a connection
+        assert_eq!(1, router.connections.len());
+        let destination = router.connections[0].borrow().id().destination();
+        assert_eq!(Ipv4Addr::new(1, 1, 1, 1), *destination.ip());
+        // nothing is answered by default
+        assert!(client.network_to_client.is_empty());
+
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn dispose_of_unknown_protocols() {
+        let policies = [
+            UnknownProtocolPolicy::Drop,
+            UnknownProtocolPolicy::Log,
+            UnknownProtocolPolicy::Reply,
+        ];
+        for &policy in &policies {
+            let mut selector = Selector::create().unwrap();
+            let mut config = RelayConfig::new(0);
+            config.set_relay_raw(false);
+            config.set_unknown_protocol_policy(policy);
+            let mut router = Router::new(Rc::new(config));
+
+            let mut client = FakeClient::new(&mut selector);
+            let mut client_channel = client.channel();
+
+            let mut raw = create_udp_packet_with_ttl(1000, 64);
+            raw[9] = 47; // GRE
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.compute_checksums();
+            router
+                .send_to_network(&mut selector, &mut client_channel, &mut ipv4_packet)
+                .unwrap();
+            assert!(router.connections.is_empty());
+
+            if policy == UnknownProtocolPolicy::Reply {
+                let mut reply = Vec::new();
+                client.network_to_client.write_to(&mut reply).unwrap();
+                let error_packet = Ipv4Packet::parse(&mut reply);
+                assert_eq!(0x12345678, error_packet.ipv4_header_data().destination());
+                let icmp_header_data = IcmpHeaderData::parse(error_packet.payload().unwrap());
+                assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+                assert_eq!(
+                    icmp_error::CODE_PROTOCOL_UNREACHABLE,
+                    icmp_header_data.code()
+                );
+            } else {
+                assert!(client.network_to_client.is_empty());
             }
         }
     }
+
+    #[test]
+    fn drop_protocols_without_raw_socket() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_relay_raw(true);
+        config.set_unknown_protocol_policy(UnknownProtocolPolicy::Reply);
+        let mut router = Router::new(Rc::new(config));
+
+        let mut client = FakeClient::new(&mut selector);
+
+        for &protocol in &[0, 255] {
+            let mut raw = create_udp_packet_with_ttl(1000, 64);
+            raw[9] = protocol;
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.compute_checksums();
+            router
+                .send_to_network(&mut selector, &mut client.channel(), &mut ipv4_packet)
+                .unwrap();
+            // no socket is opened, the client is told the protocol is unreachable
+            assert!(router.connections.is_empty());
+            let mut reply = Vec::new();
+            client.network_to_client.write_to(&mut reply).unwrap();
+            let error_packet = Ipv4Packet::parse(&mut reply);
+            let icmp_header_data = IcmpHeaderData::parse(error_packet.payload().unwrap());
+            assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp_header_data.icmp_type());
+            assert_eq!(
+                icmp_error::CODE_PROTOCOL_UNREACHABLE,
+                icmp_header_data.code()
+            );
+        }
+    }
+
+    fn create_echo_request_packet(destination: u32, sequence_number: u16) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(1).unwrap(); // protocol (ICMP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(destination).unwrap(); // destination address
+
+        raw.write_u8(8).unwrap(); // type (echo request)
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(42).unwrap(); // identifier
+        raw.write_u16::<BigEndian>(sequence_number).unwrap(); // sequence number
+
+        raw
+    }
+
+    #[test]
+    fn limit_echo_requests_per_destination() {
+        let mut config = RelayConfig::new(0);
+        config.set_icmp_rate_limit(Some(1));
+        config.set_icmp_rate_limit_burst(3);
+        let mut router = Router::new(Rc::new(config));
+        let now = Instant::now();
+
+        // flood a destination at once: only the burst passes
+        let accepted = (0..5)
+            .filter(|&seq| {
+                let mut raw = create_echo_request_packet(0x42424242, seq);
+                !router.limit_echo_request(&Ipv4Packet::parse(&mut raw), now)
+            })
+            .count();
+        assert_eq!(3, accepted);
+        assert_eq!(2, router.metrics().icmp_requests_limited);
+
+        // the other destinations have their own bucket
+        let mut raw = create_echo_request_packet(0x43434343, 0);
+        assert!(!router.limit_echo_request(&Ipv4Packet::parse(&mut raw), now));
+
+        // the bucket is refilled over time
+        let mut raw = create_echo_request_packet(0x42424242, 5);
+        let later = now + Duration::from_secs(2);
+        assert!(!router.limit_echo_request(&Ipv4Packet::parse(&mut raw), later));
+
+        // the other packets are never limited
+        let mut raw = create_udp_packet_with_ttl(1000, 64);
+        assert!(!router.limit_echo_request(&Ipv4Packet::parse(&mut raw), now));
+    }
+
+    #[test]
+    fn bound_echo_rate_limiters() {
+        let mut config = RelayConfig::new(0);
+        config.set_icmp_rate_limit(Some(1));
+        config.set_icmp_rate_limit_burst(3);
+        let mut router = Router::new(Rc::new(config));
+        let now = Instant::now();
+
+        // spray destinations at once, so that no bucket is refilled
+        for destination in 0..(MAX_ICMP_RATE_LIMITERS as u32 + 16) {
+            let mut raw = create_echo_request_packet(0x0B000000 + destination, 0);
+            assert!(!router.limit_echo_request(&Ipv4Packet::parse(&mut raw), now));
+        }
+        assert_eq!(MAX_ICMP_RATE_LIMITERS, router.icmp_rate_limiters.len());
+        // the last destination is still limited
+        let last = 0x0B000000 + MAX_ICMP_RATE_LIMITERS as u32 + 15;
+        assert!(router.icmp_rate_limiters.contains_key(&last));
+    }
+
+    #[test]
+    fn expire_staggered_connections_in_order() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        let expired = Rc::new(RefCell::new(Vec::new()));
+        let expired2 = expired.clone();
+        router.add_close_listener(Rc::new(move |connection: &ClosedConnection| {
+            assert!(connection.expired);
+            expired2.borrow_mut().push(connection.id.clone())
+        }));
+
+        let ms = Duration::from_millis;
+        let connections: Vec<_> = [(1000, 300), (1001, 100), (1002, 200), (1003, 100)]
+            .iter()
+            .map(|&(port, timeout)| {
+                let connection = create_fake_connection(port, &[]);
+                connection.borrow_mut().idle_timeout = Some(ms(timeout));
+                router.add_connection(connection.clone());
+                connection
+            })
+            .collect();
+
+        std::thread::sleep(ms(50));
+        // touched, so it now expires after 1001 but before 1002
+        connections[3].borrow_mut().idle_since = Instant::now();
+
+        // the fake connections never send anything to the client
+        let mut client = FakeClient::new(&mut selector);
+
+        while let Some(deadline) = router.next_expiry_deadline() {
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            router.clean_expired_connections(&mut selector, &mut client.channel());
+        }
+        assert!(router.connections.is_empty());
+        assert!(connections
+            .iter()
+            .all(|connection| connection.borrow().is_closed()));
+        let expected: Vec<_> = [1, 3, 2, 0]
+            .iter()
+            .map(|&i| connections[i].borrow().id.clone())
+            .collect();
+        assert_eq!(expected, *expired.borrow());
+    }
+
+    #[test]
+    fn notify_close_listeners_once_per_connection() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        let closed = Rc::new(RefCell::new(Vec::new()));
+        let closed2 = closed.clone();
+        router.add_close_listener(Rc::new(move |connection: &ClosedConnection| {
+            closed2.borrow_mut().push(connection.clone())
+        }));
+
+        let connections: Vec<_> = (0..3)
+            .map(|i| create_fake_connection(1000 + i, &[10 * (i as usize + 1)]))
+            .collect();
+        for connection in &connections {
+            router.connections.push(connection.clone());
+        }
+
+        // a connection removing itself from the router
+        {
+            let mut connection = connections[1].borrow_mut();
+            connection.close(&mut selector);
+            router.remove(&*connection);
+        }
+        assert_eq!(1, closed.borrow().len());
+        assert_eq!(*connections[1].borrow().id(), closed.borrow()[0].id);
+        assert_eq!(20, closed.borrow()[0].stats.tx_bytes);
+
+        router.clear(&mut selector);
+        // clearing an empty router notifies nothing more
+        router.clear(&mut selector);
+        let closed = closed.borrow();
+        assert_eq!(3, closed.len());
+        for connection in &connections {
+            let id = connection.borrow().id().clone();
+            assert_eq!(1, closed.iter().filter(|closed| closed.id == id).count());
+        }
+    }
+
+    #[test]
+    fn decrement_ttl() {
+        let mut raw = create_udp_packet_with_ttl(1000, 64);
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        ipv4_packet.compute_checksums();
+
+        assert!(Router::decrement_ttl(&mut ipv4_packet).is_ok());
+        assert_eq!(63, ipv4_packet.ipv4_header_data().ttl());
+    }
+
+    #[test]
+    fn reply_time_exceeded_on_ttl_expiration() {
+        let mut raw = create_udp_packet_with_ttl(1000, 1);
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+
+        let mut reply = Router::decrement_ttl(&mut ipv4_packet)
+            .unwrap_err()
+            .expect("Expected a Time Exceeded reply");
+        let error_packet = Ipv4Packet::parse(&mut reply);
+        assert_eq!(Protocol::Icmp, error_packet.ipv4_header_data().protocol());
+        assert_eq!(0x12345678, error_packet.ipv4_header_data().destination());
+        let icmp_header_data = IcmpHeaderData::parse(error_packet.payload().unwrap());
+        assert_eq!(TYPE_TIME_EXCEEDED, icmp_header_data.icmp_type());
+    }
+
+    #[test]
+    fn close_flow_on_port_unreachable() {
+        let mut selector = Selector::create().unwrap();
+        let config = RelayConfig::new(0);
+        let mut router = Router::new(Rc::new(config.clone()));
+
+        let mut raw = create_localhost_udp_packet(1000);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let connection = Router::create_connection(
+            &mut selector,
+            id,
+            Weak::new(),
+            &ipv4_packet,
+            &config,
+            None,
+            None,
+            &router.icmp_dispatcher,
+        )
+        .unwrap();
+        let local_port = connection.borrow().local_port().unwrap();
+        router.connections.push(connection.clone());
+        router.connections.push(create_fake_connection(2000, &[]));
+
+        // the datagram sent to the network, from the local socket
+        let mut sent_raw = create_udp_packet(local_port);
+        sent_raw[12..20].copy_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+        let sent = Ipv4Packet::parse(&mut sent_raw);
+        let mut error_raw =
+            icmp_error::build_destination_unreachable(&sent, icmp_error::CODE_PORT_UNREACHABLE);
+        let error_packet = Ipv4Packet::parse(&mut error_raw);
+        let message = error_packet.payload().unwrap();
+
+        let mut reply = router
+            .close_unreachable_flow(&mut selector, message)
+            .expect("Expected a Port Unreachable reply");
+        assert!(connection.borrow().is_closed());
+        assert_eq!(1, router.connections.len());
+
+        // the error forwarded to the client embeds the datagram from the client
+        let reply_packet = Ipv4Packet::parse(&mut reply);
+        assert_eq!(0x12345678, reply_packet.ipv4_header_data().destination());
+        let flow = icmp_error::port_unreachable_udp_flow(reply_packet.payload().unwrap());
+        let (source, _) = flow.unwrap();
+        assert_eq!(1000, source.port());
+
+        // no other connection matches
+        assert!(router
+            .close_unreachable_flow(&mut selector, message)
+            .is_none());
+    }
+
+    #[test]
+    fn evict_least_recently_used_connection() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_max_connections(Some(3));
+        let mut router = Router::new(Rc::new(config));
+
+        let now = Instant::now();
+        let connections: Vec<_> = [10, 30, 20]
+            .iter()
+            .enumerate()
+            .map(|(i, &idle_seconds)| {
+                let connection = create_fake_connection(1000 + i as u16, &[]);
+                connection.borrow_mut().idle_since = now - Duration::from_secs(idle_seconds);
+                connection
+            })
+            .collect();
+        for connection in &connections {
+            router.connections.push(connection.clone());
+        }
+
+        let mut raw = create_localhost_udp_packet(2000);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        router.connection(&mut selector, &ipv4_packet).unwrap();
+
+        assert_eq!(3, router.connections.len());
+        // the connection idle for 30 seconds is evicted
+        assert!(connections[1].borrow().is_closed());
+        assert!(!connections[0].borrow().is_closed());
+        assert!(!connections[2].borrow().is_closed());
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn reject_flows_beyond_protocol_limit() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_max_icmp_connections(Some(2));
+        let mut router = Router::new(Rc::new(config));
+
+        for destination in &[0x42424242, 0x43434343] {
+            let raw = create_echo_request_packet(*destination, 0);
+            router.connections.push(create_fake_connection_of(raw, &[]));
+        }
+
+        let mut raw = create_echo_request_packet(0x44444444, 0);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(router.connection(&mut selector, &ipv4_packet).is_err());
+        assert_eq!(2, router.connections.len());
+
+        // the other protocols are not limited
+        let mut raw = create_localhost_udp_packet(2000);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        router.connection(&mut selector, &ipv4_packet).unwrap();
+        assert_eq!(3, router.connections.len());
+
+        // the existing ICMP flows are still routed
+        let mut raw = create_echo_request_packet(0x42424242, 1);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(0, router.connection(&mut selector, &ipv4_packet).unwrap());
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn find_many_connections_by_id() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+
+        let mut connections = Vec::new();
+        for port in 1000..3000 {
+            connections.push(create_fake_connection(port, &[]));
+        }
+        // echo requests to the same host, distinct by their identifier
+        for identifier in 0..100u16 {
+            let mut raw = create_echo_request_packet(0x42424242, 0);
+            raw[24..26].copy_from_slice(&identifier.to_be_bytes());
+            connections.push(create_fake_connection_of(raw, &[]));
+        }
+        for connection in &connections {
+            router.connections.push(connection.clone());
+        }
+        assert_eq!(2100, router.connections.len());
+
+        // remove one connection out of two, as if each one removed itself
+        let (removed, kept): (Vec<_>, Vec<_>) = connections
+            .into_iter()
+            .enumerate()
+            .partition(|(i, _)| i % 2 == 0);
+        let removed: Vec<_> = removed
+            .into_iter()
+            .map(|(_, connection)| {
+                router.remove(&*connection.borrow_mut());
+                Rc::downgrade(&connection)
+            })
+            .collect();
+        assert_eq!(1050, router.connections.len());
+
+        for (_, connection) in &kept {
+            let id = connection.borrow().id().clone();
+            let index = router.find_index(&id).expect("Connection not found");
+            assert!(Rc::ptr_eq(
+                &router.connections[index],
+                &(connection.clone() as Rc<RefCell<dyn Connection>>)
+            ));
+        }
+        // the router holds no reference to the removed connections anymore
+        for weak in &removed {
+            assert!(weak.upgrade().is_none());
+        }
+
+        router.clear(&mut selector);
+        assert!(router.connections.is_empty());
+        for (_, connection) in &kept {
+            assert!(router.find_index(connection.borrow().id()).is_none());
+            assert_eq!(1, Rc::strong_count(connection));
+        }
+    }
+
+    #[test]
+    fn snapshot_connections() {
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+        let now = Instant::now();
+
+        let udp = create_fake_connection(1234, &[100, 200]);
+        udp.borrow_mut().created_at = now - Duration::from_secs(30);
+        udp.borrow_mut().idle_since = now - Duration::from_secs(5);
+        router.connections.push(udp);
+        let icmp = create_fake_connection_of(create_echo_request_packet(0x42424242, 0), &[]);
+        icmp.borrow_mut().created_at = now - Duration::from_secs(2);
+        icmp.borrow_mut().idle_since = now - Duration::from_secs(2);
+        router.connections.push(icmp);
+
+        let infos = router.connections_info(now);
+        assert_eq!(2, infos.len());
+
+        assert_eq!(Protocol::Udp, infos[0].protocol);
+        assert_eq!(
+            "18.52.86.120:1234".parse::<SocketAddr>().unwrap(),
+            infos[0].source
+        );
+        assert_eq!(
+            "66.66.66.66:5678".parse::<SocketAddr>().unwrap(),
+            infos[0].destination
+        );
+        assert_eq!("OPEN", infos[0].state);
+        assert_eq!(Duration::from_secs(30), infos[0].age);
+        assert_eq!(Duration::from_secs(5), infos[0].idle);
+        assert_eq!(300, infos[0].stats.tx_bytes);
+
+        assert_eq!(Protocol::Icmp, infos[1].protocol);
+        assert_eq!(
+            "66.66.66.66:0".parse::<SocketAddr>().unwrap(),
+            infos[1].destination
+        );
+        assert_eq!(Duration::from_secs(2), infos[1].age);
+    }
+
+    fn create_ipv6_udp_packet(destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp_length = 8 + payload.len() as u16;
+        let mut raw = Vec::with_capacity(40 + udp_length as usize);
+
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, class, flow label
+        raw.write_u16::<BigEndian>(udp_length).unwrap(); // payload length
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets()); // source
+        raw.extend_from_slice(&Ipv6Addr::LOCALHOST.octets()); // destination
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(destination_port).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(udp_length).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend_from_slice(payload);
+        raw
+    }
+
+    #[test]
+    fn route_ipv6_udp_packet() {
+        let server = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mut selector = Selector::create().unwrap();
+        let mut router = Router::new(Rc::new(RelayConfig::new(0)));
+
+        let mut raw = create_ipv6_udp_packet(port, b"hello");
+        let ipv6_packet = Ipv6Packet::parse(&mut raw);
+        router
+            .send_ipv6_to_network(&mut selector, &ipv6_packet)
+            .unwrap();
+        assert_eq!(1, router.udp6_connections.len());
+
+        // the same flow is routed to the same connection
+        router
+            .send_ipv6_to_network(&mut selector, &ipv6_packet)
+            .unwrap();
+        assert_eq!(1, router.udp6_connections.len());
+
+        // let the connection write the datagrams to its socket
+        let mut events = Events::with_capacity(16);
+        for _ in 0..2 {
+            selector
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            selector.run_handlers(&events);
+        }
+
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            let (r, _) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(b"hello", &buf[..r]);
+        }
+        assert_eq!(10, router.stats().tx_bytes);
+        assert_eq!(1, router.metrics().active_udp_connections);
+
+        router.clear(&mut selector);
+        assert!(router.udp6_connections.is_empty());
+    }
 }
@@ -17,6 +17,7 @@
 use log::*;
 use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Token};
 use slab::Slab;
+use std::cell::Cell;
 use std::io;
 use std::rc::Rc;
 use std::time::Duration;
@@ -36,11 +37,23 @@ where
     }
 }
 
+/// Activity of the selector since its creation.
+///
+/// Many polls for few events reveal a busy loop, many events per poll a starvation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SelectorLoad {
+    pub polls: u64,
+    pub events: u64,
+}
+
+pub type SharedSelectorLoad = Rc<Cell<SelectorLoad>>;
+
 pub struct Selector {
     poll: Poll,
     handlers: Slab<Rc<dyn EventHandler>>,
     // tokens to be removed after all the current poll events are executed
     tokens_to_remove: Vec<Token>,
+    load: SharedSelectorLoad,
 }
 
 impl Selector {
@@ -49,9 +62,15 @@ impl Selector {
             poll: Poll::new()?,
             handlers: Slab::with_capacity(1024),
             tokens_to_remove: Vec::new(),
+            load: Rc::new(Cell::new(SelectorLoad::default())),
         })
     }
 
+    /// Counters updated on every poll and handled event, to read them outside the event loop.
+    pub fn shared_load(&self) -> SharedSelectorLoad {
+        self.load.clone()
+    }
+
     pub fn register<E, H>(
         &mut self,
         handle: &E,
@@ -104,11 +123,18 @@ impl Selector {
     }
 
     pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<usize> {
-        self.poll.poll(events, timeout)
+        let count = self.poll.poll(events, timeout)?;
+        let mut load = self.load.get();
+        load.polls += 1;
+        self.load.set(load);
+        Ok(count)
     }
 
     pub fn run_handlers(&mut self, events: &Events) {
         for event in events {
+            let mut load = self.load.get();
+            load.events += 1;
+            self.load.set(load);
             debug!(target: TAG, "event={:?}", event);
             let handler = self
                 .handlers
@@ -122,3 +148,119 @@ impl Selector {
         self.clean_removed_tokens();
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use mio::{Registration, SetReadiness};
+    use std::collections::HashMap;
+
+    /// Selector whose events are triggered by the tests rather than by sockets, to drive the
+    /// handlers (to be used in other tests).
+    pub struct MockSelector {
+        pub selector: Selector,
+        registrations: HashMap<Token, (Registration, SetReadiness)>,
+    }
+
+    impl MockSelector {
+        pub fn new() -> Self {
+            Self {
+                selector: Selector::create().unwrap(),
+                registrations: HashMap::new(),
+            }
+        }
+
+        pub fn register<H>(&mut self, handler: H, interest: Ready) -> Token
+        where
+            H: EventHandler + 'static,
+        {
+            let (registration, set_readiness) = Registration::new2();
+            let token = self
+                .selector
+                .register(&registration, handler, interest, PollOpt::edge())
+                .unwrap();
+            self.registrations
+                .insert(token, (registration, set_readiness));
+            token
+        }
+
+        /// Notify the handler of `token` that it is ready for `readiness`, and return the number
+        /// of events handled.
+        pub fn trigger(&mut self, token: Token, readiness: Ready) -> usize {
+            let set_readiness = self.registrations[&token].1.clone();
+            set_readiness.set_readiness(readiness).unwrap();
+            let mut events = Events::with_capacity(16);
+            self.selector
+                .poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            // the registration is edge-triggered, the next trigger must change its readiness
+            set_readiness.set_readiness(Ready::empty()).unwrap();
+            self.selector.run_handlers(&events);
+            events.iter().count()
+        }
+    }
+
+    #[test]
+    fn trigger_mock_events() {
+        let mut mock = MockSelector::new();
+        let handled = Rc::new(Cell::new(Ready::empty()));
+        let handled2 = handled.clone();
+        let token = mock.register(
+            move |_: &mut Selector, event: Event| handled2.set(event.readiness()),
+            Ready::readable() | Ready::writable(),
+        );
+
+        assert_eq!(1, mock.trigger(token, Ready::readable()));
+        assert_eq!(Ready::readable(), handled.get());
+        assert_eq!(1, mock.trigger(token, Ready::writable()));
+        assert_eq!(Ready::writable(), handled.get());
+        assert_eq!(1, mock.trigger(token, Ready::readable()));
+        assert_eq!(Ready::readable(), handled.get());
+    }
+
+    #[test]
+    fn count_polls_and_events() {
+        let mut selector = Selector::create().unwrap();
+        let dispatched = Rc::new(Cell::new(0));
+        let registrations: Vec<_> = (0..3)
+            .map(|_| {
+                let (registration, set_readiness) = Registration::new2();
+                let dispatched = dispatched.clone();
+                selector
+                    .register(
+                        &registration,
+                        move |_: &mut Selector, _| dispatched.set(dispatched.get() + 1),
+                        Ready::readable(),
+                        PollOpt::edge(),
+                    )
+                    .unwrap();
+                set_readiness.set_readiness(Ready::readable()).unwrap();
+                (registration, set_readiness)
+            })
+            .collect();
+
+        let load = selector.shared_load();
+        let mut events = Events::with_capacity(16);
+        selector
+            .poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        selector.run_handlers(&events);
+        assert_eq!(3, dispatched.get());
+        assert_eq!(
+            SelectorLoad {
+                polls: 1,
+                events: 3
+            },
+            load.get()
+        );
+
+        // a poll without any event
+        selector
+            .poll(&mut events, Some(Duration::from_millis(1)))
+            .unwrap();
+        selector.run_handlers(&events);
+        assert_eq!(2, load.get().polls);
+        assert_eq!(3, load.get().events);
+        drop(registrations);
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+use std::str;
+
+use super::dns_blocklist;
+use super::dns_cache::{self, DNS_PORT};
+use super::ipv4_header::Protocol;
+
+const HTTP_PORT: u16 = 80;
+const HTTPS_PORT: u16 = 443;
+
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// Name a flow targets, read from its application-layer payload for diagnostics.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TargetName {
+    DnsQuery(String),
+    TlsServerName(String),
+    HttpHost(String),
+}
+
+impl fmt::Display for TargetName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetName::DnsQuery(name) => write!(f, "DNS query {}", name),
+            TargetName::TlsServerName(name) => write!(f, "TLS SNI {}", name),
+            TargetName::HttpHost(name) => write!(f, "HTTP Host {}", name),
+        }
+    }
+}
+
+/// Extract the name targeted by the payload of a packet, if its destination port is a well-known
+/// one (DNS, HTTP or HTTPS) and its content is recognized.
+///
+/// This is best-effort: only the packet itself is parsed, so a TLS ClientHello or an HTTP request
+/// split across several segments is not recognized.
+pub fn sniff(protocol: Protocol, destination_port: u16, payload: &[u8]) -> Option<TargetName> {
+    match (protocol, destination_port) {
+        (Protocol::Udp, DNS_PORT) => dns_query_name(payload).map(TargetName::DnsQuery),
+        (Protocol::Tcp, HTTPS_PORT) => tls_server_name(payload).map(TargetName::TlsServerName),
+        (Protocol::Tcp, HTTP_PORT) => http_host(payload).map(TargetName::HttpHost),
+        _ => None,
+    }
+}
+
+fn dns_query_name(query: &[u8]) -> Option<String> {
+    let question = dns_cache::parse_query(query)?;
+    dns_blocklist::question_name(&question)
+}
+
+/// Read the server name indication of a TLS ClientHello, starting a TLS record.
+fn tls_server_name(record: &[u8]) -> Option<String> {
+    if *record.first()? != TLS_CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    // record header: content type, version, length; then the handshake type and length
+    let handshake = record.get(5..)?;
+    if *handshake.first()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    // skip client version and random
+    let mut index = 4 + 2 + 32;
+    // session id, cipher suites and compression methods
+    index += 1 + *handshake.get(index)? as usize;
+    index += 2 + read_u16(handshake, index)? as usize;
+    index += 1 + *handshake.get(index)? as usize;
+    let extensions_length = read_u16(handshake, index)? as usize;
+    index += 2;
+    let extensions = handshake.get(index..index + extensions_length)?;
+
+    let mut index = 0;
+    while index + 4 <= extensions.len() {
+        let extension_type = read_u16(extensions, index)?;
+        let extension_length = read_u16(extensions, index + 2)? as usize;
+        let extension = extensions.get(index + 4..index + 4 + extension_length)?;
+        if extension_type == TLS_EXTENSION_SERVER_NAME {
+            return first_host_name(extension);
+        }
+        index += 4 + extension_length;
+    }
+    None
+}
+
+// the extension data is a list of (type, length, name)
+fn first_host_name(extension: &[u8]) -> Option<String> {
+    let list_length = read_u16(extension, 0)? as usize;
+    let list = extension.get(2..2 + list_length)?;
+    let mut index = 0;
+    while index + 3 <= list.len() {
+        let name_type = list[index];
+        let name_length = read_u16(list, index + 1)? as usize;
+        let name = list.get(index + 3..index + 3 + name_length)?;
+        if name_type == SERVER_NAME_TYPE_HOST_NAME {
+            return str::from_utf8(name).ok().map(str::to_string);
+        }
+        index += 3 + name_length;
+    }
+    None
+}
+
+/// Read the `Host` header of an HTTP/1.x request.
+fn http_host(request: &[u8]) -> Option<String> {
+    let mut lines = request.split(|&b| b == b'\n');
+    // the request line starts with a method token, like "GET "
+    let request_line = lines.next()?;
+    let method_length = request_line.iter().position(|&b| b == b' ')?;
+    if method_length == 0
+        || !request_line[..method_length]
+            .iter()
+            .all(u8::is_ascii_uppercase)
+    {
+        return None;
+    }
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // end of the headers
+            break;
+        }
+        let line = str::from_utf8(line).ok()?;
+        if let Some(index) = line.find(':') {
+            if line[..index].eq_ignore_ascii_case("host") {
+                return Some(line[index + 1..].trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+fn read_u16(buf: &[u8], index: usize) -> Option<u16> {
+    buf.get(index..index + 2).map(BigEndian::read_u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ClientHello for "example.com", with an extension before the SNI
+    fn create_client_hello() -> Vec<u8> {
+        let mut extensions = vec![0xff, 0x01, 0x00, 0x01, 0x00]; // renegotiation_info
+        let name = b"example.com";
+        extensions.extend(&[0x00, 0x00]); // server_name
+        extensions.extend(&(name.len() as u16 + 5).to_be_bytes());
+        extensions.extend(&(name.len() as u16 + 3).to_be_bytes());
+        extensions.push(SERVER_NAME_TYPE_HOST_NAME);
+        extensions.extend(&(name.len() as u16).to_be_bytes());
+        extensions.extend(name);
+
+        let mut body = vec![0x03, 0x03]; // client version
+        body.extend(&[0x42; 32]); // random
+        body.extend(&[4, 1, 2, 3, 4]); // session id
+        body.extend(&[0x00, 0x04, 0x13, 0x01, 0x13, 0x02]); // cipher suites
+        body.extend(&[0x01, 0x00]); // compression methods
+        body.extend(&(extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![TLS_HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend(&(body.len() as u16).to_be_bytes());
+        handshake.extend(body);
+
+        let mut record = vec![TLS_CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn sniff_tls_server_name() {
+        let client_hello = create_client_hello();
+        assert_eq!(
+            Some(TargetName::TlsServerName("example.com".to_string())),
+            sniff(Protocol::Tcp, HTTPS_PORT, &client_hello)
+        );
+        // truncated
+        assert_eq!(
+            None,
+            tls_server_name(&client_hello[..client_hello.len() - 4])
+        );
+        // not a handshake
+        assert_eq!(None, tls_server_name(b"\x17\x03\x03\x00\x01\x00"));
+    }
+
+    #[test]
+    fn sniff_dns_query_name() {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend(b"\x03www\x07Example\x03com\x00");
+        query.extend(&[0, 1, 0, 1]); // type A, class IN
+        assert_eq!(
+            Some(TargetName::DnsQuery("www.example.com".to_string())),
+            sniff(Protocol::Udp, DNS_PORT, &query)
+        );
+        // only on the DNS port
+        assert_eq!(None, sniff(Protocol::Udp, 5353, &query));
+    }
+
+    #[test]
+    fn sniff_http_host() {
+        let request =
+            b"GET /index.html HTTP/1.1\r\nUser-Agent: test\r\nhost: example.org:8080\r\n\r\n";
+        assert_eq!(
+            Some(TargetName::HttpHost("example.org:8080".to_string())),
+            sniff(Protocol::Tcp, HTTP_PORT, request)
+        );
+        assert_eq!(None, http_host(b"HTTP/1.1 200 OK\r\nHost: x\r\n\r\n"));
+        assert_eq!(None, http_host(b"GET / HTTP/1.1\r\n\r\nHost: x\r\n"));
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use socket2::{Socket, Type};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use super::net;
+use super::relay_config::RelayConfig;
+
+const TAG: &str = "SocketPool";
+
+pub type SharedSocketPool = Rc<RefCell<SocketPool>>;
+
+type SocketFactory = Box<dyn Fn() -> io::Result<Socket>>;
+
+/// Sockets created in advance for the outbound TCP connections, so that opening a connection does
+/// not create (and bind) its socket on the hot path.
+///
+/// The pool is refilled between the iterations of the poll loop. When it is empty, a socket is
+/// created on demand.
+pub struct SocketPool {
+    sockets: Vec<Socket>,
+    capacity: usize,
+    factory: SocketFactory,
+    // the last refill failed, warn only once until a socket is created again
+    failing: bool,
+}
+
+impl SocketPool {
+    pub fn new(capacity: usize, factory: SocketFactory) -> Self {
+        Self {
+            sockets: Vec::with_capacity(capacity),
+            capacity,
+            factory,
+            failing: false,
+        }
+    }
+
+    /// Create an (empty) pool of TCP sockets, configured like the sockets created on demand.
+    pub fn new_shared_tcp(config: Rc<RelayConfig>) -> SharedSocketPool {
+        let capacity = config.tcp_socket_pool_size();
+        let factory = Box::new(move || net::create_outbound_socket(&config, Type::STREAM, None));
+        Rc::new(RefCell::new(Self::new(capacity, factory)))
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// Take a socket from the pool, or create one if it is empty.
+    pub fn take(&mut self) -> io::Result<Socket> {
+        match self.sockets.pop() {
+            Some(socket) => Ok(socket),
+            None => {
+                debug!(target: TAG, "Pool empty, creating a socket");
+                (self.factory)()
+            }
+        }
+    }
+
+    /// Create the missing sockets, up to the capacity of the pool.
+    pub fn refill(&mut self) {
+        while self.sockets.len() < self.capacity {
+            match (self.factory)() {
+                Ok(socket) => {
+                    if self.failing {
+                        info!(target: TAG, "Pooled sockets created again");
+                        self.failing = false;
+                    }
+                    self.sockets.push(socket);
+                }
+                Err(err) => {
+                    // retried on the next refill, which happens on every iteration of the loop
+                    if self.failing {
+                        debug!(target: TAG, "Cannot create pooled socket: {}", err);
+                    } else {
+                        warn!(target: TAG, "Cannot create pooled socket: {}", err);
+                        self.failing = true;
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use socket2::Domain;
+    use std::cell::Cell;
+
+    // a pool creating plain TCP sockets, counting the sockets created
+    pub fn create_counting_pool(capacity: usize) -> (SocketPool, Rc<Cell<usize>>) {
+        let created = Rc::new(Cell::new(0));
+        let counter = created.clone();
+        let factory = Box::new(move || {
+            counter.set(counter.get() + 1);
+            Socket::new(Domain::IPV4, Type::STREAM, None)
+        });
+        (SocketPool::new(capacity, factory), created)
+    }
+
+    #[test]
+    fn take_pooled_sockets_first() {
+        let (mut pool, created) = create_counting_pool(2);
+        assert_eq!(0, pool.len());
+
+        pool.refill();
+        assert_eq!(2, pool.len());
+        assert_eq!(2, created.get());
+
+        pool.take().unwrap();
+        pool.take().unwrap();
+        assert_eq!(0, pool.len());
+        assert_eq!(2, created.get());
+
+        // on demand once the pool is empty
+        pool.take().unwrap();
+        assert_eq!(3, created.get());
+
+        pool.refill();
+        assert_eq!(2, pool.len());
+        assert_eq!(5, created.get());
+    }
+
+    #[test]
+    fn warn_once_per_failure_streak() {
+        let failures = Rc::new(Cell::new(2));
+        let remaining = failures.clone();
+        let factory = Box::new(move || {
+            if remaining.get() > 0 {
+                remaining.set(remaining.get() - 1);
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            Socket::new(Domain::IPV4, Type::STREAM, None)
+        });
+        let mut pool = SocketPool::new(1, factory);
+
+        pool.refill();
+        assert!(pool.failing);
+        assert_eq!(0, pool.len());
+        // still failing, not reported again
+        pool.refill();
+        assert!(pool.failing);
+        assert_eq!(0, failures.get());
+
+        pool.refill();
+        assert!(!pool.failing);
+        assert_eq!(1, pool.len());
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+
+// See RFC1928: <https://tools.ietf.org/html/rfc1928>
+const VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_TYPE_IPV4: u8 = 1;
+const ADDRESS_TYPE_DOMAIN_NAME: u8 = 3;
+const ADDRESS_TYPE_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    // waiting for the method selected by the proxy
+    MethodSelection,
+    // waiting for the reply to the CONNECT request
+    Connect,
+    Done,
+}
+
+/// Client side of the SOCKS5 handshake opening a TCP connection through a proxy, without
+/// authentication.
+///
+/// The handshake is driven by the readiness of the (non-blocking) stream to the proxy: the pending
+/// request is written once it is writable, the reply is read once it is readable. The replies are
+/// read exactly, so that the data relayed once the handshake is complete is left in the stream.
+pub struct Socks5Handshake {
+    destination: SocketAddrV4,
+    state: State,
+    request: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+impl Socks5Handshake {
+    pub fn new(destination: SocketAddrV4) -> Self {
+        Self {
+            destination,
+            state: State::MethodSelection,
+            request: vec![VERSION, 1, METHOD_NO_AUTHENTICATION],
+            reply: Vec::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Indicate whether a request is pending, to be written once the stream is writable.
+    pub fn has_pending_request(&self) -> bool {
+        !self.request.is_empty()
+    }
+
+    pub fn write_to<W: Write>(&mut self, target: &mut W) -> io::Result<()> {
+        let w = target.write(&self.request)?;
+        self.request.drain(..w);
+        Ok(())
+    }
+
+    /// Read (part of) the expected reply.
+    ///
+    /// Return `Ok(true)` once the handshake is complete.
+    pub fn read_from<R: Read>(&mut self, source: &mut R) -> io::Result<bool> {
+        assert!(!self.is_done(), "Handshake already complete");
+        loop {
+            let expected = self.expected_reply_length();
+            if self.reply.len() == expected {
+                self.handle_reply()?;
+                if self.is_done() || self.has_pending_request() {
+                    return Ok(self.is_done());
+                }
+                continue;
+            }
+            let mut buf = [0u8; 32];
+            let len = cmp::min(expected - self.reply.len(), buf.len());
+            let r = source.read(&mut buf[..len])?;
+            if r == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "SOCKS5 proxy closed the connection during the handshake",
+                ));
+            }
+            self.reply.extend_from_slice(&buf[..r]);
+        }
+    }
+
+    // the CONNECT reply length depends on its address type, known once its header is read
+    fn expected_reply_length(&self) -> usize {
+        match self.state {
+            State::MethodSelection => 2,
+            State::Connect => match self.reply.get(3) {
+                Some(&ADDRESS_TYPE_IPV4) => 4 + 4 + 2,
+                Some(&ADDRESS_TYPE_IPV6) => 4 + 16 + 2,
+                Some(&ADDRESS_TYPE_DOMAIN_NAME) => match self.reply.get(4) {
+                    Some(&len) => 5 + len as usize + 2,
+                    None => 5,
+                },
+                // unknown address types are rejected in handle_reply()
+                _ => 4,
+            },
+            State::Done => 0,
+        }
+    }
+
+    fn handle_reply(&mut self) -> io::Result<()> {
+        if self.reply[0] != VERSION {
+            return Err(Self::protocol_error("Unexpected SOCKS version"));
+        }
+        match self.state {
+            State::MethodSelection => {
+                if self.reply[1] != METHOD_NO_AUTHENTICATION {
+                    return Err(Self::protocol_error(
+                        "SOCKS5 proxy requires an unsupported authentication",
+                    ));
+                }
+                self.request = self.connect_request();
+                self.reply.clear();
+                self.state = State::Connect;
+            }
+            State::Connect => {
+                if self.reply.len() == 4 {
+                    // the length of a known address type is expected once the header is read
+                    return Err(Self::protocol_error("Unexpected SOCKS5 address type"));
+                }
+                if self.reply[1] != REPLY_SUCCEEDED {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("SOCKS5 proxy refused to connect (reply {})", self.reply[1]),
+                    ));
+                }
+                self.state = State::Done;
+            }
+            State::Done => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn connect_request(&self) -> Vec<u8> {
+        let mut request = vec![VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_IPV4];
+        request.extend_from_slice(&self.destination.ip().octets());
+        request.extend_from_slice(&self.destination.port().to_be_bytes());
+        request
+    }
+
+    fn protocol_error(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+
+    fn destination() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443)
+    }
+
+    fn complete_method_selection(handshake: &mut Socks5Handshake) {
+        let mut request = Vec::new();
+        handshake.write_to(&mut request).unwrap();
+        assert_eq!(vec![5, 1, 0], request);
+        let mut reply = Cursor::new(vec![5, 0]);
+        assert!(!handshake.read_from(&mut reply).unwrap());
+    }
+
+    #[test]
+    fn connect_through_proxy() {
+        let mut handshake = Socks5Handshake::new(destination());
+        complete_method_selection(&mut handshake);
+
+        let mut request = Vec::new();
+        handshake.write_to(&mut request).unwrap();
+        assert_eq!(vec![5, 1, 0, 1, 1, 2, 3, 4, 0x01, 0xBB], request);
+        assert!(!handshake.has_pending_request());
+
+        // the data following the reply must not be consumed
+        let mut reply = Cursor::new(vec![5, 0, 0, 3, 3, b'a', b'b', b'c', 0, 80, 42]);
+        assert!(handshake.read_from(&mut reply).unwrap());
+        assert!(handshake.is_done());
+        assert_eq!(10, reply.position());
+    }
+
+    // non-blocking source, which has no more data available once a chunk is read
+    struct Chunk(Cursor<Vec<u8>>);
+
+    impl Read for Chunk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                r => Ok(r),
+            }
+        }
+    }
+
+    #[test]
+    fn read_partial_replies() {
+        let mut handshake = Socks5Handshake::new(destination());
+        complete_method_selection(&mut handshake);
+        handshake.write_to(&mut Vec::new()).unwrap();
+
+        let reply = [5, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90];
+        for chunk in reply[..9].chunks(3) {
+            let mut source = Chunk(Cursor::new(chunk.to_vec()));
+            let err = handshake.read_from(&mut source).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        }
+        let mut source = Chunk(Cursor::new(reply[9..].to_vec()));
+        assert!(handshake.read_from(&mut source).unwrap());
+    }
+
+    #[test]
+    fn reject_refused_connection() {
+        let mut handshake = Socks5Handshake::new(destination());
+        complete_method_selection(&mut handshake);
+        handshake.write_to(&mut Vec::new()).unwrap();
+
+        let mut reply = Cursor::new(vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let err = handshake.read_from(&mut reply).unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+    }
+
+    #[test]
+    fn reject_authentication_requirement() {
+        let mut handshake = Socks5Handshake::new(destination());
+        handshake.write_to(&mut Vec::new()).unwrap();
+        let mut reply = Cursor::new(vec![5, 0xFF]);
+        let err = handshake.read_from(&mut reply).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
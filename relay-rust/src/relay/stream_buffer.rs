@@ -17,10 +17,16 @@
 use std::io;
 
 /// Circular buffer to store a stream. Read/write boundaries are not preserved.
+///
+/// Its capacity is the high watermark: once full, the buffer reports no space `available()` until
+/// it is drained down to its low watermark, so that the source is not resumed for a few bytes
+/// only.
 pub struct StreamBuffer {
     buf: Box<[u8]>,
     head: usize,
     tail: usize,
+    low_watermark: usize,
+    paused: bool,
 }
 
 impl StreamBuffer {
@@ -29,9 +35,19 @@ impl StreamBuffer {
             buf: vec![0; capacity + 1].into_boxed_slice(),
             head: 0,
             tail: 0,
+            low_watermark: capacity,
+            paused: false,
         }
     }
 
+    pub fn set_low_watermark(&mut self, low_watermark: usize) {
+        assert!(
+            low_watermark <= self.capacity(),
+            "The low watermark may not exceed the capacity"
+        );
+        self.low_watermark = low_watermark;
+    }
+
     pub fn is_empty(&self) -> bool {
         self.head == self.tail
     }
@@ -52,6 +68,20 @@ impl StreamBuffer {
         self.capacity() - self.size()
     }
 
+    /// Whether the buffer reached its high watermark, and did not drain to its low watermark yet.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Space to offer to the source: `remaining()`, or 0 while paused.
+    pub fn available(&self) -> usize {
+        if self.paused {
+            0
+        } else {
+            self.remaining()
+        }
+    }
+
     pub fn write_to<W: io::Write>(&mut self, destination: &mut W) -> io::Result<usize> {
         if self.head == self.tail {
             // buffer is empty, nothing to do
@@ -68,6 +98,9 @@ impl StreamBuffer {
                 w = destination.write(source_slice)?;
                 self.tail = (self.tail + w) % self.buf.len();
             }
+            if self.size() <= self.low_watermark {
+                self.paused = false;
+            }
             self.optimize();
             Ok(w)
         }
@@ -96,6 +129,9 @@ impl StreamBuffer {
             target_slice.copy_from_slice(source_slice);
         }
         self.head = (self.head + source_len) % buf_len;
+        if self.remaining() == 0 {
+            self.paused = true;
+        }
     }
 
     /// To avoid unnecessary copies, StreamBuffer writes at most until the "end" of the circular
@@ -175,6 +211,34 @@ mod tests {
         assert_eq!([0, 1, 2, 3, 4, 5, 0, 1, 2], &result[..]);
     }
 
+    #[test]
+    fn pause_between_watermarks() {
+        let data = create_data();
+        let mut stream_buffer = StreamBuffer::new(9);
+        stream_buffer.set_low_watermark(3);
+
+        stream_buffer.read_from(&data);
+        assert!(!stream_buffer.is_paused());
+        assert_eq!(3, stream_buffer.available());
+
+        // reach the high watermark
+        stream_buffer.read_from(&[0, 1, 2]);
+        assert!(stream_buffer.is_paused());
+        assert_eq!(0, stream_buffer.available());
+
+        // still above the low watermark
+        read_some(&mut stream_buffer, 4);
+        assert_eq!(5, stream_buffer.size());
+        assert!(stream_buffer.is_paused());
+        assert_eq!(0, stream_buffer.available());
+
+        // drained down to the low watermark
+        read_some(&mut stream_buffer, 2);
+        assert_eq!(3, stream_buffer.size());
+        assert!(!stream_buffer.is_paused());
+        assert_eq!(6, stream_buffer.available());
+    }
+
     fn read_some(stream_buffer: &mut StreamBuffer, bytes: usize) -> Vec<u8> {
         let mut vec = vec![0u8; bytes];
         {
@@ -18,30 +18,46 @@ use log::*;
 use mio::net::TcpStream;
 use mio::{Event, PollOpt, Ready, Token};
 use rand::random;
+use socket2::Type;
 use std::cell::RefCell;
 use std::cmp;
+use std::collections::VecDeque;
 use std::io;
+use std::net::Shutdown;
 use std::num::Wrapping;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
-use super::ipv4_header::Ipv4Header;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::connection::{self, Connection, ConnectionId, ConnectionStats};
+use super::icmp_error;
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData};
+use super::ipv4_packet::Ipv4Packet;
+use super::net;
 use super::packet_source::PacketSource;
 use super::packetizer::Packetizer;
+use super::relay_config::RelayConfig;
 use super::selector::Selector;
+use super::socket_pool::SharedSocketPool;
+use super::socks5::Socks5Handshake;
 use super::stream_buffer::StreamBuffer;
 use super::tcp_header::{self, TcpHeader, TcpHeaderMut};
 use super::transport_header::{TransportHeader, TransportHeaderMut};
 
 const TAG: &str = "TcpConnection";
 
-// same value as GnirehtetService.MTU in the client
-const MTU: u16 = 0x4000;
-// 20 bytes for IP headers, 20 bytes for TCP headers
-const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20 as u16;
+/// Maximum payload length of the TCP packets for the client having this `mtu`.
+pub fn max_payload_length(mtu: u16) -> u16 {
+    // 20 bytes for IP headers, 20 bytes for TCP headers
+    mtu - 20 - 20
+}
+
+// shift count of the windows advertised to the client, to advertise the whole client_to_network
+// buffer if the client supports window scaling
+const WINDOW_SHIFT: u8 = 2;
+// rfc7323 section 2.3
+const MAX_WINDOW_SHIFT: u8 = 14;
 
 pub struct TcpConnection {
     self_weak: Weak<RefCell<TcpConnection>>,
@@ -53,8 +69,32 @@ pub struct TcpConnection {
     client_to_network: StreamBuffer,
     network_to_client: Packetizer,
     packet_for_client_length: Option<u16>,
+    max_payload_length: u16,
     closed: bool,
     tcb: Tcb,
+    idle_since: Instant,
+    created_at: Instant,
+    idle_timeout: Option<Duration>,
+    // data sent to the client and not acknowledged yet, starting at snd_una
+    unacked: VecDeque<u8>,
+    // the network is not read beyond, whatever the client window
+    max_unacked: usize,
+    // running while some data or the FIN are not acknowledged by the client
+    retransmission: RetransmissionTimer,
+    // pending while the connection through the SOCKS5 proxy, if any, is not established
+    socks5_handshake: Option<Socks5Handshake>,
+    // SYN of the client, kept until connected to report an asynchronous connection failure
+    syn: Option<Vec<u8>>,
+    stats: ConnectionStats,
+}
+
+// Retransmission of a segment until the client acknowledges it, with exponential backoff
+struct RetransmissionTimer {
+    initial_rto: Duration,
+    max_retransmissions: u32,
+    rto: Duration,
+    retransmissions: u32,
+    deadline: Option<Instant>,
 }
 
 // Transport Control Block
@@ -66,7 +106,11 @@ struct Tcb {
     their_acknowledgement_number: u32,
     fin_sequence_number: Option<u32>,
     fin_received: bool,
-    client_window: u16,
+    // already scaled
+    client_window: u32,
+    // shift counts of the Window Scale option (rfc7323), in both directions (0 if disabled)
+    client_window_shift: u8,
+    window_shift: u8,
 }
 
 // See RFC793: <https://tools.ietf.org/html/rfc793#page-23>
@@ -94,6 +138,72 @@ impl TcpState {
             || self == &TcpState::Closing
             || self == &TcpState::LastAck
     }
+
+    // as displayed by netstat
+    fn name(&self) -> &'static str {
+        match *self {
+            TcpState::Init => "INIT",
+            TcpState::SynSent => "SYN_SENT",
+            TcpState::SynReceived => "SYN_RECV",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::CloseWait => "CLOSE_WAIT",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::Closing => "CLOSING",
+            TcpState::FinWait1 => "FIN_WAIT1",
+            TcpState::FinWait2 => "FIN_WAIT2",
+        }
+    }
+}
+
+impl RetransmissionTimer {
+    fn new(initial_rto: Duration, max_retransmissions: u32) -> Self {
+        Self {
+            initial_rto,
+            max_retransmissions,
+            rto: initial_rto,
+            retransmissions: 0,
+            deadline: None,
+        }
+    }
+
+    // return the deadline of the first retransmission
+    fn start(&mut self, now: Instant) -> Instant {
+        self.rto = self.initial_rto;
+        self.retransmissions = 0;
+        let deadline = now + self.rto;
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    // on acknowledgement progress, reset the backoff while the segments are still in flight
+    fn restart(&mut self, now: Instant) {
+        if let Some(deadline) = self.deadline {
+            self.rto = self.initial_rto;
+            self.retransmissions = 0;
+            // never earlier than scheduled, the router only wakes the connection up then
+            self.deadline = Some(cmp::max(deadline, now + self.rto));
+        }
+    }
+
+    fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    fn is_elapsed(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+
+    // return false if the segment must not be retransmitted anymore
+    fn back_off(&mut self, now: Instant) -> bool {
+        if self.retransmissions == self.max_retransmissions {
+            self.deadline = None;
+            return false;
+        }
+        self.retransmissions += 1;
+        self.rto *= 2;
+        self.deadline = Some(now + self.rto);
+        true
+    }
 }
 
 impl Tcb {
@@ -107,16 +217,45 @@ impl Tcb {
             fin_sequence_number: None,
             fin_received: false,
             client_window: 0,
+            client_window_shift: 0,
+            window_shift: 0,
+        }
+    }
+
+    /// Enable window scaling if the SYN of the client has a Window Scale option.
+    fn set_window_scale(&mut self, client_window_shift: Option<u8>) {
+        match client_window_shift {
+            Some(shift) => {
+                self.client_window_shift = cmp::min(shift, MAX_WINDOW_SHIFT);
+                self.window_shift = WINDOW_SHIFT;
+            }
+            None => {
+                self.client_window_shift = 0;
+                self.window_shift = 0;
+            }
         }
     }
 
-    fn remaining_client_window(&self) -> u16 {
+    /// Set the window advertised by the client in a segment other than its SYN.
+    fn set_client_window(&mut self, window: u16) {
+        self.client_window = u32::from(window) << self.client_window_shift;
+    }
+
+    /// Window to advertise to the client, for `free_space` bytes available to receive.
+    ///
+    /// The window of a SYN segment is never scaled.
+    fn advertised_window(&self, free_space: usize, syn: bool) -> u16 {
+        let shift = if syn { 0 } else { self.window_shift };
+        cmp::min(free_space >> shift, usize::from(u16::MAX)) as u16
+    }
+
+    fn remaining_client_window(&self) -> u32 {
         let wrapped_remaining = Wrapping(self.their_acknowledgement_number)
-            + Wrapping(u32::from(self.client_window))
+            + Wrapping(self.client_window)
             - self.sequence_number;
         let remaining = wrapped_remaining.0;
-        if remaining <= u32::from(self.client_window) {
-            remaining as u16
+        if remaining <= self.client_window {
+            remaining
         } else {
             0
         }
@@ -138,13 +277,23 @@ impl TcpConnection {
         client: Weak<RefCell<Client>>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
+        config: &RelayConfig,
+        socket_pool: Option<&SharedSocketPool>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let stream = Self::create_stream(&id)?;
+        let stream = Self::create_stream(&id, config, socket_pool, ipv4_header.data())?;
+        let socks5_handshake = config
+            .socks5_proxy()
+            .map(|_| Socks5Handshake::new(id.rewritten_destination()));
 
         let tcp_header = Self::tcp_header_of_transport(transport_header);
 
         // shrink the TCP options to pass a minimal refrence header to the packetizer
+        //
+        // The connection is terminated on both sides: the network socket negotiates its own
+        // options (SACK included), while SACK-permitted is never advertised to the client (only
+        // Window Scale may be), so the client never sends SACK blocks it would expect to be
+        // honored.
         let mut shrinked_tcp_header_raw = [0u8; 20];
         shrinked_tcp_header_raw.copy_from_slice(&tcp_header.raw()[..20]);
         let mut shrinked_tcp_header_data = tcp_header.data().clone();
@@ -159,7 +308,10 @@ impl TcpConnection {
             .bind(&shrinked_tcp_header_raw)
             .into();
 
-        let packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header);
+        let packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header, config.mtu());
+
+        let mut client_to_network = StreamBuffer::new(config.tcp_buffer_high_watermark());
+        client_to_network.set_low_watermark(config.tcp_buffer_low_watermark());
 
         // interests will be set on the first packet received
         // set the initial value now so that they won't need to be updated
@@ -171,11 +323,24 @@ impl TcpConnection {
             stream,
             interests,
             token: Token(0), // default value, will be set afterwards
-            client_to_network: StreamBuffer::new(4 * MAX_PACKET_LENGTH),
+            client_to_network,
             network_to_client: packetizer,
             packet_for_client_length: None,
+            max_payload_length: max_payload_length(config.mtu()),
             closed: false,
             tcb: Tcb::new(),
+            idle_since: Instant::now(),
+            created_at: Instant::now(),
+            idle_timeout: config.tcp_idle_timeout(),
+            unacked: VecDeque::new(),
+            max_unacked: config.tcp_buffer_high_watermark(),
+            retransmission: RetransmissionTimer::new(
+                config.tcp_initial_rto(),
+                config.tcp_max_retransmissions(),
+            ),
+            socks5_handshake,
+            syn: None,
+            stats: ConnectionStats::default(),
         }));
 
         {
@@ -195,8 +360,25 @@ impl TcpConnection {
         Ok(rc)
     }
 
-    fn create_stream(id: &ConnectionId) -> io::Result<TcpStream> {
-        TcpStream::connect(&id.rewritten_destination().into())
+    fn create_stream(
+        id: &ConnectionId,
+        config: &RelayConfig,
+        socket_pool: Option<&SharedSocketPool>,
+        ipv4_header_data: &Ipv4HeaderData,
+    ) -> io::Result<TcpStream> {
+        let socket = match socket_pool {
+            Some(socket_pool) => socket_pool.borrow_mut().take()?,
+            None => net::create_outbound_socket(config, Type::STREAM, None)?,
+        };
+        // the pooled sockets are not specific to a connection, so set them afterwards
+        net::set_outbound_tos(&socket, config, ipv4_header_data)?;
+        net::set_keepalive(&socket, config)?;
+        // through the proxy, the destination is requested during the SOCKS5 handshake
+        let addr = config
+            .socks5_proxy()
+            .unwrap_or_else(|| id.rewritten_destination());
+        net::connect_nonblocking(&socket, addr.into())?;
+        TcpStream::from_stream(socket.into())
     }
 
     fn remove_from_router(&self) {
@@ -219,12 +401,26 @@ impl TcpConnection {
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
         if !self.closed {
+            self.touch();
             let ready = event.readiness();
-            if ready.is_readable() || ready.is_writable() {
+            if self.socks5_handshake.is_some() && (ready.is_readable() || ready.is_writable()) {
+                // the connection is not established until the handshake is complete
+                self.process_socks5_handshake(selector, ready);
+                if !self.closed {
+                    self.update_interests(selector);
+                }
+            } else if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     if self.tcb.state == TcpState::SynSent {
-                        // writable is first triggered when the stream is connected
-                        self.process_connect(selector);
+                        // writable is first triggered when the stream is connected, or when the
+                        // connection failed
+                        match self.stream.take_error() {
+                            Ok(None) => self.process_connect(selector),
+                            Ok(Some(err)) | Err(err) => {
+                                self.process_network_error(selector, err);
+                                self.close(selector);
+                            }
+                        }
                     } else {
                         self.process_send(selector)?;
                     }
@@ -237,7 +433,11 @@ impl TcpConnection {
                 }
             } else {
                 cx_debug!(target: TAG, self.id, "received ready = {:?}", ready);
-                // error or hup
+                // error or hup, reported even if the stream is not read (while the client window is
+                // full) nor written
+                if let Ok(Some(err)) = self.stream.take_error() {
+                    self.process_network_error(selector, err);
+                }
                 self.close(selector);
             }
             if self.closed {
@@ -254,6 +454,7 @@ impl TcpConnection {
             Ok(w) => {
                 if w != 0 {
                     self.tcb.acknowledgement_number += Wrapping(w as u32);
+                    self.stats.record_tx(w);
 
                     if self.tcb.fin_received && self.client_to_network.is_empty() {
                         let client_rc = self.client.upgrade().expect("Expected client not found");
@@ -307,12 +508,21 @@ impl TcpConnection {
             remaining_client_window > 0,
             "process_received() must not be called when window == 0"
         );
+        assert!(
+            self.unacked.len() < self.max_unacked,
+            "process_received() must not be called when the unacknowledged data are full"
+        );
         let max_payload_length =
-            Some(cmp::min(remaining_client_window, MAX_PAYLOAD_LENGTH) as usize);
+            cmp::min(remaining_client_window, u32::from(self.max_payload_length)) as usize;
+        let max_payload_length = Some(cmp::min(
+            max_payload_length,
+            self.max_unacked - self.unacked.len(),
+        ));
         Self::update_headers(
             &mut self.network_to_client,
             &self.tcb,
             tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+            self.client_to_network.available(),
         );
         match self
             .network_to_client
@@ -330,6 +540,8 @@ impl TcpConnection {
                             self.tcb.numbers()
                         );
                         self.tcb.sequence_number += Wrapping(len as u32);
+                        self.unacked.extend(ipv4_packet.payload().unwrap());
+                        self.stats.record_rx(len);
                     }
                     Err(_) => {
                         // ask to the client to pull when its buffer is not full
@@ -340,6 +552,9 @@ impl TcpConnection {
                         self.packet_for_client_length = Some(ipv4_packet.length());
                     }
                 };
+                // the deferred packet will be sent while the client is borrowed, so the timer is
+                // started now in both cases
+                self.start_retransmission();
             }
             Ok(None) => {
                 self.eof(selector);
@@ -349,10 +564,39 @@ impl TcpConnection {
                     // rethrow
                     return Err(err);
                 }
+                if err.kind() == io::ErrorKind::ConnectionReset {
+                    // not a failure of the relay, the reset is just propagated to the client
+                    cx_info!(target: TAG, self.id, "Reset by the network");
+                } else {
+                    cx_error!(
+                        target: TAG,
+                        self.id,
+                        "Cannot read: [{:?}] {}",
+                        err.kind(),
+                        err
+                    );
+                }
+                self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn process_socks5_handshake(&mut self, selector: &mut Selector, ready: Ready) {
+        match self.advance_socks5_handshake(ready) {
+            Ok(true) => {
+                cx_debug!(target: TAG, self.id, "Connected through the SOCKS5 proxy");
+                self.socks5_handshake = None;
+                self.process_connect(selector);
+            }
+            Ok(false) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => {
                 cx_error!(
                     target: TAG,
                     self.id,
-                    "Cannot read: [{:?}] {}",
+                    "SOCKS5 handshake failed: [{:?}] {}",
                     err.kind(),
                     err
                 );
@@ -360,17 +604,115 @@ impl TcpConnection {
                 self.close(selector);
             }
         }
-        Ok(())
+    }
+
+    // return Ok(true) once the handshake is complete
+    fn advance_socks5_handshake(&mut self, ready: Ready) -> io::Result<bool> {
+        let handshake = self
+            .socks5_handshake
+            .as_mut()
+            .expect("No SOCKS5 handshake in progress");
+        if handshake.has_pending_request() {
+            // writable is first triggered when the stream to the proxy is connected
+            if ready.is_writable() {
+                handshake.write_to(&mut self.stream)?;
+            }
+            Ok(false)
+        } else if ready.is_readable() {
+            handshake.read_from(&mut self.stream)
+        } else {
+            Ok(false)
+        }
     }
 
     fn process_connect(&mut self, selector: &mut Selector) {
         assert_eq!(self.tcb.state, TcpState::SynSent);
+        self.syn = None;
         self.tcb.state = TcpState::SynReceived;
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
-        self.send_empty_packet_to_client(selector, tcp_header::FLAG_SYN | tcp_header::FLAG_ACK);
+        self.send_syn_ack_to_client(selector);
         self.tcb.sequence_number += Wrapping(1); // SYN counts for 1 byte
     }
 
+    fn process_network_error(&mut self, selector: &mut Selector, err: io::Error) {
+        cx_info!(target: TAG, self.id, "Network error: {}", err);
+        if !self.send_destination_unreachable(selector, &err) {
+            self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
+        }
+    }
+
+    /// Notify the client that the destination is unreachable, if the connection failed for this
+    /// reason.
+    ///
+    /// The connection is reset otherwise, so return `false` if nothing was sent.
+    fn send_destination_unreachable(&mut self, selector: &mut Selector, err: &io::Error) -> bool {
+        let code = match err.kind() {
+            io::ErrorKind::NetworkUnreachable => icmp_error::CODE_NET_UNREACHABLE,
+            io::ErrorKind::HostUnreachable => icmp_error::CODE_HOST_UNREACHABLE,
+            // a refused connection is reset, like the destination did
+            _ => return false,
+        };
+        // only known until connected
+        let mut syn = match self.syn.take() {
+            Some(syn) => syn,
+            None => return false,
+        };
+        let mut raw = icmp_error::build_destination_unreachable(&Ipv4Packet::parse(&mut syn), code);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = Self::send_to_client(&self.client, selector, &ipv4_packet) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send Destination Unreachable to client: {}",
+                err
+            );
+        }
+        true
+    }
+
+    fn send_syn_ack_to_client(&mut self, selector: &mut Selector) {
+        let flags = tcp_header::FLAG_SYN | tcp_header::FLAG_ACK;
+        if self.tcb.window_shift == 0 {
+            self.send_empty_packet_to_client(selector, flags);
+            return;
+        }
+        // window scaling is enabled only if the SYN-ACK has a Window Scale option too
+        let mut raw = {
+            let ipv4_packet = Self::create_empty_response_packet(
+                &self.id,
+                &mut self.network_to_client,
+                &self.tcb,
+                flags,
+                self.client_to_network.available(),
+            );
+            Self::add_window_scale_option(ipv4_packet.raw(), self.tcb.window_shift)
+        };
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = Self::send_to_client(&self.client, selector, &ipv4_packet) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send SYN-ACK to client: {}",
+                err
+            );
+        }
+    }
+
+    /// Copy `raw`, an empty TCP packet without options, adding a Window Scale option.
+    fn add_window_scale_option(raw: &[u8], shift: u8) -> Vec<u8> {
+        let mut raw = raw.to_vec();
+        // NOP for alignment on 32 bits
+        raw.extend_from_slice(&[1, tcp_header::OPTION_WINDOW_SCALE, 3, shift]);
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let total_length = ipv4_packet.length() + 4;
+        ipv4_packet.ipv4_header_mut().set_total_length(total_length);
+        let transport_index = ipv4_packet.ipv4_header_data().header_length() as usize;
+        // one more 32-bit word in the data offset (the 4 high bits)
+        raw[transport_index + 12] += 1 << 4;
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
     fn send_to_client(
         client: &Weak<RefCell<Client>>,
         selector: &mut Selector,
@@ -378,7 +720,7 @@ impl TcpConnection {
     ) -> io::Result<()> {
         let client_rc = client.upgrade().expect("Expected client not found");
         let mut client = client_rc.borrow_mut();
-        client.send_to_client(selector, &ipv4_packet)
+        client.send_to_client(selector, ipv4_packet)
     }
 
     /// Borrow self.client and send empty packet to it
@@ -405,6 +747,7 @@ impl TcpConnection {
             &mut self.network_to_client,
             &self.tcb,
             flags,
+            self.client_to_network.available(),
         );
         if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
             // losing such an empty packet will not break the TCP connection
@@ -427,6 +770,101 @@ impl TcpConnection {
             TcpState::FinWait1
         };
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
+        self.start_retransmission();
+    }
+
+    /// Retransmit the data and the FIN sent until the client acknowledges them, unless the timer
+    /// is already running.
+    ///
+    /// To be used if called by on_ready() (so the client is not borrowed yet).
+    fn start_retransmission(&mut self) {
+        if self.retransmission.deadline.is_some() {
+            return;
+        }
+        let deadline = self.retransmission.start(Instant::now());
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let connection: Weak<RefCell<dyn Connection>> = self.self_weak.clone();
+        client_rc
+            .borrow_mut()
+            .router()
+            .schedule_timeout(deadline, connection);
+    }
+
+    // sequence number of the first byte of data not acknowledged by the client (snd_una)
+    fn unacked_sequence_number(&self) -> Wrapping<u32> {
+        // the FIN is sent after all the data
+        let end = self
+            .tcb
+            .fin_sequence_number
+            .map_or(self.tcb.sequence_number, Wrapping);
+        end - Wrapping(self.unacked.len() as u32)
+    }
+
+    /// Forget the data acknowledged by `acknowledgement_number`.
+    fn release_acknowledged(&mut self, acknowledgement_number: u32) {
+        let acked = (Wrapping(acknowledgement_number) - self.unacked_sequence_number()).0 as usize;
+        // the ACK of the FIN is one beyond the data, an older ACK wraps around
+        if acked == 0 || acked > self.unacked.len() + 1 {
+            return;
+        }
+        let acked = cmp::min(acked, self.unacked.len());
+        self.unacked.drain(..acked);
+        if self.unacked.is_empty()
+            && self.packet_for_client_length.is_none()
+            && self.tcb.fin_sequence_number.is_none()
+        {
+            self.retransmission.stop();
+        } else {
+            self.retransmission.restart(Instant::now());
+        }
+    }
+
+    // resend the first segment not acknowledged, from snd_una
+    fn retransmit_data(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        let len = cmp::min(self.unacked.len(), usize::from(self.max_payload_length));
+        let sequence_number = self.tcb.sequence_number;
+        self.tcb.sequence_number = self.unacked_sequence_number();
+        Self::update_headers(
+            &mut self.network_to_client,
+            &self.tcb,
+            tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+            self.client_to_network.available(),
+        );
+        self.tcb.sequence_number = sequence_number;
+        let mut payload = &self.unacked.make_contiguous()[..len];
+        match self
+            .network_to_client
+            .packetize_read(&mut payload, Some(len))
+        {
+            Ok(Some(ipv4_packet)) => {
+                if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+                    // retried on the next timeout
+                    cx_warn!(
+                        target: TAG,
+                        self.id,
+                        "Cannot retransmit data to client: {}",
+                        err
+                    );
+                }
+            }
+            _ => unreachable!("Reading from a non-empty slice"),
+        }
+    }
+
+    fn retransmit_fin(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        let fin_sequence_number = self
+            .tcb
+            .fin_sequence_number
+            .expect("Retransmitting a FIN never sent");
+        // nothing is sent after the FIN, so only its sequence number differs
+        let sequence_number = self.tcb.sequence_number;
+        self.tcb.sequence_number = Wrapping(fin_sequence_number);
+        self.reply_empty_packet_to_client(
+            selector,
+            client_channel,
+            tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
+        );
+        self.tcb.sequence_number = sequence_number;
     }
 
     #[inline]
@@ -456,11 +894,13 @@ impl TcpConnection {
         }
     }
 
-    fn update_headers(packetizer: &mut Packetizer, tcb: &Tcb, flags: u16) {
+    fn update_headers(packetizer: &mut Packetizer, tcb: &Tcb, flags: u16, free_space: usize) {
         let mut tcp_header = Self::tcp_header_of_transport_mut(packetizer.transport_header_mut());
         tcp_header.set_sequence_number(tcb.sequence_number.0);
         tcp_header.set_acknowledgement_number(tcb.acknowledgement_number.0);
         tcp_header.set_flags(flags);
+        let syn = flags & tcp_header::FLAG_SYN != 0;
+        tcp_header.set_window(tcb.advertised_window(free_space, syn));
     }
 
     fn handle_packet(
@@ -497,7 +937,7 @@ impl TcpConnection {
             return;
         }
 
-        self.tcb.client_window = tcp_header.window();
+        self.tcb.set_client_window(tcp_header.window());
         self.tcb.their_acknowledgement_number = tcp_header.acknowledgement_number();
 
         cx_debug!(
@@ -521,6 +961,7 @@ impl TcpConnection {
                 tcp_header.acknowledgement_number()
             );
 
+            self.release_acknowledged(tcp_header.acknowledgement_number());
             self.handle_ack(selector, client_channel, ipv4_packet);
         }
 
@@ -557,7 +998,10 @@ impl TcpConnection {
                 self.tcb.sequence_number,
                 self.tcb.acknowledgement_number
             );
-            self.tcb.client_window = tcp_header.window();
+            // the window of a SYN is never scaled
+            self.tcb.client_window = u32::from(tcp_header.window());
+            self.tcb.set_window_scale(tcp_header.window_scale());
+            self.syn = Some(ipv4_packet.raw().to_vec());
             self.tcb.state = TcpState::SynSent;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else {
@@ -589,6 +1033,7 @@ impl TcpConnection {
             // first SYN
             self.tcb.syn_sequence_number = their_sequence_number;
             self.tcb.acknowledgement_number = Wrapping(their_sequence_number) + Wrapping(1);
+            self.tcb.set_window_scale(tcp_header.window_scale());
         } else if their_sequence_number != self.tcb.syn_sequence_number {
             // duplicate SYN with different sequence number
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
@@ -620,16 +1065,18 @@ impl TcpConnection {
         self.tcb.acknowledgement_number += Wrapping(1); // received FIN counts for 1 byte
 
         if self.tcb.state == TcpState::Established {
-            self.reply_empty_packet_to_client(
-                selector,
-                client_channel,
-                tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
-            );
-            self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
-            self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-                                                     // the connection will be closed by RAII, so switch immediately to LastAck
-                                                     // (bypass CloseWait)
-            self.tcb.state = TcpState::LastAck;
+            // half-close: the client will not send anymore, but the network may still send data
+            // to the client until it closes (our FIN is sent on EOF)
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            if let Err(err) = self.stream.shutdown(Shutdown::Write) {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot shutdown the network stream: {}",
+                    err
+                );
+            }
+            self.tcb.state = TcpState::CloseWait;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait1 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
@@ -649,6 +1096,7 @@ impl TcpConnection {
     }
 
     fn handle_fin_ack(&mut self, selector: &mut Selector) {
+        self.retransmission.stop();
         if self.tcb.state == TcpState::LastAck || self.tcb.state == TcpState::Closing {
             self.close(selector);
         } else if self.tcb.state == TcpState::FinWait1 {
@@ -682,7 +1130,7 @@ impl TcpConnection {
                 target: TAG,
                 self.id,
                 "{}",
-                binary::build_packet_string(ipv4_packet.raw())
+                binary::build_trace_packet_string(ipv4_packet.raw())
             );
         }
 
@@ -692,8 +1140,10 @@ impl TcpConnection {
             return;
         }
 
+        // the client may still send the data in flight when the window was closed
         if self.client_to_network.remaining() < payload.len() {
             cx_warn!(target: TAG, self.id, "Not enough space, dropping packet");
+            self.stats.record_tx_dropped();
             return;
         }
 
@@ -706,8 +1156,9 @@ impl TcpConnection {
         packetizer: &'a mut Packetizer,
         tcb: &Tcb,
         flags: u16,
+        free_space: usize,
     ) -> Ipv4Packet<'a> {
-        Self::update_headers(packetizer, tcb, flags);
+        Self::update_headers(packetizer, tcb, flags, free_space);
         cx_debug!(
             target: TAG,
             id,
@@ -724,7 +1175,7 @@ impl TcpConnection {
                 target: TAG,
                 id,
                 "{}",
-                binary::build_packet_string(ipv4_packet.raw())
+                binary::build_trace_packet_string(ipv4_packet.raw())
             );
         }
         ipv4_packet
@@ -733,7 +1184,13 @@ impl TcpConnection {
     fn update_interests(&mut self, selector: &mut Selector) {
         assert!(!self.closed);
         let mut ready = Ready::empty();
-        if self.tcb.state == TcpState::SynSent {
+        if let Some(handshake) = &self.socks5_handshake {
+            ready = if handshake.has_pending_request() {
+                Ready::writable()
+            } else {
+                Ready::readable()
+            }
+        } else if self.tcb.state == TcpState::SynSent {
             // waiting for connectable
             ready = Ready::writable()
         } else {
@@ -754,6 +1211,10 @@ impl TcpConnection {
         }
     }
 
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+    }
+
     fn may_read(&self) -> bool {
         if !self.tcb.state.is_connected() || self.tcb.state.is_closed() {
             return false;
@@ -762,7 +1223,9 @@ impl TcpConnection {
             // a packet is already pending
             return false;
         }
-        self.tcb.remaining_client_window() > 0
+        // the client window may be huge (scaled), but a client never acknowledging the data must
+        // not make the relay buffer the whole window
+        self.unacked.len() < self.max_unacked && self.tcb.remaining_client_window() > 0
     }
 
     fn may_write(&self) -> bool {
@@ -781,6 +1244,7 @@ impl Connection for TcpConnection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
+        self.touch();
         self.handle_packet(selector, client_channel, ipv4_packet);
         if !self.closed {
             self.update_interests(selector);
@@ -804,17 +1268,103 @@ impl Connection for TcpConnection {
     }
 
     fn is_expired(&self) -> bool {
-        // no external timeout expiration
-        false
+        // only established (possibly half-closed by the client) connections may expire, the
+        // others are closing or about to be
+        (self.tcb.state == TcpState::Established || self.tcb.state == TcpState::CloseWait)
+            && self.idle_timeout.is_some_and(|idle_timeout| {
+                connection::is_idle_expired(self.idle_since, idle_timeout)
+            })
+    }
+
+    fn expiry(&self) -> Option<Instant> {
+        let idle_expiry = self
+            .idle_timeout
+            .map(|idle_timeout| self.idle_since + idle_timeout);
+        match (idle_expiry, self.retransmission.deadline) {
+            (Some(expiry), Some(deadline)) => Some(expiry.min(deadline)),
+            (expiry, deadline) => expiry.or(deadline),
+        }
+    }
+
+    fn handle_timeout(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        let now = Instant::now();
+        if !self.retransmission.is_elapsed(now) {
+            return;
+        }
+        if self.packet_for_client_length.is_some() {
+            // the client does not even read the packets already sent, and the packetizer holds
+            // the deferred one: wait for it to be sent
+            self.retransmission.restart(now);
+            return;
+        }
+        if self.retransmission.back_off(now) {
+            if self.unacked.is_empty() {
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Retransmitting FIN {}",
+                    self.tcb.numbers()
+                );
+                self.retransmit_fin(selector, client_channel);
+            } else {
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Retransmitting data from {}",
+                    self.unacked_sequence_number()
+                );
+                self.retransmit_data(selector, client_channel);
+            }
+        } else {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Segment not acknowledged after {} retransmissions, resetting",
+                self.retransmission.retransmissions
+            );
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+            self.close(selector);
+        }
+    }
+
+    fn reset(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        if !self.closed {
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+            // a zero linger timeout makes the socket send a RST to the network on close
+            if let Err(err) = self.stream.set_linger(Some(Duration::from_secs(0))) {
+                cx_warn!(target: TAG, self.id, "Cannot abort TCP stream: {}", err);
+            }
+            self.close(selector);
+        }
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn idle_since(&self) -> Instant {
+        self.idle_since
+    }
+
+    fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    fn state(&self) -> &'static str {
+        self.tcb.state.name()
+    }
+
+    fn is_established(&self) -> bool {
+        self.tcb.state == TcpState::Established
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
 }
 
 impl PacketSource for TcpConnection {
-    fn get(&mut self) -> Option<Ipv4Packet> {
+    fn get(&mut self) -> Option<Ipv4Packet<'_>> {
         if let Some(len) = self.packet_for_client_length {
             Some(self.network_to_client.inflate(len))
         } else {
@@ -833,8 +1383,123 @@ impl PacketSource for TcpConnection {
             len,
             self.tcb.numbers()
         );
-        self.tcb.sequence_number += Wrapping(u32::from(len));
+        let ipv4_packet = self.network_to_client.inflate(len);
+        let payload = ipv4_packet.payload().unwrap_or(&[]);
+        // the sequence number counts the payload only, not the headers
+        self.tcb.sequence_number += Wrapping(payload.len() as u32);
+        self.unacked.extend(payload);
+        self.stats.record_rx(payload.len());
         self.packet_for_client_length = None;
         self.update_interests(selector);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::relay_config::DEFAULT_TCP_BUFFER_HIGH_WATERMARK;
+    use crate::relay::tcp_header::TcpHeaderData;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn create_tcp_header(flags: u16, window: u16, options: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(80).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(0x11111111).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0).unwrap(); // acknowledgement number
+        let data_offset = (20 + options.len() as u16) / 4;
+        raw.write_u16::<BigEndian>(data_offset << 12 | flags)
+            .unwrap();
+        raw.write_u16::<BigEndian>(window).unwrap();
+        raw.write_u32::<BigEndian>(0).unwrap(); // checksum, urgent pointer
+        raw.extend_from_slice(options);
+        raw
+    }
+
+    #[test]
+    fn scale_windows_on_window_scale_option() {
+        // MSS = 1460, NOP, Window Scale = 7
+        let options = [2, 4, 0x05, 0xB4, 1, 3, 3, 7];
+        let raw = create_tcp_header(tcp_header::FLAG_SYN, 1000, &options);
+        let tcp_header_data = TcpHeaderData::parse(&raw);
+        let syn = tcp_header_data.bind(&raw);
+        assert_eq!(Some(7), syn.window_scale());
+
+        let mut tcb = Tcb::new();
+        tcb.client_window = u32::from(syn.window());
+        tcb.set_window_scale(syn.window_scale());
+        assert_eq!(7, tcb.client_window_shift);
+        assert_eq!(WINDOW_SHIFT, tcb.window_shift);
+
+        // the windows after the SYN are scaled
+        tcb.set_client_window(1000);
+        assert_eq!(128_000, tcb.client_window);
+        tcb.sequence_number = Wrapping(5000);
+        tcb.their_acknowledgement_number = 1000;
+        assert_eq!(124_000, tcb.remaining_client_window());
+
+        assert_eq!(1000, tcb.advertised_window(4000, false));
+        assert_eq!(4000, tcb.advertised_window(4000, true));
+        let capacity_window = tcb.advertised_window(DEFAULT_TCP_BUFFER_HIGH_WATERMARK, false);
+        assert_eq!(
+            DEFAULT_TCP_BUFFER_HIGH_WATERMARK >> WINDOW_SHIFT,
+            capacity_window as usize + 1
+        );
+
+        // no scaling if the client does not support it
+        tcb.set_window_scale(None);
+        tcb.set_client_window(1000);
+        assert_eq!(1000, tcb.client_window);
+        assert_eq!(4000, tcb.advertised_window(4000, false));
+    }
+
+    #[test]
+    fn add_window_scale_option_to_syn_ack() {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(40).unwrap(); // total length 20 + 20
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // destination address
+        let flags = tcp_header::FLAG_SYN | tcp_header::FLAG_ACK;
+        raw.extend_from_slice(&create_tcp_header(flags, 1000, &[]));
+
+        let mut raw = TcpConnection::add_window_scale_option(&raw, WINDOW_SHIFT);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(ipv4_packet.is_valid());
+        assert_eq!(44, ipv4_packet.length());
+        assert!(ipv4_packet.payload().unwrap().is_empty());
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => {
+                assert_eq!(24, tcp_header.header_length());
+                assert_eq!(flags, tcp_header.flags());
+                assert_eq!(Some(WINDOW_SHIFT), tcp_header.window_scale());
+            }
+            _ => panic!("Expected a TCP header"),
+        }
+    }
+
+    #[test]
+    fn double_retransmission_timeout_until_exhausted() {
+        let ms = Duration::from_millis;
+        let mut timer = RetransmissionTimer::new(ms(100), 2);
+        let start = Instant::now();
+        assert_eq!(start + ms(100), timer.start(start));
+        assert!(!timer.is_elapsed(start + ms(99)));
+        assert!(timer.is_elapsed(start + ms(100)));
+
+        assert!(timer.back_off(start + ms(100)));
+        assert_eq!(Some(start + ms(300)), timer.deadline);
+        assert!(timer.back_off(start + ms(300)));
+        assert_eq!(Some(start + ms(700)), timer.deadline);
+        assert!(!timer.back_off(start + ms(700)));
+        assert_eq!(None, timer.deadline);
+
+        // restarting resets the backoff
+        assert_eq!(start + ms(1100), timer.start(start + ms(1000)));
+    }
+}
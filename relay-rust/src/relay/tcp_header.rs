@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use super::checksum;
 use super::ipv4_header::Ipv4HeaderData;
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
@@ -47,6 +48,37 @@ pub const FLAG_RST: u16 = 1 << 2;
 pub const FLAG_PSH: u16 = 1 << 3;
 pub const FLAG_ACK: u16 = 1 << 4;
 
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+pub const OPTION_MSS: u8 = 2;
+pub const OPTION_WINDOW_SCALE: u8 = 3;
+
+// length of the header without options
+const MIN_HEADER_LENGTH: usize = 20;
+
+/// Find the option of type `kind` in the raw TCP `options`, and return its offset.
+fn find_option(options: &[u8], kind: u8) -> Option<usize> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => return None,
+            OPTION_NOP => i += 1,
+            option_kind => {
+                let length = *options.get(i + 1)? as usize;
+                if length < 2 || i + length > options.len() {
+                    // malformed options
+                    return None;
+                }
+                if option_kind == kind {
+                    return Some(i);
+                }
+                i += length;
+            }
+        }
+    }
+    None
+}
+
 #[allow(dead_code)]
 impl TcpHeaderData {
     pub fn parse(raw: &[u8]) -> Self {
@@ -62,6 +94,20 @@ impl TcpHeaderData {
         }
     }
 
+    /// Parse a header received from the outside, `None` if it is truncated or if its data offset
+    /// is inconsistent.
+    pub fn try_parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < MIN_HEADER_LENGTH {
+            return None;
+        }
+        let data = Self::parse(raw);
+        let header_length = data.header_length as usize;
+        if header_length < MIN_HEADER_LENGTH || header_length > raw.len() {
+            return None;
+        }
+        Some(data)
+    }
+
     #[inline]
     pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> TcpHeader<'c> {
         TcpHeader::new(raw, self)
@@ -141,8 +187,8 @@ macro_rules! tcp_header_common {
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
                 Self {
-                    raw: raw,
-                    data: data,
+                    raw,
+                    data,
                 }
             }
 
@@ -215,6 +261,38 @@ macro_rules! tcp_header_common {
             pub fn is_ack(&self) -> bool {
                 self.data.is_ack()
             }
+
+            /// Raw TCP options, between the fixed header and the payload (empty if none).
+            pub fn options(&self) -> &[u8] {
+                let header_length = self.data.header_length as usize;
+                &self.raw[MIN_HEADER_LENGTH.min(header_length)..header_length]
+            }
+
+            /// Maximum Segment Size option, if any.
+            pub fn mss(&self) -> Option<u16> {
+                self.mss_offset()
+                    .map(|offset| BigEndian::read_u16(&self.raw[offset..offset + 2]))
+            }
+
+            // offset of the MSS value in the header
+            fn mss_offset(&self) -> Option<usize> {
+                let options = self.options();
+                let index = find_option(options, OPTION_MSS)?;
+                if options[index + 1] != 4 {
+                    return None;
+                }
+                Some(MIN_HEADER_LENGTH + index + 2)
+            }
+
+            /// Window Scale option (the shift count), if any.
+            pub fn window_scale(&self) -> Option<u8> {
+                let options = self.options();
+                let index = find_option(options, OPTION_WINDOW_SCALE)?;
+                if options[index + 1] != 3 {
+                    return None;
+                }
+                Some(options[index + 2])
+            }
         }
     };
 }
@@ -266,6 +344,12 @@ impl<'a> TcpHeaderMut<'a> {
         BigEndian::write_u32(&mut self.raw[8..12], acknowledgement_number);
     }
 
+    #[inline]
+    pub fn set_window(&mut self, window: u16) {
+        self.data.window = window;
+        BigEndian::write_u16(&mut self.raw[14..16], window);
+    }
+
     #[inline]
     pub fn set_flags(&mut self, flags: u16) {
         self.data.flags = flags;
@@ -288,6 +372,35 @@ impl<'a> TcpHeaderMut<'a> {
         self.data.header_length = data_offset << 2;
     }
 
+    /// Lower the MSS option (if any) to `max_mss`, and update the checksum incrementally.
+    ///
+    /// Return `true` if the MSS was clamped.
+    pub fn clamp_mss(&mut self, max_mss: u16) -> bool {
+        match (self.mss_offset(), self.mss()) {
+            (Some(offset), Some(mss)) if mss > max_mss => {
+                self.write_u16_updating_checksum(offset, max_mss);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // the value may not be aligned on the 16-bit words of the checksum (options are not)
+    fn write_u16_updating_checksum(&mut self, offset: usize, value: u16) {
+        let words = (offset - offset % 2..offset + 2 + offset % 2).step_by(2);
+        let old_words: Vec<u16> = words
+            .clone()
+            .map(|i| BigEndian::read_u16(&self.raw[i..i + 2]))
+            .collect();
+        BigEndian::write_u16(&mut self.raw[offset..offset + 2], value);
+        let mut checksum = self.checksum();
+        for (i, old) in words.zip(old_words) {
+            let new = BigEndian::read_u16(&self.raw[i..i + 2]);
+            checksum = checksum::update_incremental(checksum, old, new);
+        }
+        self.set_checksum(checksum);
+    }
+
     #[inline]
     fn checksum(&self) -> u16 {
         BigEndian::read_u16(&self.raw[16..18])
@@ -306,7 +419,7 @@ impl<'a> TcpHeaderMut<'a> {
             ipv4_header_data.total_length() - u16::from(ipv4_header_data.header_length());
 
         let header_length = self.header_length();
-        debug_assert!(header_length % 2 == 0 && header_length >= 20);
+        debug_assert!(header_length.is_multiple_of(2) && header_length >= 20);
 
         let payload_length = transport_length - u16::from(header_length);
         debug_assert_eq!(
@@ -315,12 +428,8 @@ impl<'a> TcpHeaderMut<'a> {
             "Payload length does not match"
         );
 
-        let mut sum = 6u32; // protocol: TCP = 6
-        sum += source >> 16;
-        sum += source & 0xFFFF;
-        sum += destination >> 16;
-        sum += destination & 0xFFFF;
-        sum += u32::from(transport_length);
+        // protocol: TCP = 6
+        let mut sum = checksum::ipv4_pseudo_header_sum(source, destination, 6, transport_length);
 
         // reset checksum field, so that it can be added with other bytes
         self.set_checksum(0);
@@ -347,7 +456,7 @@ impl<'a> TcpHeaderMut<'a> {
                 sum += u32::from(*p.offset(1));
                 p = p.offset(2);
             }
-            if payload_length % 2 != 0 {
+            if !payload_length.is_multiple_of(2) {
                 // if payload length is odd, the last byte is considered high-order
                 hsum += u32::from(*payload.get_unchecked((payload_length - 1) as usize));
             }
@@ -356,23 +465,20 @@ impl<'a> TcpHeaderMut<'a> {
         // add high-order bytes sum to the global sum
         sum += hsum << 8;
 
-        while (sum & !0xFFFF) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        self.set_checksum(!checksum::fold(sum));
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)]
 mod tests {
     use super::*;
     use crate::relay::ipv4_packet::Ipv4Packet;
-    use crate::relay::transport_header::TransportHeaderMut;
+    use crate::relay::transport_header::{TransportHeader, TransportHeaderMut};
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(44);
+        let mut raw = Vec::with_capacity(44);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -399,8 +505,7 @@ mod tests {
     }
 
     fn create_odd_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -429,8 +534,7 @@ mod tests {
     }
 
     fn create_empty_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(40);
+        let mut raw = Vec::with_capacity(40);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -455,8 +559,7 @@ mod tests {
     }
 
     fn create_tcp_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(20);
+        let mut raw = Vec::with_capacity(20);
 
         raw.write_u16::<BigEndian>(0x1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(0x5678).unwrap(); // destination port
@@ -639,9 +742,83 @@ mod tests {
         }
     }
 
+    fn create_syn_packet(options: &[u8]) -> Vec<u8> {
+        let header_length = 20 + options.len() as u16;
+        let mut raw = Vec::with_capacity(20 + header_length as usize);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(20 + header_length).unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0xA2A24242).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(0x5678).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(0x111).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0).unwrap(); // acknowledgement number
+        let data_offset_and_flags = (header_length / 4) << 12 | FLAG_SYN;
+        raw.write_u16::<BigEndian>(data_offset_and_flags).unwrap();
+        raw.write_u16::<BigEndian>(0xFFFF).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+
+        raw.extend_from_slice(options);
+
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        ipv4_packet.compute_checksums();
+        raw
+    }
+
+    fn assert_mss_clamped(options: &[u8]) {
+        let raw = &mut create_syn_packet(options)[..];
+        let mut ipv4_packet = Ipv4Packet::parse(raw);
+        let (ipv4_header, mut transport) = ipv4_packet.split_mut();
+        if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
+            assert_eq!(Some(1460), tcp_header.mss());
+            assert!(tcp_header.clamp_mss(1200));
+            assert_eq!(Some(1200), tcp_header.mss());
+            // already lower
+            assert!(!tcp_header.clamp_mss(1300));
+
+            let checksum = tcp_header.checksum();
+            tcp_header.update_checksum(ipv4_header.data(), payload);
+            assert_eq!(tcp_header.checksum(), checksum);
+        } else {
+            panic!("Not a TCP packet");
+        }
+    }
+
+    #[test]
+    fn clamp_mss() {
+        // MSS 1460 (0x05B4)
+        assert_mss_clamped(&[OPTION_MSS, 4, 0x05, 0xB4]);
+    }
+
+    #[test]
+    fn clamp_unaligned_mss() {
+        let options = [
+            OPTION_NOP, OPTION_MSS, 4, 0x05, 0xB4, OPTION_NOP, OPTION_NOP, OPTION_END,
+        ];
+        assert_mss_clamped(&options);
+    }
+
+    #[test]
+    fn no_mss() {
+        let raw = &mut create_syn_packet(&[OPTION_NOP, OPTION_NOP, OPTION_NOP, OPTION_END])[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        if let Some(TransportHeader::Tcp(tcp_header)) = ipv4_packet.transport_header() {
+            assert_eq!(None, tcp_header.mss());
+        } else {
+            panic!("Not a TCP packet");
+        }
+    }
+
     fn create_long_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -0,0 +1,156 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Hashed timer wheel, to find the items whose deadline elapsed without scanning all of them.
+///
+/// Time is divided in ticks, and an item is stored in the slot of the tick containing its
+/// deadline (modulo the number of slots, so a slot holds the items of several rounds). Expiring
+/// only visits the slots of the ticks elapsed since the previous call.
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick: Duration,
+    // first tick not entirely processed yet
+    current_tick: u64,
+    slots: Vec<Vec<(Instant, T)>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(start: Instant, tick: Duration, slot_count: usize) -> Self {
+        assert!(slot_count > 0, "A timer wheel needs at least one slot");
+        Self {
+            start,
+            tick,
+            current_tick: 0,
+            slots: (0..slot_count).map(|_| Vec::new()).collect(),
+            len: 0,
+        }
+    }
+
+    fn tick_of(&self, instant: Instant) -> u64 {
+        let elapsed = instant.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.tick.as_nanos()) as u64
+    }
+
+    fn slot_index(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+
+    /// Schedule `item` to expire at `deadline`.
+    ///
+    /// A deadline already elapsed expires on the next call to `expire()`.
+    pub fn schedule(&mut self, deadline: Instant, item: T) {
+        let tick = cmp::max(self.tick_of(deadline), self.current_tick);
+        let index = self.slot_index(tick);
+        self.slots[index].push((deadline, item));
+        self.len += 1;
+    }
+
+    /// Remove and return the items whose deadline is not after `now`, by deadline order.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let now_tick = self.tick_of(now);
+        if now_tick < self.current_tick || self.len == 0 {
+            self.current_tick = cmp::max(self.current_tick, now_tick);
+            return Vec::new();
+        }
+        // every slot is visited at most once, even if several rounds elapsed
+        let ticks = cmp::min(now_tick - self.current_tick + 1, self.slots.len() as u64);
+        let mut expired = Vec::new();
+        for tick in self.current_tick..self.current_tick + ticks {
+            let index = self.slot_index(tick);
+            let (due, pending) = mem::take(&mut self.slots[index])
+                .into_iter()
+                .partition(|&(deadline, _)| deadline <= now);
+            self.slots[index] = pending;
+            expired.extend::<Vec<_>>(due);
+        }
+        // the current tick may still contain items expiring later
+        self.current_tick = now_tick;
+        self.len -= expired.len();
+        expired.sort_by_key(|&(deadline, _)| deadline);
+        expired.into_iter().map(|(_, item)| item).collect()
+    }
+
+    /// Earliest deadline of the items expiring in the next round, or the end of the next round if
+    /// all the items expire later.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        let slot_count = self.slots.len() as u64;
+        for tick in self.current_tick..self.current_tick + slot_count {
+            let tick_end = self.start + self.tick * (tick + 1) as u32;
+            // the other items of the slot belong to later rounds
+            let deadline = self.slots[self.slot_index(tick)]
+                .iter()
+                .map(|&(deadline, _)| deadline)
+                .filter(|&deadline| deadline < tick_end)
+                .min();
+            if deadline.is_some() {
+                return deadline;
+            }
+        }
+        Some(self.start + self.tick * (self.current_tick + slot_count) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expire_staggered_deadlines_in_order() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut wheel = TimerWheel::new(start, ms(100), 8);
+        wheel.schedule(start + ms(350), "c");
+        wheel.schedule(start + ms(120), "a");
+        wheel.schedule(start + ms(190), "b");
+        // 2 rounds later, in the same slot as "a"
+        wheel.schedule(start + ms(1720), "d");
+        assert_eq!(4, wheel.len);
+        assert_eq!(Some(start + ms(120)), wheel.next_deadline());
+
+        assert!(wheel.expire(start + ms(100)).is_empty());
+        assert_eq!(vec!["a"], wheel.expire(start + ms(150)));
+        assert_eq!(Some(start + ms(190)), wheel.next_deadline());
+        assert_eq!(vec!["b", "c"], wheel.expire(start + ms(400)));
+
+        // "d" is not expired when its slot is visited in the first round
+        assert!(wheel.expire(start + ms(1000)).is_empty());
+        assert_eq!(Some(start + ms(1720)), wheel.next_deadline());
+        assert_eq!(vec!["d"], wheel.expire(start + ms(5000)));
+        assert_eq!(0, wheel.len);
+        assert_eq!(None, wheel.next_deadline());
+    }
+
+    #[test]
+    fn expire_elapsed_deadline_on_next_call() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut wheel = TimerWheel::new(start, ms(100), 8);
+        assert!(wheel.expire(start + ms(500)).is_empty());
+
+        // scheduled in a tick already processed
+        wheel.schedule(start + ms(200), 42);
+        assert_eq!(Some(start + ms(200)), wheel.next_deadline());
+        assert_eq!(vec![42], wheel.expire(start + ms(510)));
+    }
+}
@@ -41,11 +41,13 @@ pub enum TransportHeaderData {
 
 #[allow(dead_code)]
 impl TransportHeaderData {
+    /// Parse the transport header of `protocol`, `None` if it is not supported or if the header
+    /// is malformed.
     pub fn parse(protocol: Protocol, raw: &[u8]) -> Option<Self> {
         match protocol {
-            Protocol::Udp => Some(UdpHeaderData::parse(raw).into()),
-            Protocol::Tcp => Some(TcpHeaderData::parse(raw).into()),
-            Protocol::Icmp => Some(IcmpHeaderData::parse(raw).into()),
+            Protocol::Udp => UdpHeaderData::try_parse(raw).map(Into::into),
+            Protocol::Tcp => TcpHeaderData::try_parse(raw).map(Into::into),
+            Protocol::Icmp => IcmpHeaderData::try_parse(raw).map(Into::into),
             _ => None,
         }
     }
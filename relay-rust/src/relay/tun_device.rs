@@ -0,0 +1,321 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use std::cmp;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use super::ipv4_header::{self, MIN_HEADER_LENGTH};
+use super::ipv6_header;
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+// from <linux/if_tun.h>
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+// struct ifreq: the interface name, followed by a union (the flags, an address...)
+#[repr(C)]
+struct InterfaceRequest {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: [u8; 24],
+}
+
+impl InterfaceRequest {
+    // the name must be shorter than IFNAMSIZ, to be nul-terminated
+    fn new(name: &str) -> Self {
+        let mut request = Self {
+            name: [0; libc::IFNAMSIZ],
+            data: [0; 24],
+        };
+        for (target, &byte) in request.name.iter_mut().zip(name.as_bytes()) {
+            *target = byte as libc::c_char;
+        }
+        request
+    }
+
+    fn name(&self) -> String {
+        // the name is nul-terminated
+        unsafe { CStr::from_ptr(self.name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn set_flags(&mut self, flags: libc::c_short) {
+        self.data[..2].copy_from_slice(&flags.to_ne_bytes());
+    }
+}
+
+/// Local TUN interface (`/dev/net/tun`), to relay the packets of the host itself rather than those
+/// of a device.
+///
+/// Each read returns a single IP packet sent through the interface. Conversely, the kernel expects
+/// a single packet per write, whereas the client buffer writes a stream of packets: the packets
+/// are written one by one, and those split across several writes are reassembled first.
+///
+/// The outbound sockets must not be routed back through the interface (see
+/// `RelayConfig::bind_device()`).
+pub struct TunDevice {
+    file: File,
+    name: String,
+    partial_packet: Vec<u8>,
+}
+
+impl TunDevice {
+    /// Open the TUN interface `name`, creating it if it does not exist.
+    ///
+    /// The name may contain `%d`, replaced by the kernel by the first available number. Creating
+    /// an interface requires `CAP_NET_ADMIN`.
+    pub fn open(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Interface name too long: {}", name),
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(TUN_PATH)?;
+        let mut request = InterfaceRequest::new(name);
+        request.set_flags(IFF_TUN | IFF_NO_PI);
+        interface_ioctl(file.as_raw_fd(), TUNSETIFF, &mut request)?;
+        Ok(Self {
+            file,
+            // the kernel wrote the actual name
+            name: request.name(),
+            partial_packet: Vec::new(),
+        })
+    }
+
+    /// Name of the interface, as assigned by the kernel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn interface_ioctl(
+    fd: libc::c_int,
+    request_code: libc::c_ulong,
+    request: &mut InterfaceRequest,
+) -> io::Result<()> {
+    // the request outlives the call, and has the layout expected by the kernel
+    let r = unsafe { libc::ioctl(fd, request_code as _, request as *mut InterfaceRequest) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the length of the packet starting at the beginning of `raw`, if known
+fn peek_packet_length(raw: &[u8]) -> Option<usize> {
+    let length = match raw.first().map(|&b| b >> 4) {
+        Some(6) => ipv6_header::peek_length(raw)?,
+        // a malformed length must still span the header, or the stream would never progress
+        _ => cmp::max(
+            ipv4_header::peek_version_length(raw)?.1,
+            MIN_HEADER_LENGTH as u16,
+        ),
+    };
+    Some(length as usize)
+}
+
+impl Read for TunDevice {
+    // the buffer must be large enough for any packet, or the end of the packet is lost
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+// write the first packet of the stream `buf` to `destination` in a single write, and return the
+// number of bytes consumed from `buf`
+//
+// A packet truncated at the end of `buf` is kept in `partial_packet`, until the next calls provide
+// its remaining bytes.
+fn write_packet<W: Write>(
+    destination: &mut W,
+    partial_packet: &mut Vec<u8>,
+    buf: &[u8],
+) -> io::Result<usize> {
+    if partial_packet.is_empty() {
+        match peek_packet_length(buf) {
+            Some(length) if length <= buf.len() => {
+                write_whole(destination, &buf[..length])?;
+                return Ok(length);
+            }
+            _ => {}
+        }
+    }
+    let previous_length = partial_packet.len();
+    partial_packet.extend_from_slice(buf);
+    match peek_packet_length(partial_packet) {
+        Some(length) if length <= partial_packet.len() => {
+            let mut packet = mem::take(partial_packet);
+            packet.truncate(length);
+            write_whole(destination, &packet)?;
+            Ok(length - previous_length)
+        }
+        _ => Ok(buf.len()),
+    }
+}
+
+fn write_whole<W: Write>(destination: &mut W, packet: &[u8]) -> io::Result<()> {
+    // a TUN write is never partial: the packet is either queued or dropped
+    let w = destination.write(packet)?;
+    if w < packet.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Packet partially written",
+        ));
+    }
+    Ok(())
+}
+
+impl Write for TunDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_packet(&mut self.file, &mut self.partial_packet, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for TunDevice {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    /// Assign `address` (in a /24) to the interface `name`, and bring it up, to be used in other
+    /// tests.
+    pub fn configure_interface(name: &str, address: Ipv4Addr) -> io::Result<()> {
+        // any socket may configure the interfaces
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let fd = socket.as_raw_fd();
+
+        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        for &(request_code, address) in &[
+            (libc::SIOCSIFADDR, address),
+            (libc::SIOCSIFNETMASK, netmask),
+        ] {
+            let mut request = InterfaceRequest::new(name);
+            // struct sockaddr_in
+            request.data[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            request.data[4..8].copy_from_slice(&address.octets());
+            interface_ioctl(fd, request_code, &mut request)?;
+        }
+
+        let mut request = InterfaceRequest::new(name);
+        interface_ioctl(fd, libc::SIOCGIFFLAGS, &mut request)?;
+        let flags = libc::c_short::from_ne_bytes([request.data[0], request.data[1]]);
+        request.set_flags(flags | libc::IFF_UP as libc::c_short);
+        interface_ioctl(fd, libc::SIOCSIFFLAGS, &mut request)
+    }
+
+    // record every write separately, like a TUN device
+    #[derive(Default)]
+    struct PacketRecorder {
+        packets: Vec<Vec<u8>>,
+    }
+
+    impl Write for PacketRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.packets.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_packet(total_length: u16, filler: u8) -> Vec<u8> {
+        let mut raw = vec![filler; total_length as usize];
+        raw[0] = 4 << 4 | 5;
+        raw[2..4].copy_from_slice(&total_length.to_be_bytes());
+        raw
+    }
+
+    #[test]
+    fn write_one_packet_at_a_time() {
+        let mut stream = create_packet(28, 1);
+        stream.extend_from_slice(&create_packet(40, 2));
+
+        let mut recorder = PacketRecorder::default();
+        let mut partial_packet = Vec::new();
+        let w = write_packet(&mut recorder, &mut partial_packet, &stream).unwrap();
+        assert_eq!(28, w);
+        let w = write_packet(&mut recorder, &mut partial_packet, &stream[28..]).unwrap();
+        assert_eq!(40, w);
+
+        assert_eq!(
+            vec![create_packet(28, 1), create_packet(40, 2)],
+            recorder.packets
+        );
+    }
+
+    #[test]
+    fn reassemble_split_packet() {
+        let mut stream = create_packet(28, 1);
+        stream.extend_from_slice(&create_packet(40, 2));
+
+        let mut recorder = PacketRecorder::default();
+        let mut partial_packet = Vec::new();
+        // split inside the length field, as at the end of a circular buffer
+        let w = write_packet(&mut recorder, &mut partial_packet, &stream[..3]).unwrap();
+        assert_eq!(3, w);
+        assert!(recorder.packets.is_empty());
+        // the remaining bytes of the first packet are consumed, not those of the next one
+        let w = write_packet(&mut recorder, &mut partial_packet, &stream[3..]).unwrap();
+        assert_eq!(25, w);
+        assert!(partial_packet.is_empty());
+        assert_eq!(vec![create_packet(28, 1)], recorder.packets);
+    }
+}
@@ -19,12 +19,24 @@ use mio::net::TcpListener;
 use mio::{Event, PollOpt, Ready};
 use std::cell::RefCell;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{self, SocketAddr};
 use std::ptr;
 use std::rc::{Rc, Weak};
+use std::time::Instant;
 
-use super::client::Client;
+use super::client::{Client, ClientStream};
+use super::connection::{
+    ConnectionId, ConnectionInfo, SharedConnectionCloseListener, SharedConnectionOpenListener,
+};
+use super::connection_log::SharedConnectionLog;
+use super::dns_blocklist::SharedDnsBlocklist;
+use super::metrics::RelayMetrics;
+use super::pcap::SharedPcapWriter;
+use super::relay_config::RelayConfig;
 use super::selector::Selector;
+use super::socket_pool::{SharedSocketPool, SocketPool};
+#[cfg(target_os = "linux")]
+use super::tun_device::TunDevice;
 
 const TAG: &str = "TunnelServer";
 
@@ -33,16 +45,48 @@ pub struct TunnelServer {
     clients: Vec<Rc<RefCell<Client>>>,
     tcp_listener: TcpListener,
     next_client_id: u32,
+    config: Rc<RelayConfig>,
+    pcap_writer: Option<SharedPcapWriter>,
+    connection_open_listeners: Vec<SharedConnectionOpenListener>,
+    connection_close_listeners: Vec<SharedConnectionCloseListener>,
+    dns_blocklist: Option<SharedDnsBlocklist>,
+    connection_log: Option<SharedConnectionLog>,
+    tcp_socket_pool: Option<SharedSocketPool>,
+    // activity of the clients already disconnected
+    removed_metrics: RelayMetrics,
 }
 
 impl TunnelServer {
-    pub fn create(port: u16, selector: &mut Selector) -> io::Result<Rc<RefCell<Self>>> {
-        let tcp_listener = Self::start_socket(port)?;
+    pub fn create(
+        config: Rc<RelayConfig>,
+        pcap_writer: Option<SharedPcapWriter>,
+        connection_open_listeners: Vec<SharedConnectionOpenListener>,
+        connection_close_listeners: Vec<SharedConnectionCloseListener>,
+        dns_blocklist: Option<SharedDnsBlocklist>,
+        connection_log: Option<SharedConnectionLog>,
+        selector: &mut Selector,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let addr = SocketAddr::new(config.listen_address(), config.port());
+        // bind through std, the net2 version used by mio misreads the addresses on recent Rust
+        let tcp_listener = TcpListener::from_std(net::TcpListener::bind(addr)?)?;
+        let tcp_socket_pool = if config.tcp_socket_pool_size() > 0 {
+            Some(SocketPool::new_shared_tcp(config.clone()))
+        } else {
+            None
+        };
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
             clients: Vec::new(),
             tcp_listener,
             next_client_id: 0,
+            config,
+            pcap_writer,
+            connection_open_listeners,
+            connection_close_listeners,
+            dns_blocklist,
+            connection_log,
+            tcp_socket_pool,
+            removed_metrics: RelayMetrics::default(),
         }));
 
         // keep a shared reference to this
@@ -61,11 +105,8 @@ impl TunnelServer {
         Ok(rc)
     }
 
-    fn start_socket(port: u16) -> io::Result<TcpListener> {
-        let localhost = Ipv4Addr::new(127, 0, 0, 1).into();
-        let addr = SocketAddr::new(localhost, port);
-        let server = TcpListener::bind(&addr)?;
-        Ok(server)
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
     }
 
     fn on_ready(&mut self, selector: &mut Selector, _: Event) {
@@ -80,6 +121,28 @@ impl TunnelServer {
 
     fn accept_client(&mut self, selector: &mut Selector) -> io::Result<()> {
         let (stream, _) = self.tcp_listener.accept()?;
+        let client_id = self.add_client(selector, ClientStream::Tcp(stream))?;
+        info!(target: TAG, "Client #{} connected", client_id);
+        Ok(())
+    }
+
+    /// Relay the packets of the local TUN interface `device`, as those of an additional client.
+    #[cfg(target_os = "linux")]
+    pub fn attach_tun_device(
+        &mut self,
+        selector: &mut Selector,
+        device: TunDevice,
+    ) -> io::Result<()> {
+        let name = device.name().to_string();
+        let client_id = self.add_client(selector, ClientStream::Tun(device))?;
+        info!(
+            target: TAG,
+            "Client #{} attached to TUN interface {}", client_id, name
+        );
+        Ok(())
+    }
+
+    fn add_client(&mut self, selector: &mut Selector, stream: ClientStream) -> io::Result<u32> {
         let client_id = self.next_client_id;
         self.next_client_id += 1;
         let weak = self.self_weak.clone();
@@ -94,10 +157,37 @@ impl TunnelServer {
                 );
             }
         });
-        let client = Client::create(client_id, selector, stream, on_client_closed)?;
+        let client = Client::create(
+            client_id,
+            selector,
+            stream,
+            on_client_closed,
+            self.config.clone(),
+            self.pcap_writer.clone(),
+            self.connection_close_listeners.clone(),
+        )?;
+        client
+            .borrow_mut()
+            .router()
+            .set_dns_blocklist(self.dns_blocklist.clone());
+        client
+            .borrow_mut()
+            .router()
+            .set_tcp_socket_pool(self.tcp_socket_pool.clone());
+        for listener in &self.connection_open_listeners {
+            client
+                .borrow_mut()
+                .router()
+                .add_open_listener(listener.clone());
+        }
+        if let Some(connection_log) = &self.connection_log {
+            client
+                .borrow_mut()
+                .router()
+                .set_connection_log(connection_log.clone(), client_id);
+        }
         self.clients.push(client);
-        info!(target: TAG, "Client #{} connected", client_id);
-        Ok(())
+        Ok(client_id)
     }
 
     fn remove_client(&mut self, client: &Client) {
@@ -111,6 +201,97 @@ impl TunnelServer {
             })
             .expect("Trying to remove an unknown client");
         self.clients.swap_remove(index);
+        self.removed_metrics += client.metrics();
+    }
+
+    pub fn clients(&self) -> &[Rc<RefCell<Client>>] {
+        &self.clients
+    }
+
+    /// Reset the connection identified by `id` of the client `client_id`, if any.
+    ///
+    /// The UDP flows over IPv6 are not identified by a `ConnectionId`, so they cannot be reset:
+    /// they are only closed once idle.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn reset_connection(
+        &mut self,
+        selector: &mut Selector,
+        client_id: u32,
+        id: &ConnectionId,
+    ) -> bool {
+        self.clients
+            .iter()
+            .find(|client| client.borrow().id() == client_id)
+            .is_some_and(|client| client.borrow_mut().reset_connection(selector, id))
+    }
+
+    /// Snapshot of the live connections of all the clients, along with their client id.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn connections_info(&self, now: Instant) -> Vec<(u32, ConnectionInfo)> {
+        let mut infos = Vec::new();
+        for client in &self.clients {
+            let client = client.borrow();
+            let client_id = client.id();
+            infos.extend(
+                client
+                    .connections_info(now)
+                    .into_iter()
+                    .map(|info| (client_id, info)),
+            );
+        }
+        infos
+    }
+
+    /// Aggregate the activity of all the clients, including the disconnected ones.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn metrics(&self) -> RelayMetrics {
+        let mut metrics = self.removed_metrics;
+        for client in &self.clients {
+            metrics += client.borrow().metrics();
+        }
+        metrics
+    }
+
+    /// Earliest instant when a throttled client may be resumed, if any.
+    pub fn next_throttle_deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.borrow().throttled_until())
+            .min()
+    }
+
+    /// Earliest instant when a connection may expire, if any.
+    pub fn next_expiry_deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.borrow().next_expiry_deadline())
+            .min()
+    }
+
+    /// Resume the throttled clients whose delay elapsed.
+    ///
+    /// Return `true` if at least one client was resumed.
+    pub fn resume_throttled(&mut self, selector: &mut Selector) -> bool {
+        let mut resumed = false;
+        for client in &self.clients {
+            resumed |= client.borrow_mut().resume_throttled(selector);
+        }
+        resumed
+    }
+
+    /// Resume the clients blocked on a full connection, if it drained.
+    pub fn resume_blocked(&mut self, selector: &mut Selector) {
+        for client in &self.clients {
+            client.borrow_mut().resume_blocked(selector);
+        }
+    }
+
+    /// Create the sockets missing from the TCP socket pool (if any), between the iterations of the
+    /// poll loop.
+    pub fn refill_socket_pool(&mut self) {
+        if let Some(tcp_socket_pool) = &self.tcp_socket_pool {
+            tcp_socket_pool.borrow_mut().refill();
+        }
     }
 
     pub fn clean_up(&mut self, selector: &mut Selector) {
@@ -119,3 +300,29 @@ impl TunnelServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn listen_on_configured_address() {
+        let mut selector = Selector::create().unwrap();
+        let mut config = RelayConfig::new(0);
+        config.set_listen_address(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let tunnel_server = TunnelServer::create(
+            Rc::new(config),
+            None,
+            Vec::new(),
+            Vec::new(),
+            None,
+            None,
+            &mut selector,
+        )
+        .unwrap();
+        let local_addr = tunnel_server.borrow().local_addr().unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), local_addr.ip());
+        assert_ne!(0, local_addr.port());
+    }
+}
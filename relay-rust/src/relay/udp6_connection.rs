@@ -0,0 +1,398 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::UdpSocket;
+use mio::{Event, PollOpt, Ready, Token};
+use socket2::Type;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::net::SocketAddrV6;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use super::binary;
+use super::client::Client;
+use super::connection::{self, ConnectionStats};
+use super::datagram_buffer::DatagramBuffer;
+use super::ipv6_packet::Ipv6Packet;
+use super::ipv6_packetizer::Ipv6Packetizer;
+use super::net;
+use super::relay_config::RelayConfig;
+use super::selector::Selector;
+
+const TAG: &str = "Udp6Connection";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Udp6ConnectionId {
+    source: SocketAddrV6,
+    destination: SocketAddrV6,
+}
+
+impl Udp6ConnectionId {
+    /// Identify the UDP flow of `ipv6_packet`, if it is a valid UDP packet.
+    pub fn from_packet(ipv6_packet: &Ipv6Packet) -> Option<Self> {
+        let transport_header_data = ipv6_packet.transport_header_data()?;
+        let ipv6_header_data = ipv6_packet.ipv6_header_data();
+        Some(Self {
+            source: SocketAddrV6::new(
+                ipv6_header_data.source(),
+                transport_header_data.source_port(),
+                0,
+                0,
+            ),
+            destination: SocketAddrV6::new(
+                ipv6_header_data.destination(),
+                transport_header_data.destination_port(),
+                0,
+                0,
+            ),
+        })
+    }
+
+    pub fn source(&self) -> &SocketAddrV6 {
+        &self.source
+    }
+
+    pub fn destination(&self) -> &SocketAddrV6 {
+        &self.destination
+    }
+}
+
+impl fmt::Display for Udp6ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.source, self.destination)
+    }
+}
+
+/// UDP flow from the client over IPv6, relayed through an IPv6 socket.
+pub struct Udp6Connection {
+    id: Udp6ConnectionId,
+    self_weak: Weak<RefCell<Udp6Connection>>,
+    client: Weak<RefCell<Client>>,
+    socket: UdpSocket,
+    interests: Ready,
+    token: Token,
+    client_to_network: DatagramBuffer,
+    network_to_client: Ipv6Packetizer,
+    // the last send failed with ENOBUFS, do not poll for writability until the retry timer
+    no_buffer_space: bool,
+    closed: bool,
+    idle_since: Instant,
+    created_at: Instant,
+    idle_timeout: Duration,
+    stats: ConnectionStats,
+}
+
+impl Udp6Connection {
+    pub fn create(
+        selector: &mut Selector,
+        id: Udp6ConnectionId,
+        client: Weak<RefCell<Client>>,
+        config: &RelayConfig,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        cx_info!(target: TAG, id, "Open");
+        let socket = Self::create_socket(&id, config)?;
+        let packetizer = Ipv6Packetizer::new(id.source(), id.destination());
+        let mut client_to_network =
+            DatagramBuffer::with_max_datagrams(config.udp_buffer_datagrams());
+        client_to_network.set_policy(config.datagram_queue_policy());
+        let interests = Ready::readable();
+        let rc = Rc::new(RefCell::new(Self {
+            id,
+            self_weak: Weak::new(),
+            client,
+            socket,
+            interests,
+            token: Token(0), // default value, will be set afterwards
+            client_to_network,
+            network_to_client: packetizer,
+            no_buffer_space: false,
+            closed: false,
+            idle_since: Instant::now(),
+            created_at: Instant::now(),
+            idle_timeout: config.udp_idle_timeout(),
+            stats: ConnectionStats::default(),
+        }));
+
+        {
+            let mut self_ref = rc.borrow_mut();
+            self_ref.self_weak = Rc::downgrade(&rc);
+
+            let rc2 = rc.clone();
+            // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+            let handler =
+                move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+            let token =
+                selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
+            self_ref.token = token;
+        }
+        Ok(rc)
+    }
+
+    fn create_socket(id: &Udp6ConnectionId, config: &RelayConfig) -> io::Result<UdpSocket> {
+        let socket = net::create_outbound_socket_v6(config, Type::DGRAM, None)?;
+        let udp_socket = UdpSocket::from_socket(socket.into())?;
+        udp_socket.connect((*id.destination()).into())?;
+        Ok(udp_socket)
+    }
+
+    pub fn id(&self) -> &Udp6ConnectionId {
+        &self.id
+    }
+
+    fn remove_from_router(&self) {
+        // route is embedded in router which is embedded in client: the client necessarily exists
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        client.router().remove_udp6(self);
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        #[allow(clippy::match_wild_err_arm)]
+        match self.process(selector, event) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
+            }
+            Err(_) => panic!("Unexpected unhandled error"),
+        }
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
+        if !self.closed {
+            self.touch();
+            let ready = event.readiness();
+            if ready.is_readable() || ready.is_writable() {
+                if ready.is_writable() {
+                    self.process_send(selector)?;
+                    if self.no_buffer_space {
+                        self.schedule_send_retry();
+                    }
+                }
+                if !self.closed && ready.is_readable() {
+                    self.process_receive(selector)?;
+                }
+                if !self.closed {
+                    self.update_interests(selector);
+                }
+            } else {
+                // error or hup
+                self.close(selector);
+            }
+            if self.closed {
+                // on_ready is not called from the router, so the connection must remove itself
+                self.remove_from_router();
+            }
+        }
+        Ok(())
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_send(&mut self, selector: &mut Selector) -> io::Result<()> {
+        match self.write() {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
+            }
+            Err(ref err) if net::is_no_buffer_space(err) => {
+                // the datagrams are kept, and retried once the retry timer elapses
+                cx_debug!(target: TAG, self.id, "No buffer space, retrying later");
+                self.no_buffer_space = true;
+            }
+            Err(err) => {
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot write: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
+        match self.read(selector) {
+            Ok(_) => (),
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    // rethrow
+                    return Err(err);
+                }
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot read: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let ipv6_packet = self.network_to_client.packetize(&mut self.socket)?;
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        match client_rc
+            .borrow_mut()
+            .send_ipv6_to_client(selector, &ipv6_packet)
+        {
+            Ok(_) => {
+                self.stats
+                    .record_rx(ipv6_packet.payload().map_or(0, <[u8]>::len));
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Packet ({} bytes) sent to client",
+                    ipv6_packet.length()
+                );
+                if log_enabled!(target: TAG, Level::Trace) {
+                    cx_trace!(
+                        target: TAG,
+                        self.id,
+                        "{}",
+                        binary::build_trace_packet_string(ipv6_packet.raw())
+                    );
+                }
+            }
+            Err(_) => cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet"),
+        }
+        Ok(())
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        let (datagrams, bytes) = self.client_to_network.write_batch_to(&mut self.socket)?;
+        self.stats.record_tx_datagrams(datagrams, bytes);
+        Ok(())
+    }
+
+    /// Poll for writability again once `NO_BUFFER_SPACE_RETRY_DELAY` elapsed.
+    ///
+    /// To be used if called by on_ready() (so the client is not borrowed yet).
+    fn schedule_send_retry(&self) {
+        let deadline = Instant::now() + net::NO_BUFFER_SPACE_RETRY_DELAY;
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        client_rc
+            .borrow_mut()
+            .router()
+            .schedule_udp6_timeout(deadline, self.self_weak.clone());
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let ready = if self.client_to_network.is_empty() || self.no_buffer_space {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
+        if self.interests != ready {
+            // interests must be changed
+            self.interests = ready;
+            selector
+                .reregister(&self.socket, self.token, ready, PollOpt::level())
+                .expect("Cannot register on poll");
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+    }
+
+    pub fn send_to_network(&mut self, selector: &mut Selector, ipv6_packet: &Ipv6Packet) {
+        match self
+            .client_to_network
+            .read_from(ipv6_packet.payload().expect("No payload"))
+        {
+            Ok(evicted) => {
+                self.stats.record_tx_evicted(evicted);
+                self.update_interests(selector);
+            }
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.stats.record_tx_dropped();
+            }
+        }
+    }
+
+    /// Indicate whether the connection may accept `ipv6_packet` now.
+    pub fn can_accept(&self, ipv6_packet: &Ipv6Packet) -> bool {
+        let length = ipv6_packet.payload().map_or(0, <[u8]>::len);
+        self.client_to_network.accepts(length)
+    }
+
+    pub fn close(&mut self, selector: &mut Selector) {
+        cx_info!(target: TAG, self.id, "Close");
+        self.closed = true;
+        if let Err(err) = selector.deregister(&self.socket, self.token) {
+            // do not panic, this can happen in mio
+            // see <https://github.com/Genymobile/gnirehtet/issues/136>
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Fail to deregister UDP stream: {:?}",
+                err
+            );
+        }
+        // socket will be closed by RAII
+    }
+
+    pub fn is_expired(&self) -> bool {
+        connection::is_idle_expired(self.idle_since, self.idle_timeout)
+    }
+
+    /// Instant when the connection expires if it stays idle.
+    pub fn expiry(&self) -> Instant {
+        self.idle_since + self.idle_timeout
+    }
+
+    /// Handle the timers elapsed, called by the router at the deadlines scheduled by the
+    /// connection (unless it expired).
+    pub fn handle_timeout(&mut self, selector: &mut Selector) {
+        if self.no_buffer_space {
+            self.no_buffer_space = false;
+            self.update_interests(selector);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn idle_since(&self) -> Instant {
+        self.idle_since
+    }
+
+    pub fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+}
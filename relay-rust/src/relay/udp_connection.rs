@@ -17,36 +17,54 @@
 use log::*;
 use mio::net::UdpSocket;
 use mio::{Event, PollOpt, Ready, Token};
+use socket2::{Socket, Type};
 use std::cell::RefCell;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::SocketAddrV4;
 use std::rc::{Rc, Weak};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::connection::{self, Connection, ConnectionId, ConnectionStats};
+use super::datagram::DatagramSender;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::datagram::TtlReceiver;
 use super::datagram_buffer::DatagramBuffer;
-use super::ipv4_header::Ipv4Header;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
-use super::packetizer::Packetizer;
+use super::dns_cache::{SharedDnsCache, DNS_PORT};
+use super::icmp_error;
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData};
+use super::ipv4_packet::Ipv4Packet;
+use super::net;
+use super::packetizer::{PacketizeError, Packetizer};
+use super::relay_config::RelayConfig;
 use super::selector::Selector;
 use super::transport_header::TransportHeader;
 
 const TAG: &str = "UdpConnection";
 
-pub const IDLE_TIMEOUT_SECONDS: u64 = 2 * 60;
-
 pub struct UdpConnection {
     id: ConnectionId,
+    self_weak: Weak<RefCell<UdpConnection>>,
     client: Weak<RefCell<Client>>,
     socket: UdpSocket,
     interests: Ready,
     token: Token,
     client_to_network: DatagramBuffer,
     network_to_client: Packetizer,
+    // the last send failed with ENOBUFS, do not poll for writability until the retry timer
+    no_buffer_space: bool,
     closed: bool,
     idle_since: Instant,
+    created_at: Instant,
+    idle_timeout: Duration,
+    stats: ConnectionStats,
+    // to store the responses, if this connection is towards a DNS server
+    dns_cache: Option<SharedDnsCache>,
+    local_port: u16,
+    destination: SocketAddrV4,
+    // headers of the first datagram from the client, to build the ICMP errors sent back
+    client_headers: Vec<u8>,
 }
 
 impl UdpConnection {
@@ -57,25 +75,47 @@ impl UdpConnection {
         client: Weak<RefCell<Client>>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
+        config: &RelayConfig,
+        dns_cache: Option<SharedDnsCache>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let socket = Self::create_socket(&id)?;
-        let packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let destination = Self::network_destination_of(&id, config);
+        if destination != id.rewritten_destination() {
+            cx_debug!(target: TAG, id, "DNS query redirected to {}", destination);
+        }
+        let socket = Self::create_socket(&destination, config, ipv4_header.data())?;
+        let local_port = socket.local_addr()?.port();
+        let client_headers = Self::copy_headers(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header, config.mtu());
+        packetizer.set_preserve_ttl(config.preserve_ttl());
+        let mut client_to_network =
+            DatagramBuffer::with_max_datagrams(config.udp_buffer_datagrams());
+        client_to_network.set_policy(config.datagram_queue_policy());
         let interests = Ready::readable();
         let rc = Rc::new(RefCell::new(Self {
             id,
+            self_weak: Weak::new(),
             client,
             socket,
             interests,
             token: Token(0), // default value, will be set afterwards
-            client_to_network: DatagramBuffer::new(4 * MAX_PACKET_LENGTH),
+            client_to_network,
             network_to_client: packetizer,
+            no_buffer_space: false,
             closed: false,
             idle_since: Instant::now(),
+            created_at: Instant::now(),
+            idle_timeout: config.udp_idle_timeout(),
+            stats: ConnectionStats::default(),
+            dns_cache,
+            local_port,
+            destination,
+            client_headers,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
+            self_ref.self_weak = Rc::downgrade(&rc);
 
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
@@ -88,13 +128,54 @@ impl UdpConnection {
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<UdpSocket> {
-        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
-        let udp_socket = UdpSocket::bind(&autobind_addr)?;
-        udp_socket.connect(id.rewritten_destination().into())?;
+    // the DNS queries are sent to the configured resolver, if any; the responses are still
+    // packetized from the destination of the client
+    fn network_destination_of(id: &ConnectionId, config: &RelayConfig) -> SocketAddrV4 {
+        let destination = id.rewritten_destination();
+        match config.dns_upstream() {
+            Some(dns_upstream) if destination.port() == DNS_PORT => dns_upstream,
+            _ => destination,
+        }
+    }
+
+    fn create_socket(
+        destination: &SocketAddrV4,
+        config: &RelayConfig,
+        ipv4_header_data: &Ipv4HeaderData,
+    ) -> io::Result<UdpSocket> {
+        let socket = net::create_outbound_socket(config, Type::DGRAM, None)?;
+        net::set_outbound_tos(&socket, config, ipv4_header_data)?;
+        if config.preserve_ttl() {
+            Self::enable_received_ttl(&socket)?;
+        }
+        let udp_socket = UdpSocket::from_socket(socket.into())?;
+        udp_socket.connect((*destination).into())?;
         Ok(udp_socket)
     }
 
+    // the TTL of the datagrams received is only reported on Linux
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn enable_received_ttl(socket: &Socket) -> io::Result<()> {
+        net::set_socket_option(socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn enable_received_ttl(_: &Socket) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn copy_headers(ipv4_header: &Ipv4Header, transport_header: &TransportHeader) -> Vec<u8> {
+        let mut raw = [ipv4_header.raw(), transport_header.raw()].concat();
+        let ipv4_header_length = ipv4_header.header_length() as usize;
+        let total_length = raw.len() as u16;
+        // the payload is not kept
+        let mut ipv4_header_data = Ipv4HeaderData::parse(&raw);
+        let mut ipv4_header = ipv4_header_data.bind_mut(&mut raw[..ipv4_header_length]);
+        ipv4_header.set_total_length(total_length);
+        ipv4_header.update_checksum();
+        raw
+    }
+
     fn remove_from_router(&self) {
         // route is embedded in router which is embedded in client: the client necessarily exists
         let client_rc = self.client.upgrade().expect("Expected client not found");
@@ -121,6 +202,9 @@ impl UdpConnection {
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     self.process_send(selector)?;
+                    if self.no_buffer_space {
+                        self.schedule_send_retry();
+                    }
                 }
                 if !self.closed && ready.is_readable() {
                     self.process_receive(selector)?;
@@ -142,11 +226,32 @@ impl UdpConnection {
 
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process_send(&mut self, selector: &mut Selector) -> io::Result<()> {
-        match self.write() {
+        let result = Self::write(
+            &mut self.client_to_network,
+            &mut self.socket,
+            &mut self.stats,
+        );
+        self.handle_send_result(selector, result)
+    }
+
+    fn handle_send_result(
+        &mut self,
+        selector: &mut Selector,
+        result: io::Result<()>,
+    ) -> io::Result<()> {
+        match result {
             Ok(_) => (),
             Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
                 cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
             }
+            Err(ref err) if net::is_no_buffer_space(err) => {
+                // the datagrams are kept, and retried once the retry timer elapses
+                cx_debug!(target: TAG, self.id, "No buffer space, retrying later");
+                self.no_buffer_space = true;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                self.port_unreachable(selector);
+            }
             Err(err) => {
                 if err.kind() == io::ErrorKind::WouldBlock {
                     // rethrow
@@ -169,6 +274,9 @@ impl UdpConnection {
     fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
         match self.read(selector) {
             Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                self.port_unreachable(selector);
+            }
             Err(err) => {
                 if err.kind() == io::ErrorKind::WouldBlock {
                     // rethrow
@@ -187,14 +295,48 @@ impl UdpConnection {
         Ok(())
     }
 
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn packetize<'a>(
+        packetizer: &'a mut Packetizer,
+        socket: &mut UdpSocket,
+    ) -> Result<Ipv4Packet<'a>, PacketizeError> {
+        if packetizer.preserves_ttl() {
+            packetizer.packetize(&mut TtlReceiver::new(socket))
+        } else {
+            packetizer.packetize(socket)
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn packetize<'a>(
+        packetizer: &'a mut Packetizer,
+        socket: &mut UdpSocket,
+    ) -> Result<Ipv4Packet<'a>, PacketizeError> {
+        packetizer.packetize(socket)
+    }
+
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self.network_to_client.packetize(&mut self.socket)?;
+        let ipv4_packet = match Self::packetize(&mut self.network_to_client, &mut self.socket) {
+            Ok(ipv4_packet) => ipv4_packet,
+            Err(PacketizeError::Io(err)) => return Err(err),
+            Err(err) => {
+                cx_warn!(target: TAG, self.id, "Dropping datagram: {}", err);
+                return Ok(());
+            }
+        };
+        if let Some(ref dns_cache) = self.dns_cache {
+            if let Some(payload) = ipv4_packet.payload() {
+                dns_cache.borrow_mut().store(payload, Instant::now());
+            }
+        }
         let client_rc = self.client.upgrade().expect("Expected client not found");
         match client_rc
             .borrow_mut()
             .send_to_client(selector, &ipv4_packet)
         {
             Ok(_) => {
+                self.stats
+                    .record_rx(ipv4_packet.payload().map_or(0, <[u8]>::len));
                 cx_debug!(
                     target: TAG,
                     self.id,
@@ -206,7 +348,7 @@ impl UdpConnection {
                         target: TAG,
                         self.id,
                         "{}",
-                        binary::build_packet_string(ipv4_packet.raw())
+                        binary::build_trace_packet_string(ipv4_packet.raw())
                     );
                 }
             }
@@ -215,13 +357,39 @@ impl UdpConnection {
         Ok(())
     }
 
-    fn write(&mut self) -> io::Result<()> {
-        self.client_to_network.write_to(&mut self.socket)?;
+    // the fields are borrowed separately, so that the socket may be substituted in tests
+    fn write<S: DatagramSender>(
+        client_to_network: &mut DatagramBuffer,
+        socket: &mut S,
+        stats: &mut ConnectionStats,
+    ) -> io::Result<()> {
+        let (datagrams, bytes) = client_to_network.write_batch_to(socket)?;
+        stats.record_tx_datagrams(datagrams, bytes);
         Ok(())
     }
 
+    /// Poll for writability again once `NO_BUFFER_SPACE_RETRY_DELAY` elapsed.
+    ///
+    /// To be used if called by on_ready() (so the client is not borrowed yet).
+    fn schedule_send_retry(&self) {
+        let deadline = Instant::now() + net::NO_BUFFER_SPACE_RETRY_DELAY;
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let connection: Weak<RefCell<dyn Connection>> = self.self_weak.clone();
+        client_rc
+            .borrow_mut()
+            .router()
+            .schedule_timeout(deadline, connection);
+    }
+
+    fn retry_send(&mut self, selector: &mut Selector) {
+        if self.no_buffer_space {
+            self.no_buffer_space = false;
+            self.update_interests(selector);
+        }
+    }
+
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
+        let ready = if self.client_to_network.is_empty() || self.no_buffer_space {
             Ready::readable()
         } else {
             Ready::readable() | Ready::writable()
@@ -236,6 +404,32 @@ impl UdpConnection {
         }
     }
 
+    // the kernel reports the ICMP Port Unreachable received for a connected socket as an error
+    fn port_unreachable(&mut self, selector: &mut Selector) {
+        cx_debug!(target: TAG, self.id, "Port unreachable");
+        let mut raw = self.build_port_unreachable_packet();
+        let error_packet = Ipv4Packet::parse(&mut raw);
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        if let Err(err) = client_rc
+            .borrow_mut()
+            .send_to_client(selector, &error_packet)
+        {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send Port Unreachable to client: {}",
+                err
+            );
+        }
+        self.close(selector);
+    }
+
+    fn build_port_unreachable_packet(&self) -> Vec<u8> {
+        let mut original_raw = self.client_headers.clone();
+        let original = Ipv4Packet::parse(&mut original_raw);
+        icmp_error::build_destination_unreachable(&original, icmp_error::CODE_PORT_UNREACHABLE)
+    }
+
     fn touch(&mut self) {
         self.idle_since = Instant::now();
     }
@@ -256,18 +450,27 @@ impl Connection for UdpConnection {
             .client_to_network
             .read_from(ipv4_packet.payload().expect("No payload"))
         {
-            Ok(_) => {
+            Ok(evicted) => {
+                self.stats.record_tx_evicted(evicted);
                 self.update_interests(selector);
             }
-            Err(err) => cx_warn!(
-                target: TAG,
-                self.id,
-                "Cannot send to network, drop packet: {}",
-                err
-            ),
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.stats.record_tx_dropped();
+            }
         }
     }
 
+    fn can_accept(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let length = ipv4_packet.payload().map_or(0, <[u8]>::len);
+        self.client_to_network.accepts(length)
+    }
+
     fn close(&mut self, selector: &mut Selector) {
         cx_info!(target: TAG, self.id, "Close");
         self.closed = true;
@@ -285,10 +488,218 @@ impl Connection for UdpConnection {
     }
 
     fn is_expired(&self) -> bool {
-        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+        connection::is_idle_expired(self.idle_since, self.idle_timeout)
+    }
+
+    fn expiry(&self) -> Option<Instant> {
+        Some(self.idle_since + self.idle_timeout)
+    }
+
+    fn handle_timeout(&mut self, selector: &mut Selector, _: &mut ClientChannel) {
+        self.retry_send(selector);
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn idle_since(&self) -> Instant {
+        self.idle_since
+    }
+
+    fn created_at(&self) -> Instant {
+        self.created_at
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    fn local_port(&self) -> Option<u16> {
+        Some(self.local_port)
+    }
+
+    fn network_destination(&self) -> SocketAddrV4 {
+        self.destination
+    }
+
+    fn build_port_unreachable(&self) -> Option<Vec<u8>> {
+        Some(self.build_port_unreachable_packet())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn create_udp_packet() -> Vec<u8> {
+        let mut raw = Vec::with_capacity(28);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length 20 + 8
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7F000001).unwrap(); // destination address (localhost)
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(8).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw
+    }
+
+    fn connection_id_to(destination: u32, destination_port: u16) -> ConnectionId {
+        let mut raw = create_udp_packet();
+        raw[16..20].copy_from_slice(&destination.to_be_bytes());
+        raw[22..24].copy_from_slice(&destination_port.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    #[test]
+    fn redirect_dns_to_upstream() {
+        let mut config = RelayConfig::new(31416);
+        let dns_id = connection_id_to(0x0A000001, 53);
+        let other_id = connection_id_to(0x0A000001, 5678);
+        let original: SocketAddrV4 = "10.0.0.1:53".parse().unwrap();
+        assert_eq!(
+            original,
+            UdpConnection::network_destination_of(&dns_id, &config)
+        );
+
+        let upstream = "1.1.1.1:53".parse().unwrap();
+        config.set_dns_upstream(Some(upstream));
+        assert_eq!(
+            upstream,
+            UdpConnection::network_destination_of(&dns_id, &config)
+        );
+        // only the DNS queries are redirected
+        assert_eq!(
+            "10.0.0.1:5678".parse::<SocketAddrV4>().unwrap(),
+            UdpConnection::network_destination_of(&other_id, &config)
+        );
+    }
+
+    #[test]
+    fn expire_after_configured_idle_timeout() {
+        let mut raw = create_udp_packet();
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+
+        let mut config = RelayConfig::new(31416);
+        config.set_udp_idle_timeout(Duration::from_secs(10));
+
+        let mut selector = Selector::create().unwrap();
+        let connection = UdpConnection::create(
+            &mut selector,
+            id,
+            Weak::new(),
+            ipv4_header,
+            transport_header.unwrap(),
+            &config,
+            None,
+        )
+        .unwrap();
+        let mut connection = connection.borrow_mut();
+        assert!(!connection.is_expired());
+
+        connection.idle_since = Instant::now() - Duration::from_secs(5);
+        assert!(!connection.is_expired());
+
+        // the default timeout (2 minutes) would not have expired yet
+        connection.idle_since = Instant::now() - Duration::from_secs(11);
+        assert!(connection.is_expired());
+
+        connection.close(&mut selector);
+    }
+
+    // socket failing once with ENOBUFS, as if the interface queue was full
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    struct NoBufferSpaceOnce<'a> {
+        socket: &'a UdpSocket,
+        failed: bool,
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    impl DatagramSender for NoBufferSpaceOnce<'_> {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+            }
+            self.socket.send(buf)
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn retry_send_without_buffer_space() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        let mut raw = create_udp_packet();
+        raw[22..24].copy_from_slice(&port.to_be_bytes());
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+
+        let config = RelayConfig::new(31416);
+        let mut selector = Selector::create().unwrap();
+        let connection = UdpConnection::create(
+            &mut selector,
+            id,
+            Weak::new(),
+            ipv4_header,
+            transport_header.unwrap(),
+            &config,
+            None,
+        )
+        .unwrap();
+        let mut connection = connection.borrow_mut();
+        let connection = &mut *connection;
+        connection.client_to_network.read_from(b"hello").unwrap();
+
+        let mut socket = NoBufferSpaceOnce {
+            socket: &connection.socket,
+            failed: false,
+        };
+        let result = UdpConnection::write(
+            &mut connection.client_to_network,
+            &mut socket,
+            &mut connection.stats,
+        );
+        connection
+            .handle_send_result(&mut selector, result)
+            .unwrap();
+        // the datagram is kept
+        assert!(!connection.is_closed());
+        assert!(!connection.client_to_network.is_empty());
+        // the socket is not polled for writability until the retry timer elapses
+        connection.update_interests(&mut selector);
+        assert!(!connection.interests.is_writable());
+        connection.retry_send(&mut selector);
+        assert!(connection.interests.is_writable());
+
+        connection.process_send(&mut selector).unwrap();
+        assert!(connection.client_to_network.is_empty());
+        let mut buf = [0u8; 16];
+        let size = receiver.recv(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert_eq!(1, connection.stats.tx_packets);
+
+        connection.close(&mut selector);
+    }
 }
@@ -47,6 +47,14 @@ impl UdpHeaderData {
         }
     }
 
+    /// Parse a header received from the outside, `None` if it is truncated.
+    pub fn try_parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < UDP_HEADER_LENGTH as usize {
+            return None;
+        }
+        Some(Self::parse(raw))
+    }
+
     #[inline]
     pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> UdpHeader<'c> {
         UdpHeader::new(raw, self)
@@ -76,8 +84,8 @@ macro_rules! udp_header_common {
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
                 Self {
-                    raw: raw,
-                    data: data,
+                    raw,
+                    data,
                 }
             }
 
@@ -163,8 +171,7 @@ mod tests {
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(8);
+        let mut raw = Vec::with_capacity(8);
         raw.write_u16::<BigEndian>(1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
         raw.write_u16::<BigEndian>(42).unwrap(); // length